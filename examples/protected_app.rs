@@ -0,0 +1,54 @@
+//! Minimal actix app reusing the library middleware.
+//!
+//! ```text
+//! TENANT_ID=<tenant> API_AUDIENCE=api://<app-id> cargo run --example protected_app
+//! ```
+
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use managed_identity_concept::authority::Cloud;
+use managed_identity_concept::{
+    Authority, BearerAuth, BearerAuthConfig, Claims, JwtValidator, Principal,
+};
+
+async fn whoami(claims: Claims) -> impl Responder {
+    HttpResponse::Ok().json(claims)
+}
+
+async fn me(principal: Principal) -> impl Responder {
+    HttpResponse::Ok().json(principal)
+}
+
+async fn public() -> impl Responder {
+    HttpResponse::Ok().body("Hello from a public route")
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+    dotenv::dotenv().ok();
+
+    let tenant_id = std::env::var("TENANT_ID")?;
+    let audience = std::env::var("API_AUDIENCE")?;
+    let authority = Authority::AzureAd {
+        tenant_id: tenant_id.clone(),
+        cloud: Cloud::Public,
+    };
+
+    let validator = JwtValidator::for_tenant(authority.jwks_url(), audience, &tenant_id);
+    let config =
+        BearerAuthConfig::new(validator).with_required_roles(vec!["Task.HelloWorld".to_string()]);
+
+    HttpServer::new(move || {
+        App::new().route("/public", web::get().to(public)).service(
+            web::scope("/api")
+                .wrap(BearerAuth::new(config.clone()))
+                .route("/whoami", web::get().to(whoami))
+                .route("/me", web::get().to(me)),
+        )
+    })
+    .bind("127.0.0.1:8080")?
+    .run()
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::claims::Claims;
+
+/// The longest rule accepted, in bytes.
+pub const MAX_RULE_LENGTH: usize = 1024;
+
+/// The deepest nesting of parentheses and `NOT` accepted.
+pub const MAX_RULE_DEPTH: usize = 16;
+
+/// A boolean rule over the roles, scopes and groups of a token, e.g.
+/// `role:Admin OR (scope:Data.Read AND group:Analysts)`, see
+/// `BearerAuthConfig::with_access_rule`.
+///
+/// The grammar has three kinds of terms and three operators, nothing else:
+///
+/// * `role:<value>` - `roles` contains the value.
+/// * `scope:<value>` - the space-separated `scp` contains the value.
+/// * `group:<value>` - `groups` contains the value, ignoring case as group object IDs are GUIDs.
+/// * `NOT`, `AND` and `OR`, binding in that order from tightest to loosest, and parentheses.
+///
+/// Operators are matched ignoring case; values are compared exactly and end at whitespace or a
+/// parenthesis. Rules are limited to `MAX_RULE_LENGTH` bytes and `MAX_RULE_DEPTH` levels of
+/// nesting, so parsing and evaluating a rule is always cheap. Displayed and serialized as
+/// written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRule {
+    source: String,
+    root: Node,
+}
+
+/// A parsed `AccessRule`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Role(String),
+    Scope(String),
+    Group(String),
+    Not(Box<Node>),
+    All(Vec<Node>),
+    Any(Vec<Node>),
+}
+
+impl AccessRule {
+    /// Whether `claims` satisfy the rule.
+    pub fn evaluate(&self, claims: &Claims) -> bool {
+        self.root.evaluate(claims)
+    }
+}
+
+impl Node {
+    fn evaluate(&self, claims: &Claims) -> bool {
+        match self {
+            Node::Role(role) => claims
+                .roles
+                .as_deref()
+                .unwrap_or_default()
+                .iter()
+                .any(|held| held == role),
+            Node::Scope(scope) => claims
+                .scp
+                .as_deref()
+                .unwrap_or_default()
+                .split(' ')
+                .any(|held| held == scope),
+            Node::Group(group) => claims
+                .groups
+                .as_deref()
+                .unwrap_or_default()
+                .iter()
+                .any(|held| held.eq_ignore_ascii_case(group)),
+            Node::Not(node) => !node.evaluate(claims),
+            Node::All(nodes) => nodes.iter().all(|node| node.evaluate(claims)),
+            Node::Any(nodes) => nodes.iter().any(|node| node.evaluate(claims)),
+        }
+    }
+}
+
+impl fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Serialized as written, e.g. `"role:Admin OR scope:Data.Read"`.
+impl Serialize for AccessRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for AccessRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.len() > MAX_RULE_LENGTH {
+            return Err(format!(
+                "access rule is longer than {} bytes",
+                MAX_RULE_LENGTH
+            ));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(value),
+            position: 0,
+        };
+        let root = parser.any(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?} in access rule {:?}", token, value));
+        }
+        Ok(Self {
+            source: value.trim().to_string(),
+            root,
+        })
+    }
+}
+
+/// Splits a rule into parentheses and the words between them.
+fn tokenize(value: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (index, c) in value.char_indices() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if let Some(start) = start.take() {
+                tokens.push(&value[start..index]);
+            }
+            if !c.is_whitespace() {
+                tokens.push(&value[index..index + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(index);
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&value[start..]);
+    }
+    tokens
+}
+
+/// A recursive descent parser over the tokens of a rule, one method per precedence level.
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    /// Consumes the next token if it is the operator `keyword`.
+    fn eat(&mut self, keyword: &str) -> bool {
+        let matched = self
+            .peek()
+            .is_some_and(|token| token.eq_ignore_ascii_case(keyword));
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    /// `all ( OR all )*`
+    fn any(&mut self, depth: usize) -> Result<Node, String> {
+        let mut nodes = vec![self.all(depth)?];
+        while self.eat("OR") {
+            nodes.push(self.all(depth)?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            Node::Any(nodes)
+        })
+    }
+
+    /// `unary ( AND unary )*`
+    fn all(&mut self, depth: usize) -> Result<Node, String> {
+        let mut nodes = vec![self.unary(depth)?];
+        while self.eat("AND") {
+            nodes.push(self.unary(depth)?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            Node::All(nodes)
+        })
+    }
+
+    /// `NOT unary | ( any ) | term`
+    fn unary(&mut self, depth: usize) -> Result<Node, String> {
+        if depth >= MAX_RULE_DEPTH {
+            return Err(format!(
+                "access rule is nested deeper than {} levels",
+                MAX_RULE_DEPTH
+            ));
+        }
+        if self.eat("NOT") {
+            return Ok(Node::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        match self.next() {
+            Some("(") => {
+                let node = self.any(depth + 1)?;
+                match self.next() {
+                    Some(")") => Ok(node),
+                    Some(token) => Err(format!("expected ')' in access rule, found {:?}", token)),
+                    None => Err("unclosed '(' in access rule".to_string()),
+                }
+            }
+            Some(token) => term(token),
+            None => Err("access rule ends where a term is expected".to_string()),
+        }
+    }
+}
+
+/// Parses a `kind:value` term.
+fn term(token: &str) -> Result<Node, String> {
+    let operator = ["AND", "OR", "NOT", ")"]
+        .iter()
+        .any(|operator| token.eq_ignore_ascii_case(operator));
+    if operator {
+        return Err(format!("expected a term in access rule, found {:?}", token));
+    }
+    let (kind, value) = token
+        .split_once(':')
+        .filter(|(_, value)| !value.is_empty())
+        .ok_or_else(|| format!("{:?} is not role:, scope: or group: with a value", token))?;
+    match kind.to_ascii_lowercase().as_str() {
+        "role" => Ok(Node::Role(value.to_string())),
+        "scope" => Ok(Node::Scope(value.to_string())),
+        "group" => Ok(Node::Group(value.to_string())),
+        _ => Err(format!(
+            "unknown term kind {:?} in access rule, expected role, scope or group",
+            kind
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rule: &str) -> Node {
+        rule.parse::<AccessRule>().unwrap().root
+    }
+
+    fn role(value: &str) -> Node {
+        Node::Role(value.to_string())
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_than_or() {
+        assert_eq!(
+            parse("role:A OR role:B AND NOT role:C"),
+            Node::Any(vec![
+                role("A"),
+                Node::All(vec![role("B"), Node::Not(Box::new(role("C")))]),
+            ])
+        );
+        assert_eq!(
+            parse("NOT role:A AND role:B or role:C"),
+            Node::Any(vec![
+                Node::All(vec![Node::Not(Box::new(role("A"))), role("B")]),
+                role("C"),
+            ])
+        );
+    }
+
+    #[test]
+    fn parentheses_group_terms() {
+        assert_eq!(
+            parse("(role:A OR role:B) AND role:C"),
+            Node::All(vec![Node::Any(vec![role("A"), role("B")]), role("C")])
+        );
+        assert_eq!(
+            parse("NOT (role:A OR scope:Data.Read)"),
+            Node::Not(Box::new(Node::Any(vec![
+                role("A"),
+                Node::Scope("Data.Read".to_string()),
+            ])))
+        );
+        assert!("(role:A OR role:B".parse::<AccessRule>().is_err());
+        assert!("role:A)".parse::<AccessRule>().is_err());
+    }
+
+    #[test]
+    fn rules_evaluate_against_the_claims() {
+        let claims: Claims = serde_json::from_value(serde_json::json!({
+            "aud": "api://test",
+            "iss": "https://sts.windows.net/test-tenant/",
+            "sub": "test-subject",
+            "exp": 0,
+            "roles": ["Reader"],
+            "scp": "Data.Read Data.Write",
+            "groups": ["6A1B0C2D-0000-0000-0000-000000000000"],
+        }))
+        .unwrap();
+        let evaluate = |rule: &str| rule.parse::<AccessRule>().unwrap().evaluate(&claims);
+        assert!(evaluate("role:Reader"));
+        assert!(!evaluate("role:reader"));
+        assert!(evaluate(
+            "scope:Data.Write AND group:6a1b0c2d-0000-0000-0000-000000000000"
+        ));
+        assert!(!evaluate("role:Admin OR NOT scope:Data.Read"));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nots = |depth: usize| format!("{}role:A", "NOT ".repeat(depth));
+        assert!(nots(MAX_RULE_DEPTH - 1).parse::<AccessRule>().is_ok());
+        assert!(nots(MAX_RULE_DEPTH).parse::<AccessRule>().is_err());
+
+        let parentheses =
+            |depth: usize| format!("{}role:A{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parentheses(MAX_RULE_DEPTH - 1)
+            .parse::<AccessRule>()
+            .is_ok());
+        assert!(parentheses(MAX_RULE_DEPTH).parse::<AccessRule>().is_err());
+    }
+
+    #[test]
+    fn length_is_limited() {
+        let rule = |length: usize| format!("role:{}", "A".repeat(length - "role:".len()));
+        assert!(rule(MAX_RULE_LENGTH).parse::<AccessRule>().is_ok());
+        assert_eq!(
+            rule(MAX_RULE_LENGTH + 1).parse::<AccessRule>(),
+            Err(format!(
+                "access rule is longer than {} bytes",
+                MAX_RULE_LENGTH
+            ))
+        );
+    }
+
+    #[test]
+    fn only_known_term_kinds_with_values_are_accepted() {
+        for rule in ["user:alice", "role", "role:", ":Admin", "AND", ""] {
+            assert!(rule.parse::<AccessRule>().is_err(), "{:?}", rule);
+        }
+        assert_eq!(
+            parse("ROLE:Admin OR Group:g"),
+            Node::Any(vec![role("Admin"), Node::Group("g".to_string())])
+        );
+    }
+
+    #[test]
+    fn trailing_tokens_are_refused() {
+        for rule in [
+            "role:A role:B",
+            "role:A OR",
+            "role:A AND NOT",
+            "(role:A) role:B",
+        ] {
+            assert!(rule.parse::<AccessRule>().is_err(), "{:?}", rule);
+        }
+        assert_eq!(
+            "role:A role:B".parse::<AccessRule>(),
+            Err("unexpected \"role:B\" in access rule \"role:A role:B\"".to_string())
+        );
+    }
+
+    #[test]
+    fn rules_display_as_written() {
+        let rule: AccessRule = "  role:Admin OR (scope:Data.Read AND group:g) "
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "role:Admin OR (scope:Data.Read AND group:g)"
+        );
+        assert_eq!(
+            serde_json::to_value(&rule).unwrap(),
+            "role:Admin OR (scope:Data.Read AND group:g)"
+        );
+    }
+}
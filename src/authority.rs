@@ -0,0 +1,279 @@
+use std::str::FromStr;
+
+/// The Azure cloud an `AzureAd` authority lives in.
+///
+/// # Variants
+///
+/// * `Public` - The global Azure cloud, the default.
+/// * `UsGov` - Azure Government.
+/// * `China` - Azure China, operated by 21Vianet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cloud {
+    #[default]
+    Public,
+    UsGov,
+    China,
+}
+
+impl Cloud {
+    /// The cloud named by `AZURE_CLOUD` (`public`, `usgov` or `china`), `Public` when unset.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("AZURE_CLOUD") {
+            Ok(cloud) => cloud.parse(),
+            Err(_) => Ok(Cloud::Public),
+        }
+    }
+
+    /// The login host issuing v2.0 tokens and serving discovery and keys.
+    pub fn login_host(self) -> &'static str {
+        match self {
+            Cloud::Public => "login.microsoftonline.com",
+            Cloud::UsGov => "login.microsoftonline.us",
+            Cloud::China => "login.partner.microsoftonline.cn",
+        }
+    }
+
+    /// The authority tokens are requested from, e.g. `https://login.microsoftonline.com`.
+    pub fn authority_url(self) -> String {
+        format!("https://{}", self.login_host())
+    }
+
+    /// The host serving the signing keys, which is the login host in every cloud.
+    pub fn jwks_host(self) -> &'static str {
+        self.login_host()
+    }
+
+    /// The security token service host named in v1.0 issuers.
+    pub fn sts_host(self) -> &'static str {
+        match self {
+            Cloud::Public | Cloud::UsGov => "sts.windows.net",
+            Cloud::China => "sts.chinacloudapi.cn",
+        }
+    }
+
+    /// The Microsoft Graph endpoint of this cloud.
+    pub fn graph_url(self) -> &'static str {
+        match self {
+            Cloud::Public => "https://graph.microsoft.com",
+            Cloud::UsGov => "https://graph.microsoft.us",
+            Cloud::China => "https://microsoftgraph.chinacloudapi.cn",
+        }
+    }
+
+    /// The `iss` of `version` tokens for `tenant_id`: `https://<sts host>/<tenant>/` for v1.0
+    /// and `https://<login host>/<tenant>/v2.0` for v2.0.
+    pub fn issuer(self, version: TokenVersion, tenant_id: &str) -> String {
+        match version {
+            TokenVersion::V1 => format!("https://{}/{}/", self.sts_host(), tenant_id),
+            TokenVersion::V2 => format!("https://{}/{}/v2.0", self.login_host(), tenant_id),
+        }
+    }
+}
+
+impl FromStr for Cloud {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "public" => Ok(Cloud::Public),
+            "usgov" => Ok(Cloud::UsGov),
+            "china" => Ok(Cloud::China),
+            other => Err(format!("Unsupported cloud: {}", other)),
+        }
+    }
+}
+
+/// The `ver` of an Azure AD access token, which decides the shape of its issuer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenVersion {
+    V1,
+    V2,
+}
+
+impl TokenVersion {
+    /// Both versions, for APIs that accept either.
+    pub const ALL: [TokenVersion; 2] = [TokenVersion::V1, TokenVersion::V2];
+
+    /// The token versions to accept given an optional required `ver` (`1.0` or `2.0`).
+    pub fn accepted(required: Option<&str>) -> Vec<TokenVersion> {
+        match required {
+            Some("1.0") => vec![TokenVersion::V1],
+            Some("2.0") => vec![TokenVersion::V2],
+            _ => Self::ALL.to_vec(),
+        }
+    }
+
+    /// The `ver` claim of tokens of this version.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenVersion::V1 => "1.0",
+            TokenVersion::V2 => "2.0",
+        }
+    }
+}
+
+/// Builds the issuers Azure AD puts in the `iss` claim of tokens for `tenant_id`, see
+/// `Cloud::issuer`.
+///
+/// # Arguments
+///
+/// * `tenant_id` - The directory (tenant) ID.
+/// * `cloud` - The Azure cloud the tenant lives in.
+/// * `token_versions` - The token versions to build issuers for.
+pub fn expected_issuers(
+    tenant_id: &str,
+    cloud: Cloud,
+    token_versions: &[TokenVersion],
+) -> Vec<String> {
+    token_versions
+        .iter()
+        .map(|version| cloud.issuer(*version, tenant_id))
+        .collect()
+}
+
+/// The identity provider that issues the tokens accepted by the API.
+///
+/// # Variants
+///
+/// * `AzureAd` - Workforce Azure AD (Entra ID), the default, in the given cloud.
+/// * `B2C` - Azure AD B2C, where keys and issuer are scoped to a user flow / custom policy.
+#[derive(Debug, Clone)]
+pub enum Authority {
+    AzureAd {
+        tenant_id: String,
+        cloud: Cloud,
+    },
+    B2C {
+        tenant_id: String,
+        tenant_name: String,
+        policy: String,
+        custom_domain: Option<String>,
+    },
+}
+
+impl Authority {
+    /// Builds the authority from `AUTH_MODE` (`aad` or `b2c`) and the related environment variables.
+    ///
+    /// AAD mode reads the optional `AZURE_CLOUD` (`public`, `usgov` or `china`).
+    /// B2C mode reads `B2C_TENANT_NAME` (e.g. `contoso`), `B2C_POLICY` (e.g. `B2C_1_signin`) and
+    /// the optional `B2C_CUSTOM_DOMAIN` (e.g. `login.contoso.com`).
+    pub fn from_env(tenant_id: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mode = std::env::var("AUTH_MODE").unwrap_or_else(|_| "aad".to_string());
+        match mode.to_ascii_lowercase().as_str() {
+            "aad" => Ok(Authority::AzureAd {
+                tenant_id: tenant_id.to_string(),
+                cloud: Cloud::from_env()?,
+            }),
+            "b2c" => Ok(Authority::B2C {
+                tenant_id: tenant_id.to_string(),
+                tenant_name: std::env::var("B2C_TENANT_NAME")?,
+                policy: std::env::var("B2C_POLICY")?,
+                custom_domain: std::env::var("B2C_CUSTOM_DOMAIN").ok(),
+            }),
+            other => Err(format!("Unsupported AUTH_MODE: {}", other).into()),
+        }
+    }
+
+    /// The host serving B2C endpoints, either the custom domain or `<tenant>.b2clogin.com`.
+    fn b2c_host(tenant_name: &str, custom_domain: &Option<String>) -> String {
+        custom_domain
+            .clone()
+            .unwrap_or_else(|| format!("{}.b2clogin.com", tenant_name))
+    }
+
+    /// The OpenID Connect discovery document URL for this authority.
+    pub fn discovery_url(&self) -> String {
+        match self {
+            Authority::AzureAd { tenant_id, cloud } => format!(
+                "{}/{}/v2.0/.well-known/openid-configuration",
+                cloud.authority_url(),
+                tenant_id
+            ),
+            Authority::B2C {
+                tenant_name,
+                policy,
+                custom_domain,
+                ..
+            } => format!(
+                "https://{}/{}.onmicrosoft.com/{}/v2.0/.well-known/openid-configuration",
+                Self::b2c_host(tenant_name, custom_domain),
+                tenant_name,
+                policy
+            ),
+        }
+    }
+
+    /// The JWKS URL for this authority.
+    pub fn jwks_url(&self) -> String {
+        match self {
+            Authority::AzureAd { tenant_id, cloud } => format!(
+                "https://{}/{}/discovery/v2.0/keys",
+                cloud.jwks_host(),
+                tenant_id
+            ),
+            Authority::B2C {
+                tenant_name,
+                policy,
+                custom_domain,
+                ..
+            } => format!(
+                "https://{}/{}.onmicrosoft.com/{}/discovery/v2.0/keys",
+                Self::b2c_host(tenant_name, custom_domain),
+                tenant_name,
+                policy
+            ),
+        }
+    }
+
+    /// The WS-Federation metadata URL of this authority, which lists the same signing keys as
+    /// the JWKS as X.509 certificates. `None` for B2C, which publishes none.
+    pub fn federation_metadata_url(&self) -> Option<String> {
+        match self {
+            Authority::AzureAd { tenant_id, cloud } => Some(format!(
+                "{}/{}/federationmetadata/2007-06/federationmetadata.xml",
+                cloud.authority_url(),
+                tenant_id
+            )),
+            Authority::B2C { .. } => None,
+        }
+    }
+
+    /// The issuer of each token version, as `(ver, iss)` pairs for
+    /// `JwtValidator::with_version_issuers`.
+    ///
+    /// Empty for B2C, whose access tokens carry `ver` `1.0` with a v2.0-style issuer.
+    pub fn issuers_by_version(&self) -> Vec<(String, String)> {
+        match self {
+            Authority::AzureAd { .. } => TokenVersion::ALL
+                .iter()
+                .flat_map(|version| {
+                    self.issuers(&[*version])
+                        .into_iter()
+                        .map(|issuer| (version.as_str().to_string(), issuer))
+                })
+                .collect(),
+            Authority::B2C { .. } => Vec::new(),
+        }
+    }
+
+    /// The issuers accepted in the `iss` claim of tokens of the given versions.
+    ///
+    /// B2C only issues v2.0 tokens, so its single issuer is returned regardless of `token_versions`.
+    pub fn issuers(&self, token_versions: &[TokenVersion]) -> Vec<String> {
+        match self {
+            Authority::AzureAd { tenant_id, cloud } => {
+                expected_issuers(tenant_id, *cloud, token_versions)
+            }
+            Authority::B2C {
+                tenant_id,
+                tenant_name,
+                custom_domain,
+                ..
+            } => vec![format!(
+                "https://{}/{}/v2.0/",
+                Self::b2c_host(tenant_name, custom_domain),
+                tenant_id
+            )],
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use azure_core::auth::TokenCredential;
+use azure_identity::{DefaultAzureCredential, TokenCredentialOptions};
+use log::{debug, info};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Loads settings from Azure App Configuration and/or Key Vault using `DefaultAzureCredential`.
+///
+/// Sources are read from these environment variables:
+///
+/// * `AZURE_APP_CONFIG_ENDPOINT` - e.g. `https://myconfig.azconfig.io`. All key-values are loaded,
+///   optionally filtered by `AZURE_APP_CONFIG_LABEL`.
+/// * `AZURE_KEY_VAULT_URL` - e.g. `https://myvault.vault.azure.net`, together with
+///   `AZURE_KEY_VAULT_SECRETS`, a comma-separated list of secret names. Key Vault names cannot
+///   contain underscores, so `API-AUDIENCE` is exposed as `API_AUDIENCE`.
+///
+/// Key Vault values take precedence over App Configuration values with the same name.
+///
+/// # Errors
+///
+/// Returns an error if the credential cannot be created or any request fails.
+pub async fn load_settings() -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let credential = DefaultAzureCredential::create(TokenCredentialOptions::default())?;
+    let client = Client::new();
+    let mut settings = HashMap::new();
+
+    if let Ok(endpoint) = std::env::var("AZURE_APP_CONFIG_ENDPOINT") {
+        let label = std::env::var("AZURE_APP_CONFIG_LABEL").ok();
+        let values = load_app_configuration(&client, &credential, &endpoint, label).await?;
+        info!("Loaded {} settings from App Configuration", values.len());
+        settings.extend(values);
+    }
+
+    if let Ok(vault_url) = std::env::var("AZURE_KEY_VAULT_URL") {
+        let names = std::env::var("AZURE_KEY_VAULT_SECRETS").unwrap_or_default();
+        let values = load_key_vault_secrets(&client, &credential, &vault_url, &names).await?;
+        info!("Loaded {} secrets from Key Vault", values.len());
+        settings.extend(values);
+    }
+
+    Ok(settings)
+}
+
+async fn load_app_configuration(
+    client: &Client,
+    credential: &DefaultAzureCredential,
+    endpoint: &str,
+    label: Option<String>,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let scope = format!("{}/.default", endpoint);
+    let token = credential.get_token(&[scope.as_str()]).await?;
+
+    let mut url = format!("{}/kv?api-version=1.0", endpoint);
+    if let Some(label) = label {
+        url.push_str(&format!("&label={}", label));
+    }
+
+    let mut settings = HashMap::new();
+    loop {
+        debug!("Fetching App Configuration page {}", url);
+        let page: serde_json::Value = client
+            .get(&url)
+            .bearer_auth(token.token.secret())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        for item in page["items"].as_array().into_iter().flatten() {
+            if let (Some(key), Some(value)) = (item["key"].as_str(), item["value"].as_str()) {
+                settings.insert(key.to_string(), value.to_string());
+            }
+        }
+        match page["@nextLink"].as_str() {
+            Some(next) => url = format!("{}{}", endpoint, next),
+            None => break,
+        }
+    }
+    Ok(settings)
+}
+
+async fn load_key_vault_secrets(
+    client: &Client,
+    credential: &DefaultAzureCredential,
+    vault_url: &str,
+    names: &str,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let vault_url = vault_url.trim_end_matches('/');
+    let token = credential
+        .get_token(&["https://vault.azure.net/.default"])
+        .await?;
+
+    let mut settings = HashMap::new();
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let secret: serde_json::Value = client
+            .get(format!("{}/secrets/{}?api-version=7.4", vault_url, name))
+            .bearer_auth(token.token.secret())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if let Some(value) = secret["value"].as_str() {
+            settings.insert(name.replace('-', "_").to_uppercase(), value.to_string());
+        }
+    }
+    Ok(settings)
+}
@@ -1,217 +1,3249 @@
+use actix_web::http::header::HttpDate;
+use actix_web::http::KeepAlive;
 use actix_web::{web, HttpRequest, HttpResponse, HttpServer, Responder};
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use log::{debug, error, info};
-use reqwest::Client;
+use azure_identity::{DefaultAzureCredential, TokenCredentialOptions};
+use jsonwebtoken::Algorithm;
+use log::{debug, error, info, warn};
+use managed_identity_concept::access_rule::AccessRule;
+use managed_identity_concept::authority::TokenVersion;
+use managed_identity_concept::concurrency::{limit_concurrency, ConcurrencyLimit};
+use managed_identity_concept::config::{
+    env_flag, env_list, env_or, redact_url, validate_route_path, REDACTED,
+};
+use managed_identity_concept::content_type::{require_content_type, ContentTypePolicy};
+use managed_identity_concept::correlation::correlation_id;
+use managed_identity_concept::cors::{cors, CorsPolicy};
+use managed_identity_concept::deadline::{request_deadline, RequestDeadline};
+use managed_identity_concept::discovery::{ApiConfig, API_CONFIG_PATH};
+use managed_identity_concept::error::{error_bodies, ErrorBodyTemplate};
+use managed_identity_concept::inflight::{track_in_flight, InFlightRequests};
+use managed_identity_concept::ipfilter::{
+    client_origin, ip_allow_list, parse_cidrs, IpAllowList, TrustedProxies,
+};
+use managed_identity_concept::jwks::{
+    jwks_client, parse_jwks, parse_tls_version, read_pinned_keys, JwksMetrics, RefreshState,
+    RetryPolicy, DEFAULT_MAX_JWKS_BYTES, INLINE_JWKS_URL,
+};
+use managed_identity_concept::mtls::ClientCertBinding;
+use managed_identity_concept::outbound::OutboundTokenProvider;
+use managed_identity_concept::reload::Reloadable;
+use managed_identity_concept::sampling::LogSampler;
+use managed_identity_concept::subscription::{subscription_key, SubscriptionKeys};
+use managed_identity_concept::usage::{count_usage, UsageCounters, UsageKey};
+use managed_identity_concept::validator::client_id_audiences;
+use managed_identity_concept::{
+    ApiError, AudienceProfile, Authority, BearerAuth, BearerAuthConfig, Claims, Enforcement,
+    JwksCache, JwtValidator,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::sync::OnceCell;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
-/// Represents the claims contained in a JWT token.
+// Protected API Endpoint
+// Claims are optional so the endpoint keeps answering during an AUTH_ENFORCEMENT=log rollout;
+// with enforcement on, the middleware never lets a request without them through
+async fn protected_endpoint(claims: Option<Claims>) -> impl Responder {
+    match claims {
+        Some(claims) => HttpResponse::Ok().json(format!("Welcome! Your ID is {}", claims.sub)),
+        None => HttpResponse::Ok().json("Welcome, anonymous caller"),
+    }
+}
+
+/// The protected route at `path`, answered by `protected_endpoint` or, with `proxy`, by
+/// `proxy_protected`.
+fn protected_resource(path: &str, proxy: bool) -> actix_web::Resource {
+    let resource = web::resource(path);
+    if proxy {
+        resource
+            .route(web::get().to(proxy_protected))
+            .route(web::post().to(proxy_protected))
+    } else {
+        resource
+            .route(web::get().to(protected_endpoint))
+            .route(web::post().to(protected_endpoint))
+    }
+}
+
+/// Pulls settings from Azure App Configuration / Key Vault into the environment.
+#[cfg(feature = "azure-app-config")]
+async fn load_azure_settings() -> Result<(), Box<dyn std::error::Error>> {
+    let settings = managed_identity_concept::azure_config::load_settings().await?;
+    let applied = managed_identity_concept::config::apply_settings(settings);
+    info!("Settings taken from Azure: {:?}", applied);
+    Ok(())
+}
+
+#[cfg(not(feature = "azure-app-config"))]
+async fn load_azure_settings() -> Result<(), Box<dyn std::error::Error>> {
+    Err("USE_AZURE_APP_CONFIG=true requires building with --features azure-app-config".into())
+}
+
+/// The body accepted by `POST /api/echo`.
+#[derive(Debug, Deserialize)]
+struct EchoRequest {
+    message: String,
+    #[serde(default)]
+    data: Option<serde_json::Value>,
+}
+
+/// The body returned by `POST /api/echo`: the request plus the caller's subject.
+#[derive(Debug, Serialize)]
+struct EchoResponse {
+    subject: String,
+    message: String,
+    data: Option<serde_json::Value>,
+}
+
+// Protected echo endpoint, a template for write endpoints: the middleware authenticates
+// before the JSON body is extracted, so a missing token is a 401 even with a malformed body.
+async fn echo_endpoint(claims: Claims, body: web::Json<EchoRequest>) -> impl Responder {
+    let body = body.into_inner();
+    debug!("Echo from {}: {:?}", claims.sub, body);
+    HttpResponse::Ok().json(EchoResponse {
+        subject: claims.sub,
+        message: body.message,
+        data: body.data,
+    })
+}
+
+/// What `GET /health/detail` reports on: every JWKS cache in use plus a summary of the
+/// non-secret configuration.
+struct HealthState {
+    caches: Vec<Arc<JwksCache>>,
+    config: serde_json::Value,
+}
+
+// Protected health detail endpoint: JWKS freshness, key counts and refresh breaker state
+async fn health_detail(_claims: Claims, state: web::Data<HealthState>) -> impl Responder {
+    let mut jwks = Vec::with_capacity(state.caches.len());
+    for cache in &state.caches {
+        jwks.push(cache.status().await);
+    }
+    let healthy = jwks
+        .iter()
+        .all(|status| status.key_count > 0 && status.refresh_state != RefreshState::Open);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "jwks": jwks,
+        "config": state.config,
+    }))
+}
+
+/// Appends one metric family in the Prometheus text format, one sample per JWKS URL.
+fn push_metric(body: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    body.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n",
+        name, help, name, kind
+    ));
+    for (jwks_url, value) in samples {
+        body.push_str(&format!(
+            "{}{{jwks_url=\"{}\"}} {}\n",
+            name, jwks_url, value
+        ));
+    }
+}
+
+// Prometheus metrics endpoint, unauthenticated so scrapers need no token
+async fn metrics(
+    state: web::Data<HealthState>,
+    in_flight: web::Data<InFlightRequests>,
+    concurrency_limit: Option<web::Data<ConcurrencyLimit>>,
+) -> impl Responder {
+    let mut caches = Vec::with_capacity(state.caches.len());
+    for cache in &state.caches {
+        let label = cache
+            .jwks_url()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        caches.push((label, cache.metrics(), cache.status().await.key_count));
+    }
+    let samples = |value: fn(&JwksMetrics) -> u64| -> Vec<(String, u64)> {
+        caches
+            .iter()
+            .map(|(label, metrics, _)| (label.clone(), value(metrics)))
+            .collect()
+    };
+
+    let mut body = String::new();
+    push_metric(
+        &mut body,
+        "jwks_cache_hits_total",
+        "counter",
+        "Lookups answered from fresh cached keys",
+        &samples(|m| m.hits),
+    );
+    push_metric(
+        &mut body,
+        "jwks_cache_misses_total",
+        "counter",
+        "Lookups that had to refresh the keys",
+        &samples(|m| m.misses),
+    );
+    push_metric(
+        &mut body,
+        "jwks_refreshes_total",
+        "counter",
+        "Successful JWKS fetches",
+        &samples(|m| m.refreshes),
+    );
+    push_metric(
+        &mut body,
+        "jwks_refresh_failures_total",
+        "counter",
+        "Failed JWKS fetches",
+        &samples(|m| m.refresh_failures),
+    );
+    push_metric(
+        &mut body,
+        "jwks_served_stale_total",
+        "counter",
+        "Lookups answered with expired keys",
+        &samples(|m| m.served_stale),
+    );
+    push_metric(
+        &mut body,
+        "jwks_evicted_keys_total",
+        "counter",
+        "Keys dropped to stay within JWKS_MAX_KEYS",
+        &samples(|m| m.evicted_keys),
+    );
+    push_metric(
+        &mut body,
+        "jwks_unknown_kid_refreshes_total",
+        "counter",
+        "Refreshes for tokens signed with a kid the cached keys lacked",
+        &samples(|m| m.unknown_kid_refreshes),
+    );
+    let key_counts: Vec<(String, u64)> = caches
+        .iter()
+        .map(|(label, _, key_count)| (label.clone(), *key_count as u64))
+        .collect();
+    push_metric(
+        &mut body,
+        "jwks_keys",
+        "gauge",
+        "Number of cached signing keys",
+        &key_counts,
+    );
+    body.push_str(&format!(
+        "# HELP http_requests_in_flight Requests currently being handled\n\
+         # TYPE http_requests_in_flight gauge\n\
+         http_requests_in_flight {}\n",
+        in_flight.count()
+    ));
+    if let Some(limit) = concurrency_limit {
+        body.push_str(&format!(
+            "# HELP http_requests_rejected_overload_total Requests refused with 503 over MAX_CONCURRENT_REQUESTS\n\
+             # TYPE http_requests_rejected_overload_total counter\n\
+             http_requests_rejected_overload_total {}\n",
+            limit.rejected()
+        ));
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+/// The body accepted by `POST /validate`.
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    tokens: Vec<String>,
+}
+
+/// The outcome for one token of a batch, in the position of the token in the request.
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claims: Option<Claims>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_description: Option<String>,
+}
+
+/// Shared by all `POST /validate` calls: `limiter` bounds the validations in flight across
+/// every batch, so a few large batches cannot starve the rest of the server.
+struct BatchState {
+    auth: BearerAuth,
+    limiter: Arc<Semaphore>,
+    max_tokens: usize,
+}
+
+// Protected batch validation endpoint: validates every token concurrently and reports the
+// results in request order. One failing (or panicking) validation never fails the batch.
+async fn validate_batch(
+    _claims: Claims,
+    req: HttpRequest,
+    state: web::Data<BatchState>,
+    body: web::Json<BatchRequest>,
+) -> Result<impl Responder, ApiError> {
+    let tokens = body.into_inner().tokens;
+    if tokens.len() > state.max_tokens {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "At most {} tokens per batch",
+            state.max_tokens
+        )));
+    }
+
+    // Each token is validated in a task of its own, so a validation that panics only loses
+    // its own result
+    let tasks: Vec<_> = tokens
+        .into_iter()
+        .map(|token| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let _permit = state.limiter.acquire().await;
+                state.auth.config().validate_token(&token).await
+            })
+        })
+        .collect();
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        results.push(match task.await {
+            Ok(Ok(claims)) => BatchResult {
+                valid: true,
+                claims: Some(claims),
+                error: None,
+                error_description: None,
+            },
+            Ok(Err(e)) => BatchResult {
+                valid: false,
+                claims: None,
+                error: Some(e.code()),
+                error_description: Some(e.to_string()),
+            },
+            Err(e) => {
+                error!("Token validation failed unexpectedly: {}", e);
+                BatchResult {
+                    valid: false,
+                    claims: None,
+                    error: Some("internal_error"),
+                    error_description: Some("Validation failed unexpectedly".to_string()),
+                }
+            }
+        });
+    }
+    Ok(batch_response(&req, results))
+}
+
+/// Answers a batch in protobuf when `req` accepts `application/x-protobuf`, in JSON otherwise.
+#[cfg(feature = "protobuf")]
+fn batch_response(req: &HttpRequest, results: Vec<BatchResult>) -> HttpResponse {
+    use managed_identity_concept::protobuf::{self, ValidationResult, ValidationResults};
+    use prost::Message;
+
+    let accepts_protobuf = req
+        .headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(protobuf::accepts_protobuf);
+    if !accepts_protobuf {
+        return HttpResponse::Ok().json(results);
+    }
+    let results = ValidationResults {
+        results: results
+            .into_iter()
+            .map(|result| ValidationResult {
+                valid: result.valid,
+                claims: result.claims.map(Into::into),
+                error: result.error.map(str::to_string),
+                error_description: result.error_description,
+            })
+            .collect(),
+    };
+    HttpResponse::Ok()
+        .content_type(protobuf::CONTENT_TYPE)
+        .body(results.encode_to_vec())
+}
+
+#[cfg(not(feature = "protobuf"))]
+fn batch_response(_req: &HttpRequest, results: Vec<BatchResult>) -> HttpResponse {
+    HttpResponse::Ok().json(results)
+}
+
+/// The middleware per route: routes listed in `ROUTE_AUDIENCES` (a JSON object such as
+/// `{"/api/echo": "api://echo"}`) accept their own audience, routes listed in
+/// `ROUTE_ACCESS_RULES` (such as `{"/api/echo": "role:Admin OR scope:Echo"}`) are authorized by
+/// their own `AccessRule`, and the `ADMIN_ROUTES` require `ADMIN_ROLES` instead of the required
+/// roles. The rest use the default middleware.
+#[derive(Clone)]
+struct RouteAuth {
+    default: BearerAuth,
+    overrides: HashMap<String, (RouteOverride, BearerAuth)>,
+}
+
+/// What a route changes in the default configuration.
+#[derive(Clone, Default)]
+struct RouteOverride {
+    audience: Option<String>,
+    access_rule: Option<AccessRule>,
+    required_roles: Option<Vec<String>>,
+}
+
+impl RouteOverride {
+    /// `config` with the overrides applied.
+    fn apply(&self, mut config: BearerAuthConfig) -> BearerAuthConfig {
+        if let Some(audience) = &self.audience {
+            config = config.for_audience(audience.clone());
+        }
+        if self.access_rule.is_some() {
+            config = config.with_access_rule(self.access_rule.clone());
+        }
+        if let Some(roles) = &self.required_roles {
+            config = config.with_required_roles(roles.clone());
+        }
+        config
+    }
+}
+
+/// The routes authorized by `ADMIN_ROLES`.
+const ADMIN_ROUTES: [&str; 3] = ["/admin/reload", "/admin/authz", "/admin/usage"];
+
+impl RouteAuth {
+    /// `default` for every route, with `overrides` applied to the routes they name.
+    fn new(default: BearerAuth, overrides: HashMap<String, RouteOverride>) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(path, route)| {
+                let auth = BearerAuth::new(route.apply(default.config().as_ref().clone()));
+                (path, (route, auth))
+            })
+            .collect();
+        Self { default, overrides }
+    }
+
+    /// The middleware protecting `path`.
+    fn for_route(&self, path: &str) -> BearerAuth {
+        self.overrides
+            .get(path)
+            .map(|(_, auth)| auth)
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    /// Switches every route to `config`, keeping the route overrides.
+    fn reload(&self, config: BearerAuthConfig) {
+        for (route, auth) in self.overrides.values() {
+            auth.reload(route.apply(config.clone()));
+        }
+        self.default.reload(config);
+    }
+}
+
+/// The settings `RELOAD_CONFIG_PATH` may change at runtime. Omitted fields keep the value the
+/// server was started with.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReloadableSettings {
+    required_roles: Option<Vec<String>>,
+    audiences: Option<Vec<String>>,
+    ip_allow_list: Option<String>,
+    trusted_proxies: Option<String>,
+}
+
+/// Re-reads `RELOAD_CONFIG_PATH` on `SIGHUP`, `POST /admin/reload` or (with `RELOAD_WATCH`) a
+/// change to the file, and swaps the result in without dropping connections.
+///
+/// Every reload starts again from the startup configuration (`base_auth`, `base_ip_allow`,
+/// `base_trusted_proxies`), so removing a field from the file restores its original value.
+struct Reloader {
+    path: String,
+    base_auth: BearerAuthConfig,
+    base_ip_allow: Option<IpAllowList>,
+    base_trusted_proxies: TrustedProxies,
+    route_auth: RouteAuth,
+    ip_allow: Option<web::Data<Reloadable<IpAllowList>>>,
+    trusted_proxies: web::Data<Reloadable<TrustedProxies>>,
+}
+
+impl Reloader {
+    /// Applies the settings file, or leaves everything unchanged if it is invalid.
+    fn reload(&self) -> Result<(), String> {
+        let json = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("cannot read {}: {}", self.path, e))?;
+        let settings: ReloadableSettings =
+            serde_json::from_str(&json).map_err(|e| format!("invalid {}: {}", self.path, e))?;
+
+        let ip_allow = match &self.base_ip_allow {
+            Some(base) => Some(IpAllowList {
+                allowed: match &settings.ip_allow_list {
+                    Some(list) => parse_cidrs(list)?,
+                    None => base.allowed.clone(),
+                },
+            }),
+            None if settings.ip_allow_list.is_some() => {
+                return Err(
+                    "the IP allow-list can only be reloaded when IP_ALLOW_LIST is set".into(),
+                );
+            }
+            None => None,
+        };
+        let trusted_proxies = match &settings.trusted_proxies {
+            Some(list) => TrustedProxies(parse_cidrs(list)?),
+            None => self.base_trusted_proxies.clone(),
+        };
+
+        let mut config = self.base_auth.clone();
+        if let Some(roles) = settings.required_roles {
+            config = config.with_required_roles(roles);
+        }
+        if let Some(audiences) = settings.audiences {
+            if audiences.is_empty() {
+                return Err("audiences must not be empty".into());
+            }
+            config = config.with_audiences(audiences);
+        }
+        self.route_auth.reload(config);
+        if let (Some(reloadable), Some(ip_allow)) = (&self.ip_allow, ip_allow) {
+            reloadable.store(ip_allow);
+        }
+        self.trusted_proxies.store(trusted_proxies);
+        info!("Reloaded settings from {}", self.path);
+        Ok(())
+    }
+}
+
+// Protected admin endpoint: re-reads RELOAD_CONFIG_PATH, like SIGHUP
+async fn admin_reload(
+    _claims: Claims,
+    reloader: web::Data<Reloader>,
+) -> Result<impl Responder, ApiError> {
+    reloader.reload().map_err(|e| {
+        error!("Reload failed: {}", e);
+        ApiError::BadRequest(format!("Reload failed: {}", e))
+    })?;
+    Ok(HttpResponse::Ok().json("Settings reloaded"))
+}
+
+/// The state behind `GET /admin/authz`: the middleware of every route, the IP allow-list and the
+/// trusted proxies, read on each request so reloaded settings show up.
+struct AuthzState {
+    routes: Vec<String>,
+    route_auth: RouteAuth,
+    ip_allow: Option<web::Data<Reloadable<IpAllowList>>>,
+    trusted_proxies: web::Data<Reloadable<TrustedProxies>>,
+}
+
+// Protected authorization introspection endpoint: the rules each route currently enforces
+async fn admin_authz(_claims: Claims, state: web::Data<AuthzState>) -> impl Responder {
+    let routes: serde_json::Map<String, serde_json::Value> = state
+        .routes
+        .iter()
+        .map(|path| {
+            let rules = state.route_auth.for_route(path).config().rules();
+            (path.clone(), serde_json::json!(rules))
+        })
+        .collect();
+    let ip_allow_list = state.ip_allow.as_ref().map(|ip_allow| ip_allow.load());
+    HttpResponse::Ok().json(serde_json::json!({
+        "routes": routes,
+        "ip_allow_list": ip_allow_list.as_deref(),
+        "trusted_proxies": state.trusted_proxies.load().as_ref(),
+    }))
+}
+
+/// The query of `GET /admin/usage`.
+#[derive(Deserialize)]
+struct UsageQuery {
+    top: Option<usize>,
+}
+
+// Protected usage endpoint: the callers with the most authenticated requests in this window
+async fn admin_usage(
+    _claims: Claims,
+    counters: web::Data<UsageCounters>,
+    query: web::Query<UsageQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(counters.report(query.top.unwrap_or(10)))
+}
+
+/// Reloads the settings on every `SIGHUP`.
+#[cfg(unix)]
+fn reload_on_sighup(reloader: web::Data<Reloader>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("SIGHUP received, reloading settings");
+            if let Err(e) = reloader.reload() {
+                error!("Reload failed, keeping the current settings: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reloads the settings whenever `RELOAD_CONFIG_PATH` changes on disk.
+///
+/// The directory is watched rather than the file, since many editors save by writing a new
+/// file and renaming it over the old one. Editors also touch a file several times per save, so
+/// changes are collected for a moment before reloading once.
+#[cfg(feature = "watch-config")]
+fn reload_on_change(reloader: web::Data<Reloader>) -> Result<(), Box<dyn std::error::Error>> {
+    use notify::Watcher;
+    use std::path::Path;
+
+    let path = Path::new(&reloader.path);
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    info!("Watching {} for changes", reloader.path);
+    tokio::spawn(async move {
+        // Dropping the watcher stops it
+        let _watcher = watcher;
+        while let Some(event) = rx.recv().await {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Watching {} failed: {}", reloader.path, e);
+                    continue;
+                }
+            };
+            let ours = event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == file_name.as_deref());
+            if !ours || !(event.kind.is_modify() || event.kind.is_create()) {
+                continue;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            while rx.try_recv().is_ok() {}
+            info!("{} changed, reloading settings", reloader.path);
+            if let Err(e) = reloader.reload() {
+                error!("Reload failed, keeping the current settings: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "watch-config"))]
+fn reload_on_change(_reloader: web::Data<Reloader>) -> Result<(), Box<dyn std::error::Error>> {
+    Err("RELOAD_WATCH requires building with --features watch-config".into())
+}
+
+/// The federation metadata URL of `authority`, for `USE_FEDERATION_METADATA`.
+#[cfg(feature = "federation-metadata")]
+fn federation_metadata_url(authority: &Authority) -> Result<String, Box<dyn std::error::Error>> {
+    authority
+        .federation_metadata_url()
+        .ok_or_else(|| "USE_FEDERATION_METADATA is not supported for B2C".into())
+}
+
+#[cfg(not(feature = "federation-metadata"))]
+fn federation_metadata_url(_authority: &Authority) -> Result<String, Box<dyn std::error::Error>> {
+    Err("USE_FEDERATION_METADATA requires building with --features federation-metadata".into())
+}
+
+/// The OpenAPI 3 description served at `GET /openapi.json`, built once at startup.
+struct OpenApiDocument(serde_json::Value);
+
+/// Describes the routes this server registers, their bearer authentication and the
+/// `ApiError` body. `/admin/reload`, `/admin/usage` and `/api/downstream` are only listed when
+/// `with_reload`, `with_usage` and `with_downstream` are set; with `proxy`, the protected route is
+/// described as proxying to the downstream API.
+fn openapi_document(
+    protected_route_path: &str,
+    with_reload: bool,
+    with_usage: bool,
+    with_downstream: bool,
+    proxy: bool,
+) -> serde_json::Value {
+    let json =
+        |schema: serde_json::Value| serde_json::json!({ "application/json": { "schema": schema } });
+    let error = |description: &str| {
+        serde_json::json!({
+            "description": description,
+            "content": json(serde_json::json!({ "$ref": "#/components/schemas/ApiError" })),
+        })
+    };
+    let protected = |summary: &str,
+                     request: Option<serde_json::Value>,
+                     response: serde_json::Value| {
+        let mut operation = serde_json::json!({
+            "summary": summary,
+            "security": [{ "bearerAuth": [] }],
+            "responses": {
+                "200": { "description": "OK", "content": json(response) },
+                "401": { "description": "Missing or invalid bearer token, see the WWW-Authenticate header" },
+                "403": { "description": "The caller is not authorized or its address is not allowed" },
+            },
+        });
+        if let Some(request) = request {
+            operation["requestBody"] =
+                serde_json::json!({ "required": true, "content": json(request) });
+            operation["responses"]["400"] = error("Malformed request body");
+        }
+        operation
+    };
+    let object = || serde_json::json!({ "type": "object" });
+    let string = || serde_json::json!({ "type": "string" });
+
+    let mut paths = serde_json::Map::new();
+    let greeting = if proxy {
+        let mut proxied = protected(
+            "Passes the request on to the downstream API and answers with its response",
+            None,
+            object(),
+        );
+        proxied["responses"]["502"] =
+            serde_json::json!({ "description": "The downstream API could not be called" });
+        proxied["responses"]["504"] =
+            serde_json::json!({ "description": "The downstream API did not answer in time" });
+        proxied
+    } else {
+        protected("Greets the caller", None, string())
+    };
+    paths.insert(
+        protected_route_path.to_string(),
+        serde_json::json!({ "get": greeting, "post": greeting }),
+    );
+    paths.insert(
+        "/api/echo".to_string(),
+        serde_json::json!({ "post": protected(
+            "Echoes the message with the caller's subject",
+            Some(serde_json::json!({
+                "type": "object",
+                "required": ["message"],
+                "properties": { "message": string(), "data": {} },
+            })),
+            serde_json::json!({
+                "type": "object",
+                "properties": { "subject": string(), "message": string(), "data": {} },
+            }),
+        ) }),
+    );
+    let mut validate = protected(
+        "Validates a batch of tokens",
+        Some(serde_json::json!({
+            "type": "object",
+            "required": ["tokens"],
+            "properties": { "tokens": { "type": "array", "items": string() } },
+        })),
+        serde_json::json!({ "type": "array", "items": {
+            "type": "object",
+            "properties": {
+                "valid": { "type": "boolean" },
+                "claims": object(),
+                "error": string(),
+                "error_description": string(),
+            },
+        } }),
+    );
+    validate["responses"]["413"] = error("Too many tokens in the batch");
+    paths.insert(
+        "/validate".to_string(),
+        serde_json::json!({ "post": validate }),
+    );
+    paths.insert(
+        "/api/token-ttl".to_string(),
+        serde_json::json!({ "get": protected(
+            "Reports how long the caller's token stays valid",
+            None,
+            serde_json::json!({
+                "type": "object",
+                "required": ["expires_in_secs", "expires_at"],
+                "properties": {
+                    "expires_in_secs": { "type": "integer", "minimum": 0 },
+                    "expires_at": { "type": "integer" },
+                },
+            }),
+        ) }),
+    );
+    paths.insert(
+        "/api/token-info".to_string(),
+        serde_json::json!({ "post": protected(
+            "Reports the claims of a token without checking its audience",
+            Some(serde_json::json!({
+                "type": "object",
+                "required": ["token"],
+                "properties": { "token": string() },
+            })),
+            object(),
+        ) }),
+    );
+    paths.insert(
+        "/health/detail".to_string(),
+        serde_json::json!({ "get": protected("Reports JWKS freshness and the configuration", None, object()) }),
+    );
+    paths.insert(
+        "/admin/authz".to_string(),
+        serde_json::json!({ "get": protected("Reports the authorization rules of every route", None, object()) }),
+    );
+    if with_reload {
+        let mut reload = protected("Reloads the settings file", None, string());
+        reload["responses"]["400"] = error("The settings could not be reloaded");
+        paths.insert(
+            "/admin/reload".to_string(),
+            serde_json::json!({ "post": reload }),
+        );
+    }
+    if with_downstream {
+        let mut downstream = protected(
+            "Calls the downstream API with the server's own token",
+            None,
+            serde_json::json!({
+                "type": "object",
+                "required": ["status", "body"],
+                "properties": { "status": { "type": "integer" }, "body": string() },
+            }),
+        );
+        downstream["responses"]["502"] =
+            serde_json::json!({ "description": "The downstream API could not be called" });
+        paths.insert(
+            "/api/downstream".to_string(),
+            serde_json::json!({ "get": downstream }),
+        );
+    }
+    if with_usage {
+        let mut usage = protected("Reports the callers with the most requests", None, object());
+        usage["parameters"] = serde_json::json!([{
+            "name": "top",
+            "in": "query",
+            "description": "How many callers to report, 10 by default",
+            "schema": { "type": "integer", "minimum": 0 },
+        }]);
+        paths.insert(
+            "/admin/usage".to_string(),
+            serde_json::json!({ "get": usage }),
+        );
+    }
+    paths.insert(
+        "/metrics".to_string(),
+        serde_json::json!({ "get": {
+            "summary": "JWKS cache metrics in the Prometheus text format",
+            "responses": { "200": { "description": "OK", "content": { "text/plain": { "schema": string() } } } },
+        } }),
+    );
+    paths.insert(
+        API_CONFIG_PATH.to_string(),
+        serde_json::json!({ "get": {
+            "summary": "The audience, tenant and issuers clients request tokens for",
+            "responses": { "200": { "description": "OK", "content": json(serde_json::json!({
+                "type": "object",
+                "required": ["audience", "tenant_id", "issuers", "scope"],
+                "properties": {
+                    "audience": string(),
+                    "tenant_id": string(),
+                    "issuers": { "type": "array", "items": string() },
+                    "scope": string(),
+                },
+            })) } },
+        } }),
+    );
+    paths.insert(
+        "/openapi.json".to_string(),
+        serde_json::json!({ "get": {
+            "summary": "This document",
+            "responses": { "200": { "description": "OK", "content": json(object()) } },
+        } }),
+    );
+
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "managed-identity-concept",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            },
+            "schemas": {
+                "ApiError": {
+                    "type": "object",
+                    "required": ["error", "error_description"],
+                    "properties": {
+                        "error": { "type": "string", "enum": ["bad_request", "payload_too_large", "internal_error"] },
+                        "error_description": string(),
+                        "support_url": string(),
+                        "correlation_id": string(),
+                        "message": string(),
+                    },
+                },
+            },
+        },
+    })
+}
+
+// Public OpenAPI endpoint, for API gateways and client generators
+async fn openapi(document: web::Data<OpenApiDocument>) -> impl Responder {
+    HttpResponse::Ok().json(&document.0)
+}
+
+// Public discovery endpoint, so clients can find the audience and tenant to request tokens for
+async fn api_config(config: web::Data<ApiConfig>) -> impl Responder {
+    HttpResponse::Ok().json(config.get_ref())
+}
+
+/// The body accepted by `POST /api/token-info`.
+#[derive(Debug, Deserialize)]
+struct TokenInfoRequest {
+    token: String,
+}
+
+/// Validates tokens for `POST /api/token-info`: signature, issuer and expiry, but no audience.
+///
+/// With `diagnostics` (`DIAGNOSTICS_MODE=true`, never in production) the response also carries
+/// the decoded token header, which shows key rotation and algorithm mismatches at a glance.
+/// `GET /api/token-ttl` reads the server clock from the same `validator`.
+struct TokenInfoState {
+    validator: JwtValidator,
+    diagnostics: bool,
+}
+
+// Protected introspection endpoint. It reports a token's audience instead of enforcing it, so
+// its answer is NOT an authorization decision for any API.
+async fn token_info(
+    _claims: Claims,
+    state: web::Data<TokenInfoState>,
+    body: web::Json<TokenInfoRequest>,
+) -> impl Responder {
+    let mut info = match state.validator.validate(&body.token).await {
+        Ok(claims) => serde_json::json!({
+            "active": true,
+            "audience_checked": false,
+            "aud": claims.aud,
+            "iss": claims.iss,
+            "sub": claims.sub,
+            "exp": claims.exp,
+            "roles": claims.roles,
+        }),
+        Err(e) => serde_json::json!({
+            "active": false,
+            "error": e.code(),
+            "error_description": e.to_string(),
+        }),
+    };
+    if state.diagnostics {
+        info["header"] = match jsonwebtoken::decode_header(&body.token) {
+            Ok(header) => serde_json::json!({
+                "alg": header.alg,
+                "kid": header.kid,
+                "typ": header.typ,
+                "x5t": header.x5t,
+            }),
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        };
+    }
+    HttpResponse::Ok().json(info)
+}
+
+/// The lifetime left on the caller's token, as served by `GET /api/token-ttl`.
+///
+/// # Fields
+///
+/// * `expires_in_secs` - Seconds until `exp` by the server clock, 0 once it has passed (tokens
+///   are still accepted for the clock skew leeway after that).
+/// * `expires_at` - The token's `exp`, in seconds since the Unix epoch.
+#[derive(Debug, Serialize)]
+struct TokenTtl {
+    expires_in_secs: u64,
+    expires_at: i64,
+}
+
+// Protected token lifetime endpoint: how long the caller's own token stays valid, by the server
+// clock, so clients can refresh before it runs out mid-request
+async fn token_ttl(claims: Claims, state: web::Data<TokenInfoState>) -> impl Responder {
+    let now = i64::try_from(state.validator.unix_now()).unwrap_or(i64::MAX);
+    HttpResponse::Ok().json(TokenTtl {
+        expires_in_secs: u64::try_from(claims.exp.saturating_sub(now)).unwrap_or(0),
+        expires_at: claims.exp,
+    })
+}
+
+/// The downstream API `GET /api/downstream` calls with the server's own token.
 ///
 /// # Fields
 ///
-/// * `aud` - A string that holds the audience of the token. Must match `API_AUDIENCE`.
-/// * `iss` - A string that holds the issuer of the token. Must be Azure AD.
-/// * `sub` - A string that holds the subject of the token (Service Principal or Managed Identity).
-/// * `exp` - A usize that holds the expiration time of the token.
-/// * `roles` - An optional vector of strings that holds the roles associated with the token.
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    aud: String,                // Audience must match API_AUDIENCE
-    iss: String,                // Issuer must be Azure AD
-    sub: String,                // Subject (Service Principal or Managed Identity)
-    exp: usize,                 // Expiration time
-    roles: Option<Vec<String>>, // Roles
-}
-
-/// Represents the application state containing configuration details.
+/// * `url` - The URL called, `DOWNSTREAM_URL`.
+/// * `tokens` - Gets the server's tokens for `DOWNSTREAM_SCOPE`.
+/// * `client` - The HTTP client for the calls.
+/// * `timeout` - How long a call may take, `DOWNSTREAM_TIMEOUT_SECS`.
+/// * `forward_headers` - The request headers passed on when proxying the protected route,
+///   lowercase, `DOWNSTREAM_FORWARD_HEADERS`.
+struct DownstreamState {
+    url: String,
+    tokens: OutboundTokenProvider,
+    client: reqwest::Client,
+    timeout: Duration,
+    forward_headers: Vec<String>,
+}
+
+/// Headers that only concern one connection, so a proxy never passes them on. `authorization`
+/// is never forwarded either: the downstream API gets the server's token, not the caller's.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+/// The answer of the downstream API, as served by `GET /api/downstream`.
 ///
 /// # Fields
 ///
-/// * `jwks_url` - A string that holds the URL to fetch the JSON Web Key Sets (JWKS).
-/// * `api_audience` - A string that holds the expected audience for the API.
-/// * `tenant_id` - A string that holds the tenant ID for the Azure Active Directory.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct AppState {
+/// * `status` - The HTTP status of the downstream response.
+/// * `body` - Its body, as text.
+#[derive(Debug, Serialize)]
+struct DownstreamResponse {
+    status: u16,
+    body: String,
+}
+
+// Protected downstream demo endpoint: calls DOWNSTREAM_URL with the server's own token (not the
+// caller's), as a template for handlers calling other APIs
+async fn downstream(claims: Claims, state: web::Data<DownstreamState>) -> impl Responder {
+    debug!("Calling {} for {}", redact_url(&state.url), claims.sub);
+    let request = state.client.get(&state.url).timeout(state.timeout);
+    let request = match state.tokens.authorize(request).await {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Cannot get a token for {}: {}", state.tokens.scope(), e);
+            return HttpResponse::BadGateway().body("Cannot authenticate to the downstream API");
+        }
+    };
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Calling {} failed: {}", redact_url(&state.url), e);
+            return HttpResponse::BadGateway().body("The downstream API is unavailable");
+        }
+    };
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        // The token may have been revoked or the downstream rotated its keys: get a new one
+        warn!("{} rejected the server's token", redact_url(&state.url));
+        state.tokens.invalidate().await;
+    }
+    let body = response.text().await.unwrap_or_default();
+    HttpResponse::Ok().json(DownstreamResponse {
+        status: status.as_u16(),
+        body,
+    })
+}
+
+// Protected proxy endpoint: with DOWNSTREAM_PROXY=true the protected route passes authenticated
+// requests on to DOWNSTREAM_URL with the server's own token and answers with the response.
+// Claims are required whatever AUTH_ENFORCEMENT says: an unauthenticated request must never be
+// sent on with the server's identity
+async fn proxy_protected(
+    req: HttpRequest,
+    body: web::Bytes,
+    claims: Claims,
+    state: web::Data<DownstreamState>,
+) -> HttpResponse {
+    let mut url = state.url.clone();
+    if !req.query_string().is_empty() {
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str(req.query_string());
+    }
+    debug!(
+        "Proxying {} {} for {} to {}",
+        req.method(),
+        req.path(),
+        claims.sub,
+        redact_url(&url)
+    );
+    let method = match reqwest::Method::from_bytes(req.method().as_str().as_bytes()) {
+        Ok(method) => method,
+        Err(_) => return HttpResponse::MethodNotAllowed().finish(),
+    };
+    let mut request = state
+        .client
+        .request(method, &url)
+        .timeout(state.timeout)
+        .body(body);
+    for name in &state.forward_headers {
+        if name.eq_ignore_ascii_case("authorization") {
+            continue;
+        }
+        for value in req.headers().get_all(name.as_str()) {
+            request = request.header(name.as_str(), value.as_bytes());
+        }
+    }
+    let mut request = match request.build() {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Cannot build the request to {}: {}", redact_url(&url), e);
+            return HttpResponse::BadGateway().body("The downstream API is unavailable");
+        }
+    };
+    let token = match state.tokens.token().await {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Cannot get a token for {}: {}", state.tokens.scope(), e);
+            return HttpResponse::BadGateway().body("Cannot authenticate to the downstream API");
+        }
+    };
+    let mut authorization =
+        match reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token.expose())) {
+            Ok(authorization) => authorization,
+            Err(_) => {
+                error!(
+                    "The token for {} is not a valid header",
+                    state.tokens.scope()
+                );
+                return HttpResponse::BadGateway()
+                    .body("Cannot authenticate to the downstream API");
+            }
+        };
+    authorization.set_sensitive(true);
+    // Replaced rather than appended, so the server's token is the only one sent
+    request
+        .headers_mut()
+        .insert(reqwest::header::AUTHORIZATION, authorization);
+    let response = match state.client.execute(request).await {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() => {
+            warn!(
+                "{} did not answer within {:?}",
+                redact_url(&url),
+                state.timeout
+            );
+            return HttpResponse::GatewayTimeout()
+                .body("The downstream API did not answer in time");
+        }
+        Err(e) => {
+            error!("Proxying to {} failed: {}", redact_url(&url), e);
+            return HttpResponse::BadGateway().body("The downstream API is unavailable");
+        }
+    };
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        warn!("{} rejected the server's token", redact_url(&state.url));
+        state.tokens.invalidate().await;
+    }
+    let mut proxied = HttpResponse::build(
+        actix_web::http::StatusCode::from_u16(status.as_u16())
+            .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY),
+    );
+    for (name, value) in response.headers() {
+        if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            proxied.append_header((name.as_str(), value.as_bytes()));
+        }
+    }
+    match response.bytes().await {
+        Ok(body) => proxied.body(body),
+        Err(e) => {
+            error!("Reading the response of {} failed: {}", redact_url(&url), e);
+            HttpResponse::BadGateway().body("The downstream API is unavailable")
+        }
+    }
+}
+
+/// Mints tokens for `GET /selftest` with the `AUTH_DEV_HS256_SECRET` and validates them with
+/// `auth`, the middleware of `path`, the protected route.
+struct SelfTestState {
+    auth: BearerAuth,
+    secret: String,
+    path: String,
+}
+
+impl SelfTestState {
+    /// A one-minute HS256 token with the first audience, issuer and required role of `config`.
+    fn mint(&self, config: &BearerAuthConfig) -> Result<String, jsonwebtoken::errors::Error> {
+        let validator = config.validator();
+        let now = validator.unix_now();
+        let rules = config.rules();
+        let claims = serde_json::json!({
+            "aud": validator.audiences().first(),
+            "iss": validator.issuers().first().map(String::as_str).unwrap_or("selftest"),
+            "sub": "selftest",
+            "idtyp": "app",
+            "roles": rules.required_roles.first().into_iter().collect::<Vec<_>>(),
+            "iat": now,
+            "exp": now + 60,
+        });
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS256),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(self.secret.as_bytes()),
+        )
+    }
+}
+
+// Public self-test endpoint, only served with AUTH_DEV_HS256_SECRET: runs a freshly minted token
+// through the protected route's validation, for smoke tests needing no identity provider
+async fn selftest(state: web::Data<SelfTestState>) -> impl Responder {
+    let config = state.auth.config();
+    let token = match state.mint(&config) {
+        Ok(token) => token,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "ok": false,
+                "stage": "mint",
+                "error": e.to_string(),
+            }))
+        }
+    };
+    match config.validate_token(&token).await {
+        Ok(claims) => HttpResponse::Ok().json(serde_json::json!({
+            "ok": true,
+            "route": state.path,
+            "sub": claims.sub,
+            "aud": claims.aud,
+            "roles": claims.roles,
+        })),
+        Err(e) => {
+            warn!("Self-test token was refused: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "ok": false,
+                "stage": "validate",
+                "route": state.path,
+                "error": e.code(),
+            }))
+        }
+    }
+}
+
+/// One entry of `ALLOWED_ISSUERS`, a JSON array such as
+/// `[{"issuer": "https://partner.example/", "jwks_url": "https://partner.example/keys"}]`.
+///
+/// Tokens whose `iss` matches are verified with the partner's keys instead of the tenant's.
+#[derive(Debug, Deserialize)]
+struct PartnerIssuerSpec {
+    issuer: String,
     jwks_url: String,
-    api_audience: String,
-    tenant_id: String,
 }
-// Global cache for JWKS keys
-static JWKS_CACHE: OnceCell<HashMap<String, DecodingKey>> = OnceCell::const_new();
 
-/// Fetches JSON Web Key Sets (JWKS) from the specified URL and returns a HashMap of decoding keys.
-///
-/// # Arguments
-///
-/// * `jwks_url` - A string slice that holds the URL to fetch the JWKS from.
-///
-/// # Returns
-///
-/// A `HashMap` where the keys are the Key IDs (KID) and the values are the corresponding `DecodingKey` objects.
-///
-/// # Errors
-///
-/// This function will panic if the HTTP request fails or if the response cannot be parsed as JSON.
-///
-/// # Example
-///
-/// ```
-/// let jwks_url = "https://example.com/jwks";
-/// let keys = fetch_jwks(jwks_url).await;
-/// ```
-///
-/// # Remarks
-///
-/// This function uses the `reqwest` crate to perform the HTTP request and the `serde_json` crate to parse the JSON response.
-async fn fetch_jwks(jwks_url: &str) -> HashMap<String, DecodingKey> {
-    let client = Client::new();
-    let response = client.get(jwks_url).send().await.unwrap();
-    let json: serde_json::Value = response.json().await.unwrap();
+/// One entry of `AUDIENCE_PROFILES`, a JSON array such as
+/// `[{"audience": "api://orders", "required_roles": ["Orders.Read"], "algorithms": ["RS256"]}]`.
+///
+/// Omitted `issuers` default to the main configuration; `jwks_url` selects a separate key set.
+/// `subjects` restricts the accepted `sub` values, see `JwtValidator::with_allowed_subjects`.
+#[derive(Debug, Deserialize)]
+struct AudienceProfileSpec {
+    audience: String,
+    #[serde(default)]
+    issuers: Option<Vec<String>>,
+    #[serde(default)]
+    subjects: Vec<String>,
+    #[serde(default)]
+    required_roles: Vec<String>,
+    #[serde(default)]
+    algorithms: Option<Vec<Algorithm>>,
+    #[serde(default)]
+    jwks_url: Option<String>,
+}
+
+impl AudienceProfileSpec {
+    /// Builds the profile, deriving from `base` for everything the spec leaves out. A
+    /// `jwks_url` gets a cache built with `jwks_settings`.
+    fn into_profile(self, base: &JwtValidator, jwks_settings: &JwksSettings) -> AudienceProfile {
+        let mut validator = base.clone().with_audiences(vec![self.audience.clone()]);
+        if let Some(jwks_url) = self.jwks_url {
+            validator = validator.with_jwks(Arc::new(jwks_settings.cache(jwks_url)));
+        }
+        if let Some(issuers) = self.issuers {
+            validator = validator.with_issuers(issuers);
+        }
+        if let Some(algorithms) = self.algorithms {
+            validator = validator.with_algorithms(algorithms);
+        }
+        if !self.subjects.is_empty() {
+            validator = validator.with_allowed_subjects(self.subjects);
+        }
+        AudienceProfile {
+            audience: self.audience,
+            validator,
+            required_roles: self.required_roles,
+        }
+    }
+}
+
+/// The settings every JWKS cache of the server is built with, whichever keys it holds.
+///
+/// # Fields
+///
+/// * `ttl` - How long fetched keys are used, `JWKS_CACHE_TTL_SECS`.
+/// * `client` - The HTTP client, enforcing `MIN_TLS_VERSION`.
+/// * `max_document_bytes` - The largest JWKS document read, `MAX_JWKS_BYTES`.
+/// * `retry_policy` - Retries of failed fetches, `JWKS_FETCH_MAX_ATTEMPTS` and
+///   `JWKS_FETCH_BASE_DELAY_MS`.
+/// * `retired_key_retention` - How long keys that left the JWKS are still trusted,
+///   `RETIRED_KEY_RETENTION_SECS`.
+/// * `max_keys` - The most keys a cache holds, `JWKS_MAX_KEYS`.
+/// * `unknown_kid_refresh` - The least time between refetches for unknown kids,
+///   `JWKS_UNKNOWN_KID_REFRESH_SECS`.
+/// * `fetch_limiter` - Bounds the fetches in flight over all caches,
+///   `JWKS_MAX_CONCURRENT_FETCHES`.
+struct JwksSettings {
+    ttl: Duration,
+    client: reqwest::Client,
+    max_document_bytes: usize,
+    retry_policy: RetryPolicy,
+    retired_key_retention: Duration,
+    max_keys: Option<usize>,
+    unknown_kid_refresh: Duration,
+    fetch_limiter: Arc<Semaphore>,
+}
+
+impl JwksSettings {
+    /// A cache for the JWKS at `url` with these settings.
+    fn cache(&self, url: impl Into<String>) -> JwksCache {
+        JwksCache::new(url, self.ttl)
+            .with_client(self.client.clone())
+            .with_max_document_bytes(self.max_document_bytes)
+            .with_retry_policy(self.retry_policy)
+            .with_retired_key_retention(self.retired_key_retention)
+            .with_max_keys(self.max_keys)
+            .with_unknown_kid_refresh_interval(self.unknown_kid_refresh)
+            .with_fetch_limiter(self.fetch_limiter.clone())
+    }
+}
+
+/// Enables decryption of JWE tokens with the PEM private key at `path`.
+#[cfg(feature = "jwe")]
+fn enable_jwe(
+    validator: JwtValidator,
+    path: &str,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    let pem = std::fs::read_to_string(path)?;
+    info!("JWE decryption enabled with key {}", path);
+    Ok(validator.with_jwe_decryptor(managed_identity_concept::jwe::JweDecryptor::from_pem(&pem)?))
+}
+
+#[cfg(not(feature = "jwe"))]
+fn enable_jwe(
+    _validator: JwtValidator,
+    _path: &str,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    Err("JWE_PRIVATE_KEY_PATH requires building with --features jwe".into())
+}
+
+/// Reports authentication outcomes to the sink at `url`, batched in the background, or to
+/// stderr as NDJSON when `url` is `stderr`.
+#[cfg(feature = "auth-events")]
+fn enable_event_sink(
+    config: BearerAuthConfig,
+    url: String,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    use managed_identity_concept::events::{EventSink, EventSinkConfig};
+
+    let mut sink_config = EventSinkConfig::new(url);
+    if sink_config.url == "stderr" {
+        info!("Auth events are written to stderr");
+        return Ok(config.with_event_sink(EventSink::spawn_stderr(sink_config.queue_capacity)));
+    }
+    sink_config.authorization = std::env::var("AUTH_EVENT_SINK_AUTHORIZATION").ok();
+    sink_config.batch_size = env_or("AUTH_EVENT_BATCH_SIZE", sink_config.batch_size)?;
+    sink_config.flush_interval = Duration::from_millis(env_or(
+        "AUTH_EVENT_FLUSH_MS",
+        sink_config.flush_interval.as_millis() as u64,
+    )?);
+    if sink_config.batch_size == 0 {
+        return Err("AUTH_EVENT_BATCH_SIZE must be at least 1".into());
+    }
+    info!("Auth events are sent to {}", redact_url(&sink_config.url));
+    Ok(config.with_event_sink(EventSink::spawn(sink_config)))
+}
+
+#[cfg(not(feature = "auth-events"))]
+fn enable_event_sink(
+    _config: BearerAuthConfig,
+    _url: String,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    Err("AUTH_EVENT_SINK requires building with --features auth-events".into())
+}
+
+/// Resolves group overages from Microsoft Graph as the app registration `client_id`.
+#[cfg(feature = "graph-groups")]
+fn enable_graph_groups(
+    config: BearerAuthConfig,
+    tenant_id: &str,
+    client_id: String,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    use managed_identity_concept::authority::Cloud;
+    use managed_identity_concept::graph::{GraphGroupsConfig, GroupResolver};
+
+    let client_secret = std::env::var("GRAPH_GROUPS_CLIENT_SECRET")
+        .map_err(|_| "GRAPH_GROUPS_CLIENT_ID requires GRAPH_GROUPS_CLIENT_SECRET")?;
+    let mut graph_config =
+        GraphGroupsConfig::new(tenant_id, client_id, client_secret).with_cloud(Cloud::from_env()?);
+    if let Ok(host) = std::env::var("GRAPH_GROUPS_AUTHORITY_HOST") {
+        graph_config.authority_host = host;
+    }
+    if let Ok(url) = std::env::var("GRAPH_URL") {
+        graph_config.graph_url = url;
+    }
+    graph_config.ttl = Duration::from_secs(env_or(
+        "GRAPH_GROUPS_CACHE_TTL_SECS",
+        graph_config.ttl.as_secs(),
+    )?);
+    info!(
+        "Group overages are resolved from {} (cache ttl {:?})",
+        graph_config.graph_url, graph_config.ttl
+    );
+    Ok(config.with_group_resolver(GroupResolver::new(graph_config)))
+}
+
+#[cfg(not(feature = "graph-groups"))]
+fn enable_graph_groups(
+    _config: BearerAuthConfig,
+    _tenant_id: &str,
+    _client_id: String,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    Err("GRAPH_GROUPS_CLIENT_ID requires building with --features graph-groups".into())
+}
+
+/// Accepts DPoP-bound tokens, with proofs at most `DPOP_MAX_AGE_SECS` old.
+#[cfg(feature = "dpop")]
+fn enable_dpop(config: BearerAuthConfig) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    use managed_identity_concept::dpop::DpopVerifier;
+
+    let max_age_secs = env_or("DPOP_MAX_AGE_SECS", 300)?;
+    info!(
+        "Accepting DPoP-bound tokens with proofs up to {}s old",
+        max_age_secs
+    );
+    Ok(config.with_dpop(DpopVerifier::new(max_age_secs)))
+}
+
+#[cfg(not(feature = "dpop"))]
+fn enable_dpop(_config: BearerAuthConfig) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    Err("DPOP_ENABLED=true requires building with --features dpop".into())
+}
+
+/// Leaves authorization to the policy engine at `url`, waiting at most `POLICY_TIMEOUT_MS` for
+/// each decision.
+#[cfg(feature = "policy-engine")]
+fn enable_policy_engine(
+    config: BearerAuthConfig,
+    url: String,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    use managed_identity_concept::policy::PolicyEngine;
+
+    let timeout = Duration::from_millis(env_or("POLICY_TIMEOUT_MS", 2000)?);
+    info!(
+        "Authorization is decided by the policy engine at {} (timeout {:?})",
+        redact_url(&url),
+        timeout
+    );
+    Ok(config.with_policy_engine(PolicyEngine::new(url, timeout)?))
+}
+
+#[cfg(not(feature = "policy-engine"))]
+fn enable_policy_engine(
+    _config: BearerAuthConfig,
+    _url: String,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    Err("POLICY_URL requires building with --features policy-engine".into())
+}
+
+/// Takes access tokens from requests signed with `jwks`, read from `SIGNED_REQUEST_HEADER` and
+/// the `SIGNED_REQUEST_TOKEN_CLAIM`. `TOKEN_CONFLICT_POLICY` (`first-wins` or
+/// `reject-on-conflict`) says what to do when `Authorization` carries another token.
+#[cfg(feature = "signed-requests")]
+fn enable_signed_requests(
+    config: BearerAuthConfig,
+    jwks: Arc<JwksCache>,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    use managed_identity_concept::signed_request::SignedRequestUnwrapper;
+    use managed_identity_concept::TokenConflictPolicy;
+
+    let mut unwrapper = SignedRequestUnwrapper::new(jwks)
+        .with_issuer(std::env::var("SIGNED_REQUEST_ISSUER").ok())
+        .with_audience(std::env::var("SIGNED_REQUEST_AUDIENCE").ok());
+    if let Ok(header_name) = std::env::var("SIGNED_REQUEST_HEADER") {
+        unwrapper = unwrapper.with_header_name(header_name);
+    }
+    if let Ok(claim) = std::env::var("SIGNED_REQUEST_TOKEN_CLAIM") {
+        unwrapper = unwrapper.with_token_claim(claim);
+    }
+    let token_conflict = env_or("TOKEN_CONFLICT_POLICY", TokenConflictPolicy::FirstWins)?;
+    info!(
+        "Accepting access tokens in signed requests from the {} header, token conflicts: {:?}",
+        unwrapper.header_name(),
+        token_conflict
+    );
+    Ok(config
+        .with_signed_requests(unwrapper)
+        .with_token_conflict_policy(token_conflict))
+}
+
+#[cfg(not(feature = "signed-requests"))]
+fn enable_signed_requests(
+    _config: BearerAuthConfig,
+    _jwks: Arc<JwksCache>,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    Err("SIGNED_REQUEST_JWKS_URL requires building with --features signed-requests".into())
+}
+
+/// Turns off signature verification, loudly. Only possible in `insecure-dev` builds.
+#[cfg(feature = "insecure-dev")]
+fn enable_insecure_no_verify(
+    validator: JwtValidator,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    log::error!(
+        "!!! AUTH_INSECURE_NO_VERIFY=true: token signatures are NOT verified. \
+         Anyone can forge tokens. Never run this build in production !!!"
+    );
+    Ok(validator.with_insecure_no_verify(true))
+}
+
+#[cfg(not(feature = "insecure-dev"))]
+fn enable_insecure_no_verify(
+    _validator: JwtValidator,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    Err("AUTH_INSECURE_NO_VERIFY=true is refused: this build was compiled without the insecure-dev feature".into())
+}
+
+/// Accepts HS256 tokens signed with `secret`. Only possible in `insecure-dev` builds.
+#[cfg(feature = "insecure-dev")]
+fn enable_dev_hs256(
+    validator: JwtValidator,
+    secret: &str,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    warn!("AUTH_DEV_HS256_SECRET is set: anyone with the secret can mint accepted tokens");
+    Ok(validator.with_hs256_secret(secret))
+}
+
+#[cfg(not(feature = "insecure-dev"))]
+fn enable_dev_hs256(
+    _validator: JwtValidator,
+    _secret: &str,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    Err("AUTH_DEV_HS256_SECRET is refused: this build was compiled without the insecure-dev feature".into())
+}
+
+/// The shared state and routes of the app, registered on each worker by `configure`. The
+/// optional parts are only registered when their setting is on.
+#[derive(Clone)]
+struct AppRoutes {
+    in_flight: web::Data<InFlightRequests>,
+    trusted_proxies: web::Data<Reloadable<TrustedProxies>>,
+    deadline: Option<web::Data<RequestDeadline>>,
+    concurrency_limit: Option<web::Data<ConcurrencyLimit>>,
+    ip_allow: Option<web::Data<Reloadable<IpAllowList>>>,
+    cors_policy: Option<web::Data<CorsPolicy>>,
+    error_template: Option<web::Data<ErrorBodyTemplate>>,
+    content_type_policy: Option<web::Data<ContentTypePolicy>>,
+    subscription_keys: Option<web::Data<SubscriptionKeys>>,
+    selftest: Option<web::Data<SelfTestState>>,
+    downstream: Option<web::Data<DownstreamState>>,
+    usage_counters: Option<web::Data<UsageCounters>>,
+    reloader: Option<web::Data<Reloader>>,
+    health: web::Data<HealthState>,
+    openapi_document: web::Data<OpenApiDocument>,
+    discovery: web::Data<ApiConfig>,
+    batch: web::Data<BatchState>,
+    token_info: web::Data<TokenInfoState>,
+    authz: web::Data<AuthzState>,
+    route_auth: RouteAuth,
+    protected_route_path: String,
+    downstream_proxy: bool,
+}
+
+impl AppRoutes {
+    /// Registers the state and the routes on `config`.
+    fn configure(&self, config: &mut web::ServiceConfig) {
+        config
+            .app_data(self.in_flight.clone())
+            .app_data(self.trusted_proxies.clone());
+        if let Some(deadline) = &self.deadline {
+            config.app_data(deadline.clone());
+        }
+        if let Some(concurrency_limit) = &self.concurrency_limit {
+            config.app_data(concurrency_limit.clone());
+        }
+        if let Some(ip_allow) = &self.ip_allow {
+            config.app_data(ip_allow.clone());
+        }
+        if let Some(cors_policy) = &self.cors_policy {
+            config.app_data(cors_policy.clone());
+        }
+        if let Some(error_template) = &self.error_template {
+            config.app_data(error_template.clone());
+        }
+        if let Some(content_type_policy) = &self.content_type_policy {
+            config.app_data(content_type_policy.clone());
+        }
+        if let Some(subscription_keys) = &self.subscription_keys {
+            config.app_data(subscription_keys.clone());
+        }
+        if let Some(selftest_state) = &self.selftest {
+            config
+                .app_data(selftest_state.clone())
+                .route("/selftest", web::get().to(selftest));
+        }
+        if let Some(downstream_state) = &self.downstream {
+            config
+                .app_data(downstream_state.clone())
+                .service(self.protect(
+                    "/api/downstream",
+                    web::resource("/api/downstream").route(web::get().to(downstream)),
+                ));
+        }
+        if let Some(usage_counters) = &self.usage_counters {
+            config
+                .app_data(usage_counters.clone())
+                .service(self.protect(
+                    "/admin/usage",
+                    web::resource("/admin/usage").route(web::get().to(admin_usage)),
+                ));
+        }
+        if let Some(reloader) = &self.reloader {
+            config.app_data(reloader.clone()).service(self.protect(
+                "/admin/reload",
+                web::resource("/admin/reload").route(web::post().to(admin_reload)),
+            ));
+        }
+        let path = self.protected_route_path.as_str();
+        config
+            .service(self.protect(path, protected_resource(path, self.downstream_proxy)))
+            .app_data(self.health.clone())
+            .route("/metrics", web::get().to(metrics))
+            .app_data(self.openapi_document.clone())
+            .route("/openapi.json", web::get().to(openapi))
+            .app_data(self.discovery.clone())
+            .route(API_CONFIG_PATH, web::get().to(api_config))
+            .app_data(self.batch.clone())
+            .app_data(self.token_info.clone())
+            .app_data(self.authz.clone());
+        for (path, route) in [
+            ("/admin/authz", web::get().to(admin_authz)),
+            ("/api/token-info", web::post().to(token_info)),
+            ("/api/token-ttl", web::get().to(token_ttl)),
+            ("/validate", web::post().to(validate_batch)),
+            ("/health/detail", web::get().to(health_detail)),
+            ("/api/echo", web::post().to(echo_endpoint)),
+        ] {
+            config.service(self.protect(path, web::resource(path).route(route)));
+        }
+    }
+
+    /// `resource`, the protected route at `path`, behind its middleware: the subscription key,
+    /// then the IP allow-list, then its `BearerAuth`, counting the authenticated requests.
+    fn protect(
+        &self,
+        path: &str,
+        resource: actix_web::Resource,
+    ) -> impl actix_web::dev::HttpServiceFactory {
+        resource
+            .wrap(actix_web::middleware::from_fn(count_usage))
+            .wrap(self.route_auth.for_route(path))
+            .wrap(actix_web::middleware::from_fn(ip_allow_list))
+            .wrap(actix_web::middleware::from_fn(subscription_key))
+    }
+}
+
+/// Adds the entries of `part`, a JSON object, to the effective configuration `summary` served
+/// by `GET /health/detail`.
+fn summarize(summary: &mut serde_json::Map<String, serde_json::Value>, part: serde_json::Value) {
+    if let serde_json::Value::Object(part) = part {
+        summary.extend(part);
+    }
+}
+
+/// The main JWKS cache of `authority`, or of `JWKS_URL`, `JWKS_URLS` or `JWKS_INLINE`, with the
+/// settings every other cache is built with.
+fn jwks_setup(
+    authority: &Authority,
+    summary: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<(JwksCache, JwksSettings), Box<dyn std::error::Error>> {
+    // JWKS_URL overrides the authority's keys, e.g. with a file:// or data: URL for offline use.
+    // JWKS_URLS (comma-separated) trusts several key sets at once, merged into one cache.
+    let (jwks_url, additional_jwks_urls) = match env_list("JWKS_URLS") {
+        Some(_) if std::env::var_os("JWKS_URL").is_some() => {
+            return Err("Set either JWKS_URL or JWKS_URLS, not both".into());
+        }
+        Some(urls) => {
+            let mut urls = urls.into_iter();
+            let primary = urls.next().ok_or("JWKS_URLS must list at least one URL")?;
+            (primary, urls.collect())
+        }
+        None => match std::env::var("JWKS_URL") {
+            Ok(url) => (url, Vec::new()),
+            // USE_FEDERATION_METADATA reads the keys from the tenant's WS-Federation metadata
+            Err(_) if env_flag("USE_FEDERATION_METADATA") => {
+                (federation_metadata_url(authority)?, Vec::new())
+            }
+            Err(_) => (authority.jwks_url(), Vec::new()),
+        },
+    };
 
-    debug!("JWKS: {:#?}", json);
+    let jwks_cache_ttl_secs = env_or("JWKS_CACHE_TTL_SECS", 3600)?;
+    // RETIRED_KEY_RETENTION_SECS keeps trusting keys that left the JWKS, for tokens signed
+    // just before a rotation
+    let retired_key_retention_secs = env_or("RETIRED_KEY_RETENTION_SECS", 0)?;
+    let retired_key_retention = Duration::from_secs(retired_key_retention_secs);
+    // JWKS_MAX_KEYS caps the keys each JWKS cache holds, dropping the least recently used
+    let max_jwks_keys = match env_or("JWKS_MAX_KEYS", 0)? {
+        0 => None,
+        max_keys => Some(max_keys),
+    };
+    // JWKS_UNKNOWN_KID_REFRESH_SECS limits how often a token with an unknown kid refetches the
+    // keys, to pick up a rotated key between TTL refreshes
+    let unknown_kid_refresh_secs = env_or("JWKS_UNKNOWN_KID_REFRESH_SECS", 300)?;
+    let unknown_kid_refresh = Duration::from_secs(unknown_kid_refresh_secs);
+    let retry_policy = RetryPolicy {
+        max_attempts: env_or("JWKS_FETCH_MAX_ATTEMPTS", 3)?,
+        base_delay: Duration::from_millis(env_or("JWKS_FETCH_BASE_DELAY_MS", 200)?),
+    };
+
+    debug!("Fetching JWKS from {}", jwks_url);
 
-    let mut keys = HashMap::new();
-    for key in json["keys"].as_array().unwrap() {
-        let kid = key["kid"].as_str().unwrap().to_string();
-        let n = key["n"].as_str().unwrap();
-        let e = key["e"].as_str().unwrap();
-        let decoding_key = DecodingKey::from_rsa_components(n, e).unwrap();
-        keys.insert(kid, decoding_key);
+    // Shared by every JWKS cache so all key fetches together stay under the limit
+    let max_concurrent_fetches: usize = env_or("JWKS_MAX_CONCURRENT_FETCHES", 4)?;
+    if max_concurrent_fetches == 0 {
+        return Err("JWKS_MAX_CONCURRENT_FETCHES must be at least 1".into());
+    }
+    let fetch_limiter = Arc::new(Semaphore::new(max_concurrent_fetches));
+    // rustls never negotiates below TLS 1.2; MIN_TLS_VERSION=1.3 tightens it for every JWKS cache
+    let min_tls_version = std::env::var("MIN_TLS_VERSION").unwrap_or_else(|_| "1.2".to_string());
+    let jwks_client = jwks_client(
+        parse_tls_version(&min_tls_version)
+            .map_err(|e| format!("Invalid MIN_TLS_VERSION: {}", e))?,
+    )?;
+    info!(
+        "JWKS fetches require TLS {} or later",
+        min_tls_version.trim()
+    );
+    // JWKS_INLINE holds a whole JWKS document, parsed once here, for deployments that should
+    // never fetch keys (tests, ephemeral environments)
+    let inline_jwks = match std::env::var("JWKS_INLINE") {
+        Ok(_)
+            if std::env::var_os("JWKS_URL").is_some()
+                || std::env::var_os("JWKS_URLS").is_some() =>
+        {
+            return Err("Set either JWKS_INLINE or JWKS_URL/JWKS_URLS, not both".into());
+        }
+        Ok(document) => {
+            Some(parse_jwks(&document).map_err(|e| format!("Invalid JWKS_INLINE: {}", e))?)
+        }
+        Err(_) => None,
+    };
+    let jwks_inline = inline_jwks.is_some();
+    let logged_jwks_urls: Vec<String> = if jwks_inline {
+        vec![INLINE_JWKS_URL.to_string()]
+    } else {
+        std::iter::once(&jwks_url)
+            .chain(&additional_jwks_urls)
+            .map(|url| redact_url(url))
+            .collect()
+    };
+    let max_jwks_bytes: usize = env_or("MAX_JWKS_BYTES", DEFAULT_MAX_JWKS_BYTES)?;
+    if max_jwks_bytes == 0 {
+        return Err("MAX_JWKS_BYTES must be at least 1".into());
+    }
+    let jwks_settings = JwksSettings {
+        ttl: Duration::from_secs(jwks_cache_ttl_secs),
+        client: jwks_client.clone(),
+        max_document_bytes: max_jwks_bytes,
+        retry_policy,
+        retired_key_retention,
+        max_keys: max_jwks_keys,
+        unknown_kid_refresh,
+        fetch_limiter,
+    };
+    // PINNED_KIDS=kid1=/keys/kid1.pem,... trusts these keys on top of the JWKS during rotations
+    let pinned_keys = match std::env::var("PINNED_KIDS") {
+        Ok(value) => read_pinned_keys(&value)?,
+        Err(_) => HashMap::new(),
+    };
+    let mut pinned_kids: Vec<String> = pinned_keys.keys().cloned().collect();
+    pinned_kids.sort();
+    if !pinned_kids.is_empty() {
+        warn!(
+            "Trusting pinned keys on top of the JWKS: {}",
+            pinned_kids.join(", ")
+        );
     }
-    keys
+    // JWKS_DISK_CACHE_PATH saves the keys to a file, loaded on startup while younger than
+    // JWKS_DISK_CACHE_MAX_AGE_SECS, so a cold start answers without fetching first
+    let jwks_disk_cache_path = std::env::var("JWKS_DISK_CACHE_PATH").ok();
+    let jwks_disk_cache_max_age_secs: u64 =
+        env_or("JWKS_DISK_CACHE_MAX_AGE_SECS", jwks_cache_ttl_secs)?;
+    let jwks = match inline_jwks {
+        Some(mut keys) => {
+            keys.extend(pinned_keys);
+            info!(
+                "Trusting the {} keys of JWKS_INLINE, never fetching",
+                keys.len()
+            );
+            JwksCache::from_keys(keys)
+        }
+        None => {
+            let mut jwks = jwks_settings
+                .cache(jwks_url)
+                .with_additional_urls(additional_jwks_urls)
+                .with_pinned_keys(pinned_keys);
+            if let Some(path) = &jwks_disk_cache_path {
+                info!(
+                    "Caching the JWKS in {} for {}s",
+                    path, jwks_disk_cache_max_age_secs
+                );
+                jwks =
+                    jwks.with_disk_cache(path, Duration::from_secs(jwks_disk_cache_max_age_secs));
+            }
+            jwks
+        }
+    };
+    summarize(
+        summary,
+        serde_json::json!({
+            "jwks_urls": logged_jwks_urls,
+            "jwks_inline": jwks_inline,
+            "use_federation_metadata": env_flag("USE_FEDERATION_METADATA"),
+            "jwks_cache_ttl_secs": jwks_cache_ttl_secs,
+            "retired_key_retention_secs": retired_key_retention_secs,
+            "jwks_max_keys": max_jwks_keys,
+            "jwks_unknown_kid_refresh_secs": unknown_kid_refresh_secs,
+            "jwks_disk_cache_path": jwks_disk_cache_path,
+            "jwks_disk_cache_max_age_secs": jwks_disk_cache_max_age_secs,
+            "jwks_max_concurrent_fetches": max_concurrent_fetches,
+            "pinned_kids": pinned_kids,
+            "max_jwks_bytes": max_jwks_bytes,
+            "min_tls_version": min_tls_version.trim(),
+            "jwks_fetch_max_attempts": retry_policy.max_attempts,
+            "jwks_fetch_base_delay_ms": retry_policy.base_delay.as_millis(),
+        }),
+    );
+    Ok((jwks, jwks_settings))
 }
 
-/// Validates a JWT token using the JWKS fetched from the specified URL and the provided API audience.
-///
-/// # Arguments
-///
-/// * `token` - A string slice that holds the JWT token to be validated.
-/// * `jwks_url` - A string slice that holds the URL to fetch the JWKS from.
-/// * `api_audience` - A string slice that holds the expected audience for the token.
-///
-/// # Returns
-///
-/// A `Result` which is:
-/// * `Ok(Claims)` if the token is valid and contains the expected claims.
-/// * `Err(&'static str)` if the token is invalid or any error occurs during validation.
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// * The token header is invalid.
-/// * The KID (Key ID) is not found in the token header.
-/// * There is no matching JWK (JSON Web Key) for the KID.
-/// * The token is invalid according to the provided validation criteria.
-///
-/// # Example
-///
-/// ```
-/// let token = "your.jwt.token";
-/// let jwks_url = "https://example.com/jwks";
-/// let api_audience = "your_api_audience";
-/// let claims = validate_token(token, jwks_url, api_audience).await;
-/// ```
-async fn validate_token(
-    token: &str,
-    jwks_url: &str,
-    api_audience: &str,
-) -> Result<Claims, &'static str> {
-    // Use a closure to call `fetch_jwks` with the `jwks_url` parameter
-    let keys = JWKS_CACHE
-        .get_or_init(|| async { fetch_jwks(jwks_url).await })
-        .await;
+/// The validator of `audience` tokens from `authority`, signed with `jwks` or, for the
+/// `ALLOWED_ISSUERS` partners, their own keys. Every cache it reads keys from is added to
+/// `caches`.
+fn validator_setup(
+    authority: &Authority,
+    audience: &str,
+    jwks: JwksCache,
+    jwks_settings: &JwksSettings,
+    dev_hs256_secret: Option<&str>,
+    caches: &mut Vec<Arc<JwksCache>>,
+    summary: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+    // CLAIMS_CACHE_TTL_SECS > 0 skips re-verifying a token seen within that many seconds
+    let claims_cache_ttl_secs = env_or("CLAIMS_CACHE_TTL_SECS", 0)?;
+    // CLAIMS_CACHE_EXPIRY_MARGIN_SECS stops serving a cached token that long before its exp
+    let claims_cache_margin_secs = env_or("CLAIMS_CACHE_EXPIRY_MARGIN_SECS", 0)?;
 
+    let fail_fast_on_cold_jwks = env_flag("FAIL_FAST_ON_COLD_JWKS");
 
-    let header = jsonwebtoken::decode_header(token).map_err(|_| "Invalid token header")?;
-    debug!("Header: {:#?}", header);
-    let kid = header.kid.ok_or("No KID found")?;
-    debug!("KID: {}", kid);
-    let decoding_key = keys.get(&kid).ok_or("No matching JWK found")?;
-    let mut validation = Validation::new(Algorithm::RS256);
-    validation.set_audience(&[api_audience]);
-    let token_data = decode::<Claims>(token, decoding_key, &validation).map_err(|e| {
-        error!("Error: {:#?}", e);
-        "Invalid token"
-    })?;
-    debug!("Token: {:#?}", token_data);
-    Ok(token_data.claims)
+    let required_token_version = match std::env::var("REQUIRED_TOKEN_VERSION") {
+        Ok(version) if version == "1.0" || version == "2.0" => Some(version),
+        Ok(version) => {
+            return Err(
+                format!("REQUIRED_TOKEN_VERSION must be 1.0 or 2.0, got {}", version).into(),
+            )
+        }
+        Err(_) => None,
+    };
+
+    // Without REQUIRED_TOKEN_VERSION both the v1.0 and v2.0 issuer of the tenant are accepted,
+    // so a migrating deployment takes either token without listing issuers by hand
+    let issuers = authority.issuers(&TokenVersion::accepted(required_token_version.as_deref()));
+    info!("Accepted issuers: {}", issuers.join(", "));
+    // Either issuer is only accepted on tokens whose ver calls for it
+    let validator = JwtValidator::new(Arc::new(jwks), audience)
+        .with_issuers(issuers.clone())
+        .with_version_issuers(authority.issuers_by_version())
+        .with_fail_fast_on_cold_jwks(fail_fast_on_cold_jwks)
+        .with_required_token_version(required_token_version.clone())
+        .with_claims_cache(Duration::from_secs(claims_cache_ttl_secs))
+        .with_claims_cache_margin(Duration::from_secs(claims_cache_margin_secs));
+
+    // ERROR_LOG_SAMPLE_THRESHOLD > 0 logs that many rejections per error code and window, then
+    // one in ERROR_LOG_SAMPLE_EVERY
+    let error_log_sample_threshold: u64 = env_or("ERROR_LOG_SAMPLE_THRESHOLD", 0)?;
+    let error_log_sample_every: u64 = env_or("ERROR_LOG_SAMPLE_EVERY", 100)?;
+    let error_log_sample_window_secs: u64 = env_or("ERROR_LOG_SAMPLE_WINDOW_SECS", 60)?;
+    let validator = if error_log_sample_threshold > 0 {
+        info!(
+            "Sampling token errors: {} per code every {}s, then 1 in {}",
+            error_log_sample_threshold, error_log_sample_window_secs, error_log_sample_every
+        );
+        validator.with_error_log_sampling(LogSampler::new(
+            error_log_sample_threshold,
+            error_log_sample_every,
+            Duration::from_secs(error_log_sample_window_secs),
+        ))
+    } else {
+        validator
+    };
+
+    let required_claims = env_list("REQUIRED_CLAIMS").unwrap_or_default();
+    let validator = validator
+        .with_required_claims(&required_claims)
+        .map_err(|e| format!("Invalid REQUIRED_CLAIMS: {}", e))?;
+
+    caches.push(validator.jwks().clone());
+    let mut validator = validator;
+    if let Ok(json) = std::env::var("ALLOWED_ISSUERS") {
+        let partners: Vec<PartnerIssuerSpec> = serde_json::from_str(&json)?;
+        for partner in partners {
+            info!(
+                "Accepting issuer {} with keys from {}",
+                partner.issuer,
+                redact_url(&partner.jwks_url)
+            );
+            let jwks = Arc::new(jwks_settings.cache(partner.jwks_url));
+            caches.push(jwks.clone());
+            validator = validator.with_issuer_jwks(partner.issuer, jwks);
+        }
+    }
+
+    let validator = match std::env::var("JWE_PRIVATE_KEY_PATH") {
+        Ok(path) => enable_jwe(validator, &path)?,
+        Err(_) => validator,
+    };
+
+    let validator = if env_flag("AUTH_INSECURE_NO_VERIFY") {
+        enable_insecure_no_verify(validator)?
+    } else {
+        validator
+    };
+
+    let validator = match dev_hs256_secret {
+        Some(secret) => enable_dev_hs256(validator, secret)?,
+        None => validator,
+    };
+
+    summarize(
+        summary,
+        serde_json::json!({
+            "issuers": issuers,
+            "required_token_version": required_token_version,
+            "fail_fast_on_cold_jwks": fail_fast_on_cold_jwks,
+            "claims_cache_ttl_secs": claims_cache_ttl_secs,
+            "claims_cache_expiry_margin_secs": claims_cache_margin_secs,
+            "error_log_sample_threshold": error_log_sample_threshold,
+            "error_log_sample_every": error_log_sample_every,
+            "error_log_sample_window_secs": error_log_sample_window_secs,
+            "required_claims": required_claims,
+            "jwe_private_key_path": std::env::var("JWE_PRIVATE_KEY_PATH").ok(),
+            "dev_hs256_secret": dev_hs256_secret.map(|_| REDACTED),
+        }),
+    );
+    Ok(validator)
 }
 
-// Protected API Endpoint
-async fn protected_endpoint(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
-    let auth_header = req.headers().get("Authorization");
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().body("Missing Authorization header");
-    }
-
-    let token = auth_header
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .replace("Bearer ", "");
-
-    debug!("Token: {}", token);
-
-    // Validate the token
-    let api_audience = &app_state.api_audience;
-    let jwks_url = &app_state.jwks_url;
-    match validate_token(&token, jwks_url, api_audience).await {
-        Ok(claims) => {
-            if let Some(roles) = claims.roles {
-                debug!("Roles: {:#?}", roles);
-                // Check if the user has the required role
-                // In this example, we are checking for the "Task.HelloWorld" role
-                if !roles.contains(&"Task.HelloWorld".to_string()) {
-                    return HttpResponse::Forbidden().body("Not authorized");
-                }
-                HttpResponse::Ok().json(format!("Welcome! Your ID is {}", claims.sub))
-            } else {
-                HttpResponse::Forbidden().body("Forbidden")
+/// The middleware configuration over `validator`: who is authorized, which audiences are
+/// accepted and the optional checks. Caches it creates are added to `caches`.
+fn auth_config_setup(
+    validator: JwtValidator,
+    tenant_id: &str,
+    audience: &str,
+    jwks_settings: &JwksSettings,
+    caches: &mut Vec<Arc<JwksCache>>,
+    summary: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<BearerAuthConfig, Box<dyn std::error::Error>> {
+    // POLICY_URL leaves authorization to an external policy engine (e.g. OPA): tokens are only
+    // checked for signature, issuer and expiry, and the engine decides on their claims
+    let policy_url = std::env::var("POLICY_URL").ok();
+    let validator = if policy_url.is_some() {
+        warn!("POLICY_URL is set: the audience and roles are left to the policy engine");
+        validator.with_audience_check(false)
+    } else {
+        validator
+    };
+
+    // REQUIRED_ROLES (comma-separated, default Task.HelloWorld) lets callers holding any one of
+    // the app roles in; set to an empty value, any authenticated caller is let in
+    let required_roles =
+        env_list("REQUIRED_ROLES").unwrap_or_else(|| vec!["Task.HelloWorld".to_string()]);
+    let required_roles = if policy_url.is_some() {
+        if std::env::var("REQUIRED_ROLES").is_ok() {
+            warn!("REQUIRED_ROLES is ignored: POLICY_URL decides on the roles");
+        }
+        Vec::new()
+    } else {
+        if required_roles.is_empty() {
+            warn!("REQUIRED_ROLES is empty: any authenticated caller is authorized");
+        } else {
+            info!(
+                "Callers need one of the roles {}",
+                required_roles.join(", ")
+            );
+        }
+        required_roles
+    };
+    let enforcement: Enforcement = env_or("AUTH_ENFORCEMENT", Enforcement::Enforce)?;
+    if enforcement != Enforcement::Enforce {
+        warn!(
+            "Auth enforcement is {:?}: requests failing authentication are NOT rejected",
+            enforcement
+        );
+    }
+    let realm = std::env::var("AUTH_REALM").unwrap_or_else(|_| "api".to_string());
+    let mut auth_config = BearerAuthConfig::new(validator)
+        .with_required_roles(required_roles.clone())
+        .with_role_case_insensitive(env_flag("ROLE_CASE_INSENSITIVE"))
+        .with_app_only(env_flag("APP_ONLY"))
+        .with_managed_identity_required(env_flag("REQUIRE_MANAGED_IDENTITY"))
+        .with_https_required(env_flag("REQUIRE_HTTPS"))
+        .with_cert_client_auth(env_flag("REQUIRE_CERT_CLIENT_AUTH"))
+        .with_diagnostics(env_flag("DIAGNOSTICS_MODE"))
+        .with_enforcement(enforcement)
+        .with_realm(realm.clone());
+
+    let required_scopes = env_list("REQUIRED_SCOPES").unwrap_or_default();
+    if !required_scopes.is_empty() {
+        info!(
+            "Delegated tokens need one of the scopes {}",
+            required_scopes.join(", ")
+        );
+        auth_config = auth_config.with_required_scopes(required_scopes.clone());
+    }
+
+    // AUTHORIZE_BY_TOKEN_TYPE=true checks roles for app-only tokens and scopes for delegated ones
+    let token_type_authorization = env_flag("AUTHORIZE_BY_TOKEN_TYPE");
+    if token_type_authorization {
+        if required_scopes.is_empty() {
+            return Err("AUTHORIZE_BY_TOKEN_TYPE requires REQUIRED_SCOPES".into());
+        }
+        info!("App-only tokens are authorized by role, delegated tokens by scope");
+        auth_config = auth_config.with_token_type_authorization(true);
+    }
+
+    let required_wids = env_list("REQUIRED_WIDS").unwrap_or_default();
+    if !required_wids.is_empty() {
+        info!(
+            "Callers need one of the directory roles {}",
+            required_wids.join(", ")
+        );
+        auth_config = auth_config.with_required_wids(required_wids.clone());
+    }
+
+    let require_mfa = env_flag("REQUIRE_MFA");
+    if require_mfa {
+        info!("Callers must have completed multi-factor authentication");
+        auth_config = auth_config.with_mfa_required(true);
+    }
+
+    // AUDIENCE_ALIASES (comma-separated) accepts more audiences as equivalent to API_AUDIENCE,
+    // and DERIVE_CLIENT_ID_AUDIENCES=true adds the client ID or api://<client ID> form of each
+    let mut audience_aliases = env_list("AUDIENCE_ALIASES").unwrap_or_default();
+    let derive_client_id_audiences = env_flag("DERIVE_CLIENT_ID_AUDIENCES");
+    if derive_client_id_audiences {
+        audience_aliases = std::iter::once(audience)
+            .chain(audience_aliases.iter().map(String::as_str))
+            .flat_map(client_id_audiences)
+            .collect();
+    }
+    audience_aliases.retain(|alias| alias != audience);
+    audience_aliases.sort();
+    audience_aliases.dedup();
+    if !audience_aliases.is_empty() {
+        info!("Accepting audience aliases {:?}", audience_aliases);
+        let mut audiences = auth_config.validator().audiences().to_vec();
+        audiences.extend(audience_aliases.iter().cloned());
+        auth_config = auth_config.with_audiences(audiences);
+    }
+
+    // DEPRECATED_AUDIENCE keeps accepting an old audience while warning its callers, with the
+    // AUDIENCE_SUNSET date (an HTTP date) it stops being accepted
+    let deprecated_audience = std::env::var("DEPRECATED_AUDIENCE").ok();
+    let audience_sunset = std::env::var("AUDIENCE_SUNSET").ok();
+    if let Some(deprecated) = &deprecated_audience {
+        let sunset = match &audience_sunset {
+            Some(date) => Some(date.parse::<HttpDate>().map_err(|_| {
+                format!(
+                    "Invalid AUDIENCE_SUNSET {:?}, expected an HTTP date such as \
+                     Thu, 31 Dec 2026 23:59:59 GMT",
+                    date
+                )
+            })?),
+            None => None,
+        };
+        warn!(
+            "Audience {} is deprecated (sunset: {})",
+            deprecated,
+            audience_sunset.as_deref().unwrap_or("not set")
+        );
+        let mut audiences = auth_config.validator().audiences().to_vec();
+        audiences.push(deprecated.clone());
+        auth_config = auth_config
+            .with_audiences(audiences)
+            .with_deprecated_audience(deprecated.clone(), sunset);
+    } else if audience_sunset.is_some() {
+        return Err("AUDIENCE_SUNSET requires DEPRECATED_AUDIENCE".into());
+    }
+
+    // REQUIRE_CLAIM=env=prod,tier=gold requires every listed claim to have its value
+    let mut required_claim_values = Vec::new();
+    for requirement in env_list("REQUIRE_CLAIM").unwrap_or_default() {
+        let (name, value) = requirement
+            .split_once('=')
+            .filter(|(name, _)| !name.trim().is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "REQUIRE_CLAIM entries must be name=value, got {:?}",
+                    requirement
+                )
+            })?;
+        required_claim_values.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    for (name, value) in &required_claim_values {
+        info!("Requiring claim {} = {:?}", name, value);
+        auth_config = auth_config.with_required_claim_value(name, value);
+    }
+
+    // ALLOWED_TENANT_APP_PAIRS=<tid>:<appid>,... only accepts these tenant and client ID pairings
+    let mut allowed_tenant_apps = Vec::new();
+    for pair in env_list("ALLOWED_TENANT_APP_PAIRS").unwrap_or_default() {
+        let (tenant, app) = pair
+            .split_once(':')
+            .map(|(tenant, app)| (tenant.trim(), app.trim()))
+            .filter(|(tenant, app)| !tenant.is_empty() && !app.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "ALLOWED_TENANT_APP_PAIRS entries must be tid:appid, got {:?}",
+                    pair
+                )
+            })?;
+        allowed_tenant_apps.push((tenant.to_string(), app.to_string()));
+    }
+    if !allowed_tenant_apps.is_empty() {
+        info!(
+            "Accepting {} tenant and app pairs",
+            allowed_tenant_apps.len()
+        );
+        auth_config = auth_config.with_allowed_tenant_apps(allowed_tenant_apps.clone());
+    }
+
+    if let Ok(header_name) = std::env::var("MTLS_CLIENT_CERT_HEADER") {
+        let require_token_binding = env_flag("MTLS_REQUIRE_TOKEN_BINDING");
+        info!(
+            "Client certificates required via {} (token binding: {})",
+            header_name, require_token_binding
+        );
+        auth_config = auth_config
+            .with_client_cert(ClientCertBinding::new(header_name, require_token_binding));
+    }
+
+    if let Ok(url) = std::env::var("AUTH_EVENT_SINK") {
+        auth_config = enable_event_sink(auth_config, url)?;
+    }
+
+    if let Ok(client_id) = std::env::var("GRAPH_GROUPS_CLIENT_ID") {
+        auth_config = enable_graph_groups(auth_config, tenant_id, client_id)?;
+    }
+
+    if let Some(url) = policy_url.clone() {
+        auth_config = enable_policy_engine(auth_config, url)?;
+    }
+
+    // SIGNED_REQUEST_JWKS_URL accepts access tokens wrapped in requests signed by these keys
+    let signed_request_jwks_url = std::env::var("SIGNED_REQUEST_JWKS_URL").ok();
+    let signed_request_jwks = signed_request_jwks_url.as_ref().map(|url| {
+        let jwks = Arc::new(jwks_settings.cache(url.clone()));
+        caches.push(jwks.clone());
+        jwks
+    });
+
+    if let Some(jwks) = signed_request_jwks {
+        auth_config = enable_signed_requests(auth_config, jwks)?;
+    }
+
+    let dpop_enabled = env_flag("DPOP_ENABLED");
+    if dpop_enabled && signed_request_jwks_url.is_some() {
+        return Err("Set either DPOP_ENABLED or SIGNED_REQUEST_JWKS_URL, not both".into());
+    }
+    if dpop_enabled {
+        auth_config = enable_dpop(auth_config)?;
+    }
+
+    let mut profile_audiences = Vec::new();
+    if let Ok(json) = std::env::var("AUDIENCE_PROFILES") {
+        let specs: Vec<AudienceProfileSpec> = serde_json::from_str(&json)?;
+        let base = auth_config.validator().clone();
+        for spec in specs {
+            info!("Audience profile: {:?}", spec);
+            let profile = spec.into_profile(&base, jwks_settings);
+            if !caches
+                .iter()
+                .any(|c| Arc::ptr_eq(c, profile.validator.jwks()))
+            {
+                caches.push(profile.validator.jwks().clone());
+            }
+            profile_audiences.push(profile.audience.clone());
+            auth_config = auth_config.with_audience_profile(profile);
+        }
+    }
+
+    // EXTERNAL_OIDC_ISSUER trusts tokens of another OpenID Connect provider, such as GitHub
+    // Actions (https://token.actions.githubusercontent.com), for EXTERNAL_OIDC_AUDIENCE. Anyone
+    // can get a token from such a provider, so EXTERNAL_OIDC_SUBJECTS must name the callers.
+    let external_oidc_issuer = std::env::var("EXTERNAL_OIDC_ISSUER").ok();
+    if let Some(issuer) = &external_oidc_issuer {
+        let audience = std::env::var("EXTERNAL_OIDC_AUDIENCE")
+            .map_err(|_| "EXTERNAL_OIDC_ISSUER requires EXTERNAL_OIDC_AUDIENCE")?;
+        let subjects = env_list("EXTERNAL_OIDC_SUBJECTS").unwrap_or_default();
+        if subjects.is_empty() {
+            return Err("EXTERNAL_OIDC_ISSUER requires EXTERNAL_OIDC_SUBJECTS".into());
+        }
+        // GitHub serves its keys here; other providers list theirs in their discovery document
+        let jwks_url = std::env::var("EXTERNAL_OIDC_JWKS_URL")
+            .unwrap_or_else(|_| format!("{}/.well-known/jwks", issuer.trim_end_matches('/')));
+        let spec = AudienceProfileSpec {
+            audience,
+            issuers: Some(vec![issuer.clone()]),
+            subjects,
+            required_roles: Vec::new(),
+            algorithms: None,
+            jwks_url: Some(jwks_url),
+        };
+        info!("External OIDC provider: {:?}", spec);
+        // Other providers' tokens carry no Azure AD `ver`
+        let base = auth_config
+            .validator()
+            .clone()
+            .with_required_token_version(None);
+        let profile = spec.into_profile(&base, jwks_settings);
+        caches.push(profile.validator.jwks().clone());
+        profile_audiences.push(profile.audience.clone());
+        auth_config = auth_config.with_audience_profile(profile);
+    }
+
+    summarize(
+        summary,
+        serde_json::json!({
+            "realm": realm,
+            "app_only": env_flag("APP_ONLY"),
+            "require_managed_identity": env_flag("REQUIRE_MANAGED_IDENTITY"),
+            "require_https": env_flag("REQUIRE_HTTPS"),
+            "require_cert_client_auth": env_flag("REQUIRE_CERT_CLIENT_AUTH"),
+            "diagnostics_mode": env_flag("DIAGNOSTICS_MODE"),
+            "enforcement": enforcement,
+            "required_roles": required_roles,
+            "required_scopes": required_scopes,
+            "authorize_by_token_type": token_type_authorization,
+            "required_wids": required_wids,
+            "required_claim_values": required_claim_values,
+            "require_mfa": require_mfa,
+            "allowed_tenant_app_pairs": allowed_tenant_apps
+                .iter()
+                .map(|(tenant, app)| format!("{}:{}", tenant, app))
+                .collect::<Vec<_>>(),
+            "audience_aliases": audience_aliases,
+            "derive_client_id_audiences": derive_client_id_audiences,
+            "deprecated_audience": deprecated_audience,
+            "audience_sunset": audience_sunset,
+            "dpop_enabled": dpop_enabled,
+            "policy_url": policy_url.as_deref().map(redact_url),
+            "signed_request_jwks_url": signed_request_jwks_url.as_deref().map(redact_url),
+            "token_conflict_policy": std::env::var("TOKEN_CONFLICT_POLICY").ok(),
+            "audience_profiles": profile_audiences,
+            "external_oidc_issuer": external_oidc_issuer,
+            "auth_event_sink": std::env::var("AUTH_EVENT_SINK").ok().map(|url| redact_url(&url)),
+            "auth_event_sink_authorization": std::env::var_os("AUTH_EVENT_SINK_AUTHORIZATION")
+                .map(|_| REDACTED),
+            "graph_groups_client_id": std::env::var("GRAPH_GROUPS_CLIENT_ID").ok(),
+            "graph_groups_client_secret": std::env::var_os("GRAPH_GROUPS_CLIENT_SECRET")
+                .map(|_| REDACTED),
+        }),
+    );
+    Ok(auth_config)
+}
+
+/// The downstream API of `GET /api/downstream` and of the proxied protected route, called with
+/// `client`, or `None` without `DOWNSTREAM_URL`.
+fn downstream_setup(
+    client: &reqwest::Client,
+    summary: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<Option<web::Data<DownstreamState>>, Box<dyn std::error::Error>> {
+    // DOWNSTREAM_URL enables GET /api/downstream, which calls it with a token the server gets
+    // for DOWNSTREAM_SCOPE from its own managed identity (DefaultAzureCredential), giving up
+    // after DOWNSTREAM_TIMEOUT_SECS
+    let downstream_url = std::env::var("DOWNSTREAM_URL").ok();
+    let downstream_scope = std::env::var("DOWNSTREAM_SCOPE").ok();
+    let downstream_state = match &downstream_url {
+        Some(url) => {
+            let scope = downstream_scope
+                .clone()
+                .ok_or("DOWNSTREAM_URL requires DOWNSTREAM_SCOPE")?;
+            let credential = DefaultAzureCredential::create(TokenCredentialOptions::default())?;
+            let refresh_margin_secs = env_or("DOWNSTREAM_TOKEN_REFRESH_MARGIN_SECS", 300)?;
+            let timeout_secs: u64 = env_or("DOWNSTREAM_TIMEOUT_SECS", 30)?;
+            if timeout_secs == 0 {
+                return Err("DOWNSTREAM_TIMEOUT_SECS must be at least 1".into());
+            }
+            let forward_headers = env_list("DOWNSTREAM_FORWARD_HEADERS")
+                .unwrap_or_else(|| {
+                    ["accept", "content-type", "x-correlation-id"]
+                        .map(str::to_string)
+                        .to_vec()
+                })
+                .into_iter()
+                .map(|name| name.to_ascii_lowercase())
+                .map(|name| {
+                    if name == "authorization" || HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+                        Err(format!(
+                            "DOWNSTREAM_FORWARD_HEADERS cannot forward {}",
+                            name
+                        ))
+                    } else if actix_web::http::header::HeaderName::try_from(name.as_str()).is_err()
+                    {
+                        Err(format!(
+                            "Invalid header name {:?} in DOWNSTREAM_FORWARD_HEADERS",
+                            name
+                        ))
+                    } else {
+                        Ok(name)
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            info!(
+                "GET /api/downstream calls {} with tokens for {}",
+                redact_url(url),
+                scope
+            );
+            Some(web::Data::new(DownstreamState {
+                url: url.clone(),
+                tokens: OutboundTokenProvider::new(Arc::new(credential), scope)
+                    .with_refresh_margin(Duration::from_secs(refresh_margin_secs)),
+                client: client.clone(),
+                timeout: Duration::from_secs(timeout_secs),
+                forward_headers,
+            }))
+        }
+        None => None,
+    };
+    summarize(
+        summary,
+        serde_json::json!({
+            "downstream_url": downstream_url.as_deref().map(redact_url),
+            "downstream_scope": downstream_scope,
+            "downstream_timeout_secs": downstream_state
+                .as_ref()
+                .map(|state| state.timeout.as_secs()),
+            "downstream_forward_headers": downstream_state
+                .as_ref()
+                .map(|state| &state.forward_headers),
+        }),
+    );
+    Ok(downstream_state)
+}
+
+/// The middleware of each of `routes`: `bearer_auth`, or a copy changed by `ROUTE_AUDIENCES`,
+/// `ROUTE_ACCESS_RULES` or, on the `ADMIN_ROUTES`, `ADMIN_ROLES`.
+fn route_auth_setup(
+    bearer_auth: &BearerAuth,
+    routes: &[&str],
+    summary: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<RouteAuth, Box<dyn std::error::Error>> {
+    let mut route_overrides: HashMap<String, RouteOverride> = HashMap::new();
+    if let Ok(json) = std::env::var("ROUTE_AUDIENCES") {
+        let route_audiences: HashMap<String, String> = serde_json::from_str(&json)?;
+        for (path, audience) in route_audiences {
+            if !routes.contains(&path.as_str()) {
+                return Err(format!("ROUTE_AUDIENCES names unknown route {}", path).into());
+            }
+            info!("Route {} accepts audience {}", path, audience);
+            route_overrides.entry(path).or_default().audience = Some(audience);
+        }
+    }
+    // ROUTE_ACCESS_RULES authorizes routes by a rule over roles, scopes and groups instead of
+    // the required roles, e.g. {"/api/echo": "role:Admin OR (scope:Data.Read AND group:...)"}
+    if let Ok(json) = std::env::var("ROUTE_ACCESS_RULES") {
+        let route_rules: HashMap<String, String> = serde_json::from_str(&json)?;
+        for (path, rule) in route_rules {
+            if !routes.contains(&path.as_str()) {
+                return Err(format!("ROUTE_ACCESS_RULES names unknown route {}", path).into());
             }
+            let rule: AccessRule = rule
+                .parse()
+                .map_err(|e| format!("Invalid ROUTE_ACCESS_RULES rule for {}: {}", path, e))?;
+            info!("Route {} is authorized by {}", path, rule);
+            route_overrides.entry(path).or_default().access_rule = Some(rule);
         }
-        Err(err) => HttpResponse::Unauthorized().body(err),
     }
+    // ADMIN_ROLES (comma-separated, Api.Admin by default) are required on the admin routes
+    // instead of REQUIRED_ROLES; empty lets any authenticated caller in
+    let admin_roles = env_list("ADMIN_ROLES").unwrap_or_else(|| vec!["Api.Admin".to_string()]);
+    for path in ADMIN_ROUTES {
+        route_overrides
+            .entry(path.to_string())
+            .or_default()
+            .required_roles = Some(admin_roles.clone());
+    }
+    info!("Admin routes require one of the roles {:?}", admin_roles);
+    summarize(summary, serde_json::json!({ "admin_roles": admin_roles }));
+    Ok(RouteAuth::new(bearer_auth.clone(), route_overrides))
 }
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
+    // LOG_TARGET=stdout sends the app logs to stdout, e.g. to keep stderr for
+    // AUTH_EVENT_SINK=stderr
+    let mut logger = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    if std::env::var("LOG_TARGET").as_deref() == Ok("stdout") {
+        logger.target(pretty_env_logger::env_logger::Target::Stdout);
+    }
+    logger.init();
     info!("Starting server");
 
     dotenv::dotenv().ok();
+
+    if env_flag("USE_AZURE_APP_CONFIG") {
+        load_azure_settings().await?;
+    }
+
     let tenant_id = std::env::var("TENANT_ID")?;
     let audience = std::env::var("API_AUDIENCE")?;
-    let jwks_url = format!(
-        "https://login.microsoftonline.com/{}/discovery/v2.0/keys",
-        tenant_id
+    let authority = Authority::from_env(&tenant_id)?;
+    debug!("Authority: {:#?}", authority);
+    debug!("Discovery document: {}", authority.discovery_url());
+    debug!("Tenant: {}, audience: {}", tenant_id, audience);
+
+    // The effective configuration, added to by each part of the setup
+    let mut summary = serde_json::Map::new();
+    summarize(
+        &mut summary,
+        serde_json::json!({
+            "tenant_id": tenant_id,
+            "audience": audience,
+            "discovery_url": authority.discovery_url(),
+        }),
     );
+    let (jwks, jwks_settings) = jwks_setup(&authority, &mut summary)?;
+    // AUTH_DEV_HS256_SECRET accepts locally minted HS256 tokens and serves GET /selftest
+    let dev_hs256_secret = std::env::var("AUTH_DEV_HS256_SECRET").ok();
+    let mut caches = Vec::new();
+    let validator = validator_setup(
+        &authority,
+        &audience,
+        jwks,
+        &jwks_settings,
+        dev_hs256_secret.as_deref(),
+        &mut caches,
+        &mut summary,
+    )?;
+    let auth_config = auth_config_setup(
+        validator,
+        &tenant_id,
+        &audience,
+        &jwks_settings,
+        &mut caches,
+        &mut summary,
+    )?;
+    let issuers = auth_config.validator().issuers().to_vec();
 
-    debug!("Fetching JWKS from {}", jwks_url);
+    let protected_route_path =
+        std::env::var("PROTECTED_ROUTE_PATH").unwrap_or_else(|_| "/api_protected".to_string());
+    validate_route_path(&protected_route_path)?;
+    info!("Protected route: {}", protected_route_path);
+
+    // CORS_ALLOWED_ORIGINS lets browser apps on these origins (or * for any) call the API
+    let cors_policy = match env_list("CORS_ALLOWED_ORIGINS") {
+        Some(origins) => {
+            let mut policy = CorsPolicy::new(origins);
+            policy.max_age_secs = env_or("CORS_MAX_AGE_SECS", policy.max_age_secs)?;
+            info!(
+                "CORS allowed for origins {}",
+                policy.allowed_origins.join(", ")
+            );
+            Some(web::Data::new(policy))
+        }
+        None => None,
+    };
+    let logged_cors_origins = cors_policy
+        .as_ref()
+        .map(|policy| policy.allowed_origins.clone());
+
+    // ALLOWED_CONTENT_TYPES (e.g. application/json) answers 415 to POST, PUT and PATCH bodies of
+    // any other media type
+    let content_type_policy = env_list("ALLOWED_CONTENT_TYPES").map(|allowed| {
+        info!("Request bodies must be one of {}", allowed.join(", "));
+        web::Data::new(ContentTypePolicy { allowed })
+    });
+    let logged_content_types = content_type_policy
+        .as_ref()
+        .map(|policy| policy.allowed.clone());
+
+    // SUBSCRIPTION_HEADER_NAME (e.g. Ocp-Apim-Subscription-Key) requires one of the
+    // SUBSCRIPTION_KEYS on protected routes, on top of the token
+    let subscription_keys = match std::env::var("SUBSCRIPTION_HEADER_NAME") {
+        Ok(header_name) => {
+            let keys = env_list("SUBSCRIPTION_KEYS")
+                .ok_or("SUBSCRIPTION_HEADER_NAME requires SUBSCRIPTION_KEYS")?;
+            let keys = SubscriptionKeys::new(&header_name, &keys)
+                .map_err(|e| format!("Invalid SUBSCRIPTION_KEYS: {}", e))?;
+            info!(
+                "Protected routes require one of {} subscription keys in {}",
+                keys.len(),
+                keys.header_name()
+            );
+            Some(web::Data::new(keys))
+        }
+        Err(_) => None,
+    };
+
+    let downstream_state = downstream_setup(&jwks_settings.client, &mut summary)?;
+    // DOWNSTREAM_PROXY=true turns the protected route into an authenticating reverse proxy to
+    // DOWNSTREAM_URL, forwarding the method, query, body and DOWNSTREAM_FORWARD_HEADERS
+    let downstream_proxy = env_flag("DOWNSTREAM_PROXY");
+    if downstream_proxy {
+        let state = downstream_state
+            .as_ref()
+            .ok_or("DOWNSTREAM_PROXY requires DOWNSTREAM_URL")?;
+        info!(
+            "{} proxies to {}, forwarding {:?}",
+            protected_route_path,
+            redact_url(&state.url),
+            state.forward_headers
+        );
+    }
+
+    // USAGE_MAX_CALLERS counts authenticated requests per caller (USAGE_KEY: sub or client_id)
+    // for GET /admin/usage, over windows of USAGE_WINDOW_SECS
+    let usage_max_callers: usize = env_or("USAGE_MAX_CALLERS", 0)?;
+    let usage_key: UsageKey = env_or("USAGE_KEY", UsageKey::Subject)?;
+    let usage_window_secs: u64 = env_or("USAGE_WINDOW_SECS", 3600)?;
+    let usage_counters = (usage_max_callers > 0).then(|| {
+        info!(
+            "Counting requests of up to {} callers by {:?} per {}s",
+            usage_max_callers, usage_key, usage_window_secs
+        );
+        web::Data::new(UsageCounters::new(
+            usage_key,
+            usage_max_callers,
+            Duration::from_secs(usage_window_secs),
+        ))
+    });
+
+    // ERROR_SUPPORT_URL, ERROR_INCLUDE_CORRELATION_ID and ERROR_MESSAGE add fields to error bodies
+    let error_template = ErrorBodyTemplate {
+        support_url: std::env::var("ERROR_SUPPORT_URL").ok(),
+        include_correlation_id: env_flag("ERROR_INCLUDE_CORRELATION_ID"),
+        custom_message: std::env::var("ERROR_MESSAGE").ok(),
+    };
+    let logged_error_template = error_template.clone();
+    let error_template = (!error_template.is_empty()).then(|| web::Data::new(error_template));
+
+    // Refreshing ahead of the TTL keeps newly published keys cached before their first token
+    let jwks_refresh_interval_secs: u64 = env_or(
+        "JWKS_REFRESH_INTERVAL_SECS",
+        jwks_settings.ttl.as_secs() / 2,
+    )?;
+    if jwks_refresh_interval_secs > 0 {
+        info!(
+            "Refreshing {} JWKS caches every {}s in the background",
+            caches.len(),
+            jwks_refresh_interval_secs
+        );
+        for cache in &caches {
+            cache.spawn_background_refresh(Duration::from_secs(jwks_refresh_interval_secs));
+        }
+    }
+
+    let token_info_state = web::Data::new(TokenInfoState {
+        validator: auth_config.validator().clone().with_audience_check(false),
+        diagnostics: env_flag("DIAGNOSTICS_MODE"),
+    });
+
+    let bearer_auth = BearerAuth::new(auth_config.clone());
+    let batch = web::Data::new(BatchState {
+        auth: bearer_auth.clone(),
+        limiter: Arc::new(Semaphore::new(env_or("BATCH_MAX_CONCURRENCY", 16)?.max(1))),
+        max_tokens: env_or("BATCH_MAX_TOKENS", 100)?,
+    });
+
+    let deadline = match env_or("REQUEST_DEADLINE_MS", 0)? {
+        0 => None,
+        ms => {
+            info!("Request deadline: {}ms", ms);
+            Some(web::Data::new(RequestDeadline(Duration::from_millis(ms))))
+        }
+    };
+
+    // MAX_CONCURRENT_REQUESTS > 0 answers 503 to requests beyond that many in flight, asking
+    // them to come back after OVERLOAD_RETRY_AFTER_SECS
+    let concurrency_limit = match env_or("MAX_CONCURRENT_REQUESTS", 0)? {
+        0 => None,
+        max => {
+            info!("At most {} requests in flight", max);
+            let retry_after = Duration::from_secs(env_or("OVERLOAD_RETRY_AFTER_SECS", 1)?);
+            Some(web::Data::new(ConcurrencyLimit::new(max, retry_after)))
+        }
+    };
+
+    let routes = [
+        protected_route_path.as_str(),
+        "/validate",
+        "/health/detail",
+        "/api/echo",
+        "/api/token-info",
+        "/api/token-ttl",
+        "/admin/reload",
+        "/admin/authz",
+        "/admin/usage",
+        "/api/downstream",
+    ];
+    let route_auth = route_auth_setup(&bearer_auth, &routes, &mut summary)?;
+
+    // TRUSTED_PROXIES (comma-separated CIDRs) are the only peers whose X-Forwarded-For,
+    // X-Forwarded-Proto and X-Forwarded-Host are believed, for the IP allow-list, access logs and
+    // DPoP
+    let trusted_proxies = TrustedProxies(
+        parse_cidrs(&std::env::var("TRUSTED_PROXIES").unwrap_or_default())
+            .map_err(|e| format!("Invalid TRUSTED_PROXIES: {}", e))?,
+    );
+    if !trusted_proxies.0.is_empty() {
+        info!(
+            "Forwarding headers trusted from {} proxy networks",
+            trusted_proxies.0.len()
+        );
+    }
+    let reloadable_trusted_proxies = web::Data::new(Reloadable::new(trusted_proxies.clone()));
+
+    let ip_allow = match std::env::var("IP_ALLOW_LIST") {
+        Ok(allowed) => {
+            let allowed =
+                parse_cidrs(&allowed).map_err(|e| format!("Invalid IP_ALLOW_LIST: {}", e))?;
+            info!(
+                "Protected routes only accept {} allowed networks",
+                allowed.len()
+            );
+            Some(IpAllowList { allowed })
+        }
+        Err(_) => None,
+    };
+    let reloadable_ip_allow = ip_allow
+        .clone()
+        .map(|ip_allow| web::Data::new(Reloadable::new(ip_allow)));
 
-    let app_state = AppState {
-        jwks_url,
-        api_audience: audience,
-        tenant_id,
+    let reloader = match std::env::var("RELOAD_CONFIG_PATH") {
+        Ok(path) => {
+            let reloader = web::Data::new(Reloader {
+                path,
+                base_auth: auth_config,
+                base_ip_allow: ip_allow,
+                base_trusted_proxies: trusted_proxies.clone(),
+                route_auth: route_auth.clone(),
+                ip_allow: reloadable_ip_allow.clone(),
+                trusted_proxies: reloadable_trusted_proxies.clone(),
+            });
+            reloader.reload()?;
+            #[cfg(unix)]
+            reload_on_sighup(reloader.clone())?;
+            // RELOAD_WATCH=true also reloads when the file changes, for local development
+            if env_flag("RELOAD_WATCH") {
+                reload_on_change(reloader.clone())?;
+            }
+            Some(reloader)
+        }
+        Err(_) => None,
     };
 
-    debug!("App State: {:#?}", app_state);
+    let authz = web::Data::new(AuthzState {
+        routes: routes
+            .iter()
+            .filter(|path| reloader.is_some() || **path != "/admin/reload")
+            .filter(|path| usage_counters.is_some() || **path != "/admin/usage")
+            .filter(|path| downstream_state.is_some() || **path != "/api/downstream")
+            .map(|path| path.to_string())
+            .collect(),
+        route_auth: route_auth.clone(),
+        ip_allow: reloadable_ip_allow.clone(),
+        trusted_proxies: reloadable_trusted_proxies.clone(),
+    });
+
+    let selftest_state = dev_hs256_secret.map(|secret| {
+        web::Data::new(SelfTestState {
+            auth: route_auth.for_route(&protected_route_path),
+            secret,
+            path: protected_route_path.clone(),
+        })
+    });
+
+    let discovery = web::Data::new(ApiConfig::new(audience.clone(), tenant_id.clone(), issuers));
+    let openapi_document = web::Data::new(OpenApiDocument(openapi_document(
+        &protected_route_path,
+        reloader.is_some(),
+        usage_counters.is_some(),
+        downstream_state.is_some(),
+        downstream_proxy,
+    )));
 
-    HttpServer::new(move || {
+    summarize(
+        &mut summary,
+        serde_json::json!({
+            "downstream_proxy": downstream_proxy,
+            "protected_route_path": protected_route_path,
+            "cors_allowed_origins": logged_cors_origins,
+            "trusted_proxies": std::env::var("TRUSTED_PROXIES").ok(),
+            "allowed_content_types": logged_content_types,
+            "error_body_template": logged_error_template,
+            "subscription_header_name": subscription_keys
+                .as_ref()
+                .map(|keys| keys.header_name().to_string()),
+            "subscription_keys": subscription_keys.as_ref().map(|_| REDACTED),
+            "usage_max_callers": usage_max_callers,
+            "usage_key": usage_key,
+            "usage_window_secs": usage_window_secs,
+            "jwks_refresh_interval_secs": jwks_refresh_interval_secs,
+            "log_target": std::env::var("LOG_TARGET").unwrap_or_else(|_| "stderr".to_string()),
+            "request_deadline_ms": env_or("REQUEST_DEADLINE_MS", 0)?,
+            "reload_watch": env_flag("RELOAD_WATCH"),
+            "max_concurrent_requests": env_or("MAX_CONCURRENT_REQUESTS", 0)?,
+            "shutdown_timeout_secs": env_or("SHUTDOWN_TIMEOUT_SECS", 30)?,
+            "http_workers": std::env::var("HTTP_WORKERS").ok(),
+            "keep_alive_secs": std::env::var("KEEP_ALIVE_SECS").ok(),
+        }),
+    );
+    let health = web::Data::new(HealthState {
+        caches,
+        config: serde_json::Value::Object(summary),
+    });
+    // One line with everything in effect; secrets only show whether they are set
+    info!("Effective configuration: {}", health.config);
+
+    let in_flight = web::Data::new(InFlightRequests::default());
+    let app_routes = AppRoutes {
+        in_flight: in_flight.clone(),
+        trusted_proxies: reloadable_trusted_proxies,
+        deadline,
+        concurrency_limit,
+        ip_allow: reloadable_ip_allow,
+        cors_policy,
+        error_template,
+        content_type_policy,
+        subscription_keys,
+        selftest: selftest_state,
+        downstream: downstream_state,
+        usage_counters,
+        reloader,
+        health,
+        openapi_document,
+        discovery,
+        batch,
+        token_info: token_info_state,
+        authz,
+        route_auth,
+        protected_route_path,
+        downstream_proxy,
+    };
+    let server = HttpServer::new(move || {
+        // CORS wraps the app, outside every route's BearerAuth, so preflights never need a token
         actix_web::App::new()
-            .app_data(actix_web::web::Data::new(app_state.clone()))
-            .wrap(actix_web::middleware::Logger::default())
-            .route(
-                "/api_protected",
-                actix_web::web::get().to(protected_endpoint),
-            )
-            .route(
-                "/api_protected",
-                actix_web::web::post().to(protected_endpoint),
+            .configure(|config| app_routes.configure(config))
+            .wrap(actix_web::middleware::from_fn(require_content_type))
+            .wrap(actix_web::middleware::from_fn(cors))
+            .wrap(actix_web::middleware::from_fn(request_deadline))
+            .wrap(actix_web::middleware::from_fn(error_bodies))
+            .wrap(actix_web::middleware::from_fn(correlation_id))
+            .wrap(actix_web::middleware::from_fn(limit_concurrency))
+            .wrap(
+                actix_web::middleware::Logger::new(
+                    r#"%{client_ip}xi "%r" %s %b %T correlation_id=%{X-Correlation-Id}o"#,
+                )
+                .custom_request_replace("client_ip", |req| {
+                    client_origin(req.request())
+                        .ip
+                        .map_or_else(|| "-".to_string(), |ip| ip.to_string())
+                }),
             )
-    })
-    .bind("0.0.0.0:8888")?
-    .run()
-    .await?;
+            .wrap(actix_web::middleware::from_fn(track_in_flight))
+    });
+
+    // Unset keeps the actix defaults: one worker per CPU and a 5s keep-alive
+    let server = match std::env::var("HTTP_WORKERS") {
+        Ok(_) => {
+            let workers: usize = env_or("HTTP_WORKERS", 0)?;
+            if !(1..=1024).contains(&workers) {
+                return Err(
+                    format!("HTTP_WORKERS must be between 1 and 1024, got {}", workers).into(),
+                );
+            }
+            server.workers(workers)
+        }
+        Err(_) => server,
+    };
+    let server = match std::env::var("KEEP_ALIVE_SECS") {
+        Ok(_) => {
+            let secs: u64 = env_or("KEEP_ALIVE_SECS", 0)?;
+            if secs > 3600 {
+                return Err(format!("KEEP_ALIVE_SECS must be at most 3600, got {}", secs).into());
+            }
+            // 0 disables keep-alive
+            server.keep_alive(match secs {
+                0 => KeepAlive::Disabled,
+                secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+            })
+        }
+        Err(_) => server,
+    };
+
+    // Sidecars can talk to the server over a Unix domain socket instead of TCP
+    let server = match std::env::var("UDS_PATH") {
+        #[cfg(unix)]
+        Ok(path) => {
+            managed_identity_concept::config::prepare_uds_path(&path)?;
+            info!("Listening on unix socket {}", path);
+            server.bind_uds(&path)?
+        }
+        #[cfg(not(unix))]
+        Ok(_) => return Err("UDS_PATH is only supported on Unix".into()),
+        Err(_) => server.bind("0.0.0.0:8888")?,
+    };
+    let shutdown_timeout: u64 = env_or("SHUTDOWN_TIMEOUT_SECS", 30)?;
+    // Signals are handled here instead of by actix so the drain can be logged
+    let server = server
+        .shutdown_timeout(shutdown_timeout)
+        .disable_signals()
+        .run();
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!(
+            "Shutting down gracefully (timeout {}s), {} requests in flight",
+            shutdown_timeout,
+            in_flight.count()
+        );
+        tokio::spawn(async move {
+            while in_flight.count() > 0 {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                info!("Draining, {} requests in flight", in_flight.count());
+            }
+        });
+        handle.stop(true).await;
+    });
+    server.await?;
+    info!("Server stopped");
 
     Ok(())
 }
+
+/// Completes on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use azure_core::auth::{AccessToken, Secret, TokenCredential};
+    use futures_util::future::BoxFuture;
+    use managed_identity_concept::testing::TestTokenFactory;
+    use managed_identity_concept::timing::ServerTiming;
+    use managed_identity_concept::{TokenValidator, ValidationError};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Accepts `caller` at once and `token-<n>` after `(10 - n) * 20` ms, so later tokens finish
+    /// first, tracking the most validations in flight at a time. Panics on `panic`; anything
+    /// else is invalid.
+    #[derive(Default)]
+    struct SlowValidator {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl TokenValidator for SlowValidator {
+        fn validate_timed<'a>(
+            &'a self,
+            token: &'a str,
+            _timing: &'a mut ServerTiming,
+        ) -> BoxFuture<'a, Result<Claims, ValidationError>> {
+            Box::pin(async move {
+                let delay = match token.strip_prefix("token-") {
+                    Some(n) => 10u64.saturating_sub(n.parse().unwrap_or(10)) * 20,
+                    None if token == "caller" => 0,
+                    None if token == "panic" => panic!("validator bug"),
+                    None => return Err(ValidationError::InvalidToken),
+                };
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                serde_json::from_value(serde_json::json!({
+                    "aud": "api://test",
+                    "iss": "https://test/",
+                    "sub": token,
+                    "exp": i64::MAX,
+                }))
+                .map_err(|_| ValidationError::MalformedClaims)
+            })
+        }
+    }
+
+    /// A `BearerAuth` validating every token with `validator`.
+    fn fake_auth(validator: Arc<dyn TokenValidator>) -> BearerAuth {
+        let unused =
+            JwtValidator::new(Arc::new(JwksCache::from_keys(HashMap::new())), "api://test");
+        BearerAuth::new(BearerAuthConfig::new(unused).with_token_validator(validator))
+    }
+
+    /// Posts `tokens` to `POST /validate`, validated by `validator`.
+    async fn validate_tokens(
+        validator: Arc<SlowValidator>,
+        tokens: &[String],
+    ) -> Vec<serde_json::Value> {
+        let auth = fake_auth(validator);
+        let batch = web::Data::new(BatchState {
+            auth: auth.clone(),
+            limiter: Arc::new(Semaphore::new(16)),
+            max_tokens: 100,
+        });
+        let app = test::init_service(
+            App::new().app_data(batch).service(
+                web::resource("/validate")
+                    .wrap(auth)
+                    .route(web::post().to(validate_batch)),
+            ),
+        )
+        .await;
+        let request = test::TestRequest::post()
+            .uri("/validate")
+            .insert_header(("Authorization", "Bearer caller"))
+            .set_json(serde_json::json!({ "tokens": tokens }))
+            .to_request();
+        test::call_and_read_body_json(&app, request).await
+    }
+
+    #[actix_web::test]
+    async fn batch_validates_concurrently_in_request_order() {
+        let validator = Arc::new(SlowValidator::default());
+        let tokens: Vec<String> = (0..10)
+            .map(|n| match n {
+                3 => "forged".to_string(),
+                n => format!("token-{}", n),
+            })
+            .collect();
+
+        let started = Instant::now();
+        let results = validate_tokens(validator.clone(), &tokens).await;
+        let elapsed = started.elapsed();
+
+        assert_eq!(results.len(), tokens.len());
+        for (token, result) in tokens.iter().zip(&results) {
+            if token == "forged" {
+                assert_eq!(result["valid"], false);
+                assert_eq!(result["error"], "invalid_token");
+            } else {
+                assert_eq!(result["valid"], true);
+                assert_eq!(result["claims"]["sub"], token.as_str());
+            }
+        }
+        // Sequentially the nine delays would add up to 960 ms
+        assert!(validator.max_in_flight.load(Ordering::SeqCst) > 1);
+        assert!(elapsed < Duration::from_millis(600), "took {:?}", elapsed);
+    }
+
+    #[actix_web::test]
+    async fn a_panicking_validation_only_fails_its_token() {
+        let tokens = vec![
+            "token-8".to_string(),
+            "panic".to_string(),
+            "token-9".to_string(),
+        ];
+        let results = validate_tokens(Arc::new(SlowValidator::default()), &tokens).await;
+
+        assert_eq!(results[0]["valid"], true);
+        assert_eq!(results[1]["valid"], false);
+        assert_eq!(results[1]["error"], "internal_error");
+        assert_eq!(results[2]["valid"], true);
+    }
+
+    /// Issues `server-token` for any scope.
+    #[derive(Debug)]
+    struct ServerCredential;
+
+    #[async_trait::async_trait]
+    impl TokenCredential for ServerCredential {
+        async fn get_token(&self, _scopes: &[&str]) -> azure_core::Result<AccessToken> {
+            Ok(AccessToken::new(
+                Secret::new("server-token"),
+                time::OffsetDateTime::now_utc() + time::Duration::hours(1),
+            ))
+        }
+
+        async fn clear_cache(&self) -> azure_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The `Authorization` values of each request a downstream API received.
+    type SeenAuthorizations = web::Data<Mutex<Vec<Vec<String>>>>;
+
+    /// A downstream API answering `proxied`, recording what it received, and its URL.
+    fn start_downstream() -> (SeenAuthorizations, String) {
+        let seen: SeenAuthorizations = web::Data::new(Mutex::new(Vec::new()));
+        let data = seen.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(data.clone()).default_service(web::to(
+                |req: HttpRequest, seen: SeenAuthorizations| async move {
+                    let authorizations = req
+                        .headers()
+                        .get_all("authorization")
+                        .map(|value| value.to_str().unwrap().to_string())
+                        .collect();
+                    seen.lock().unwrap().push(authorizations);
+                    HttpResponse::Ok().body("proxied")
+                },
+            ))
+        })
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/downstream", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        (seen, url)
+    }
+
+    /// `GET /api_protected` proxied to `url` under `enforcement`, requiring `Task.HelloWorld`,
+    /// with `token` as the caller's bearer token if any.
+    async fn proxy_status(
+        factory: &TestTokenFactory,
+        url: String,
+        enforcement: Enforcement,
+        token: Option<&str>,
+    ) -> (u16, String) {
+        let auth = BearerAuth::new(
+            BearerAuthConfig::new(factory.validator().unwrap())
+                .with_required_roles(vec!["Task.HelloWorld".to_string()])
+                .with_enforcement(enforcement),
+        );
+        let state = web::Data::new(DownstreamState {
+            url,
+            tokens: OutboundTokenProvider::new(
+                Arc::new(ServerCredential),
+                "api://downstream/.default",
+            ),
+            client: reqwest::Client::new(),
+            timeout: Duration::from_secs(5),
+            // Refused by DOWNSTREAM_FORWARD_HEADERS, listed to check the handler skips it too
+            forward_headers: vec!["x-correlation-id".to_string(), "authorization".to_string()],
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(protected_resource("/api_protected", true).wrap(auth)),
+        )
+        .await;
+        let mut request = test::TestRequest::get()
+            .uri("/api_protected")
+            .insert_header(("x-correlation-id", "abc"));
+        if let Some(token) = token {
+            request = request.insert_header(("Authorization", format!("Bearer {}", token)));
+        }
+        let response = test::call_service(&app, request.to_request()).await;
+        let status = response.status().as_u16();
+        let body = test::read_body(response).await;
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[actix_web::test]
+    async fn proxied_requests_carry_only_the_server_token() {
+        let factory = TestTokenFactory::new().unwrap();
+        let (seen, url) = start_downstream();
+        let token = factory
+            .token()
+            .with_roles(&["Task.HelloWorld"])
+            .sign()
+            .unwrap();
+
+        let (status, body) = proxy_status(&factory, url, Enforcement::Enforce, Some(&token)).await;
+
+        assert_eq!(status, 200);
+        assert_eq!(body, "proxied");
+        assert_eq!(*seen.lock().unwrap(), vec![vec!["Bearer server-token"]]);
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_requests_are_not_proxied() {
+        let factory = TestTokenFactory::new().unwrap();
+        let (seen, url) = start_downstream();
+        let other = factory.token().with_roles(&["Task.Other"]).sign().unwrap();
+
+        let (status, _) = proxy_status(&factory, url.clone(), Enforcement::Enforce, None).await;
+        assert_eq!(status, 401);
+        let (status, _) =
+            proxy_status(&factory, url.clone(), Enforcement::Enforce, Some(&other)).await;
+        assert_eq!(status, 403);
+        // Without enforcement the request is let through, but not passed on
+        let (status, _) = proxy_status(&factory, url.clone(), Enforcement::Log, None).await;
+        assert_eq!(status, 401);
+        let (status, _) = proxy_status(&factory, url.clone(), Enforcement::Log, Some(&other)).await;
+        assert_eq!(status, 401);
+        let (status, _) = proxy_status(&factory, url, Enforcement::Off, None).await;
+        assert_eq!(status, 401);
+
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    /// Route auth requiring `Task.HelloWorld` by default and `Api.Admin` on the `ADMIN_ROUTES`.
+    fn admin_route_auth(factory: &TestTokenFactory) -> RouteAuth {
+        let config = BearerAuthConfig::new(factory.validator().unwrap())
+            .with_required_roles(vec!["Task.HelloWorld".to_string()]);
+        let overrides = ADMIN_ROUTES
+            .iter()
+            .map(|path| {
+                let route = RouteOverride {
+                    required_roles: Some(vec!["Api.Admin".to_string()]),
+                    ..RouteOverride::default()
+                };
+                (path.to_string(), route)
+            })
+            .collect();
+        RouteAuth::new(BearerAuth::new(config), overrides)
+    }
+
+    /// The status of `GET path`, protected by `route_auth`, for a token carrying `roles`.
+    async fn status_for(
+        route_auth: &RouteAuth,
+        factory: &TestTokenFactory,
+        path: &str,
+        roles: &[&str],
+    ) -> u16 {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::resource("/api_protected")
+                        .wrap(route_auth.for_route("/api_protected"))
+                        .to(HttpResponse::Ok),
+                )
+                .service(
+                    web::resource("/admin/authz")
+                        .wrap(route_auth.for_route("/admin/authz"))
+                        .to(HttpResponse::Ok),
+                ),
+        )
+        .await;
+        let token = factory.token().with_roles(roles).sign().unwrap();
+        let request = test::TestRequest::get()
+            .uri(path)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        test::call_service(&app, request).await.status().as_u16()
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_require_an_admin_role() {
+        let factory = TestTokenFactory::new().unwrap();
+        let route_auth = admin_route_auth(&factory);
+        let user = ["Task.HelloWorld"];
+        let admin = ["Api.Admin"];
+
+        assert_eq!(
+            status_for(&route_auth, &factory, "/api_protected", &user).await,
+            200
+        );
+        assert_eq!(
+            status_for(&route_auth, &factory, "/admin/authz", &user).await,
+            403
+        );
+        assert_eq!(
+            status_for(&route_auth, &factory, "/admin/authz", &admin).await,
+            200
+        );
+        assert_eq!(
+            status_for(&route_auth, &factory, "/api_protected", &admin).await,
+            403
+        );
+    }
+
+    #[actix_web::test]
+    async fn reloading_the_required_roles_keeps_the_admin_roles() {
+        let factory = TestTokenFactory::new().unwrap();
+        let route_auth = admin_route_auth(&factory);
+        route_auth.reload(
+            BearerAuthConfig::new(factory.validator().unwrap())
+                .with_required_roles(vec!["Task.Other".to_string()]),
+        );
+
+        let other = ["Task.Other"];
+        assert_eq!(
+            status_for(&route_auth, &factory, "/api_protected", &other).await,
+            200
+        );
+        assert_eq!(
+            status_for(&route_auth, &factory, "/admin/authz", &other).await,
+            403
+        );
+        assert_eq!(
+            status_for(&route_auth, &factory, "/admin/authz", &["Api.Admin"]).await,
+            200
+        );
+    }
+
+    /// `GET /selftest` with tokens minted with `minting_secret`, validated by the dev
+    /// configuration accepting tokens signed with `secret` that carry `Task.HelloWorld`.
+    #[cfg(feature = "insecure-dev")]
+    async fn selftest_with(secret: &str, minting_secret: &str) -> (u16, serde_json::Value) {
+        let validator =
+            JwtValidator::new(Arc::new(JwksCache::from_keys(HashMap::new())), "api://test")
+                .with_issuers(vec!["https://sts.windows.net/test-tenant/".to_string()]);
+        let validator = enable_dev_hs256(validator, secret).unwrap();
+        let auth = BearerAuth::new(
+            BearerAuthConfig::new(validator)
+                .with_required_roles(vec!["Task.HelloWorld".to_string()]),
+        );
+        let state = web::Data::new(SelfTestState {
+            auth,
+            secret: minting_secret.to_string(),
+            path: "/api_protected".to_string(),
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .route("/selftest", web::get().to(selftest)),
+        )
+        .await;
+        let request = test::TestRequest::get().uri("/selftest").to_request();
+        let response = test::call_service(&app, request).await;
+        let status = response.status().as_u16();
+        (status, test::read_body_json(response).await)
+    }
+
+    #[cfg(feature = "insecure-dev")]
+    #[actix_web::test]
+    async fn selftest_succeeds_under_the_dev_config() {
+        let (status, body) = selftest_with("dev-secret", "dev-secret").await;
+
+        assert_eq!(status, 200);
+        assert_eq!(body["ok"], true);
+        assert_eq!(body["route"], "/api_protected");
+        assert_eq!(body["sub"], "selftest");
+        assert_eq!(body["aud"], serde_json::json!(["api://test"]));
+        assert_eq!(body["roles"], serde_json::json!(["Task.HelloWorld"]));
+    }
+
+    #[cfg(feature = "insecure-dev")]
+    #[actix_web::test]
+    async fn selftest_reports_a_refused_token() {
+        let (status, body) = selftest_with("dev-secret", "other-secret").await;
+
+        assert_eq!(status, 500);
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["stage"], "validate");
+    }
+}
@@ -1,37 +1,619 @@
-use azure_core::auth::TokenCredential;
+use azure_core::auth::{AccessToken, TokenCredential};
 use azure_identity::{DefaultAzureCredential, TokenCredentialOptions};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use dotenv::dotenv;
-use log::{debug, info};
-use reqwest::Client;
+use log::{debug, info, warn};
+use managed_identity_concept::authority::Cloud;
+use managed_identity_concept::discovery::{api_config_url, ApiConfig};
+use managed_identity_concept::outbound::OutboundTokenCache;
+use reqwest::{Client, StatusCode};
 use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An access token that cannot leak through logging: `Debug` and `Display` print a placeholder.
+///
+/// The only way to the secret is `expose`, which keeps every use of it easy to find.
+struct SensitiveToken(String);
+
+impl SensitiveToken {
+    /// The raw token, for the `Authorization` header.
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for SensitiveToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SensitiveToken(<redacted, {} bytes>)", self.0.len())
+    }
+}
+
+impl std::fmt::Display for SensitiveToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Builds the HTTP client used to call the protected API.
+///
+/// # Arguments
+///
+/// * `insecure` - When `true`, TLS certificate verification is disabled. This is only meant for
+///   local development against a server with a self-signed certificate.
+fn build_http_client(insecure: bool) -> reqwest::Result<Client> {
+    if insecure {
+        eprintln!(
+            "WARNING: TLS certificate verification is DISABLED (--insecure / INSECURE_SKIP_VERIFY). \
+             Never use this outside local development."
+        );
+    }
+    Client::builder()
+        .danger_accept_invalid_certs(insecure)
+        .build()
+}
+
+/// Returns `true` when insecure TLS was requested via `--insecure` or `INSECURE_SKIP_VERIFY=true`.
+fn insecure_requested() -> bool {
+    std::env::args().any(|arg| arg == "--insecure")
+        || std::env::var("INSECURE_SKIP_VERIFY")
+            .map(|value| value.eq_ignore_ascii_case("true") || value == "1")
+            .unwrap_or(false)
+}
+
+/// Gets a token, retrying failed attempts to ride out transient failures such as IMDS throttling.
+///
+/// Attempt `n` (starting at 1) waits `base_delay * 2^(n-1)` before the next one.
+async fn get_token_with_retry(
+    credential: &dyn TokenCredential,
+    scopes: &[&str],
+    max_attempts: u32,
+    base_delay: Duration,
+) -> azure_core::Result<AccessToken> {
+    let mut attempt = 1;
+    loop {
+        match credential.get_token(scopes).await {
+            Ok(token) => return Ok(token),
+            Err(e) if attempt < max_attempts.max(1) => {
+                let delay = base_delay * 2u32.saturating_pow(attempt - 1);
+                warn!(
+                    "Token acquisition attempt {}/{} failed, retrying in {:?}: {}",
+                    attempt, max_attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Lists why each credential in the chain failed.
+///
+/// `DefaultAzureCredential` reports one line per credential it tried, each with the causes
+/// joined by ` - `; this flattens the error and its sources into those lines.
+fn credential_failure_report(error: &(dyn Error + 'static)) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = Some(error);
+    while let Some(err) = current {
+        for line in err.to_string().lines().map(str::trim) {
+            // Skip empty lines and headings such as "Multiple errors were encountered ...:"
+            if !line.is_empty() && !line.ends_with(':') && !lines.iter().any(|seen| seen == line) {
+                lines.push(line.to_string());
+            }
+        }
+        current = err.source();
+    }
+    lines
+}
+
+/// Prints the credential chain report for a failed token acquisition to stderr.
+fn report_credential_failure(error: &azure_core::Error, attempts: u32) {
+    eprintln!("Could not acquire a token after {} attempt(s):", attempts);
+    for line in credential_failure_report(error) {
+        eprintln!("  - {}", line);
+    }
+}
+
+/// How the middle-tier app authenticates itself in the on-behalf-of exchange.
+///
+/// # Variants
+///
+/// * `Secret` - A client secret of the app registration.
+/// * `Assertion` - A signed assertion, e.g. a managed identity token for
+///   `api://AzureADTokenExchange` when the app trusts the identity as a federated credential.
+enum ClientAuth {
+    Secret(String),
+    Assertion(String),
+}
+
+/// Audience of the managed identity token used as a federated client assertion.
+const TOKEN_EXCHANGE_AUDIENCE: &str = "api://AzureADTokenExchange";
+
+/// The form fields of an on-behalf-of token request (RFC 7523 `jwt-bearer` grant).
+fn obo_request_form(
+    client_id: &str,
+    client_auth: &ClientAuth,
+    incoming_token: &str,
+    scope: &str,
+) -> Vec<(&'static str, String)> {
+    let mut form = vec![
+        (
+            "grant_type",
+            "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string(),
+        ),
+        ("client_id", client_id.to_string()),
+        ("assertion", incoming_token.to_string()),
+        ("scope", scope.to_string()),
+        ("requested_token_use", "on_behalf_of".to_string()),
+    ];
+    match client_auth {
+        ClientAuth::Secret(secret) => form.push(("client_secret", secret.clone())),
+        ClientAuth::Assertion(assertion) => {
+            form.push((
+                "client_assertion_type",
+                "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".to_string(),
+            ));
+            form.push(("client_assertion", assertion.clone()));
+        }
+    }
+    form
+}
+
+/// The `.default` scope for `resource`, as the v2.0 token endpoint expects.
+fn default_scope(resource: &str) -> String {
+    if resource.ends_with("/.default") {
+        resource.to_string()
+    } else {
+        format!("{}/.default", resource.trim_end_matches('/'))
+    }
+}
+
+/// Exchanges `incoming_token` for a token for `scope` with the on-behalf-of flow.
+async fn on_behalf_of(
+    client: &Client,
+    token_endpoint: &str,
+    form: &[(&'static str, String)],
+) -> Result<SensitiveToken, Box<dyn Error>> {
+    let response = client.post(token_endpoint).form(form).send().await?;
+    let status = response.status();
+    let body: serde_json::Value = response.json().await?;
+    if !status.is_success() {
+        return Err(format!(
+            "on-behalf-of exchange failed ({}): {} {}",
+            status,
+            body["error"].as_str().unwrap_or("unknown_error"),
+            body["error_description"].as_str().unwrap_or_default()
+        )
+        .into());
+    }
+    body["access_token"]
+        .as_str()
+        .map(|token| SensitiveToken(token.to_string()))
+        .ok_or_else(|| "on-behalf-of response has no access_token".into())
+}
+
+/// Returns `true` when the on-behalf-of mode was requested via `--obo`.
+fn obo_requested() -> bool {
+    std::env::args().any(|arg| arg == "--obo")
+}
+
+/// Returns `true` when the `watch` subcommand was given as the first argument.
+fn watch_requested() -> bool {
+    std::env::args().nth(1).as_deref() == Some("watch")
+}
+
+/// Returns `true` when the `discover` subcommand was given as the first argument.
+fn discover_requested() -> bool {
+    std::env::args().nth(1).as_deref() == Some("discover")
+}
+
+/// Fetches the `ApiConfig` the API at `base_url` publishes, which needs no token.
+async fn discover(client: &Client, base_url: &str) -> Result<ApiConfig, Box<dyn Error>> {
+    let url = api_config_url(base_url);
+    info!("Discovering the API configuration from {}", url);
+    let response = client.get(&url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("API configuration discovery failed ({}) at {}", status, url).into());
+    }
+    Ok(response.json().await?)
+}
+
+/// Calls the protected API once with `access_token`, returning the response status and body.
+async fn call_api(
+    client: &Client,
+    api_url: &str,
+    access_token: &SensitiveToken,
+) -> Result<(StatusCode, String), Box<dyn Error>> {
+    // Correlate this call with the server logs
+    let correlation_id = uuid::Uuid::new_v4().to_string();
+    info!("Correlation Id: {}", correlation_id);
+
+    // Call the protected API with the token
+    let api_response = client
+        .get(api_url)
+        .bearer_auth(access_token.expose())
+        .header("X-Correlation-Id", &correlation_id)
+        .send()
+        .await?;
+
+    match api_response
+        .headers()
+        .get("X-Correlation-Id")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(echoed) if echoed == correlation_id => debug!("Correlation Id echoed by the server"),
+        Some(echoed) => warn!(
+            "Server echoed a different Correlation Id: {} (sent {})",
+            echoed, correlation_id
+        ),
+        None => warn!("Server did not echo the Correlation Id"),
+    }
+
+    let status = api_response.status();
+    if let Some(challenge) = api_response
+        .headers()
+        .get("WWW-Authenticate")
+        .and_then(|value| value.to_str().ok())
+    {
+        warn!("Server challenge: {}", challenge);
+    }
+    Ok((status, api_response.text().await?))
+}
+
+/// When `watch` stops: after `max_calls` calls if set, otherwise on Ctrl+C.
+///
+/// # Fields
+///
+/// * `interval` - The delay between two calls.
+/// * `max_calls` - Stop after this many calls; `None` runs until interrupted.
+struct WatchSchedule {
+    interval: Duration,
+    max_calls: Option<u64>,
+}
+
+impl WatchSchedule {
+    /// Reads `WATCH_INTERVAL_SECS` (default 60) and `WATCH_MAX_CALLS` (default unlimited).
+    fn from_env() -> Result<Self, Box<dyn Error>> {
+        let interval: u64 = match std::env::var("WATCH_INTERVAL_SECS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|e| format!("Invalid WATCH_INTERVAL_SECS={:?}: {}", value, e))?,
+            Err(_) => 60,
+        };
+        if interval == 0 {
+            return Err("WATCH_INTERVAL_SECS must be at least 1".into());
+        }
+        let max_calls = match std::env::var("WATCH_MAX_CALLS") {
+            Ok(value) => Some(
+                value
+                    .parse()
+                    .map_err(|e| format!("Invalid WATCH_MAX_CALLS={:?}: {}", value, e))?,
+            ),
+            Err(_) => None,
+        };
+        Ok(Self {
+            interval: Duration::from_secs(interval),
+            max_calls,
+        })
+    }
+
+    /// Whether another call is due after `calls` calls.
+    fn continues_after(&self, calls: u64) -> bool {
+        self.max_calls.is_none_or(|max_calls| calls < max_calls)
+    }
+}
+
+/// Calls the API on every tick of `schedule`, for soak-testing token rotation.
+///
+/// The token comes from `tokens`, which only asks the credential again when it is within 5
+/// minutes of expiring, so a changed token means it was refreshed, which is logged with its new
+/// expiry. Failed token acquisitions and API calls are logged and counted, and the loop goes on.
+async fn watch(
+    client: &Client,
+    api_url: &str,
+    tokens: &OutboundTokenCache,
+    resource: &str,
+    schedule: &WatchSchedule,
+) {
+    info!(
+        "Watching {} every {:?} ({})",
+        api_url,
+        schedule.interval,
+        match schedule.max_calls {
+            Some(max_calls) => format!("{} calls", max_calls),
+            None => "until interrupted".to_string(),
+        }
+    );
+    let mut current: Option<SensitiveToken> = None;
+    let (mut calls, mut refreshes, mut failures) = (0u64, 0u64, 0u64);
+    while schedule.continues_after(calls) {
+        if calls > 0 {
+            tokio::select! {
+                _ = tokio::time::sleep(schedule.interval) => {}
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Interrupted");
+                    break;
+                }
+            }
+        }
+        calls += 1;
+        let token = match tokens.get_valid_token(resource).await {
+            Ok(token) => token,
+            Err(e) => {
+                failures += 1;
+                warn!("Call {}: could not acquire a token: {}", calls, e);
+                continue;
+            }
+        };
+        let token_changed = current
+            .as_ref()
+            .is_none_or(|current| current.expose() != token.expose());
+        if token_changed {
+            if current.is_some() {
+                refreshes += 1;
+                info!(
+                    "Call {}: token refreshed, expires {}",
+                    calls,
+                    token.expires_on()
+                );
+            } else {
+                info!(
+                    "Call {}: token acquired, expires {}",
+                    calls,
+                    token.expires_on()
+                );
+            }
+            current = Some(SensitiveToken(token.expose().to_string()));
+        }
+        let Some(access_token) = &current else {
+            continue;
+        };
+        match call_api(client, api_url, access_token).await {
+            Ok((status, _)) if status.is_success() => info!("Call {}: {}", calls, status),
+            Ok((status, body)) => {
+                failures += 1;
+                warn!("Call {}: {} {}", calls, status, body);
+            }
+            Err(e) => {
+                failures += 1;
+                warn!("Call {}: request failed: {}", calls, e);
+            }
+        }
+    }
+    info!(
+        "Watch finished: {} calls, {} token refreshes ({} acquisitions), {} failures",
+        calls,
+        refreshes,
+        tokens.acquisitions(),
+        failures
+    );
+}
+
+/// Claims the acquired token must carry, from `--expect-role <role>`, `--expect-audience <aud>`
+/// and `--expect-claim <name>=<value>` (each repeatable), to use the client as a deployment gate.
+///
+/// # Fields
+///
+/// * `roles` - App roles `roles` must contain.
+/// * `audiences` - Values `aud` must be or contain.
+/// * `claims` - Claim names with the value they must have, or contain when they are arrays.
+#[derive(Default)]
+struct Expectations {
+    roles: Vec<String>,
+    audiences: Vec<String>,
+    claims: Vec<(String, String)>,
+}
+
+impl Expectations {
+    /// Reads the `--expect-*` flags from the command line.
+    fn from_args() -> Result<Self, Box<dyn Error>> {
+        let mut expectations = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--expect-") {
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?;
+            match arg.as_str() {
+                "--expect-role" => expectations.roles.push(value),
+                "--expect-audience" => expectations.audiences.push(value),
+                "--expect-claim" => {
+                    let (name, expected) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("--expect-claim {:?} is not name=value", value))?;
+                    expectations
+                        .claims
+                        .push((name.to_string(), expected.to_string()));
+                }
+                _ => return Err(format!("Unknown option {}", arg).into()),
+            }
+        }
+        Ok(expectations)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.roles.is_empty() && self.audiences.is_empty() && self.claims.is_empty()
+    }
+
+    /// Prints a `[PASS]` or `[FAIL]` line per expectation and returns how many failed.
+    fn check(&self, claims: &serde_json::Value) -> usize {
+        let checks = self
+            .roles
+            .iter()
+            .map(|role| ("role", "roles", role))
+            .chain(self.audiences.iter().map(|aud| ("audience", "aud", aud)))
+            .chain(
+                self.claims
+                    .iter()
+                    .map(|(name, expected)| ("claim", name.as_str(), expected)),
+            );
+        let mut failed = 0;
+        for (kind, name, expected) in checks {
+            let actual = &claims[name];
+            if claim_matches(actual, expected) {
+                println!("[PASS] {:<8} {} has {:?}", kind, name, expected);
+            } else {
+                println!(
+                    "[FAIL] {:<8} {} is {}, expected {:?}",
+                    kind, name, actual, expected
+                );
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+/// Whether the claim `actual` is `expected`, or contains it when it is an array. Numbers and
+/// booleans match `expected` read as JSON, e.g. `--expect-claim acrs=true`.
+fn claim_matches(actual: &serde_json::Value, expected: &str) -> bool {
+    match actual {
+        serde_json::Value::String(value) => value == expected,
+        serde_json::Value::Array(values) => {
+            values.iter().any(|value| claim_matches(value, expected))
+        }
+        serde_json::Value::Null => false,
+        value => serde_json::from_str::<serde_json::Value>(expected)
+            .is_ok_and(|expected| &expected == value),
+    }
+}
+
+/// The claims of `token`, decoded without checking the signature: the token comes straight from
+/// the credential and is only inspected, the API validates it.
+fn decode_claims(token: &SensitiveToken) -> Result<serde_json::Value, Box<dyn Error>> {
+    let payload = token
+        .expose()
+        .split('.')
+        .nth(1)
+        .ok_or("The access token is not a JWT")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|e| format!("The access token payload is not base64url: {}", e))?;
+    Ok(serde_json::from_slice(&payload)?)
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
     dotenv().ok();
 
+    let client = build_http_client(insecure_requested())?;
+
+    // discover <base URL> prints the RESOURCE_NAME and TENANT_ID the API expects
+    if discover_requested() {
+        let base_url = std::env::args()
+            .nth(2)
+            .filter(|arg| !arg.starts_with("--"))
+            .or_else(|| std::env::var("API_BASE_URL").ok())
+            .ok_or("discover needs the API base URL as an argument or in API_BASE_URL")?;
+        let config = discover(&client, &base_url).await?;
+        if let Ok(resource) = std::env::var("RESOURCE_NAME") {
+            if default_scope(&resource) != config.scope {
+                warn!(
+                    "RESOURCE_NAME is {}, but the API expects tokens for {}",
+                    resource, config.audience
+                );
+            }
+        }
+        println!("RESOURCE_NAME={}", config.audience);
+        println!("TENANT_ID={}", config.tenant_id);
+        return Ok(());
+    }
+
     let api_url = std::env::var("API_URL").expect("API_URL is not set");
     let resource = std::env::var("RESOURCE_NAME").expect("RESOURCE_NAME is not set");
 
-    let client = Client::new();
-
     // Use Managed Identity with DefaultAzureCredential
-    let credential = DefaultAzureCredential::create(TokenCredentialOptions::default())?;
-    // Get a token for the resource
-    // Example resource > "https://management.azure.com/" or api://<resource-id>
-    let token_response = credential.get_token(&[resource.as_str()]).await?;
-    let access_token = token_response.token.secret();
+    let credential = DefaultAzureCredential::create(TokenCredentialOptions::default())
+        .inspect_err(|e| report_credential_failure(e, 1))?;
+    let max_attempts = std::env::var("TOKEN_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(3);
 
-    debug!("Access Token: {}", access_token);
+    let expectations = Expectations::from_args()?;
 
-    // Call the protected API with the token
-    let api_response = client
-        .get(&api_url)
-        .bearer_auth(access_token)
-        .send()
-        .await?;
+    if watch_requested() {
+        if obo_requested() {
+            return Err("watch does not support --obo".into());
+        }
+        if !expectations.is_empty() {
+            return Err("watch does not support --expect-*".into());
+        }
+        let schedule = WatchSchedule::from_env()?;
+        let tokens = OutboundTokenCache::new(Arc::new(credential))
+            .with_retry(max_attempts, Duration::from_millis(500));
+        watch(&client, &api_url, &tokens, &resource, &schedule).await;
+        return Ok(());
+    }
+
+    // On-behalf-of: forward an incoming user token as a downstream token for the resource
+    let access_token = if obo_requested() {
+        let tenant_id = std::env::var("TENANT_ID")?;
+        let client_id = std::env::var("OBO_CLIENT_ID")?;
+        let incoming_token = std::env::var("OBO_INCOMING_TOKEN")?;
+        let client_auth = match std::env::var("OBO_CLIENT_SECRET") {
+            Ok(secret) => ClientAuth::Secret(secret),
+            Err(_) => {
+                let assertion = get_token_with_retry(
+                    &credential,
+                    &[TOKEN_EXCHANGE_AUDIENCE],
+                    max_attempts,
+                    Duration::from_millis(500),
+                )
+                .await
+                .inspect_err(|e| report_credential_failure(e, max_attempts))?;
+                ClientAuth::Assertion(assertion.token.secret().to_string())
+            }
+        };
+        let authority_host = match std::env::var("OBO_AUTHORITY_HOST") {
+            Ok(host) => host,
+            Err(_) => Cloud::from_env()?.authority_url(),
+        };
+        let token_endpoint = format!(
+            "{}/{}/oauth2/v2.0/token",
+            authority_host.trim_end_matches('/'),
+            tenant_id
+        );
+        info!("Exchanging the incoming token on behalf of the user");
+        let form = obo_request_form(
+            &client_id,
+            &client_auth,
+            &incoming_token,
+            &default_scope(&resource),
+        );
+        on_behalf_of(&client, &token_endpoint, &form).await?
+    } else {
+        // Get a token for the resource
+        // Example resource > "https://management.azure.com/" or api://<resource-id>
+        let token_response = get_token_with_retry(
+            &credential,
+            &[resource.as_str()],
+            max_attempts,
+            Duration::from_millis(500),
+        )
+        .await
+        .inspect_err(|e| report_credential_failure(e, max_attempts))?;
+        SensitiveToken(token_response.token.secret().to_string())
+    };
+
+    debug!("Access Token: {:?}", access_token);
+
+    // Fail before calling the API when the token lacks an expected claim
+    if !expectations.is_empty() {
+        let failed = expectations.check(&decode_claims(&access_token)?);
+        if failed > 0 {
+            return Err(format!("{} token expectation(s) failed", failed).into());
+        }
+        println!("All token expectations passed");
+    }
 
-    let result = api_response.text().await?;
+    let (_, result) = call_api(&client, &api_url, &access_token).await?;
     info!("API Response: {}", result);
 
     Ok(())
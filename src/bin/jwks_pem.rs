@@ -0,0 +1,118 @@
+use managed_identity_concept::jwks::{fetch_jwks_document, is_signing_key, rsa_public_key_pem};
+use managed_identity_concept::Authority;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: jwks-pem [--tenant <tenant-id>] [--url <jwks-url>] <output-dir>
+
+Fetches a JWKS and writes each RSA key to <output-dir>/<kid>.pem.
+Point the server at the directory with JWKS_URL=file://<output-dir> to pin those keys.
+--url defaults to JWKS_URL, then to the JWKS of the tenant (--tenant or TENANT_ID).";
+
+/// Command line options of the `jwks-pem` tool.
+struct Options {
+    jwks_url: String,
+    output_dir: PathBuf,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut tenant_id = std::env::var("TENANT_ID").ok();
+        let mut jwks_url = std::env::var("JWKS_URL").ok();
+        let mut output_dir = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tenant" => tenant_id = args.next(),
+                "--url" => jwks_url = args.next(),
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ => output_dir = Some(PathBuf::from(arg)),
+            }
+        }
+
+        let output_dir = output_dir.ok_or(USAGE)?;
+        let jwks_url = match jwks_url {
+            Some(url) => url,
+            None => {
+                let tenant_id = tenant_id.ok_or("TENANT_ID is required without --url")?;
+                Authority::from_env(&tenant_id)
+                    .map_err(|e| e.to_string())?
+                    .jwks_url()
+            }
+        };
+        Ok(Self {
+            jwks_url,
+            output_dir,
+        })
+    }
+}
+
+/// Whether `kid` can be used as a file name as is, so the server reads back the same kid.
+fn is_safe_kid(kid: &str) -> bool {
+    !kid.is_empty() && !kid.starts_with('.') && !kid.contains(['/', '\\'])
+}
+
+/// Writes the PEM of one JWK, returning the file written.
+fn write_key(output_dir: &Path, key: &serde_json::Value) -> Result<PathBuf, String> {
+    let kid = key["kid"].as_str().ok_or("key has no kid")?;
+    if !is_safe_kid(kid) {
+        return Err(format!("kid {:?} cannot be used as a file name", kid));
+    }
+    if !is_signing_key(key) {
+        return Err(format!("kid {}: not a signing key", kid));
+    }
+    if key["kty"].as_str() != Some("RSA") {
+        return Err(format!("kid {}: only RSA keys are supported", kid));
+    }
+    let (Some(n), Some(e)) = (key["n"].as_str(), key["e"].as_str()) else {
+        return Err(format!("kid {}: missing n or e", kid));
+    };
+    let pem = rsa_public_key_pem(n, e).map_err(|e| format!("kid {}: {}", kid, e))?;
+    let path = output_dir.join(format!("{}.pem", kid));
+    std::fs::write(&path, pem).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let document = match fetch_jwks_document(&options.jwks_url).await {
+        Ok(document) => document,
+        Err(e) => {
+            eprintln!("Cannot fetch {}: {}", options.jwks_url, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(keys) = document["keys"].as_array() else {
+        eprintln!("{} has no keys array", options.jwks_url);
+        return ExitCode::FAILURE;
+    };
+    if let Err(e) = std::fs::create_dir_all(&options.output_dir) {
+        eprintln!("Cannot create {}: {}", options.output_dir.display(), e);
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed = false;
+    for key in keys {
+        match write_key(&options.output_dir, key) {
+            Ok(path) => println!("Wrote {}", path.display()),
+            Err(e) => {
+                eprintln!("Skipped a key: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
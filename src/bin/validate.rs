@@ -0,0 +1,208 @@
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use managed_identity_concept::authority::TokenVersion;
+use managed_identity_concept::{Authority, JwksCache, JwtValidator};
+use std::io::Read;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: validate [--tenant <tenant-id>] [--audience <audience>] [token]
+
+Validates an access token against the tenant's JWKS and prints a report.
+The token is read from stdin when not given as an argument.
+--tenant and --audience default to TENANT_ID and API_AUDIENCE.";
+
+/// Command line options of the `validate` tool.
+struct Options {
+    tenant_id: String,
+    audience: String,
+    token: String,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut tenant_id = std::env::var("TENANT_ID").ok();
+        let mut audience = std::env::var("API_AUDIENCE").ok();
+        let mut token = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tenant" => tenant_id = args.next(),
+                "--audience" => audience = args.next(),
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ => token = Some(arg),
+            }
+        }
+
+        let token = match token {
+            Some(token) => token,
+            None => {
+                let mut input = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut input)
+                    .map_err(|e| format!("Failed to read token from stdin: {}", e))?;
+                input
+            }
+        };
+
+        Ok(Self {
+            tenant_id: tenant_id.ok_or("Missing --tenant (or TENANT_ID)")?,
+            audience: audience.ok_or("Missing --audience (or API_AUDIENCE)")?,
+            token: token.trim().to_string(),
+        })
+    }
+}
+
+/// Prints one line of the report and returns whether the check passed.
+fn check(name: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("[PASS] {:<12} {}", name, detail);
+            true
+        }
+        Err(detail) => {
+            println!("[FAIL] {:<12} {}", name, detail);
+            false
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    pretty_env_logger::init();
+    dotenv::dotenv().ok();
+
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let authority = match Authority::from_env(&options.tenant_id) {
+        Ok(authority) => authority,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let jwks = Arc::new(JwksCache::new(
+        authority.jwks_url(),
+        Duration::from_secs(3600),
+    ));
+    let validator = JwtValidator::new(jwks.clone(), options.audience.clone())
+        .with_issuers(authority.issuers(&TokenVersion::ALL));
+
+    println!("Token validation report");
+    println!("  JWKS:     {}", jwks.jwks_url());
+    println!("  Audience: {}", options.audience);
+    println!();
+
+    let header = decode_header(&options.token);
+    check(
+        "header",
+        header
+            .as_ref()
+            .map(|h| format!("alg={:?} kid={:?}", h.alg, h.kid))
+            .map_err(|e| e.to_string()),
+    );
+    let keys = jwks.get_keys().await;
+    check(
+        "jwks",
+        keys.as_ref()
+            .map(|keys| format!("{} keys loaded", keys.len()))
+            .map_err(|e| e.to_string()),
+    );
+
+    if let (Ok(header), Ok(keys)) = (&header, &keys) {
+        let key = header.kid.as_ref().and_then(|kid| keys.get(kid));
+        check(
+            "kid",
+            key.map(|_| "matching key found".to_string())
+                .ok_or_else(|| format!("no key for kid {:?}", header.kid)),
+        );
+
+        if let Some(key) = key {
+            // Check the signature alone, then each claim on top of it, so every failure is reported.
+            let mut signature_only = Validation::new(Algorithm::RS256);
+            signature_only.validate_exp = false;
+            signature_only.validate_aud = false;
+            signature_only.required_spec_claims.clear();
+            let decoded = decode::<serde_json::Value>(&options.token, key, &signature_only);
+            check(
+                "signature",
+                decoded
+                    .as_ref()
+                    .map(|_| "valid".to_string())
+                    .map_err(|e| e.to_string()),
+            );
+
+            if let Ok(data) = decoded {
+                let claims = data.claims;
+                let now = jsonwebtoken::get_current_timestamp();
+                let exp = claims["exp"].as_u64();
+                check(
+                    "exp",
+                    match exp {
+                        Some(exp) if exp > now => Ok(format!("expires in {}s", exp - now)),
+                        Some(exp) => Err(format!("expired {}s ago", now - exp)),
+                        None => Err("missing".to_string()),
+                    },
+                );
+                let aud_matches = match &claims["aud"] {
+                    serde_json::Value::String(aud) => aud == &options.audience,
+                    serde_json::Value::Array(auds) => auds
+                        .iter()
+                        .any(|aud| aud.as_str() == Some(&options.audience)),
+                    _ => false,
+                };
+                check(
+                    "aud",
+                    if aud_matches {
+                        Ok(claims["aud"].to_string())
+                    } else {
+                        Err(format!("{} does not match", claims["aud"]))
+                    },
+                );
+                let iss = claims["iss"].as_str().unwrap_or_default().to_string();
+                check("iss", {
+                    let expected = authority.issuers(&TokenVersion::ALL);
+                    if expected.contains(&iss) {
+                        Ok(iss)
+                    } else {
+                        Err(format!("{} (expected one of {:?})", iss, expected))
+                    }
+                });
+
+                println!();
+                println!("Decoded claims:");
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&claims).unwrap_or_default()
+                );
+                println!();
+            }
+        }
+    }
+
+    // The library validator is authoritative for the exit code.
+    match validator.validate_detailed(&options.token).await {
+        Ok(validated) => {
+            println!(
+                "Result: VALID (sub={}, kid={}, alg={:?}, aud={}, iss={})",
+                validated.claims.sub,
+                validated.kid.as_deref().unwrap_or("-"),
+                validated.algorithm,
+                validated.audience.as_deref().unwrap_or("-"),
+                validated.issuer
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("Result: INVALID ({}: {})", e.code(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+/// The `WWW-Authenticate: Bearer` challenge of RFC 6750, section 3.
+///
+/// # Fields
+///
+/// * `error` - `invalid_request`, `invalid_token` or `insufficient_scope`. Omitted when the
+///   request carried no credentials at all, as the RFC recommends.
+/// * `description` - A human-readable `error_description`.
+/// * `scope` - The space-separated scopes needed for the request, so a client answered
+///   `insufficient_scope` knows what to ask for in a step-up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BearerChallenge<'a> {
+    pub error: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub scope: Option<&'a str>,
+}
+
+impl<'a> BearerChallenge<'a> {
+    /// A challenge with the given error code and description.
+    pub fn new(error: &'a str, description: &'a str) -> Self {
+        Self {
+            error: Some(error),
+            description: Some(description),
+            scope: None,
+        }
+    }
+
+    /// Also reports the `scope` needed for the request.
+    pub fn with_scope(mut self, scope: &'a str) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Renders the header value, e.g. `Bearer realm="api", error="invalid_token"`.
+    pub fn header_value(&self, realm: &str) -> String {
+        self.header_value_for("Bearer", realm)
+    }
+
+    /// Renders the header value for another auth `scheme` with the same parameters, e.g. a
+    /// `DPoP` challenge (RFC 9449, section 7.1).
+    pub fn header_value_for(&self, scheme: &str, realm: &str) -> String {
+        let mut value = format!("{} realm=\"{}\"", scheme, quote(realm));
+        if let Some(error) = self.error {
+            value.push_str(&format!(", error=\"{}\"", quote(error)));
+        }
+        if let Some(description) = self.description {
+            value.push_str(&format!(", error_description=\"{}\"", quote(description)));
+        }
+        if let Some(scope) = self.scope {
+            value.push_str(&format!(", scope=\"{}\"", quote(scope)));
+        }
+        value
+    }
+
+    /// Builds a response with `status`, this challenge and `body`.
+    pub fn response(&self, status: StatusCode, realm: &str, body: String) -> HttpResponse {
+        HttpResponse::build(status)
+            .insert_header(("WWW-Authenticate", self.header_value(realm)))
+            .body(body)
+    }
+}
+
+/// Keeps a value inside an RFC 7230 quoted-string: drops `"`, `\` and control characters.
+fn quote(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| *c != '"' && *c != '\\' && !c.is_control())
+        .collect()
+}
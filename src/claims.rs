@@ -0,0 +1,295 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+/// Represents the claims contained in a JWT token.
+///
+/// # Fields
+///
+/// * `aud` - The audiences of the token, given as a string or an array in the JWT. At least one
+///   must match a configured audience.
+/// * `iss` - A string that holds the issuer of the token. Must be Azure AD.
+/// * `sub` - A string that holds the subject of the token (Service Principal or Managed Identity).
+/// * `exp` - The expiration time of the token, in seconds since the Unix epoch.
+/// * `nbf` - The optional time before which the token must not be accepted, in seconds since
+///   the Unix epoch.
+/// * `iat` - The optional time the token was issued, in seconds since the Unix epoch.
+/// * `roles` - An optional vector of strings that holds the roles associated with the token. A
+///   single role given as a plain string is accepted too.
+/// * `ver` - An optional string that holds the token version (`1.0` or `2.0`).
+/// * `cnf` - An optional confirmation claim binding the token to a key or certificate.
+/// * `idtyp` - An optional string that holds the identity type (`app` or `user`).
+/// * `scp` - An optional string that holds the delegated scopes, present only on user tokens.
+/// * `tid` - An optional string that holds the tenant ID of the caller.
+/// * `azp` - An optional string that holds the client ID of the caller (v2.0 tokens).
+/// * `appid` - An optional string that holds the client ID of the caller (v1.0 tokens).
+/// * `azpacr` - An optional string that holds how the client authenticated (v2.0 tokens): `0`
+///   public client, `1` client secret, `2` certificate.
+/// * `appidacr` - Like `azpacr`, for v1.0 tokens.
+/// * `wids` - An optional vector of strings that holds the template IDs of the caller's
+///   tenant-wide directory roles (e.g. Global Administrator).
+/// * `amr` - An optional vector of strings that holds how the user authenticated, e.g. `pwd`
+///   and `mfa`. Only user tokens carry it.
+/// * `groups` - An optional vector of strings that holds the object IDs of the caller's groups.
+/// * `claim_names` - The `_claim_names` claim, naming claims left out of the token (group
+///   overage) and resolved elsewhere.
+/// * `extra` - Every other claim of the token, e.g. custom claims added by claims mapping.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    #[serde(deserialize_with = "one_or_many")]
+    pub aud: Vec<String>, // Audience must match API_AUDIENCE
+    pub iss: String, // Issuer must be Azure AD
+    pub sub: String, // Subject (Service Principal or Managed Identity)
+    #[serde(deserialize_with = "numeric_date")]
+    pub exp: i64, // Expiration time
+    #[serde(default, deserialize_with = "optional_numeric_date")]
+    pub nbf: Option<i64>, // Not before
+    #[serde(default, deserialize_with = "optional_numeric_date")]
+    pub iat: Option<i64>, // Issued at
+    #[serde(default, deserialize_with = "optional_one_or_many")]
+    pub roles: Option<Vec<String>>, // Roles
+    pub ver: Option<String>, // Token version
+    pub cnf: Option<Confirmation>, // Proof-of-possession confirmation
+    pub idtyp: Option<String>, // Identity type
+    pub scp: Option<String>, // Delegated scopes
+    pub tid: Option<String>, // Tenant ID
+    pub azp: Option<String>, // Client ID (v2.0)
+    pub appid: Option<String>, // Client ID (v1.0)
+    pub azpacr: Option<String>, // Client authentication method (v2.0)
+    pub appidacr: Option<String>, // Client authentication method (v1.0)
+    pub wids: Option<Vec<String>>, // Directory role template IDs
+    pub amr: Option<Vec<String>>, // Authentication methods
+    pub groups: Option<Vec<String>>, // Group object IDs
+    #[serde(rename = "_claim_names")]
+    pub claim_names: Option<HashMap<String, String>>, // Claims left out of the token
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>, // Any other claims
+}
+
+impl Claims {
+    /// The client ID of the caller, from `azp` (v2.0) or `appid` (v1.0).
+    pub fn client_id(&self) -> Option<&str> {
+        self.azp.as_deref().or(self.appid.as_deref())
+    }
+
+    /// How the client authenticated when it got the token, from `azpacr` (v2.0) or `appidacr`
+    /// (v1.0): `0` public client, `1` client secret, `2` certificate.
+    pub fn client_auth_method(&self) -> Option<&str> {
+        self.azpacr.as_deref().or(self.appidacr.as_deref())
+    }
+
+    /// Whether the token was issued to an application acting as itself (client credentials or
+    /// managed identity) rather than on behalf of a user.
+    ///
+    /// `idtyp` is authoritative when present. It is an optional claim in v1.0 tokens, so without
+    /// it a token counts as app-only as long as it carries no delegated scopes.
+    pub fn is_app_only(&self) -> bool {
+        match self.idtyp.as_deref() {
+            Some(idtyp) => idtyp == "app",
+            None => self.scp.is_none(),
+        }
+    }
+
+    /// Whether the token looks like one issued to a managed identity: app-only, with none of the
+    /// scopes or user claims (`upn`, `unique_name`, `preferred_username`, `name`) of a delegated
+    /// token, and either carrying the managed identity resource ID (`xms_mirid`) or issued to a
+    /// client that authenticated with a certificate, as managed identities always do.
+    ///
+    /// An app registration authenticating with a certificate looks the same; pin the expected
+    /// clients by ID to tell them apart.
+    pub fn is_managed_identity(&self) -> bool {
+        const USER_CLAIMS: [&str; 4] = ["upn", "unique_name", "preferred_username", "name"];
+        self.is_app_only()
+            && self.scp.is_none()
+            && !USER_CLAIMS
+                .iter()
+                .any(|name| self.extra.contains_key(*name))
+            && (self.extra.contains_key("xms_mirid") || self.client_auth_method() == Some("2"))
+    }
+
+    /// Whether the extra claim `name` has `value`: a string equal to it, a number or boolean
+    /// written as it, or an array holding one of those.
+    pub fn has_claim_value(&self, name: &str, value: &str) -> bool {
+        fn matches(claim: &serde_json::Value, value: &str) -> bool {
+            match claim {
+                serde_json::Value::String(claim) => claim == value,
+                serde_json::Value::Number(claim) => claim.to_string() == value,
+                serde_json::Value::Bool(claim) => value.parse() == Ok(*claim),
+                serde_json::Value::Array(claims) => {
+                    claims.iter().any(|claim| matches(claim, value))
+                }
+                _ => false,
+            }
+        }
+
+        self.extra
+            .get(name)
+            .is_some_and(|claim| matches(claim, value))
+    }
+
+    /// Whether the user completed multi-factor authentication, i.e. `amr` contains `mfa`.
+    pub fn used_mfa(&self) -> bool {
+        self.amr
+            .as_ref()
+            .is_some_and(|methods| methods.iter().any(|method| method == "mfa"))
+    }
+
+    /// Whether Azure AD left `groups` out because the caller is in too many groups. The
+    /// memberships then have to be read from Microsoft Graph.
+    pub fn has_groups_overage(&self) -> bool {
+        self.claim_names
+            .as_ref()
+            .is_some_and(|names| names.contains_key("groups"))
+    }
+}
+
+/// The `cnf` (confirmation) claim of a sender-constrained token.
+///
+/// # Fields
+///
+/// * `x5t_s256` - The SHA-256 thumbprint of the bound client certificate (RFC 8705).
+/// * `jkt` - The JWK thumbprint of the key DPoP proofs must be signed with (RFC 9449).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Confirmation {
+    #[serde(rename = "x5t#S256")]
+    pub x5t_s256: Option<String>,
+    pub jkt: Option<String>,
+}
+
+/// Deserializes a claim that may be either a single string or an array of strings.
+pub(crate) fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Like [`one_or_many`], for a claim that may also be absent.
+pub(crate) fn optional_one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "one_or_many")] Vec<String>);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(values)| values))
+}
+
+/// Deserializes a NumericDate (RFC 7519): seconds since the Unix epoch, given as an integer or
+/// with a fraction. Fractions are truncated and values beyond the range of `i64` saturate, so
+/// far-future dates behave the same on every platform.
+pub(crate) fn numeric_date<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumericDate {
+        Signed(i64),
+        Unsigned(u64),
+        Fraction(f64),
+    }
+
+    Ok(match NumericDate::deserialize(deserializer)? {
+        NumericDate::Signed(secs) => secs,
+        NumericDate::Unsigned(secs) => i64::try_from(secs).unwrap_or(i64::MAX),
+        NumericDate::Fraction(secs) if secs.is_finite() => secs as i64,
+        NumericDate::Fraction(_) => {
+            return Err(serde::de::Error::custom(
+                "NumericDate must be a finite number",
+            ))
+        }
+    })
+}
+
+/// Like [`numeric_date`], for a claim that may also be absent.
+pub(crate) fn optional_numeric_date<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "numeric_date")] i64);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(secs)| secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(extra: serde_json::Value) -> Result<Claims, serde_json::Error> {
+        let mut value = serde_json::json!({
+            "aud": "api://test",
+            "iss": "https://sts.windows.net/test-tenant/",
+            "sub": "test-subject",
+            "exp": 1_700_000_000,
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value)
+    }
+
+    #[test]
+    fn numeric_dates_beyond_32_bits_are_kept() {
+        let claims = claims(serde_json::json!({
+            "exp": 253_402_300_799_i64,
+            "nbf": 4_294_967_296_i64,
+            "iat": 2_147_483_648_i64,
+        }))
+        .unwrap();
+        assert_eq!(claims.exp, 253_402_300_799);
+        assert_eq!(claims.nbf, Some(4_294_967_296));
+        assert_eq!(claims.iat, Some(2_147_483_648));
+    }
+
+    #[test]
+    fn numeric_dates_saturate_and_truncate() {
+        assert_eq!(
+            claims(serde_json::json!({ "exp": u64::MAX })).unwrap().exp,
+            i64::MAX
+        );
+        assert_eq!(
+            claims(serde_json::json!({ "exp": 1e30 })).unwrap().exp,
+            i64::MAX
+        );
+        assert_eq!(
+            claims(serde_json::json!({ "exp": 1_700_000_000.9 }))
+                .unwrap()
+                .exp,
+            1_700_000_000
+        );
+        assert_eq!(claims(serde_json::json!({ "exp": -1 })).unwrap().exp, -1);
+    }
+
+    #[test]
+    fn numeric_dates_must_be_numbers() {
+        assert!(claims(serde_json::json!({ "exp": "1700000000" })).is_err());
+        assert!(claims(serde_json::json!({ "exp": null })).is_err());
+        assert!(claims(serde_json::json!({ "nbf": "soon" })).is_err());
+    }
+
+    #[test]
+    fn optional_numeric_dates_may_be_absent() {
+        let claims = claims(serde_json::json!({})).unwrap();
+        assert_eq!((claims.nbf, claims.iat), (None, None));
+    }
+
+    #[tokio::test]
+    async fn far_future_tokens_validate() {
+        let factory = crate::testing::TestTokenFactory::new().unwrap();
+        let token = factory.token().with_exp(253_402_300_799).sign().unwrap();
+        let claims = factory.validator().unwrap().validate(&token).await.unwrap();
+        assert_eq!(claims.exp, 253_402_300_799);
+    }
+}
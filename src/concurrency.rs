@@ -0,0 +1,86 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use log::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// The most requests handled at once, registered as `web::Data<ConcurrencyLimit>` app data for
+/// `limit_concurrency`. Clones share the permits and the count of rejected requests.
+///
+/// # Fields
+///
+/// * `permits` - One permit per request that may be in flight.
+/// * `max` - The number of permits.
+/// * `retry_after` - Sent in `Retry-After` to callers turned away.
+/// * `rejected` - Requests turned away since startup.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+    max: usize,
+    retry_after: Duration,
+    rejected: Arc<AtomicU64>,
+}
+
+impl ConcurrencyLimit {
+    /// Allows `max` requests at once, asking callers beyond that to retry after `retry_after`.
+    pub fn new(max: usize, retry_after: Duration) -> Self {
+        let max = max.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+            retry_after,
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The most requests handled at once.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// The number of requests turned away since startup.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that lets at most the `ConcurrencyLimit` of
+/// the app data of requests in at once. Requests beyond that are not queued: they get a
+/// `503 Service Unavailable` with `Retry-After` right away, so an overloaded server sheds load
+/// instead of piling up work for its downstreams. Without a `ConcurrencyLimit` every request is
+/// let through.
+///
+/// A request holds its permit until its response is produced; streaming the body afterwards is
+/// not included.
+pub async fn limit_concurrency(
+    limit: Option<web::Data<ConcurrencyLimit>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(limit) = limit else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let Ok(_permit) = limit.permits.clone().try_acquire_owned() else {
+        limit.rejected.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Refusing {} {}: {} requests already in flight",
+            req.method(),
+            req.path(),
+            limit.max
+        );
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", limit.retry_after.as_secs().to_string()))
+            .body("Server is overloaded");
+        return Ok(req.into_response(response));
+    };
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,128 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use log::debug;
+use serde::Serialize;
+
+/// The media types accepted in the bodies of mutating requests, registered as
+/// `web::Data<ContentTypePolicy>` app data for `require_content_type`.
+///
+/// # Fields
+///
+/// * `allowed` - Media types such as `application/json`, compared without their parameters
+///   (`; charset=utf-8`) and ignoring case.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentTypePolicy {
+    pub allowed: Vec<String>,
+}
+
+impl ContentTypePolicy {
+    /// Whether a `Content-Type` header value names one of the allowed media types.
+    pub fn allows(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(media_type))
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that answers
+/// `415 Unsupported Media Type` to `POST`, `PUT` and `PATCH` requests whose body is not of a
+/// media type of the `ContentTypePolicy` in the app data, before any handler parses it. Without
+/// a `ContentTypePolicy` every request is let through.
+///
+/// Requests without a body (no `Content-Type`, no `Transfer-Encoding` and a zero or missing
+/// `Content-Length`), such as `POST /admin/reload`, pass whatever the policy.
+pub async fn require_content_type(
+    policy: Option<web::Data<ContentTypePolicy>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let mutating = matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH);
+    let Some(policy) = policy.filter(|_| mutating) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let headers = req.headers();
+    let content_type = headers.get(header::CONTENT_TYPE);
+    let has_body = content_type.is_some()
+        || headers.contains_key(header::TRANSFER_ENCODING)
+        || headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .is_some_and(|length| length.trim() != "0");
+    let allowed = !has_body
+        || content_type
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| policy.allows(content_type));
+    if allowed {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+    debug!(
+        "Refusing {} {} with content type {:?}",
+        req.method(),
+        req.path(),
+        content_type
+    );
+    let message = format!("Content-Type must be one of {}", policy.allowed.join(", "));
+    Ok(req.into_response(HttpResponse::UnsupportedMediaType().body(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    async fn status(request: TestRequest) -> StatusCode {
+        let policy = ContentTypePolicy {
+            allowed: vec!["application/json".to_string()],
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(policy))
+                .wrap(from_fn(require_content_type))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        call_service(&app, request.uri("/validate").to_request())
+            .await
+            .status()
+    }
+
+    #[actix_web::test]
+    async fn json_bodies_are_accepted() {
+        let request = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "Application/JSON; charset=utf-8"))
+            .set_payload("{}");
+        assert_eq!(status(request).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn other_bodies_are_unsupported() {
+        let request = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload("hello");
+        assert_eq!(status(request).await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let request = TestRequest::put()
+            .insert_header((header::CONTENT_LENGTH, "5"))
+            .set_payload("hello");
+        assert_eq!(status(request).await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[actix_web::test]
+    async fn requests_without_bodies_or_not_mutating_pass() {
+        assert_eq!(status(TestRequest::post()).await, StatusCode::OK);
+        let request = TestRequest::get().insert_header((header::CONTENT_TYPE, "text/plain"));
+        assert_eq!(status(request).await, StatusCode::OK);
+    }
+}
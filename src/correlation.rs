@@ -0,0 +1,66 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use log::debug;
+
+/// The header carrying the correlation id between client and server.
+pub const CORRELATION_ID_HEADER: &str = "X-Correlation-Id";
+
+/// The correlation id of the current request, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+    /// Maximum accepted length of a client-supplied correlation id.
+    const MAX_LEN: usize = 128;
+
+    /// Generates a new random correlation id.
+    pub fn generate() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Accepts a client-supplied id if it is short and only uses `[A-Za-z0-9._-]`.
+    ///
+    /// Anything else is rejected so a client cannot inject arbitrary text into the logs.
+    pub fn parse(value: &str) -> Option<Self> {
+        let valid = !value.is_empty()
+            && value.len() <= Self::MAX_LEN
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        valid.then(|| Self(value.to_string()))
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that reads or assigns the correlation id.
+///
+/// A valid `X-Correlation-Id` from the client is kept, otherwise a new one is generated. The id
+/// is stored in the request extensions and echoed back in the response header, where the access
+/// log can pick it up with `%{X-Correlation-Id}o`.
+pub async fn correlation_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let correlation_id = req
+        .headers()
+        .get(CORRELATION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(CorrelationId::parse)
+        .unwrap_or_else(CorrelationId::generate);
+    debug!(
+        "{} {} correlation_id={}",
+        req.method(),
+        req.path(),
+        correlation_id.0
+    );
+    req.extensions_mut().insert(correlation_id.clone());
+
+    let mut res = next.call(req).await?;
+    if let Ok(value) = HeaderValue::from_str(&correlation_id.0) {
+        res.headers_mut()
+            .insert(HeaderName::from_static("x-correlation-id"), value);
+    }
+    Ok(res)
+}
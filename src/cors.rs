@@ -0,0 +1,225 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use log::debug;
+use serde::Serialize;
+
+/// The browser origins allowed to call the API, registered as `web::Data<CorsPolicy>` app data
+/// for `cors`.
+///
+/// # Fields
+///
+/// * `allowed_origins` - Origins such as `https://app.example.com`; `*` allows any origin.
+/// * `allowed_methods` - The methods a preflight may ask for.
+/// * `allowed_headers` - The request headers a preflight may ask for. `Authorization` has to be
+///   listed explicitly: the `*` wildcard does not cover it.
+/// * `exposed_headers` - Response headers scripts may read, e.g. `WWW-Authenticate`.
+/// * `max_age_secs` - How long browsers may cache a preflight answer.
+#[derive(Debug, Clone, Serialize)]
+pub struct CorsPolicy {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub max_age_secs: u64,
+}
+
+impl CorsPolicy {
+    /// A policy for `allowed_origins` that lets browsers send bearer tokens with `GET` and
+    /// `POST` requests and read the authentication challenge and correlation ID.
+    pub fn new(allowed_origins: Vec<String>) -> Self {
+        Self {
+            allowed_origins,
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                "Authorization".to_string(),
+                "Content-Type".to_string(),
+                "DPoP".to_string(),
+                "X-Correlation-Id".to_string(),
+            ],
+            exposed_headers: vec![
+                "WWW-Authenticate".to_string(),
+                "X-Correlation-Id".to_string(),
+            ],
+            max_age_secs: 600,
+        }
+    }
+
+    /// Whether requests from `origin` are allowed.
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that applies the `CorsPolicy` in the app
+/// data. Without a `CorsPolicy` requests pass through untouched.
+///
+/// Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are answered here and
+/// never reach the services behind, so wrap the app with it rather than a protected resource:
+/// preflights carry no `Authorization` header and would otherwise get a 401. Other requests
+/// from an allowed origin get `Access-Control-Allow-Origin` on their response, whatever its
+/// status, so scripts can read a 401 too.
+pub async fn cors(
+    policy: Option<web::Data<CorsPolicy>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let (Some(policy), Some(origin)) = (policy, origin) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let allowed = policy.allows(&origin);
+
+    if req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        if !allowed {
+            debug!("Refusing preflight from origin {}", origin);
+            return Ok(req.into_response(HttpResponse::Forbidden().body("Origin not allowed")));
+        }
+        let response = HttpResponse::NoContent()
+            .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+            .insert_header((
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                policy.allowed_methods.join(", "),
+            ))
+            .insert_header((
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                policy.allowed_headers.join(", "),
+            ))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, policy.max_age_secs))
+            .insert_header((header::VARY, "Origin"))
+            .finish();
+        return Ok(req.into_response(response));
+    }
+
+    let mut res = next
+        .call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)?;
+    if allowed {
+        let headers = res.headers_mut();
+        if let Ok(origin) = HeaderValue::from_str(&origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        if let Ok(exposed) = HeaderValue::from_str(&policy.exposed_headers.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{BearerAuth, BearerAuthConfig};
+    use crate::testing::TestTokenFactory;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    const ORIGIN: &str = "https://app.example.com";
+
+    /// Sends `request` to `/api_protected`, protected by `BearerAuth` in an app wrapped with
+    /// `cors` as the server wires it.
+    async fn send(factory: &TestTokenFactory, request: TestRequest) -> ServiceResponse {
+        let config = BearerAuthConfig::new(factory.validator().expect("write the JWKS"));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(CorsPolicy::new(vec![ORIGIN.to_string()])))
+                .wrap(from_fn(cors))
+                .service(
+                    web::resource("/api_protected")
+                        .wrap(BearerAuth::new(config))
+                        .to(HttpResponse::Ok),
+                ),
+        )
+        .await;
+        call_service(&app, request.uri("/api_protected").to_request()).await
+    }
+
+    fn header_value(response: &ServiceResponse, name: header::HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    fn preflight(origin: &str) -> TestRequest {
+        TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization"))
+    }
+
+    #[actix_web::test]
+    async fn preflights_are_answered_without_a_token() {
+        let factory = TestTokenFactory::new().unwrap();
+        let response = send(&factory, preflight(ORIGIN)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(ORIGIN)
+        );
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST")
+        );
+        assert!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_HEADERS)
+                .unwrap()
+                .contains("Authorization")
+        );
+    }
+
+    #[actix_web::test]
+    async fn preflights_from_other_origins_are_refused() {
+        let factory = TestTokenFactory::new().unwrap();
+        let response = send(&factory, preflight("https://evil.example.com")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn actual_requests_still_need_a_token() {
+        let factory = TestTokenFactory::new().unwrap();
+        let request = TestRequest::get().insert_header((header::ORIGIN, ORIGIN));
+        let response = send(&factory, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(ORIGIN)
+        );
+
+        let token = factory.token().sign().unwrap();
+        let request = TestRequest::get()
+            .insert_header((header::ORIGIN, ORIGIN))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+        let response = send(&factory, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_EXPOSE_HEADERS),
+            Some("WWW-Authenticate, X-Correlation-Id")
+        );
+    }
+}
@@ -0,0 +1,149 @@
+/// Extracts the bearer token from an `Authorization` header value.
+///
+/// The value is parsed as RFC 7235 credentials (`auth-scheme 1*SP token68`), with the scheme
+/// matched case-insensitively. Some proxies append further credentials after a comma, e.g.
+/// `Bearer <token>, Basic <creds>`; those are ignored as long as exactly one `Bearer`
+/// credential is present. `Bearer ` with no token yields an empty token.
+///
+/// # Errors
+///
+/// Returns a message suitable for an `invalid_request` error when there is no `Bearer`
+/// credential, more than one, or its token is not a valid `token68`.
+pub fn bearer_token(header: &str) -> Result<&str, &'static str> {
+    match find_credential(header, "bearer") {
+        Ok(token) => token.ok_or("Authorization header has no Bearer credentials"),
+        Err(CredentialProblem::Multiple) => {
+            Err("Multiple Bearer credentials in Authorization header")
+        }
+        Err(CredentialProblem::Malformed) => Err("Malformed Bearer token in Authorization header"),
+    }
+}
+
+/// Extracts the token of a `DPoP` credential (RFC 9449) from an `Authorization` header value,
+/// parsed like `bearer_token`. Returns `None` when the header has no `DPoP` credential.
+///
+/// # Errors
+///
+/// Returns a message suitable for an `invalid_request` error when there is more than one `DPoP`
+/// credential or its token is not a valid `token68`.
+pub fn dpop_token(header: &str) -> Result<Option<&str>, &'static str> {
+    find_credential(header, "dpop").map_err(|problem| match problem {
+        CredentialProblem::Multiple => "Multiple DPoP credentials in Authorization header",
+        CredentialProblem::Malformed => "Malformed DPoP token in Authorization header",
+    })
+}
+
+/// Why a credential could not be taken from an `Authorization` header.
+enum CredentialProblem {
+    Multiple,
+    Malformed,
+}
+
+/// The token of the only credential with `scheme` (lowercase) in `header`, if any.
+fn find_credential<'a>(
+    header: &'a str,
+    scheme: &str,
+) -> Result<Option<&'a str>, CredentialProblem> {
+    let mut found = None;
+    for credential in header.split(',').map(|part| part.trim_matches([' ', '\t'])) {
+        let (candidate, rest) = match credential.split_once(' ') {
+            Some((candidate, rest)) => (candidate, rest.trim_start_matches(' ')),
+            None => (credential, ""),
+        };
+        if !candidate.eq_ignore_ascii_case(scheme) {
+            // Another scheme, or an auth-param belonging to the previous credential
+            continue;
+        }
+        if found.is_some() {
+            return Err(CredentialProblem::Multiple);
+        }
+        // An empty token is left for the validator to reject as `empty_token`
+        if !rest.is_empty() && !is_token68(rest) {
+            return Err(CredentialProblem::Malformed);
+        }
+        found = Some(rest);
+    }
+    Ok(found)
+}
+
+/// Whether `value` matches `token68 = 1*( ALPHA / DIGIT / "-" / "." / "_" / "~" / "+" / "/" ) *"="`.
+fn is_token68(value: &str) -> bool {
+    let body = value.trim_end_matches('=');
+    !body.is_empty()
+        && body
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~' | '+' | '/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_tokens_are_taken_from_the_credentials() {
+        assert_eq!(
+            bearer_token("Bearer abc.def-ghi_jkl"),
+            Ok("abc.def-ghi_jkl")
+        );
+        assert_eq!(bearer_token("bearer   abc=="), Ok("abc=="));
+        assert_eq!(bearer_token("BEARER abc"), Ok("abc"));
+        assert_eq!(bearer_token("Bearer "), Ok(""));
+    }
+
+    #[test]
+    fn trailing_credentials_of_other_schemes_are_ignored() {
+        assert_eq!(bearer_token("Bearer abc, Basic dXNlcjpwYXNz"), Ok("abc"));
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz, Bearer abc"), Ok("abc"));
+        assert_eq!(
+            bearer_token("Digest realm=\"api\", nonce=\"n\", Bearer abc"),
+            Ok("abc")
+        );
+    }
+
+    #[test]
+    fn headers_without_exactly_one_bearer_credential_are_refused() {
+        assert_eq!(
+            bearer_token("Basic dXNlcjpwYXNz"),
+            Err("Authorization header has no Bearer credentials")
+        );
+        assert_eq!(
+            bearer_token("Bearerabc"),
+            Err("Authorization header has no Bearer credentials")
+        );
+        assert_eq!(
+            bearer_token("Bearer abc, Bearer def"),
+            Err("Multiple Bearer credentials in Authorization header")
+        );
+    }
+
+    #[test]
+    fn tokens_must_be_token68() {
+        for header in [
+            "Bearer abc def",
+            "Bearer a=b",
+            "Bearer ===",
+            "Bearer \"abc\"",
+        ] {
+            assert_eq!(
+                bearer_token(header),
+                Err("Malformed Bearer token in Authorization header"),
+                "{:?}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn dpop_tokens_are_parsed_like_bearer_tokens() {
+        assert_eq!(dpop_token("DPoP abc"), Ok(Some("abc")));
+        assert_eq!(dpop_token("Bearer abc"), Ok(None));
+        assert_eq!(
+            dpop_token("DPoP abc, dpop def"),
+            Err("Multiple DPoP credentials in Authorization header")
+        );
+        assert_eq!(
+            dpop_token("DPoP a b"),
+            Err("Malformed DPoP token in Authorization header")
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorGatewayTimeout;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use log::warn;
+use std::time::Duration;
+
+/// The overall time budget of a request, registered as app data for `request_deadline`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestDeadline(pub Duration);
+
+/// Middleware (for `actix_web::middleware::from_fn`) that caps the time spent on a request.
+///
+/// Authentication (including any JWKS fetch and its retries) and the handler together must
+/// finish within the `RequestDeadline` in the app data, otherwise the work is cancelled and a
+/// `504 Gateway Timeout` is returned. Without a `RequestDeadline` requests are not limited.
+pub async fn request_deadline(
+    deadline: Option<web::Data<RequestDeadline>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(deadline) = deadline else {
+        return next.call(req).await;
+    };
+    // The request itself moves into the inner services, which need it to be uniquely owned
+    let target = format!("{} {}", req.method(), req.path());
+    match tokio::time::timeout(deadline.0, next.call(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            warn!(
+                "{} exceeded the request deadline of {:?}",
+                target, deadline.0
+            );
+            Err(ErrorGatewayTimeout("Request deadline exceeded"))
+        }
+    }
+}
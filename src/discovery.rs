@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+/// Where the server publishes its `ApiConfig`, relative to its base URL.
+pub const API_CONFIG_PATH: &str = "/.well-known/api-config";
+
+/// What a client needs to request tokens this API accepts, served unauthenticated at
+/// `API_CONFIG_PATH` so a new client can be configured from the API base URL alone.
+///
+/// Nothing in it is secret: the audience and tenant are in every token the API accepts anyway.
+///
+/// # Fields
+///
+/// * `audience` - The audience tokens must be issued for, to use as the client's
+///   `RESOURCE_NAME`.
+/// * `tenant_id` - The tenant that issues the tokens.
+/// * `issuers` - The token issuers the API accepts.
+/// * `scope` - The `.default` scope of `audience`, as the v2.0 token endpoint expects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiConfig {
+    pub audience: String,
+    pub tenant_id: String,
+    pub issuers: Vec<String>,
+    pub scope: String,
+}
+
+impl ApiConfig {
+    /// Describes an API expecting tokens for `audience` from `tenant_id`.
+    pub fn new(audience: String, tenant_id: String, issuers: Vec<String>) -> Self {
+        let scope = format!("{}/.default", audience.trim_end_matches('/'));
+        Self {
+            audience,
+            tenant_id,
+            issuers,
+            scope,
+        }
+    }
+}
+
+/// The URL of the `ApiConfig` of the API at `base_url`, e.g. `https://api.contoso.com`.
+pub fn api_config_url(base_url: &str) -> String {
+    format!("{}{}", base_url.trim_end_matches('/'), API_CONFIG_PATH)
+}
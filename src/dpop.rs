@@ -0,0 +1,402 @@
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::claims::Claims;
+use crate::ipfilter::client_origin;
+
+/// The signing algorithms accepted for proofs: asymmetric only, since the client proves
+/// possession of a private key.
+const PROOF_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+/// Verifies the DPoP proofs (RFC 9449) presented with sender-constrained access tokens.
+///
+/// A token is DPoP-bound when it carries `cnf.jkt`. It must then be sent as
+/// `Authorization: DPoP <token>` together with a `DPoP` header: a JWT signed by the client's key,
+/// which it embeds as `jwk`, for this request's method (`htm`) and URL (`htu`), recently issued
+/// (`iat`) and bound to the access token (`ath`). The key's thumbprint must equal `cnf.jkt`.
+///
+/// Proof `jti`s are not remembered, so a captured proof can be replayed for the same request
+/// until it is `max_age_secs` old.
+///
+/// # Fields
+///
+/// * `max_age_secs` - How far `iat` may be from now, in either direction.
+#[derive(Debug, Clone, Serialize)]
+pub struct DpopVerifier {
+    max_age_secs: u64,
+}
+
+/// The claims of a DPoP proof.
+#[derive(Debug, Deserialize)]
+struct ProofClaims {
+    // Required, but only identifies the proof
+    #[serde(rename = "jti")]
+    _jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    ath: Option<String>,
+}
+
+impl DpopVerifier {
+    /// Accepts proofs issued at most `max_age_secs` seconds from now.
+    pub fn new(max_age_secs: u64) -> Self {
+        Self { max_age_secs }
+    }
+
+    /// Checks the DPoP binding of a validated `access_token` presented to `req`, with
+    /// `dpop_scheme` telling whether it came with the `DPoP` rather than the `Bearer` scheme.
+    ///
+    /// Tokens without `cnf.jkt` presented as `Bearer` tokens are not DPoP-bound and pass.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description for an `invalid_dpop_proof` error when a bound token is sent as a
+    /// `Bearer` token, an unbound one with the `DPoP` scheme, or the proof is missing, invalid or
+    /// made with another key.
+    pub fn check_binding(
+        &self,
+        req: &HttpRequest,
+        access_token: &str,
+        dpop_scheme: bool,
+        claims: &Claims,
+        now: u64,
+    ) -> Result<(), &'static str> {
+        let bound = claims.cnf.as_ref().and_then(|cnf| cnf.jkt.as_deref());
+        let bound = match (bound, dpop_scheme) {
+            (None, false) => return Ok(()),
+            (None, true) => return Err("Token is not DPoP-bound"),
+            (Some(_), false) => return Err("DPoP-bound token sent as a Bearer token"),
+            (Some(bound), true) => bound,
+        };
+        let mut proofs = req.headers().get_all("DPoP");
+        let proof = proofs
+            .next()
+            .and_then(|value| value.to_str().ok())
+            .ok_or("DPoP proof required")?;
+        if proofs.next().is_some() {
+            return Err("Multiple DPoP proofs");
+        }
+        let thumbprint = self.verify_proof(
+            proof,
+            req.method().as_str(),
+            &request_url(req),
+            access_token,
+            now,
+        )?;
+        debug!("DPoP key thumbprint: {}, token cnf: {}", thumbprint, bound);
+        if thumbprint != bound {
+            return Err("DPoP proof key does not match the token");
+        }
+        Ok(())
+    }
+
+    /// Verifies `proof` for a `method` request to `url` carrying `access_token`, returning the
+    /// `jkt` thumbprint of the key that signed it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first check the proof fails.
+    pub fn verify_proof(
+        &self,
+        proof: &str,
+        method: &str,
+        url: &str,
+        access_token: &str,
+        now: u64,
+    ) -> Result<String, &'static str> {
+        let header = decode_header(proof).map_err(|_| "Malformed DPoP proof")?;
+        if header.typ.as_deref() != Some("dpop+jwt") {
+            return Err("DPoP proof must have typ dpop+jwt");
+        }
+        if !PROOF_ALGORITHMS.contains(&header.alg) {
+            return Err("Unsupported DPoP proof algorithm");
+        }
+        let jwk = header.jwk.ok_or("DPoP proof has no jwk")?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| "Invalid DPoP proof jwk")?;
+        let mut validation = Validation::new(header.alg);
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+        validation.required_spec_claims.clear();
+        let claims = decode::<ProofClaims>(proof, &key, &validation)
+            .map_err(|e| {
+                debug!("DPoP proof rejected: {}", e);
+                "Invalid DPoP proof"
+            })?
+            .claims;
+
+        if claims.htm != method {
+            return Err("DPoP proof is for another method");
+        }
+        if without_query(&claims.htu) != without_query(url) {
+            debug!("DPoP htu {} for request to {}", claims.htu, url);
+            return Err("DPoP proof is for another URL");
+        }
+        let now = i64::try_from(now).unwrap_or(i64::MAX);
+        let max_age = i64::try_from(self.max_age_secs).unwrap_or(i64::MAX);
+        if claims.iat.saturating_sub(now).saturating_abs() > max_age {
+            return Err("DPoP proof is too old or from the future");
+        }
+        let ath = URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes()));
+        if claims.ath.as_deref() != Some(ath.as_str()) {
+            return Err("DPoP proof is not bound to the access token");
+        }
+        jwk_thumbprint(&jwk)
+    }
+}
+
+/// Computes the RFC 7638 JWK thumbprint of `jwk`: base64url(SHA-256) of its required members in
+/// lexicographic order, as used in `cnf.jkt`.
+///
+/// # Errors
+///
+/// Returns a message for key types other than RSA and EC.
+pub fn jwk_thumbprint(jwk: &Jwk) -> Result<String, &'static str> {
+    let quoted = |value: &str| serde_json::Value::from(value).to_string();
+    let canonical = match &jwk.algorithm {
+        AlgorithmParameters::RSA(rsa) => format!(
+            r#"{{"e":{},"kty":"RSA","n":{}}}"#,
+            quoted(&rsa.e),
+            quoted(&rsa.n)
+        ),
+        AlgorithmParameters::EllipticCurve(ec) => format!(
+            r#"{{"crv":{},"kty":"EC","x":{},"y":{}}}"#,
+            serde_json::to_string(&ec.curve).map_err(|_| "Invalid DPoP proof jwk")?,
+            quoted(&ec.x),
+            quoted(&ec.y)
+        ),
+        _ => return Err("Unsupported DPoP proof key type"),
+    };
+    Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes())))
+}
+
+/// The URL `req` was sent to, as a client would write it in `htu`. Behind a proxy, its scheme and
+/// host only come from `X-Forwarded-Proto` and `X-Forwarded-Host` when it is trusted.
+fn request_url(req: &HttpRequest) -> String {
+    let origin = client_origin(req);
+    format!("{}://{}{}", origin.scheme, origin.host, req.path())
+}
+
+/// `url` without its query and fragment, which `htu` leaves out.
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::rand_core::OsRng;
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+
+    const NOW: u64 = 1_700_000_000;
+    const TOKEN: &str = "access-token";
+
+    /// A client key signing DPoP proofs, with its public half as the proof's `jwk`.
+    struct ClientKey {
+        encoding_key: EncodingKey,
+        jwk: Jwk,
+    }
+
+    impl ClientKey {
+        fn new() -> Self {
+            let private_key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+            let public_key = private_key.to_public_key();
+            let jwk = serde_json::from_value(serde_json::json!({
+                "kty": "RSA",
+                "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+            }))
+            .unwrap();
+            let der = private_key.to_pkcs1_der().unwrap();
+            Self {
+                encoding_key: EncodingKey::from_rsa_der(der.as_bytes()),
+                jwk,
+            }
+        }
+
+        fn thumbprint(&self) -> String {
+            jwk_thumbprint(&self.jwk).unwrap()
+        }
+
+        /// A proof with the given claims.
+        fn sign(&self, claims: serde_json::Value) -> String {
+            let mut header = Header::new(Algorithm::RS256);
+            header.typ = Some("dpop+jwt".to_string());
+            header.jwk = Some(self.jwk.clone());
+            encode(&header, &claims, &self.encoding_key).unwrap()
+        }
+
+        /// A proof for a `method` request to `url` carrying `TOKEN`, issued at `NOW`.
+        fn proof(&self, method: &str, url: &str) -> String {
+            self.sign(proof_claims(method, url))
+        }
+    }
+
+    fn proof_claims(method: &str, url: &str) -> serde_json::Value {
+        serde_json::json!({
+            "jti": "proof-1",
+            "htm": method,
+            "htu": url,
+            "iat": NOW,
+            "ath": URL_SAFE_NO_PAD.encode(Sha256::digest(TOKEN.as_bytes())),
+        })
+    }
+
+    /// Claims of a token bound to `jkt`, or unbound.
+    fn claims(jkt: Option<&str>) -> Claims {
+        serde_json::from_value(serde_json::json!({
+            "aud": "api://test",
+            "iss": "https://sts.windows.net/test-tenant/",
+            "sub": "test-subject",
+            "exp": NOW + 3600,
+            "cnf": jkt.map(|jkt| serde_json::json!({ "jkt": jkt })),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn proofs_of_the_bound_key_are_accepted() {
+        let key = ClientKey::new();
+        let req = TestRequest::get().uri("/api_protected").to_http_request();
+        let url = request_url(&req);
+        let req = TestRequest::get()
+            .uri("/api_protected?page=2")
+            .insert_header(("DPoP", key.proof("GET", &url)))
+            .to_http_request();
+        let claims = claims(Some(&key.thumbprint()));
+        assert_eq!(
+            DpopVerifier::new(60).check_binding(&req, TOKEN, true, &claims, NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn proofs_of_another_key_are_refused() {
+        let key = ClientKey::new();
+        let other = ClientKey::new();
+        let req = TestRequest::get().uri("/api_protected").to_http_request();
+        let url = request_url(&req);
+        let req = TestRequest::get()
+            .uri("/api_protected")
+            .insert_header(("DPoP", other.proof("GET", &url)))
+            .to_http_request();
+        let claims = claims(Some(&key.thumbprint()));
+        assert_eq!(
+            DpopVerifier::new(60).check_binding(&req, TOKEN, true, &claims, NOW),
+            Err("DPoP proof key does not match the token")
+        );
+    }
+
+    #[test]
+    fn bound_tokens_need_the_dpop_scheme_and_a_proof() {
+        let key = ClientKey::new();
+        let claims = claims(Some(&key.thumbprint()));
+        let verifier = DpopVerifier::new(60);
+        let req = TestRequest::get().uri("/api_protected").to_http_request();
+        let url = request_url(&req);
+        let with_proof = TestRequest::get()
+            .uri("/api_protected")
+            .insert_header(("DPoP", key.proof("GET", &url)))
+            .to_http_request();
+        assert_eq!(
+            verifier.check_binding(&with_proof, TOKEN, false, &claims, NOW),
+            Err("DPoP-bound token sent as a Bearer token")
+        );
+        assert_eq!(
+            verifier.check_binding(&req, TOKEN, true, &claims, NOW),
+            Err("DPoP proof required")
+        );
+    }
+
+    #[test]
+    fn unbound_tokens_pass_as_bearer_tokens_only() {
+        let req = TestRequest::get().uri("/api_protected").to_http_request();
+        let verifier = DpopVerifier::new(60);
+        assert_eq!(
+            verifier.check_binding(&req, TOKEN, false, &claims(None), NOW),
+            Ok(())
+        );
+        assert_eq!(
+            verifier.check_binding(&req, TOKEN, true, &claims(None), NOW),
+            Err("Token is not DPoP-bound")
+        );
+    }
+
+    #[test]
+    fn proofs_for_another_request_are_refused() {
+        let key = ClientKey::new();
+        let verifier = DpopVerifier::new(60);
+        let url = "https://api.example.com/api_protected";
+        assert_eq!(
+            verifier.verify_proof(&key.proof("GET", url), "GET", url, TOKEN, NOW),
+            Ok(key.thumbprint())
+        );
+        assert_eq!(
+            verifier.verify_proof(&key.proof("POST", url), "GET", url, TOKEN, NOW),
+            Err("DPoP proof is for another method")
+        );
+        assert_eq!(
+            verifier.verify_proof(
+                &key.proof("GET", "https://api.example.com/other"),
+                "GET",
+                url,
+                TOKEN,
+                NOW
+            ),
+            Err("DPoP proof is for another URL")
+        );
+    }
+
+    #[test]
+    fn stale_and_future_proofs_are_refused() {
+        let key = ClientKey::new();
+        let verifier = DpopVerifier::new(60);
+        let url = "https://api.example.com/api_protected";
+        let proof = key.proof("GET", url);
+        assert!(verifier
+            .verify_proof(&proof, "GET", url, TOKEN, NOW + 60)
+            .is_ok());
+        assert_eq!(
+            verifier.verify_proof(&proof, "GET", url, TOKEN, NOW + 61),
+            Err("DPoP proof is too old or from the future")
+        );
+        assert_eq!(
+            verifier.verify_proof(&proof, "GET", url, TOKEN, NOW - 61),
+            Err("DPoP proof is too old or from the future")
+        );
+    }
+
+    #[test]
+    fn proofs_must_be_bound_to_the_access_token() {
+        let key = ClientKey::new();
+        let verifier = DpopVerifier::new(60);
+        let url = "https://api.example.com/api_protected";
+        let mut claims = proof_claims("GET", url);
+        claims.as_object_mut().unwrap().remove("ath");
+        assert_eq!(
+            verifier.verify_proof(&key.sign(claims), "GET", url, TOKEN, NOW),
+            Err("DPoP proof is not bound to the access token")
+        );
+        assert_eq!(
+            verifier.verify_proof(&key.proof("GET", url), "GET", url, "other-token", NOW),
+            Err("DPoP proof is not bound to the access token")
+        );
+    }
+}
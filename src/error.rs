@@ -0,0 +1,211 @@
+use actix_web::body::{to_bytes_limited, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use log::{debug, error};
+use serde::Serialize;
+
+use crate::correlation::CorrelationId;
+
+/// An error a handler can return with `?`, rendered as a JSON body of the form
+/// `{"error": "<code>", "error_description": "<message>"}`.
+///
+/// ```ignore
+/// async fn handler(body: web::Json<Request>) -> Result<impl Responder, ApiError> {
+///     let value = parse(&body.value).map_err(ApiError::BadRequest)?;
+///     Ok(HttpResponse::Ok().json(value))
+/// }
+/// ```
+///
+/// # Variants
+///
+/// * `BadRequest` - The request is invalid; carries a message for the caller.
+/// * `PayloadTooLarge` - The request carries more than the endpoint accepts.
+/// * `Internal` - Something failed on the server. The message is logged, not sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    BadRequest(String),
+    PayloadTooLarge(String),
+    Internal(String),
+}
+
+impl ApiError {
+    /// A stable, machine-readable identifier for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(message) | ApiError::PayloadTooLarge(message) => {
+                f.write_str(message)
+            }
+            ApiError::Internal(_) => f.write_str("Internal server error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(message) = self {
+            error!("Internal error: {}", message);
+        }
+        HttpResponse::build(self.status_code()).json(serde_json::json!({
+            "error": self.code(),
+            "error_description": self.to_string(),
+        }))
+    }
+}
+
+/// Extra fields added to every error body, registered as `web::Data<ErrorBodyTemplate>` app data
+/// for `error_bodies`, so teams can shape errors without forking the handlers.
+///
+/// # Fields
+///
+/// * `support_url` - Added as `support_url`, e.g. a page explaining how to request access.
+/// * `include_correlation_id` - Adds the request's `correlation_id`, which callers can quote
+///   when asking for support.
+/// * `custom_message` - Added as `message`, e.g. `Contact the platform team on #api-access`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ErrorBodyTemplate {
+    pub support_url: Option<String>,
+    pub include_correlation_id: bool,
+    pub custom_message: Option<String>,
+}
+
+impl ErrorBodyTemplate {
+    /// Bodies larger than this are passed through untouched.
+    const MAX_BODY_BYTES: usize = 64 * 1024;
+
+    /// Whether the template adds nothing.
+    pub fn is_empty(&self) -> bool {
+        self.support_url.is_none() && !self.include_correlation_id && self.custom_message.is_none()
+    }
+
+    /// Adds the configured fields to `body`, with the `correlation_id` of the request if any.
+    pub fn apply(
+        &self,
+        body: &mut serde_json::Map<String, serde_json::Value>,
+        correlation_id: Option<&str>,
+    ) {
+        if let Some(support_url) = &self.support_url {
+            body.insert("support_url".to_string(), support_url.as_str().into());
+        }
+        if let (true, Some(correlation_id)) = (self.include_correlation_id, correlation_id) {
+            body.insert("correlation_id".to_string(), correlation_id.into());
+        }
+        if let Some(message) = &self.custom_message {
+            body.insert("message".to_string(), message.as_str().into());
+        }
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that applies the `ErrorBodyTemplate` in the
+/// app data to every 4xx and 5xx response. Without an `ErrorBodyTemplate` responses pass through
+/// untouched.
+///
+/// JSON object bodies, such as those of `ApiError`, get the extra fields. Other bodies, like the
+/// plain-text 401s of `BearerAuth`, are turned into `{"error": ..., "error_description": ...}`
+/// first, taking the code from the `WWW-Authenticate` challenge when there is one. Wrap it inside
+/// `correlation_id` so the correlation id is known.
+pub async fn error_bodies(
+    template: Option<web::Data<ErrorBodyTemplate>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let res = next
+        .call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)?;
+    let Some(template) = template else {
+        return Ok(res);
+    };
+    let status = res.status();
+    let small = match res.response().body().size() {
+        BodySize::Sized(len) => len <= ErrorBodyTemplate::MAX_BODY_BYTES as u64,
+        BodySize::None => true,
+        BodySize::Stream => false,
+    };
+    if !(status.is_client_error() || status.is_server_error()) || !small {
+        return Ok(res);
+    }
+
+    let code = error_code(&res);
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let correlation_id = res
+        .request()
+        .extensions()
+        .get::<CorrelationId>()
+        .map(|id| id.0.clone());
+    let (req, res) = res.into_parts();
+    let (mut res, body) = res.into_parts();
+    let bytes = match to_bytes_limited(body, ErrorBodyTemplate::MAX_BODY_BYTES).await {
+        Ok(Ok(bytes)) => bytes,
+        _ => {
+            debug!("Could not read the {} error body, leaving it out", status);
+            Default::default()
+        }
+    };
+
+    let mut body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(body)) if is_json => body,
+        _ => {
+            let mut body = serde_json::Map::new();
+            body.insert("error".to_string(), code.into());
+            let description = String::from_utf8_lossy(&bytes);
+            let description = match description.trim() {
+                "" => status.canonical_reason().unwrap_or_default(),
+                description => description,
+            };
+            body.insert("error_description".to_string(), description.into());
+            body
+        }
+    };
+    template.apply(&mut body, correlation_id.as_deref());
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    res.headers_mut().remove(header::CONTENT_LENGTH);
+    let body = serde_json::Value::Object(body).to_string();
+    Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(body))))
+}
+
+/// The error code of an error response: the `error` of its `WWW-Authenticate` challenge, or its
+/// status reason in snake case, e.g. `service_unavailable`.
+fn error_code(res: &ServiceResponse<BoxBody>) -> String {
+    let challenge_error = res
+        .headers()
+        .get(header::WWW_AUTHENTICATE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once("error=\""))
+        .and_then(|(_, rest)| rest.split('"').next())
+        .map(str::to_string);
+    challenge_error.unwrap_or_else(|| {
+        res.status()
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_ascii_lowercase()
+            .replace([' ', '-'], "_")
+    })
+}
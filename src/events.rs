@@ -0,0 +1,237 @@
+use log::{debug, warn};
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::claims::Claims;
+
+/// A structured record of one authentication decision made by `BearerAuth`.
+///
+/// # Fields
+///
+/// * `timestamp` - Seconds since the Unix epoch.
+/// * `outcome` - `success` or `failure`.
+/// * `status` - The HTTP status of the rejection, `None` on success.
+/// * `method` - The request method.
+/// * `path` - The request path.
+/// * `route` - The pattern of the matched route, e.g. `/orders/{id}`, to group events by endpoint
+///   without one group per path parameter.
+/// * `correlation_id` - The correlation id of the request, when one was assigned.
+/// * `subject` - The `sub` claim of an accepted token.
+/// * `tenant` - The `tid` claim of an accepted token.
+/// * `app_id` - The client ID of an accepted token.
+/// * `error` - The validation error code of a rejected token, e.g. `token_expired`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthEvent {
+    pub timestamp: u64,
+    pub outcome: &'static str,
+    pub status: Option<u16>,
+    pub method: String,
+    pub path: String,
+    pub route: Option<String>,
+    pub correlation_id: Option<String>,
+    pub subject: Option<String>,
+    pub tenant: Option<String>,
+    pub app_id: Option<String>,
+    pub error: Option<String>,
+}
+
+impl AuthEvent {
+    fn new(
+        outcome: &'static str,
+        method: &str,
+        path: &str,
+        correlation_id: Option<String>,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            outcome,
+            status: None,
+            method: method.to_string(),
+            path: path.to_string(),
+            route: None,
+            correlation_id,
+            subject: None,
+            tenant: None,
+            app_id: None,
+            error: None,
+        }
+    }
+
+    /// An accepted request, described by the verified `claims`.
+    pub fn success(
+        method: &str,
+        path: &str,
+        correlation_id: Option<String>,
+        claims: &Claims,
+    ) -> Self {
+        Self {
+            subject: Some(claims.sub.clone()),
+            tenant: claims.tid.clone(),
+            app_id: claims.client_id().map(str::to_string),
+            ..Self::new("success", method, path, correlation_id)
+        }
+    }
+
+    /// Records the matched route pattern, see `HttpRequest::match_pattern`.
+    pub fn with_route(mut self, route: Option<String>) -> Self {
+        self.route = route;
+        self
+    }
+
+    /// Records why the token was rejected, see `ValidationError::code`.
+    pub fn with_error(mut self, error: Option<&str>) -> Self {
+        self.error = error.map(str::to_string);
+        self
+    }
+
+    /// A request rejected with `status`.
+    pub fn failure(method: &str, path: &str, correlation_id: Option<String>, status: u16) -> Self {
+        Self {
+            status: Some(status),
+            ..Self::new("failure", method, path, correlation_id)
+        }
+    }
+}
+
+/// Ships `AuthEvent`s to an HTTP endpoint or stderr in the background.
+///
+/// Events are posted as a JSON array, which an Azure Event Hubs REST endpoint
+/// (`https://<namespace>.servicebus.windows.net/<hub>/messages`) accepts as a batch, as does a
+/// generic webhook. A stderr sink (`spawn_stderr`) writes them as NDJSON instead. Emitting never
+/// blocks: when the queue is full, or the sink is failing, events are dropped and logged instead
+/// of holding up requests.
+///
+/// # Fields
+///
+/// * `sender` - The queue feeding the background task.
+#[derive(Clone)]
+pub struct EventSink {
+    sender: mpsc::Sender<AuthEvent>,
+}
+
+/// Settings for an `EventSink`.
+///
+/// # Fields
+///
+/// * `url` - The endpoint the batches are posted to.
+/// * `authorization` - An optional `Authorization` header value, e.g. an Event Hubs SAS token.
+/// * `batch_size` - The most events sent in one request.
+/// * `flush_interval` - How long a partial batch waits before it is sent.
+/// * `queue_capacity` - The most events waiting to be sent; further events are dropped.
+#[derive(Debug, Clone)]
+pub struct EventSinkConfig {
+    pub url: String,
+    pub authorization: Option<String>,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub queue_capacity: usize,
+}
+
+impl EventSinkConfig {
+    /// Settings for `url` with a batch of 100 events, a 5 second flush interval and room for
+    /// 10000 queued events.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            authorization: None,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(5),
+            queue_capacity: 10_000,
+        }
+    }
+}
+
+impl EventSink {
+    /// Starts the background task delivering events to `config.url`.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn spawn(config: EventSinkConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        tokio::spawn(deliver(config, receiver));
+        Self { sender }
+    }
+
+    /// Starts the background task writing events to stderr, one JSON object per line, holding
+    /// at most `queue_capacity` events waiting to be written.
+    ///
+    /// The lines bypass the logger, so a log router can split them from the app logs whatever
+    /// the log format; send the app logs to stdout to keep stderr for the events alone.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn spawn_stderr(queue_capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_capacity.max(1));
+        tokio::spawn(write_lines(receiver));
+        Self { sender }
+    }
+
+    /// Queues `event` for delivery, dropping it if the queue is full.
+    pub fn emit(&self, event: AuthEvent) {
+        if let Err(e) = self.sender.try_send(event) {
+            warn!("Dropping auth event: {}", e);
+        }
+    }
+}
+
+/// Collects events into batches and posts them until every `EventSink` is dropped.
+///
+/// A batch is sent when it is full or `flush_interval` after its first event, whichever is first.
+async fn deliver(config: EventSinkConfig, mut receiver: mpsc::Receiver<AuthEvent>) {
+    let client = reqwest::Client::new();
+    let batch_size = config.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Some(event) = receiver.recv().await {
+        batch.push(event);
+        let deadline = tokio::time::Instant::now() + config.flush_interval;
+        let mut open = true;
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(event)) => batch.push(event),
+                Ok(None) => {
+                    open = false;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        send_batch(&client, &config, &batch).await;
+        batch.clear();
+        if !open {
+            return;
+        }
+    }
+}
+
+/// Writes every event to stderr as one line of JSON until every `EventSink` is dropped.
+async fn write_lines(mut receiver: mpsc::Receiver<AuthEvent>) {
+    let mut stderr = tokio::io::stderr();
+    while let Some(event) = receiver.recv().await {
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Dropping auth event, cannot serialize it: {}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = stderr.write_all(&line).await {
+            warn!("Dropping auth event, stderr failed: {}", e);
+        }
+    }
+}
+
+/// Posts one batch, logging instead of retrying when the sink fails.
+async fn send_batch(client: &reqwest::Client, config: &EventSinkConfig, batch: &[AuthEvent]) {
+    let mut request = client.post(&config.url).json(batch);
+    if let Some(authorization) = &config.authorization {
+        request = request.header(reqwest::header::AUTHORIZATION, authorization);
+    }
+    match request.send().await.and_then(|res| res.error_for_status()) {
+        Ok(_) => debug!("Sent {} auth events", batch.len()),
+        Err(e) => warn!("Dropping {} auth events, sink failed: {}", batch.len(), e),
+    }
+}
@@ -0,0 +1,155 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use jsonwebtoken::DecodingKey;
+use log::debug;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+
+/// Whether `url` points at WS-Federation metadata rather than a JWKS: its path ends in `.xml`,
+/// as Azure AD's `federationmetadata/2007-06/federationmetadata.xml` does.
+pub fn is_federation_metadata_url(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    path.to_ascii_lowercase().ends_with(".xml")
+}
+
+/// Reads the signing keys of a WS-Federation metadata document: the RSA keys of the
+/// `X509Certificate`s in its `KeyDescriptor`s with `use="signing"` (or no `use`), by kid.
+///
+/// Azure AD sets the `kid` of its tokens to the `x5t` of the signing certificate, so each key is
+/// filed under the base64url SHA-1 thumbprint of its certificate. The certificate the metadata
+/// itself is signed with (in `ds:Signature`) is not a `KeyDescriptor` and is left out; the
+/// metadata signature is not verified, so fetch it over HTTPS only.
+///
+/// # Errors
+///
+/// Returns a message if the document is not well-formed XML, a certificate is not valid base64
+/// or holds no RSA public key, or there is no signing certificate at all.
+pub fn parse_federation_metadata(xml: &str) -> Result<HashMap<String, DecodingKey>, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+    let mut keys = HashMap::new();
+    // Depth of the signing KeyDescriptor being read, and whether an X509Certificate is open
+    let mut signing_depth: Option<usize> = None;
+    let mut depth = 0usize;
+    let mut in_certificate = false;
+    loop {
+        match reader
+            .read_event()
+            .map_err(|e| format!("invalid federation metadata: {}", e))?
+        {
+            Event::Start(element) => {
+                depth += 1;
+                match element.local_name().as_ref() {
+                    b"KeyDescriptor" => {
+                        let key_use = element
+                            .try_get_attribute("use")
+                            .map_err(|e| format!("invalid federation metadata: {}", e))?
+                            .map(|attribute| attribute.value.into_owned());
+                        if key_use
+                            .as_deref()
+                            .is_none_or(|key_use| key_use == b"signing")
+                        {
+                            signing_depth = Some(depth);
+                        }
+                    }
+                    b"X509Certificate" if signing_depth.is_some() => in_certificate = true,
+                    _ => {}
+                }
+            }
+            Event::Text(text) if in_certificate => {
+                let text = text
+                    .unescape()
+                    .map_err(|e| format!("invalid federation metadata: {}", e))?;
+                let (kid, key) = certificate_key(&text)?;
+                debug!("Signing certificate {} in federation metadata", kid);
+                keys.insert(kid, key);
+            }
+            Event::End(_) => {
+                in_certificate = false;
+                if signing_depth == Some(depth) {
+                    signing_depth = None;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if keys.is_empty() {
+        return Err("federation metadata has no signing certificates".into());
+    }
+    Ok(keys)
+}
+
+/// The `x5t` and RSA key of a base64 DER certificate, as found in `X509Certificate`.
+fn certificate_key(base64: &str) -> Result<(String, DecodingKey), String> {
+    let compact: String = base64.split_whitespace().collect();
+    let certificate = STANDARD
+        .decode(compact)
+        .map_err(|e| format!("invalid certificate in federation metadata: {}", e))?;
+    let kid = URL_SAFE_NO_PAD.encode(Sha1::digest(&certificate));
+    let public_key = rsa_public_key(&certificate)
+        .ok_or_else(|| format!("certificate {} has no RSA public key", kid))?;
+    Ok((kid, DecodingKey::from_rsa_der(public_key)))
+}
+
+/// The PKCS#1 `RSAPublicKey` inside the `subjectPublicKeyInfo` of a DER `certificate`.
+///
+/// ```text
+/// Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+/// tbsCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer,
+///                               validity, subject, subjectPublicKeyInfo, ... }
+/// subjectPublicKeyInfo ::= SEQUENCE { algorithm, subjectPublicKey BIT STRING }
+/// ```
+fn rsa_public_key(certificate: &[u8]) -> Option<&[u8]> {
+    // AlgorithmIdentifier content starting with the rsaEncryption OID (1.2.840.113549.1.1.1)
+    const RSA_ENCRYPTION: &[u8] = &[
+        0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+    ];
+    let (tag, certificate, _) = der_element(certificate)?;
+    let (_, tbs, _) = der_element(certificate).filter(|_| tag == 0x30)?;
+    let mut rest = tbs;
+    let (tag, _, after) = der_element(rest)?;
+    if tag == 0xa0 {
+        rest = after;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, spki, _) = der_element(rest)?;
+    let (_, algorithm, after) = der_element(spki)?;
+    if !algorithm.starts_with(RSA_ENCRYPTION) {
+        return None;
+    }
+    let (tag, bit_string, _) = der_element(after)?;
+    // The first byte of a BIT STRING counts the unused bits, always 0 for a key
+    match (tag, bit_string.split_first()) {
+        (0x03, Some((0, key))) => Some(key),
+        _ => None,
+    }
+}
+
+/// Splits the first DER element off `input`: its tag, its content and what follows it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | usize::from(*byte));
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
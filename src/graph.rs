@@ -0,0 +1,212 @@
+use log::{debug, warn};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::authority::Cloud;
+use crate::claims::Claims;
+
+/// Settings for resolving group memberships from Microsoft Graph.
+///
+/// The API exchanges the caller's token for a Graph token with the on-behalf-of flow, so it
+/// needs its own client credentials and the `GroupMember.Read.All` (or `Directory.Read.All`)
+/// delegated permission.
+///
+/// # Fields
+///
+/// * `tenant_id` - The tenant the API is registered in.
+/// * `client_id` - The client ID of the API's app registration.
+/// * `client_secret` - A client secret of the API's app registration.
+/// * `authority_host` - The login host, e.g. `https://login.microsoftonline.com`.
+/// * `graph_url` - The Graph endpoint, e.g. `https://graph.microsoft.com`.
+/// * `ttl` - How long resolved memberships are cached per caller.
+#[derive(Debug, Clone)]
+pub struct GraphGroupsConfig {
+    pub tenant_id: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub authority_host: String,
+    pub graph_url: String,
+    pub ttl: Duration,
+}
+
+impl GraphGroupsConfig {
+    /// Settings for the public cloud with a 10 minute cache.
+    pub fn new(
+        tenant_id: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        Self {
+            tenant_id: tenant_id.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            authority_host: "https://login.microsoftonline.com".to_string(),
+            graph_url: "https://graph.microsoft.com".to_string(),
+            ttl: Duration::from_secs(600),
+        }
+    }
+
+    /// Uses the login host and Graph endpoint of `cloud`.
+    pub fn with_cloud(mut self, cloud: Cloud) -> Self {
+        self.authority_host = cloud.authority_url();
+        self.graph_url = cloud.graph_url().to_string();
+        self
+    }
+}
+
+/// Fills in `groups` for tokens with a group overage, reading through a per-caller cache.
+///
+/// # Fields
+///
+/// * `config` - The Graph and client settings.
+/// * `client` - The HTTP client used for the token exchange and Graph calls.
+/// * `cache` - Resolved memberships by caller (tenant and subject), with the time they expire.
+pub struct GroupResolver {
+    config: GraphGroupsConfig,
+    client: Client,
+    cache: Mutex<HashMap<String, CachedGroups>>,
+}
+
+/// The group memberships of one caller and when they expire.
+struct CachedGroups {
+    expires_at: Instant,
+    groups: Vec<String>,
+}
+
+impl GroupResolver {
+    /// Creates a resolver with an empty cache.
+    pub fn new(config: GraphGroupsConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `claims` with `groups` resolved from Graph when the token has a group overage.
+    ///
+    /// Claims without an overage are returned unchanged. When Graph cannot be reached the
+    /// failure is logged and `groups` stays empty, so group checks deny rather than allow.
+    pub async fn resolve(&self, token: &str, mut claims: Claims) -> Claims {
+        if !claims.has_groups_overage() {
+            return claims;
+        }
+        let key = format!(
+            "{}/{}",
+            claims.tid.as_deref().unwrap_or_default(),
+            claims.sub
+        );
+        if let Some(groups) = self.cached(&key) {
+            debug!("Group memberships of {} served from cache", claims.sub);
+            claims.groups = Some(groups);
+            return claims;
+        }
+        match self.fetch_groups(token).await {
+            Ok(groups) => {
+                debug!(
+                    "Resolved {} groups of {} from Graph",
+                    groups.len(),
+                    claims.sub
+                );
+                self.cache.lock().unwrap_or_else(|e| e.into_inner()).insert(
+                    key,
+                    CachedGroups {
+                        expires_at: Instant::now() + self.config.ttl,
+                        groups: groups.clone(),
+                    },
+                );
+                claims.groups = Some(groups);
+            }
+            Err(e) => warn!(
+                "Could not resolve group memberships of {}: {}",
+                claims.sub, e
+            ),
+        }
+        claims
+    }
+
+    /// The cached memberships for `key`, evicting them once expired.
+    fn cached(&self, key: &str) -> Option<Vec<String>> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.get(key) {
+            Some(cached) if cached.expires_at > Instant::now() => Some(cached.groups.clone()),
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Exchanges `token` for a Graph token and lists the caller's groups with `getMemberGroups`.
+    async fn fetch_groups(&self, token: &str) -> Result<Vec<String>, String> {
+        let graph_token = self.on_behalf_of(token).await?;
+        let response = self
+            .client
+            .post(format!(
+                "{}/v1.0/me/getMemberGroups",
+                self.config.graph_url.trim_end_matches('/')
+            ))
+            .bearer_auth(graph_token)
+            .json(&serde_json::json!({ "securityEnabledOnly": false }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("getMemberGroups failed: {}", e))?;
+        let body: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|e| format!("invalid getMemberGroups response: {}", e))?;
+        Ok(body["value"]
+            .as_array()
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(|id| id.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Exchanges the caller's `token` for a Graph token with the on-behalf-of flow.
+    async fn on_behalf_of(&self, token: &str) -> Result<String, String> {
+        let endpoint = format!(
+            "{}/{}/oauth2/v2.0/token",
+            self.config.authority_host.trim_end_matches('/'),
+            self.config.tenant_id
+        );
+        let scope = format!("{}/.default", self.config.graph_url.trim_end_matches('/'));
+        let form = [
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+            ("assertion", token),
+            ("scope", scope.as_str()),
+            ("requested_token_use", "on_behalf_of"),
+        ];
+        let response = self
+            .client
+            .post(endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| format!("on-behalf-of exchange failed: {}", e))?;
+        let status = response.status();
+        let body: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|e| format!("invalid on-behalf-of response: {}", e))?;
+        if !status.is_success() {
+            return Err(format!(
+                "on-behalf-of exchange failed ({}): {}",
+                status,
+                body["error"].as_str().unwrap_or("unknown_error")
+            ));
+        }
+        body["access_token"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "on-behalf-of response has no access_token".to_string())
+    }
+}
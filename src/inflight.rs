@@ -0,0 +1,47 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of requests currently being handled, registered as app data for
+/// `track_in_flight`. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub struct InFlightRequests(Arc<AtomicU64>);
+
+impl InFlightRequests {
+    /// The number of requests in flight right now.
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Counts one request until the returned guard is dropped.
+    fn enter(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.0.clone())
+    }
+}
+
+/// Decrements the count when the request finishes, or when its future is dropped early.
+struct InFlightGuard(Arc<AtomicU64>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that counts the requests in flight in the
+/// `InFlightRequests` of the app data. Without one, requests are not counted.
+///
+/// A request counts from the moment it reaches the middleware until its response is produced;
+/// streaming the body afterwards is not included.
+pub async fn track_in_flight(
+    in_flight: Option<web::Data<InFlightRequests>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let _guard = in_flight.as_ref().map(|in_flight| in_flight.enter());
+    next.call(req).await
+}
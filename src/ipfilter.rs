@@ -0,0 +1,479 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use log::{debug, warn};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::reload::Reloadable;
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address
+/// is a single-host network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` lies in this network. IPv4-mapped IPv6 addresses match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` bits of `a` and `b` are equal.
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    let rest = prefix_len % 8;
+    rest == 0 || {
+        let mask = 0xffu8 << (8 - rest);
+        a[full_bytes] & mask == b[full_bytes] & mask
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Serialized in CIDR notation, e.g. `"10.0.0.0/8"`.
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("{:?} is not an IP address", address))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("{:?} is not a valid prefix length", prefix_len))?,
+            None => max_len,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// Parses a comma-separated list of CIDRs, ignoring empty entries.
+///
+/// # Errors
+///
+/// Returns a message naming the first entry that is not a valid CIDR.
+pub fn parse_cidrs(value: &str) -> Result<Vec<Cidr>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(Cidr::from_str)
+        .collect()
+}
+
+/// The proxies whose forwarding headers (`X-Forwarded-For`, `X-Forwarded-Proto` and
+/// `X-Forwarded-Host`) are believed, registered as `web::Data<Reloadable<TrustedProxies>>` app
+/// data. The headers of any other peer are ignored, so a client cannot spoof its address or
+/// scheme by sending them itself. Serialized as the list of CIDRs.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct TrustedProxies(pub Vec<Cidr>);
+
+/// Where a request came from, see `client_origin`.
+///
+/// # Fields
+///
+/// * `ip` - The client address. `None` without a peer address (e.g. over a Unix socket) or when
+///   a trusted proxy sent a malformed `X-Forwarded-For`.
+/// * `scheme` - The scheme the client used, `http` or `https`.
+/// * `host` - The host the client addressed, with its port if it gave one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOrigin {
+    pub ip: Option<IpAddr>,
+    pub scheme: String,
+    pub host: String,
+}
+
+impl TrustedProxies {
+    /// Whether `ip` is a trusted proxy.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The address of the client behind `peer`, given the request's `X-Forwarded-For` values.
+    ///
+    /// Only a trusted proxy's header is used. It is read right to left, skipping further trusted
+    /// proxies, and the first other address is the client: entries left of it were written by
+    /// the client itself and cannot be trusted. A malformed entry yields `None`.
+    pub fn client_ip<'a>(
+        &self,
+        peer: IpAddr,
+        forwarded_for: impl Iterator<Item = &'a str>,
+    ) -> Option<IpAddr> {
+        if !self.contains(peer) {
+            return Some(peer);
+        }
+        let hops: Vec<&str> = forwarded_for
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            client = hop.parse().ok()?;
+            if !self.contains(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    /// The address, scheme and host `req` was sent with by the client, taking
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` into account only when the
+    /// peer is a trusted proxy.
+    pub fn origin(&self, req: &HttpRequest) -> ClientOrigin {
+        let peer = req.peer_addr().map(|peer| peer.ip());
+        let trusted = peer.is_some_and(|peer| self.contains(peer));
+        let first_forwarded = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let forwarded_scheme = first_forwarded("X-Forwarded-Proto")
+            .filter(|_| trusted)
+            .map(|scheme| scheme.to_ascii_lowercase())
+            .filter(|scheme| scheme == "http" || scheme == "https");
+        let scheme = forwarded_scheme.unwrap_or_else(|| {
+            let secure = req.app_config().secure();
+            if secure { "https" } else { "http" }.to_string()
+        });
+        let host = first_forwarded("X-Forwarded-Host")
+            .filter(|_| trusted)
+            .or_else(|| {
+                req.headers()
+                    .get(header::HOST)
+                    .and_then(|host| host.to_str().ok())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| req.app_config().host().to_string());
+        ClientOrigin {
+            ip: peer.and_then(|peer| {
+                self.client_ip(
+                    peer,
+                    req.headers()
+                        .get_all("X-Forwarded-For")
+                        .filter_map(|value| value.to_str().ok()),
+                )
+            }),
+            scheme,
+            host,
+        }
+    }
+}
+
+/// Where `req` came from, believing forwarding headers only from the `TrustedProxies` in the app
+/// data. Without `TrustedProxies` no proxy is trusted.
+pub fn client_origin(req: &HttpRequest) -> ClientOrigin {
+    match req.app_data::<web::Data<Reloadable<TrustedProxies>>>() {
+        Some(proxies) => proxies.load().origin(req),
+        None => TrustedProxies::default().origin(req),
+    }
+}
+
+/// The networks allowed to call the protected endpoints, registered as
+/// `web::Data<Reloadable<IpAllowList>>` app data for `ip_allow_list`.
+///
+/// # Fields
+///
+/// * `allowed` - Client addresses, as resolved by `client_origin`, must lie in one of these
+///   networks.
+#[derive(Debug, Clone, Serialize)]
+pub struct IpAllowList {
+    pub allowed: Vec<Cidr>,
+}
+
+impl IpAllowList {
+    /// Whether `ip` may call the protected endpoints.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allowed.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that answers `403 Forbidden` to clients
+/// outside the `IpAllowList` in the app data, before the token is looked at. Without an
+/// `IpAllowList` every client is let through.
+///
+/// The client address is resolved by `client_origin`, so `X-Forwarded-For` only counts when a
+/// trusted proxy sent it. Requests without a peer address (e.g. over a Unix socket) are
+/// rejected.
+pub async fn ip_allow_list(
+    allow_list: Option<web::Data<Reloadable<IpAllowList>>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(allow_list) = allow_list.map(|allow_list| allow_list.load()) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let client_ip = client_origin(req.request()).ip;
+    match client_ip {
+        Some(ip) if allow_list.allows(ip) => {
+            debug!("Client {} is on the IP allow-list", ip);
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_boxed_body)
+        }
+        _ => {
+            warn!(
+                "Rejecting {} {} from {:?}: not on the IP allow-list",
+                req.method(),
+                req.path(),
+                client_ip
+            );
+            Ok(req.into_response(HttpResponse::Forbidden().body("Client address not allowed")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use std::net::SocketAddr;
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs_parse_with_and_without_prefix() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.0.2.1").to_string(), "192.0.2.1/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "example.com/8",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{:?}", invalid);
+        }
+        assert_eq!(
+            parse_cidrs(" 10.0.0.0/8, ,fd00::/8 "),
+            Ok(vec![cidr("10.0.0.0/8"), cidr("fd00::/8")])
+        );
+    }
+
+    #[test]
+    fn zero_prefixes_match_every_address_of_their_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_prefixes_match_one_address() {
+        assert!(cidr("192.0.2.1/32").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(ip("192.0.2.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn partial_bytes_are_masked() {
+        let network = cidr("10.0.0.0/12");
+        assert!(network.contains(ip("10.15.255.255")));
+        assert!(!network.contains(ip("10.16.0.0")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_networks() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        assert!(!cidr("::ffff:0:0/96").contains(ip("10.1.2.3")));
+    }
+
+    async fn allow_list_status(allowed: &str, peer: &str) -> actix_web::http::StatusCode {
+        let allow_list = web::Data::new(Reloadable::new(IpAllowList {
+            allowed: parse_cidrs(allowed).unwrap(),
+        }));
+        let app = init_service(
+            App::new()
+                .app_data(allow_list)
+                .wrap(from_fn(ip_allow_list))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = TestRequest::get()
+            .peer_addr(SocketAddr::new(ip(peer), 50000))
+            .to_request();
+        call_service(&app, request).await.status()
+    }
+
+    #[actix_web::test]
+    async fn allow_lists_refuse_other_clients() {
+        assert!(allow_list_status("10.0.0.0/8", "10.1.2.3")
+            .await
+            .is_success());
+        assert_eq!(
+            allow_list_status("10.0.0.0/8", "192.0.2.1").await,
+            actix_web::http::StatusCode::FORBIDDEN
+        );
+    }
+
+    fn proxies(cidrs: &str) -> TrustedProxies {
+        TrustedProxies(parse_cidrs(cidrs).unwrap())
+    }
+
+    fn client_ip(proxies: &TrustedProxies, peer: &str, forwarded_for: &[&str]) -> Option<IpAddr> {
+        proxies.client_ip(ip(peer), forwarded_for.iter().copied())
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let proxies = proxies("10.0.0.0/8");
+        assert_eq!(
+            client_ip(&proxies, "198.51.100.9", &["203.0.113.7"]),
+            Some(ip("198.51.100.9"))
+        );
+        assert_eq!(
+            client_ip(&TrustedProxies::default(), "10.0.0.1", &["203.0.113.7"]),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_read_right_to_left_past_trusted_proxies() {
+        let proxies = proxies("10.0.0.0/8");
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", &["203.0.113.7"]),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", &["203.0.113.7, 10.0.0.2", "10.0.0.3"]),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(client_ip(&proxies, "10.0.0.1", &[]), Some(ip("10.0.0.1")));
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", &["10.0.0.2"]),
+            Some(ip("10.0.0.2"))
+        );
+    }
+
+    #[test]
+    fn addresses_written_by_the_client_are_not_believed() {
+        let proxies = proxies("10.0.0.0/8");
+        // The client sent `10.0.0.5, 192.0.2.1` itself; only the entry its proxy appended counts
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", &["10.0.0.5, 192.0.2.1, 198.51.100.9"]),
+            Some(ip("198.51.100.9"))
+        );
+        assert_eq!(client_ip(&proxies, "10.0.0.1", &["not-an-ip"]), None);
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", &["garbage, 198.51.100.9"]),
+            Some(ip("198.51.100.9"))
+        );
+    }
+
+    fn origin(proxies: &TrustedProxies, peer: &str) -> ClientOrigin {
+        let req = TestRequest::get()
+            .peer_addr(SocketAddr::new(ip(peer), 50000))
+            .insert_header((header::HOST, "internal:8080"))
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .insert_header(("X-Forwarded-Proto", "HTTPS"))
+            .insert_header(("X-Forwarded-Host", "api.example.com, internal"))
+            .to_http_request();
+        proxies.origin(&req)
+    }
+
+    #[test]
+    fn forwarding_headers_count_only_from_trusted_proxies() {
+        let proxies = proxies("10.0.0.0/8");
+        assert_eq!(
+            origin(&proxies, "10.0.0.1"),
+            ClientOrigin {
+                ip: Some(ip("203.0.113.7")),
+                scheme: "https".to_string(),
+                host: "api.example.com".to_string(),
+            }
+        );
+        assert_eq!(
+            origin(&proxies, "198.51.100.9"),
+            ClientOrigin {
+                ip: Some(ip("198.51.100.9")),
+                scheme: "http".to_string(),
+                host: "internal:8080".to_string(),
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn allow_lists_see_through_trusted_proxies_only() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Reloadable::new(proxies("10.0.0.0/8"))))
+                .app_data(web::Data::new(Reloadable::new(IpAllowList {
+                    allowed: parse_cidrs("203.0.113.0/24").unwrap(),
+                })))
+                .wrap(from_fn(ip_allow_list))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |peer: &str| {
+            TestRequest::get()
+                .peer_addr(SocketAddr::new(ip(peer), 50000))
+                .insert_header(("X-Forwarded-For", "203.0.113.7"))
+                .to_request()
+        };
+        let response = call_service(&app, request("10.0.0.1")).await;
+        assert!(response.status().is_success());
+        let spoofed = call_service(&app, request("198.51.100.9")).await;
+        assert_eq!(spoofed.status(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,208 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::debug;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{Oaep, RsaPrivateKey};
+use serde::Deserialize;
+
+use crate::validator::ValidationError;
+
+/// Returns `true` if `token` has the five-segment compact JWE structure.
+pub fn is_jwe(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+/// The protected header of a compact JWE.
+#[derive(Debug, Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+}
+
+/// Decrypts nested JWE tokens so the inner JWS can be validated as usual.
+///
+/// Supports key management with `RSA-OAEP` or `RSA-OAEP-256` and content encryption with `A256GCM`.
+pub struct JweDecryptor {
+    key: RsaPrivateKey,
+}
+
+impl JweDecryptor {
+    /// Loads the RSA private key from a PKCS#8 or PKCS#1 PEM document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the PEM cannot be parsed as an RSA private key.
+    pub fn from_pem(pem: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let key =
+            RsaPrivateKey::from_pkcs8_pem(pem).or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))?;
+        Ok(Self { key })
+    }
+
+    /// Decrypts a compact JWE and returns the inner token.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ValidationError::UndecryptableToken` if the structure, algorithms, key or
+    /// authentication tag are not acceptable.
+    pub fn decrypt(&self, token: &str) -> Result<String, ValidationError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [header_b64, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+            return Err(ValidationError::UndecryptableToken);
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| ValidationError::UndecryptableToken)
+        };
+
+        let header: JweHeader = serde_json::from_slice(&decode(header_b64)?)
+            .map_err(|_| ValidationError::UndecryptableToken)?;
+        debug!("JWE header: {:?}", header);
+        if header.enc != "A256GCM" {
+            debug!("Unsupported JWE content encryption: {}", header.enc);
+            return Err(ValidationError::UndecryptableToken);
+        }
+
+        let encrypted_key = decode(encrypted_key)?;
+        let cek = match header.alg.as_str() {
+            "RSA-OAEP" => self.key.decrypt(Oaep::new::<sha1::Sha1>(), &encrypted_key),
+            "RSA-OAEP-256" => self
+                .key
+                .decrypt(Oaep::new::<sha2::Sha256>(), &encrypted_key),
+            other => {
+                debug!("Unsupported JWE key management algorithm: {}", other);
+                return Err(ValidationError::UndecryptableToken);
+            }
+        }
+        .map_err(|_| ValidationError::UndecryptableToken)?;
+
+        let iv = decode(iv)?;
+        if iv.len() != 12 {
+            return Err(ValidationError::UndecryptableToken);
+        }
+        let mut message = decode(ciphertext)?;
+        message.extend(decode(tag)?);
+
+        let cipher =
+            Aes256Gcm::new_from_slice(&cek).map_err(|_| ValidationError::UndecryptableToken)?;
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: &message,
+                    aad: header_b64.as_bytes(),
+                },
+            )
+            .map_err(|_| ValidationError::UndecryptableToken)?;
+        String::from_utf8(plaintext).map_err(|_| ValidationError::UndecryptableToken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestTokenFactory;
+    use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+    use rsa::rand_core::{OsRng, RngCore};
+    use rsa::RsaPublicKey;
+
+    /// Encrypts `inner` for `key` as a compact JWE with the `alg` key management and `enc`
+    /// header.
+    fn encrypt(key: &RsaPublicKey, alg: &str, enc: &str, inner: &str) -> String {
+        let header = URL_SAFE_NO_PAD
+            .encode(serde_json::json!({ "alg": alg, "enc": enc, "cty": "JWT" }).to_string());
+        let mut cek = [0u8; 32];
+        OsRng.fill_bytes(&mut cek);
+        let mut iv = [0u8; 12];
+        OsRng.fill_bytes(&mut iv);
+        let encrypted_key = match alg {
+            "RSA-OAEP" => key.encrypt(&mut OsRng, Oaep::new::<sha1::Sha1>(), &cek),
+            _ => key.encrypt(&mut OsRng, Oaep::new::<sha2::Sha256>(), &cek),
+        }
+        .unwrap();
+        let mut sealed = Aes256Gcm::new_from_slice(&cek)
+            .unwrap()
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: inner.as_bytes(),
+                    aad: header.as_bytes(),
+                },
+            )
+            .unwrap();
+        let tag = sealed.split_off(sealed.len() - 16);
+        [
+            header,
+            URL_SAFE_NO_PAD.encode(encrypted_key),
+            URL_SAFE_NO_PAD.encode(iv),
+            URL_SAFE_NO_PAD.encode(sealed),
+            URL_SAFE_NO_PAD.encode(tag),
+        ]
+        .join(".")
+    }
+
+    fn decryption_key() -> (RsaPublicKey, JweDecryptor) {
+        let key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+        let pem = key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        (key.to_public_key(), JweDecryptor::from_pem(&pem).unwrap())
+    }
+
+    #[tokio::test]
+    async fn jwe_wrapped_tokens_round_trip_through_the_validator() {
+        let factory = TestTokenFactory::new().unwrap();
+        let (public_key, decryptor) = decryption_key();
+        let inner = factory
+            .token()
+            .with_roles(&["Task.HelloWorld"])
+            .sign()
+            .unwrap();
+        let token = encrypt(&public_key, "RSA-OAEP-256", "A256GCM", &inner);
+        assert!(is_jwe(&token));
+        assert!(!is_jwe(&inner));
+
+        let validator = factory.validator().unwrap().with_jwe_decryptor(decryptor);
+        let claims = validator.validate(&token).await.unwrap();
+        assert_eq!(claims.roles, Some(vec!["Task.HelloWorld".to_string()]));
+    }
+
+    #[test]
+    fn both_key_management_algorithms_decrypt() {
+        let (public_key, decryptor) = decryption_key();
+        for alg in ["RSA-OAEP", "RSA-OAEP-256"] {
+            let token = encrypt(&public_key, alg, "A256GCM", "a.b.c");
+            assert_eq!(
+                decryptor.decrypt(&token),
+                Ok("a.b.c".to_string()),
+                "{}",
+                alg
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_or_foreign_tokens_are_undecryptable() {
+        let (public_key, decryptor) = decryption_key();
+        let (other_key, _) = decryption_key();
+        let token = encrypt(&public_key, "RSA-OAEP-256", "A256GCM", "a.b.c");
+
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged_tag = URL_SAFE_NO_PAD.encode([0u8; 16]);
+        parts[4] = &forged_tag;
+        let tampered = parts.join(".");
+        let undecryptable = [
+            tampered.as_str(),
+            &encrypt(&other_key, "RSA-OAEP-256", "A256GCM", "a.b.c"),
+            &encrypt(&public_key, "RSA-OAEP-256", "A128GCM", "a.b.c"),
+            &encrypt(&public_key, "RSA1_5", "A256GCM", "a.b.c"),
+            "a.b.c.d.e",
+            "a.b.c",
+        ]
+        .map(|token| decryptor.decrypt(token));
+        for result in undecryptable {
+            assert_eq!(result, Err(ValidationError::UndecryptableToken));
+        }
+    }
+}
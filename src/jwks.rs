@@ -0,0 +1,1488 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use jsonwebtoken::DecodingKey;
+use log::{debug, error, info, warn};
+use reqwest::{tls, Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{Mutex, RwLock, Semaphore};
+
+use crate::validator::ValidationError;
+
+/// A snapshot of the JWKS keys together with the instant they were fetched.
+///
+/// `last_failure` records when the most recent refresh attempt failed, so a degraded
+/// upstream is retried at most once per `REFRESH_RETRY_INTERVAL` while stale keys are served.
+/// `retired` holds the keys that left the JWKS but are still retained (and part of `keys`),
+/// with the instant they were first missing.
+struct JwksSnapshot {
+    keys: Arc<HashMap<String, DecodingKey>>,
+    fetched_at: Instant,
+    last_failure: Option<Instant>,
+    retired: HashMap<String, (DecodingKey, Instant)>,
+}
+
+/// Cache for the JWKS keys with single-flight refresh semantics.
+///
+/// Readers take the `snapshot` read lock and never wait on the network. When the
+/// snapshot is missing or expired, callers go through `refresh`, which serializes
+/// on `refresh_lock` so that only one `fetch_jwks` call is in flight at a time.
+/// Callers that queued behind an in-flight refresh notice the bumped `generation`
+/// once they acquire the lock and reuse its result instead of fetching again.
+///
+/// If a refresh fails while keys from an earlier fetch are still held, the stale keys
+/// keep being served (with a warning) instead of failing validation.
+///
+/// Every fetch caches the whole published key set, including keys AAD publishes before they
+/// start signing, so a freshly activated key is already cached as long as the set was fetched
+/// after the key was published. `spawn_background_refresh` keeps it that recent without a
+/// request ever waiting on a fetch.
+///
+/// A published key map is never modified. A refresh fetches and merges every JWKS into a
+/// new map without holding the `snapshot` lock, then swaps it in whole, so a validation in
+/// flight keeps its `Arc` to the old map and never sees a partially populated one.
+///
+/// With `with_disk_cache`, every successful fetch is also saved to a file, and the first
+/// refresh after startup loads the keys from it when they are recent enough, sparing a cold
+/// start the network fetch.
+///
+/// With `with_max_keys`, `last_used` records when each kid last verified a token, and a
+/// refresh merging more keys than the limit drops the least recently used ones.
+///
+/// A token signed with a kid the cached keys lack goes through `refresh_for_unknown_kid`, which
+/// fetches the keys again at most once per `unknown_kid_refresh_interval`, timed from
+/// `last_unknown_kid_refresh`, so a key rotated in before the TTL expires is picked up without
+/// bogus kids hammering the JWKS endpoint.
+pub struct JwksCache {
+    jwks_url: String,
+    additional_urls: Vec<String>,
+    pinned_keys: HashMap<String, DecodingKey>,
+    retired_key_retention: Duration,
+    max_keys: Option<usize>,
+    last_used: std::sync::Mutex<HashMap<String, Instant>>,
+    unknown_kid_refresh_interval: Duration,
+    last_unknown_kid_refresh: std::sync::Mutex<Option<Instant>>,
+    disk_cache: Option<(PathBuf, Duration)>,
+    ttl: Duration,
+    retry_policy: RetryPolicy,
+    client: Client,
+    max_document_bytes: usize,
+    fetch_limiter: Option<Arc<Semaphore>>,
+    snapshot: RwLock<Option<JwksSnapshot>>,
+    generation: RwLock<u64>,
+    refresh_lock: Mutex<()>,
+    warming: AtomicBool,
+    counters: JwksCounters,
+}
+
+/// Running totals behind `JwksCache::metrics`.
+#[derive(Default)]
+struct JwksCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    refreshes: AtomicU64,
+    refresh_failures: AtomicU64,
+    served_stale: AtomicU64,
+    evicted_keys: AtomicU64,
+    unknown_kid_refreshes: AtomicU64,
+}
+
+impl JwksCache {
+    /// Minimum delay between refresh attempts after a failed refresh.
+    const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Creates an empty cache for the JWKS served at `jwks_url`.
+    ///
+    /// # Arguments
+    ///
+    /// * `jwks_url` - The URL to fetch the JWKS from.
+    /// * `ttl` - How long fetched keys are used before they are refreshed.
+    pub fn new(jwks_url: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            jwks_url: jwks_url.into(),
+            additional_urls: Vec::new(),
+            pinned_keys: HashMap::new(),
+            retired_key_retention: Duration::ZERO,
+            max_keys: None,
+            last_used: std::sync::Mutex::new(HashMap::new()),
+            unknown_kid_refresh_interval: Duration::from_secs(300),
+            last_unknown_kid_refresh: std::sync::Mutex::new(None),
+            disk_cache: None,
+            ttl,
+            retry_policy: RetryPolicy::default(),
+            client: Client::new(),
+            max_document_bytes: DEFAULT_MAX_JWKS_BYTES,
+            fetch_limiter: None,
+            snapshot: RwLock::new(None),
+            generation: RwLock::new(0),
+            refresh_lock: Mutex::new(()),
+            warming: AtomicBool::new(false),
+            counters: JwksCounters::default(),
+        }
+    }
+
+    /// A cache serving `keys` (by kid) and never fetching, e.g. keys parsed by `parse_jwks` from a
+    /// JWKS given inline in configuration. Its `jwks_url` is `INLINE_JWKS_URL`, and refreshes
+    /// keep the keys as they are, so the fetch-related settings have no effect.
+    pub fn from_keys(keys: HashMap<String, DecodingKey>) -> Self {
+        let mut cache = Self::new(INLINE_JWKS_URL, Duration::MAX);
+        *cache.snapshot.get_mut() = Some(JwksSnapshot {
+            keys: Arc::new(keys),
+            fetched_at: Instant::now(),
+            last_failure: None,
+            retired: HashMap::new(),
+        });
+        cache
+    }
+
+    /// Replaces the retry policy used for each fetch.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Fetches with `client` instead of a default one, e.g. one built by `jwks_client` to
+    /// enforce a minimum TLS version.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Rejects JWKS documents larger than `max_bytes` instead of reading them into memory.
+    /// Defaults to `DEFAULT_MAX_JWKS_BYTES`.
+    pub fn with_max_document_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_document_bytes = max_bytes;
+        self
+    }
+
+    /// Also trusts the keys served at `urls`, e.g. a federation partner's JWKS.
+    ///
+    /// Every refresh fetches all URLs and merges the keys by kid. A kid served by more than one
+    /// URL keeps the key of the first (the `jwks_url` given to `new` comes first) and a warning is
+    /// logged. If any URL fails, the whole refresh fails, so a key set is never partially replaced.
+    pub fn with_additional_urls(mut self, urls: Vec<String>) -> Self {
+        self.additional_urls = urls;
+        self
+    }
+
+    /// Also trusts `keys` (by kid) whatever the JWKS serves, e.g. to pre-trust a kid announced
+    /// for a rotation or keep an old one past its removal.
+    ///
+    /// Pinned keys are merged into every refresh and win over a fetched key with the same kid.
+    pub fn with_pinned_keys(mut self, keys: HashMap<String, DecodingKey>) -> Self {
+        self.pinned_keys = keys;
+        self
+    }
+
+    /// Keeps trusting a key for `retention` after it disappears from the JWKS, so tokens signed
+    /// just before a rotation keep validating until they expire. `Duration::ZERO` (the default)
+    /// drops retired keys on the refresh that no longer sees them.
+    ///
+    /// Retention is checked on refresh, so a retired key may stay up to one refresh interval
+    /// longer. Keep it off if keys are only ever removed because they were compromised.
+    pub fn with_retired_key_retention(mut self, retention: Duration) -> Self {
+        self.retired_key_retention = retention;
+        self
+    }
+
+    /// Caches at most `max_keys` keys, or any number with `None` (the default). When a refresh
+    /// merges more (fetched, additional, pinned and retained keys together), the keys that least
+    /// recently verified a token are dropped first. A kid new to the cache counts as used when
+    /// it arrives, so a freshly rotated-in signing key is not the first to go. Pinned keys are
+    /// never dropped.
+    ///
+    /// This bounds memory when many or oversized key sets are merged into one cache; a dropped
+    /// kid fails with `UnknownKid` until a refresh sees it again.
+    pub fn with_max_keys(mut self, max_keys: Option<usize>) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+
+    /// Fetches the keys for an unknown kid at most once per `interval` instead of every 5
+    /// minutes, see `refresh_for_unknown_kid`. The TTL and background refreshes are not limited.
+    pub fn with_unknown_kid_refresh_interval(mut self, interval: Duration) -> Self {
+        self.unknown_kid_refresh_interval = interval;
+        self
+    }
+
+    /// Records that `kid` just verified a token, for `with_max_keys`. Does nothing without a
+    /// key limit.
+    pub fn record_use(&self, kid: &str) {
+        if self.max_keys.is_none() {
+            return;
+        }
+        let mut last_used = self.last_used.lock().unwrap_or_else(|e| e.into_inner());
+        match last_used.get_mut(kid) {
+            Some(used_at) => *used_at = Instant::now(),
+            None => {
+                last_used.insert(kid.to_string(), Instant::now());
+            }
+        }
+    }
+
+    /// Saves every fetched key set to `path`, and loads the keys from it instead of fetching on
+    /// the first refresh after startup if they were saved less than `max_age` ago. Loaded keys
+    /// expire as if they had been fetched when they were saved.
+    ///
+    /// The file is only reused for the same URLs. A directory of PEM files cannot be saved, so a
+    /// cache reading one never writes the file.
+    pub fn with_disk_cache(mut self, path: impl Into<PathBuf>, max_age: Duration) -> Self {
+        self.disk_cache = Some((path.into(), max_age));
+        self
+    }
+
+    /// Limits concurrent outbound fetches with `limiter`.
+    ///
+    /// Share one semaphore between every cache (e.g. one per tenant or audience profile) to cap
+    /// the total number of fetches in flight against AAD.
+    pub fn with_fetch_limiter(mut self, limiter: Arc<Semaphore>) -> Self {
+        self.fetch_limiter = Some(limiter);
+        self
+    }
+
+    /// The URL the keys are fetched from.
+    pub fn jwks_url(&self) -> &str {
+        &self.jwks_url
+    }
+
+    /// The cache counters since startup.
+    pub fn metrics(&self) -> JwksMetrics {
+        let counters = &self.counters;
+        JwksMetrics {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            refreshes: counters.refreshes.load(Ordering::Relaxed),
+            refresh_failures: counters.refresh_failures.load(Ordering::Relaxed),
+            served_stale: counters.served_stale.load(Ordering::Relaxed),
+            evicted_keys: counters.evicted_keys.load(Ordering::Relaxed),
+            unknown_kid_refreshes: counters.unknown_kid_refreshes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reports the cache state without touching the network.
+    pub async fn status(&self) -> JwksStatus {
+        let snapshot = self.snapshot.read().await;
+        let Some(snapshot) = snapshot.as_ref() else {
+            return JwksStatus {
+                jwks_url: self.jwks_url.clone(),
+                key_count: 0,
+                last_refresh: None,
+                last_refresh_age_secs: None,
+                stale: true,
+                refresh_state: RefreshState::Closed,
+            };
+        };
+        let age = snapshot.fetched_at.elapsed();
+        let refresh_state = match snapshot.last_failure {
+            Some(failed_at) if failed_at.elapsed() < Self::REFRESH_RETRY_INTERVAL => {
+                RefreshState::Open
+            }
+            Some(_) => RefreshState::HalfOpen,
+            None => RefreshState::Closed,
+        };
+        JwksStatus {
+            jwks_url: self.jwks_url.clone(),
+            key_count: snapshot.keys.len(),
+            last_refresh: (SystemTime::now() - age)
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|t| t.as_secs()),
+            last_refresh_age_secs: Some(age.as_secs()),
+            stale: age >= self.ttl,
+            refresh_state,
+        }
+    }
+
+    /// Returns the cached keys, fetching them first if the cache is empty or expired.
+    pub async fn get_keys(&self) -> Result<Arc<HashMap<String, DecodingKey>>, ValidationError> {
+        let seen_generation = *self.generation.read().await;
+        if let Some(snapshot) = self.snapshot.read().await.as_ref() {
+            if snapshot.fetched_at.elapsed() < self.ttl {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(snapshot.keys.clone());
+            }
+            if let Some(failed_at) = snapshot.last_failure {
+                if failed_at.elapsed() < Self::REFRESH_RETRY_INTERVAL {
+                    self.counters.served_stale.fetch_add(1, Ordering::Relaxed);
+                    return Ok(snapshot.keys.clone());
+                }
+            }
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        self.refresh(seen_generation).await
+    }
+
+    /// Fetches the keys now, whether or not the cached ones have expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ValidationError::JwksUnavailable` if the fetch fails and no keys are cached; with
+    /// cached keys a failure is logged and they stay in use.
+    pub async fn refresh_now(&self) -> Result<Arc<HashMap<String, DecodingKey>>, ValidationError> {
+        let seen_generation = *self.generation.read().await;
+        self.refresh(seen_generation).await
+    }
+
+    /// The keys after fetching them again for `kid`, which the cached keys lack, e.g. because
+    /// the key was rotated in since the last fetch. `None` when the keys were fetched for an
+    /// unknown kid less than `unknown_kid_refresh_interval` ago, or cannot be fetched.
+    ///
+    /// Concurrent callers missing kids share one fetch: whoever queued behind it gets its keys
+    /// without fetching or waiting out the interval. The keys returned may still lack `kid`.
+    pub async fn refresh_for_unknown_kid(
+        &self,
+        kid: &str,
+    ) -> Option<Arc<HashMap<String, DecodingKey>>> {
+        let seen_generation = *self.generation.read().await;
+        if let Some(snapshot) = self.snapshot.read().await.as_ref() {
+            if snapshot.keys.contains_key(kid) {
+                return Some(snapshot.keys.clone());
+            }
+        }
+        let _guard = self.refresh_lock.lock().await;
+        if *self.generation.read().await != seen_generation {
+            if let Some(snapshot) = self.snapshot.read().await.as_ref() {
+                debug!("JWKS already refreshed by a concurrent request");
+                return Some(snapshot.keys.clone());
+            }
+        }
+        {
+            let mut last_refresh = self
+                .last_unknown_kid_refresh
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if last_refresh.is_some_and(|refreshed_at| {
+                refreshed_at.elapsed() < self.unknown_kid_refresh_interval
+            }) {
+                debug!(
+                    "Not refreshing {} for kid {}: refreshed for an unknown kid less than {:?} ago",
+                    self.jwks_url, kid, self.unknown_kid_refresh_interval
+                );
+                return None;
+            }
+            *last_refresh = Some(Instant::now());
+        }
+        info!("Refreshing {} for unknown kid {}", self.jwks_url, kid);
+        self.counters
+            .unknown_kid_refreshes
+            .fetch_add(1, Ordering::Relaxed);
+        self.refresh_locked(seen_generation).await.ok()
+    }
+
+    /// Refreshes the keys every `interval` in a background task, until the cache is dropped
+    /// everywhere else.
+    ///
+    /// With an `interval` below the TTL the cached keys never expire, so requests are always
+    /// answered from the cache and newly published keys are picked up within `interval`. AAD
+    /// publishes signing keys well ahead of their use, so any interval of a few hours or less
+    /// has the key cached before its first token.
+    pub fn spawn_background_refresh(self: &Arc<Self>, interval: Duration) {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(cache) = cache.upgrade() else {
+                    return;
+                };
+                debug!("Background JWKS refresh of {}", cache.jwks_url);
+                // Failures are already logged and counted by `refresh`
+                let _ = cache.refresh_now().await;
+            }
+        });
+    }
+
+    /// Like `get_keys`, but never waits for the very first fetch.
+    ///
+    /// While the cache is empty this starts a background fetch (at most one at a time) and
+    /// returns `ValidationError::JwksWarmingUp` immediately so the caller can answer with 503.
+    /// Once any keys are cached it behaves exactly like `get_keys`.
+    pub async fn get_keys_or_warm(
+        self: &Arc<Self>,
+    ) -> Result<Arc<HashMap<String, DecodingKey>>, ValidationError> {
+        if self.snapshot.read().await.is_some() {
+            return self.get_keys().await;
+        }
+        if !self.warming.swap(true, Ordering::AcqRel) {
+            let cache = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = cache.get_keys().await {
+                    warn!("Background JWKS warm-up failed: {}", e);
+                }
+                cache.warming.store(false, Ordering::Release);
+            });
+        }
+        Err(ValidationError::JwksWarmingUp)
+    }
+
+    /// Refreshes the keys unless another caller already did so after `seen_generation`.
+    async fn refresh(
+        &self,
+        seen_generation: u64,
+    ) -> Result<Arc<HashMap<String, DecodingKey>>, ValidationError> {
+        let _guard = self.refresh_lock.lock().await;
+        self.refresh_locked(seen_generation).await
+    }
+
+    /// `refresh`, for a caller holding `refresh_lock`.
+    async fn refresh_locked(
+        &self,
+        seen_generation: u64,
+    ) -> Result<Arc<HashMap<String, DecodingKey>>, ValidationError> {
+        if self.jwks_url == INLINE_JWKS_URL {
+            if let Some(snapshot) = self.snapshot.read().await.as_ref() {
+                return Ok(snapshot.keys.clone());
+            }
+        }
+
+        if *self.generation.read().await != seen_generation {
+            if let Some(snapshot) = self.snapshot.read().await.as_ref() {
+                debug!("JWKS already refreshed by a concurrent request");
+                return Ok(snapshot.keys.clone());
+            }
+        }
+
+        if self.snapshot.read().await.is_none() {
+            if let Some((keys, age)) = self.load_from_disk().await {
+                info!(
+                    "JWKS loaded from the disk cache: {} keys saved {:?} ago",
+                    keys.len(),
+                    age
+                );
+                let keys = Arc::new(keys);
+                *self.snapshot.write().await = Some(JwksSnapshot {
+                    keys: keys.clone(),
+                    fetched_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                    last_failure: None,
+                    retired: HashMap::new(),
+                });
+                *self.generation.write().await += 1;
+                return Ok(keys);
+            }
+        }
+
+        let permit = match &self.fetch_limiter {
+            Some(limiter) => Some(
+                limiter
+                    .acquire()
+                    .await
+                    .map_err(|_| ValidationError::JwksUnavailable)?,
+            ),
+            None => None,
+        };
+        let result = self.fetch_merged().await;
+        drop(permit);
+        let (result, documents) = match result {
+            Ok((keys, documents)) => (Ok(keys), documents),
+            Err(e) => (Err(e), None),
+        };
+        // The new map is complete at this point; the write lock is held only for the swap.
+        let mut snapshot = self.snapshot.write().await;
+        let keys = match (result, snapshot.as_mut()) {
+            (Ok(mut keys), previous) => {
+                let mut retired = match previous {
+                    Some(previous) => self.retained_keys(previous, &keys),
+                    None => HashMap::new(),
+                };
+                for (kid, (key, _)) in &retired {
+                    keys.insert(kid.clone(), key.clone());
+                }
+                self.evict_least_recently_used(&mut keys, &mut retired);
+                let refreshes = self.counters.refreshes.fetch_add(1, Ordering::Relaxed) + 1;
+                info!(
+                    "JWKS refreshed from {}: {} keys (refreshes: {}, failures: {})",
+                    self.jwks_url,
+                    keys.len(),
+                    refreshes,
+                    self.counters.refresh_failures.load(Ordering::Relaxed)
+                );
+                let keys = Arc::new(keys);
+                *snapshot = Some(JwksSnapshot {
+                    keys: keys.clone(),
+                    fetched_at: Instant::now(),
+                    last_failure: None,
+                    retired,
+                });
+                keys
+            }
+            (Err(e), Some(stale)) => {
+                self.counters
+                    .refresh_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                self.counters.served_stale.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "JWKS refresh failed, serving stale keys fetched {:?} ago: {}",
+                    stale.fetched_at.elapsed(),
+                    e
+                );
+                stale.last_failure = Some(Instant::now());
+                stale.keys.clone()
+            }
+            (Err(e), None) => {
+                self.counters
+                    .refresh_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                error!("JWKS fetch failed and no keys are cached: {}", e);
+                return Err(ValidationError::JwksUnavailable);
+            }
+        };
+        drop(snapshot);
+        *self.generation.write().await += 1;
+        if let Some(documents) = documents {
+            self.save_to_disk(documents).await;
+        }
+        Ok(keys)
+    }
+
+    /// The keys of `previous` missing from the `fresh` fetch that are still within the retired
+    /// key retention, with the instant each was first missing.
+    fn retained_keys(
+        &self,
+        previous: &JwksSnapshot,
+        fresh: &HashMap<String, DecodingKey>,
+    ) -> HashMap<String, (DecodingKey, Instant)> {
+        if self.retired_key_retention.is_zero() {
+            return HashMap::new();
+        }
+        let mut retained = HashMap::new();
+        for (kid, key) in previous.keys.iter() {
+            if fresh.contains_key(kid) {
+                continue;
+            }
+            match previous.retired.get(kid) {
+                Some((_, retired_at)) if retired_at.elapsed() < self.retired_key_retention => {
+                    retained.insert(kid.clone(), (key.clone(), *retired_at));
+                }
+                Some(_) => info!("Retired kid {} is no longer retained", kid),
+                None => {
+                    info!(
+                        "Kid {} left the JWKS at {}, retaining it for {:?}",
+                        kid, self.jwks_url, self.retired_key_retention
+                    );
+                    retained.insert(kid.clone(), (key.clone(), Instant::now()));
+                }
+            }
+        }
+        retained
+    }
+
+    /// Drops the least recently used keys of `keys` (and `retired`) beyond `max_keys`, and
+    /// forgets the use of kids no longer cached. Kids seen for the first time count as just
+    /// used.
+    fn evict_least_recently_used(
+        &self,
+        keys: &mut HashMap<String, DecodingKey>,
+        retired: &mut HashMap<String, (DecodingKey, Instant)>,
+    ) {
+        let Some(max_keys) = self.max_keys else {
+            return;
+        };
+        let mut last_used = self.last_used.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        for kid in keys.keys() {
+            last_used.entry(kid.clone()).or_insert(now);
+        }
+        if keys.len() > max_keys {
+            let mut candidates: Vec<(Instant, String)> = keys
+                .keys()
+                .filter(|kid| !self.pinned_keys.contains_key(*kid))
+                .map(|kid| (last_used[kid], kid.clone()))
+                .collect();
+            candidates.sort();
+            let excess = keys.len() - max_keys;
+            let evicted: Vec<String> = candidates
+                .into_iter()
+                .take(excess)
+                .map(|(_, kid)| kid)
+                .collect();
+            for kid in &evicted {
+                keys.remove(kid);
+                retired.remove(kid);
+            }
+            self.counters
+                .evicted_keys
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+            warn!(
+                "JWKS {} merged more than {} keys, dropped the least recently used: {:?}",
+                self.jwks_url, max_keys, evicted
+            );
+        }
+        last_used.retain(|kid, _| keys.contains_key(kid));
+    }
+
+    /// Fetches `jwks_url` and every additional URL, merging their keys by kid.
+    ///
+    /// Also returns the fetched documents in URL order, for the disk cache; `None` when a URL
+    /// is a directory of PEM files rather than a JWKS document.
+    async fn fetch_merged(&self) -> Result<MergedKeys, Box<dyn std::error::Error + Send + Sync>> {
+        let (first, document) = fetch_source(
+            &self.client,
+            &self.jwks_url,
+            &self.retry_policy,
+            self.max_document_bytes,
+        )
+        .await?;
+        let mut documents = document.map(|document| vec![document]);
+        let mut sets = vec![(self.jwks_url.as_str(), first)];
+        for url in &self.additional_urls {
+            let (more, document) = fetch_source(
+                &self.client,
+                url,
+                &self.retry_policy,
+                self.max_document_bytes,
+            )
+            .await
+            .map_err(|e| format!("{}: {}", url, e))?;
+            documents = documents.zip(document).map(|(mut documents, document)| {
+                documents.push(document);
+                documents
+            });
+            sets.push((url.as_str(), more));
+        }
+        Ok((self.merge(sets), documents))
+    }
+
+    /// Merges the key `sets` fetched from each URL, the first set serving a kid winning, then
+    /// the pinned keys over all of them.
+    fn merge(
+        &self,
+        sets: Vec<(&str, HashMap<String, DecodingKey>)>,
+    ) -> HashMap<String, DecodingKey> {
+        let mut keys = HashMap::new();
+        for (url, set) in sets {
+            for (kid, key) in set {
+                if keys.contains_key(&kid) {
+                    warn!(
+                        "JWKS {} also serves kid {}, keeping the key fetched first",
+                        url, kid
+                    );
+                    continue;
+                }
+                keys.insert(kid, key);
+            }
+        }
+        for (kid, key) in &self.pinned_keys {
+            if keys.insert(kid.clone(), key.clone()).is_some() {
+                debug!("Pinned key {} replaces the key served by the JWKS", kid);
+            }
+        }
+        keys
+    }
+
+    /// Loads the keys saved by `save_to_disk` if they were fetched from the same URLs less than
+    /// the disk cache's maximum age ago, with how long ago that was.
+    async fn load_from_disk(&self) -> Option<(HashMap<String, DecodingKey>, Duration)> {
+        let (path, max_age) = self.disk_cache.as_ref()?;
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                debug!("No JWKS disk cache at {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let saved: DiskCache = match serde_json::from_slice(&bytes) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Ignoring corrupt JWKS disk cache {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let urls: Vec<&String> = std::iter::once(&self.jwks_url)
+            .chain(&self.additional_urls)
+            .collect();
+        if saved.jwks_urls.iter().collect::<Vec<_>>() != urls || saved.documents.len() != urls.len()
+        {
+            info!(
+                "Ignoring JWKS disk cache {}: saved for other URLs",
+                path.display()
+            );
+            return None;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        let age = Duration::from_secs(now.saturating_sub(saved.fetched_at));
+        if age >= *max_age {
+            info!(
+                "Ignoring JWKS disk cache {}: saved {:?} ago",
+                path.display(),
+                age
+            );
+            return None;
+        }
+        let mut sets = Vec::new();
+        for (url, document) in urls.iter().zip(&saved.documents) {
+            match decode_keys(document, url) {
+                Ok(keys) => sets.push((url.as_str(), keys)),
+                Err(e) => {
+                    warn!("Ignoring JWKS disk cache {}: {}", path.display(), e);
+                    return None;
+                }
+            }
+        }
+        Some((self.merge(sets), age))
+    }
+
+    /// Saves the fetched `documents` to the disk cache, replacing the file whole so a crash
+    /// never leaves a partial one. Failures are only logged.
+    async fn save_to_disk(&self, documents: Vec<serde_json::Value>) {
+        let Some((path, _)) = &self.disk_cache else {
+            return;
+        };
+        let saved = DiskCache {
+            jwks_urls: std::iter::once(&self.jwks_url)
+                .chain(&self.additional_urls)
+                .cloned()
+                .collect(),
+            fetched_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or_default(),
+            documents,
+        };
+        let result = async {
+            let bytes = serde_json::to_vec(&saved)?;
+            let temporary = path.with_extension("tmp");
+            tokio::fs::write(&temporary, bytes).await?;
+            tokio::fs::rename(&temporary, path).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        }
+        .await;
+        match result {
+            Ok(()) => debug!("Saved the JWKS to {}", path.display()),
+            Err(e) => warn!("Cannot save the JWKS to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// The merged keys of every URL of a `JwksCache`, with the documents they came from.
+type MergedKeys = (HashMap<String, DecodingKey>, Option<Vec<serde_json::Value>>);
+
+/// The file written by `JwksCache::with_disk_cache`.
+///
+/// # Fields
+///
+/// * `jwks_urls` - The URLs the documents were fetched from, `jwks_url` first.
+/// * `fetched_at` - When they were fetched, in seconds since the epoch.
+/// * `documents` - The JWKS document of each URL.
+#[derive(Serialize, Deserialize)]
+struct DiskCache {
+    jwks_urls: Vec<String>,
+    fetched_at: u64,
+    documents: Vec<serde_json::Value>,
+}
+
+/// The counters of a `JwksCache`, as reported by `JwksCache::metrics`.
+///
+/// # Fields
+///
+/// * `hits` - Lookups answered from fresh cached keys.
+/// * `misses` - Lookups that found the keys missing or expired and went to refresh them.
+/// * `refreshes` - Successful fetches.
+/// * `refresh_failures` - Failed fetches, whether or not stale keys could be served.
+/// * `served_stale` - Lookups answered with expired keys because refreshing failed.
+/// * `evicted_keys` - Keys dropped to stay within `JwksCache::with_max_keys`.
+/// * `unknown_kid_refreshes` - Refreshes for a token signed with a kid the keys lacked.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct JwksMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub refreshes: u64,
+    pub refresh_failures: u64,
+    pub served_stale: u64,
+    pub evicted_keys: u64,
+    pub unknown_kid_refreshes: u64,
+}
+
+/// A point-in-time view of a `JwksCache`, as reported by `JwksCache::status`.
+///
+/// # Fields
+///
+/// * `jwks_url` - The URL the keys are fetched from.
+/// * `key_count` - The number of cached keys.
+/// * `last_refresh` - When the keys were last fetched successfully, in seconds since the epoch.
+/// * `last_refresh_age_secs` - How long ago that was.
+/// * `stale` - Whether the keys are past their TTL (or were never loaded).
+/// * `refresh_state` - Whether refreshes are currently suppressed after a failure.
+#[derive(Debug, Clone, Serialize)]
+pub struct JwksStatus {
+    pub jwks_url: String,
+    pub key_count: usize,
+    pub last_refresh: Option<u64>,
+    pub last_refresh_age_secs: Option<u64>,
+    pub stale: bool,
+    pub refresh_state: RefreshState,
+}
+
+/// The breaker guarding JWKS refreshes.
+///
+/// * `Closed` - The last refresh succeeded; refreshes happen when the TTL expires.
+/// * `Open` - A refresh failed recently; stale keys are served without retrying.
+/// * `HalfOpen` - The retry interval has passed; the next request retries the fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Retry policy for transient JWKS fetch failures.
+///
+/// Attempt `n` (starting at 1) waits `base_delay * 2^(n-1)` before the next one. Only network
+/// errors, 5xx and 429 responses are retried; other 4xx responses fail immediately.
+///
+/// # Fields
+///
+/// * `max_attempts` - Total number of attempts, including the first one.
+/// * `base_delay` - Delay before the first retry.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay to wait after the given failed attempt (1-based).
+    fn delay_after(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// Returns `true` for failures worth retrying: network errors, 5xx and 429.
+fn is_retryable(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => err.is_connect() || err.is_timeout() || err.is_request(),
+    }
+}
+
+/// Fetches the JWKS document, retrying transient failures according to `retry_policy`.
+async fn fetch_document(
+    client: &Client,
+    jwks_url: &str,
+    retry_policy: &RetryPolicy,
+) -> Result<Response, reqwest::Error> {
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .get(jwks_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < retry_policy.max_attempts && is_retryable(&e) => {
+                let delay = retry_policy.delay_after(attempt);
+                warn!(
+                    "JWKS fetch attempt {}/{} failed, retrying in {:?}: {}",
+                    attempt, retry_policy.max_attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The `jwks_url` of a cache built by `JwksCache::from_keys`.
+pub const INLINE_JWKS_URL: &str = "inline";
+
+/// The default limit on the size of a JWKS document; real key sets are a few kilobytes.
+pub const DEFAULT_MAX_JWKS_BYTES: usize = 1 << 20;
+
+/// Why a JWKS could not be fetched or decoded.
+///
+/// # Variants
+///
+/// * `Request` - The HTTP request failed or the endpoint answered with an error status.
+/// * `Read` - A local file, key directory or `data:` URL could not be read.
+/// * `TooLarge` - The document exceeds the size limit; carries the URL and the limit.
+/// * `Json` - The document is not JSON.
+/// * `InvalidDocument` - The document is JSON (or XML) but not a usable key set, e.g. it has no
+///   `keys` array or none of its signing keys can be decoded.
+/// * `InvalidKey` - A key lacks a field or holds an invalid one; carries its kid if it has one.
+///   Fetching skips such keys with a warning, so this only ends a fetch for pinned key files.
+#[derive(Debug)]
+pub enum JwksError {
+    Request(reqwest::Error),
+    Read(String),
+    TooLarge { url: String, max_bytes: usize },
+    Json(serde_json::Error),
+    InvalidDocument(String),
+    InvalidKey { kid: Option<String>, reason: String },
+}
+
+impl std::fmt::Display for JwksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwksError::Request(e) => write!(f, "JWKS request failed: {}", e),
+            JwksError::Read(message) | JwksError::InvalidDocument(message) => f.write_str(message),
+            JwksError::TooLarge { url, max_bytes } => {
+                write!(f, "JWKS document {} exceeds {} bytes", url, max_bytes)
+            }
+            JwksError::Json(e) => write!(f, "JWKS document is not JSON: {}", e),
+            JwksError::InvalidKey { kid, reason } => {
+                write!(
+                    f,
+                    "invalid key {}: {}",
+                    kid.as_deref().unwrap_or("without kid"),
+                    reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for JwksError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JwksError::Request(e) => Some(e),
+            JwksError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for JwksError {
+    fn from(e: reqwest::Error) -> Self {
+        JwksError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for JwksError {
+    fn from(e: serde_json::Error) -> Self {
+        JwksError::Json(e)
+    }
+}
+
+/// Reads the JWKS document at `jwks_url`, from the network or locally depending on the scheme.
+async fn load_document(
+    client: &Client,
+    jwks_url: &str,
+    retry_policy: &RetryPolicy,
+    max_bytes: usize,
+) -> Result<serde_json::Value, JwksError> {
+    let bytes = load_bytes(client, jwks_url, retry_policy, max_bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Reads the document at `jwks_url`, from the network or locally depending on the scheme.
+///
+/// Documents over `max_bytes` are rejected: a declared `Content-Length` is checked before the
+/// body is read, and the body is read in chunks that stop at the limit.
+async fn load_bytes(
+    client: &Client,
+    jwks_url: &str,
+    retry_policy: &RetryPolicy,
+    max_bytes: usize,
+) -> Result<Vec<u8>, JwksError> {
+    let too_large = || JwksError::TooLarge {
+        url: jwks_url.to_string(),
+        max_bytes,
+    };
+    if let Some(path) = jwks_url.strip_prefix("file://") {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| JwksError::Read(format!("cannot read JWKS file {}: {}", path, e)))?;
+        let mut bytes = Vec::new();
+        // Reading one byte past the limit tells an oversized file from one exactly at it
+        file.take(max_bytes as u64 + 1)
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|e| JwksError::Read(format!("cannot read JWKS file {}: {}", path, e)))?;
+        if bytes.len() > max_bytes {
+            return Err(too_large());
+        }
+        return Ok(bytes);
+    }
+    if let Some(data_url) = jwks_url.strip_prefix("data:") {
+        let bytes = decode_data_url(data_url)?;
+        if bytes.len() > max_bytes {
+            return Err(too_large());
+        }
+        return Ok(bytes);
+    }
+    let mut response = fetch_document(client, jwks_url, retry_policy).await?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes as u64)
+    {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Decodes the part of a `data:` URL after the scheme: `[<media type>][;base64],<data>`, where
+/// non-base64 data may be percent-encoded.
+fn decode_data_url(data_url: &str) -> Result<Vec<u8>, JwksError> {
+    let invalid = |e: &dyn std::fmt::Display| JwksError::Read(format!("invalid data: URL: {}", e));
+    let (meta, data) = data_url
+        .split_once(',')
+        .ok_or_else(|| invalid(&"no ',' before its data"))?;
+    if meta.ends_with(";base64") {
+        return STANDARD.decode(data).map_err(|e| invalid(&e));
+    }
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut rest = data.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail) {
+            (b'%', [hi, lo, tail @ ..]) => {
+                let pair = [*hi, *lo];
+                let hex = std::str::from_utf8(&pair).map_err(|e| invalid(&e))?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|e| invalid(&e))?);
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Reads pinned keys from a directory of `<kid>.pem` files, as written by the `jwks-pem` tool.
+/// Files with another extension are ignored.
+async fn load_pem_dir(dir: &str) -> Result<HashMap<String, DecodingKey>, JwksError> {
+    let unreadable =
+        |e: std::io::Error| JwksError::Read(format!("cannot read key directory {}: {}", dir, e));
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(unreadable)?;
+    let mut keys = HashMap::new();
+    while let Some(entry) = entries.next_entry().await.map_err(unreadable)? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+            continue;
+        }
+        let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let pem = tokio::fs::read(&path).await.map_err(|e| {
+            JwksError::Read(format!("cannot read key file {}: {}", path.display(), e))
+        })?;
+        let key = DecodingKey::from_rsa_pem(&pem).map_err(|e| JwksError::InvalidKey {
+            kid: Some(kid.to_string()),
+            reason: format!("{} is not an RSA public key in PEM: {}", path.display(), e),
+        })?;
+        keys.insert(kid.to_string(), key);
+    }
+    if keys.is_empty() {
+        return Err(JwksError::InvalidDocument(format!(
+            "key directory {} has no .pem files",
+            dir
+        )));
+    }
+    Ok(keys)
+}
+
+/// Reads pinned keys given as a comma-separated list of `<kid>=<path to PEM>` entries, for
+/// `JwksCache::with_pinned_keys`. Empty entries are ignored.
+///
+/// # Errors
+///
+/// Returns a message naming the first entry without a kid, or whose file cannot be read or is
+/// not an RSA public key in PEM.
+pub fn read_pinned_keys(value: &str) -> Result<HashMap<String, DecodingKey>, String> {
+    let mut keys = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (kid, path) = entry
+            .split_once('=')
+            .map(|(kid, path)| (kid.trim(), path.trim()))
+            .filter(|(kid, _)| !kid.is_empty())
+            .ok_or_else(|| format!("{:?} is not <kid>=<path>", entry))?;
+        let pem =
+            std::fs::read(path).map_err(|e| format!("cannot read key file {}: {}", path, e))?;
+        let key = DecodingKey::from_rsa_pem(&pem)
+            .map_err(|e| format!("invalid key file {}: {}", path, e))?;
+        keys.insert(kid.to_string(), key);
+    }
+    Ok(keys)
+}
+
+/// Parses the signing keys of a JWKS document given as JSON text, e.g. pasted into
+/// configuration, for `JwksCache::from_keys`. Keys are decoded as if fetched.
+///
+/// # Errors
+///
+/// Returns `JwksError::Json` if `document` is not JSON and `JwksError::InvalidDocument` if it has
+/// no `keys` array or none of its signing keys can be decoded.
+pub fn parse_jwks(document: &str) -> Result<HashMap<String, DecodingKey>, JwksError> {
+    let json: serde_json::Value = serde_json::from_str(document)?;
+    decode_keys(&json, INLINE_JWKS_URL)
+}
+
+/// Fetches the raw JWKS document at `jwks_url` (any scheme `fetch_jwks` accepts), without
+/// decoding its keys.
+///
+/// # Errors
+///
+/// Returns an error if the document cannot be read, exceeds `DEFAULT_MAX_JWKS_BYTES` or is not
+/// JSON.
+pub async fn fetch_jwks_document(jwks_url: &str) -> Result<serde_json::Value, JwksError> {
+    load_document(
+        &Client::new(),
+        jwks_url,
+        &RetryPolicy::default(),
+        DEFAULT_MAX_JWKS_BYTES,
+    )
+    .await
+}
+
+/// Encodes an RSA public key given as the base64url `n` and `e` of a JWK as a PEM
+/// `PUBLIC KEY` (X.509 SubjectPublicKeyInfo) block.
+///
+/// # Errors
+///
+/// Returns a message if `n` or `e` is not valid base64url.
+pub fn rsa_public_key_pem(n: &str, e: &str) -> Result<String, String> {
+    let n = URL_SAFE_NO_PAD
+        .decode(n.trim_end_matches('='))
+        .map_err(|err| format!("invalid modulus: {}", err))?;
+    let e = URL_SAFE_NO_PAD
+        .decode(e.trim_end_matches('='))
+        .map_err(|err| format!("invalid exponent: {}", err))?;
+    // RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+    let rsa_public_key = der(0x30, &[der_integer(&n), der_integer(&e)].concat());
+    // AlgorithmIdentifier { rsaEncryption (1.2.840.113549.1.1.1), NULL }
+    let algorithm = der(
+        0x30,
+        &[
+            0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+        ],
+    );
+    let bit_string = der(0x03, &[&[0x00], rsa_public_key.as_slice()].concat());
+    let spki = der(0x30, &[algorithm, bit_string].concat());
+
+    let body = STANDARD.encode(spki);
+    let mut pem = String::from("-----BEGIN PUBLIC KEY-----\n");
+    for line in body.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str("-----END PUBLIC KEY-----\n");
+    Ok(pem)
+}
+
+/// A DER element with the given `tag` and `content`.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// A DER INTEGER holding the unsigned big-endian `value`.
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let value = match value.iter().position(|byte| *byte != 0) {
+        Some(start) => &value[start..],
+        None => &[0],
+    };
+    // A set high bit would make the integer negative, so it gets a leading zero byte
+    if value[0] & 0x80 != 0 {
+        der(0x02, &[&[0x00], value].concat())
+    } else {
+        der(0x02, value)
+    }
+}
+
+/// Fetches JSON Web Key Sets (JWKS) from the specified URL and returns a HashMap of decoding keys.
+///
+/// # Arguments
+///
+/// * `jwks_url` - A string slice that holds the URL to fetch the JWKS from. Besides `https://`
+///   (and `http://`) URLs, `file://` paths and `data:` URLs are read locally, which is handy for
+///   tests and offline use. A `file://` directory is read as pinned `<kid>.pem` files. With the
+///   `federation-metadata` feature, a URL ending in `.xml` is read as WS-Federation metadata.
+///
+/// # Returns
+///
+/// A `HashMap` where the keys are the Key IDs (KID) and the values are the corresponding `DecodingKey` objects.
+///
+/// # Errors
+///
+/// This function will return a `JwksError` if the HTTP request fails, the endpoint answers with
+/// an error status, or the response cannot be parsed as a JWKS document. Transient failures are
+/// retried with the default `RetryPolicy` first. Keys that cannot be decoded are skipped with a
+/// warning rather than failing the fetch.
+///
+/// # Example
+///
+/// ```ignore
+/// let jwks_url = "https://example.com/jwks";
+/// let keys = fetch_jwks(jwks_url).await;
+/// ```
+///
+/// # Remarks
+///
+/// This function uses the `reqwest` crate to perform the HTTP request and the `serde_json` crate to parse the JSON response.
+pub async fn fetch_jwks(jwks_url: &str) -> Result<HashMap<String, DecodingKey>, JwksError> {
+    fetch_jwks_with_retry(jwks_url, &RetryPolicy::default()).await
+}
+
+/// Like `fetch_jwks`, with an explicit retry policy for transient failures.
+pub async fn fetch_jwks_with_retry(
+    jwks_url: &str,
+    retry_policy: &RetryPolicy,
+) -> Result<HashMap<String, DecodingKey>, JwksError> {
+    fetch_keys(
+        &Client::new(),
+        jwks_url,
+        retry_policy,
+        DEFAULT_MAX_JWKS_BYTES,
+    )
+    .await
+}
+
+/// Parses a TLS version as written in configuration: `1.2` or `1.3`.
+///
+/// # Errors
+///
+/// Returns a message for any other value. TLS 1.0 and 1.1 are rejected because the rustls
+/// backend never negotiates them.
+pub fn parse_tls_version(value: &str) -> Result<tls::Version, String> {
+    match value.trim() {
+        "1.2" => Ok(tls::Version::TLS_1_2),
+        "1.3" => Ok(tls::Version::TLS_1_3),
+        other => Err(format!(
+            "unsupported TLS version {:?}, expected 1.2 or 1.3",
+            other
+        )),
+    }
+}
+
+/// Builds an HTTP client for JWKS fetches that refuses to negotiate a TLS version below
+/// `min_tls_version`: a handshake with a server offering only older versions fails the fetch.
+///
+/// # Errors
+///
+/// Returns an error if the TLS backend cannot be initialized.
+pub fn jwks_client(min_tls_version: tls::Version) -> Result<Client, reqwest::Error> {
+    Client::builder().min_tls_version(min_tls_version).build()
+}
+
+/// Whether a JWK may verify signatures: its `use` is `sig`, or absent as RFC 7517 allows for keys
+/// meant for any purpose. Encryption (`enc`) keys are left out.
+pub fn is_signing_key(key: &serde_json::Value) -> bool {
+    match key.get("use") {
+        None | Some(serde_json::Value::Null) => true,
+        Some(key_use) => key_use.as_str() == Some("sig"),
+    }
+}
+
+/// Fetches the JWKS at `jwks_url` with `client` and decodes its signing keys, refusing documents
+/// over `max_bytes`.
+async fn fetch_keys(
+    client: &Client,
+    jwks_url: &str,
+    retry_policy: &RetryPolicy,
+    max_bytes: usize,
+) -> Result<HashMap<String, DecodingKey>, JwksError> {
+    fetch_source(client, jwks_url, retry_policy, max_bytes)
+        .await
+        .map(|(keys, _)| keys)
+}
+
+/// Like `fetch_keys`, also returning the JWKS document, or `None` when `jwks_url` is a
+/// directory of PEM files or (with the `federation-metadata` feature) federation metadata.
+async fn fetch_source(
+    client: &Client,
+    jwks_url: &str,
+    retry_policy: &RetryPolicy,
+    max_bytes: usize,
+) -> Result<(HashMap<String, DecodingKey>, Option<serde_json::Value>), JwksError> {
+    if let Some(dir) = jwks_url
+        .strip_prefix("file://")
+        .filter(|path| std::path::Path::new(path).is_dir())
+    {
+        return Ok((load_pem_dir(dir).await?, None));
+    }
+    #[cfg(feature = "federation-metadata")]
+    if crate::federation::is_federation_metadata_url(jwks_url) {
+        let bytes = load_bytes(client, jwks_url, retry_policy, max_bytes).await?;
+        let xml = String::from_utf8(bytes).map_err(|e| {
+            JwksError::InvalidDocument(format!("federation metadata is not UTF-8: {}", e))
+        })?;
+        let keys = crate::federation::parse_federation_metadata(&xml)
+            .map_err(JwksError::InvalidDocument)?;
+        return Ok((keys, None));
+    }
+    let json = load_document(client, jwks_url, retry_policy, max_bytes).await?;
+
+    debug!("JWKS: {:#?}", json);
+
+    let keys = decode_keys(&json, jwks_url)?;
+    Ok((keys, Some(json)))
+}
+
+/// Decodes the signing keys of the JWKS document `json` served at `jwks_url`.
+///
+/// A key that cannot be decoded is skipped with a warning, so one malformed or non-RSA entry
+/// does not cost the others. A document with signing keys none of which decode is an error.
+fn decode_keys(
+    json: &serde_json::Value,
+    jwks_url: &str,
+) -> Result<HashMap<String, DecodingKey>, JwksError> {
+    let mut keys = HashMap::new();
+    let entries = json["keys"].as_array().ok_or_else(|| {
+        JwksError::InvalidDocument(format!("JWKS document {} has no keys array", jwks_url))
+    })?;
+    let mut skipped = 0;
+    for key in entries {
+        if !is_signing_key(key) {
+            debug!(
+                "Skipping key {:?} of {}: use is {:?}, not sig",
+                key["kid"].as_str().unwrap_or_default(),
+                jwks_url,
+                key["use"]
+            );
+            continue;
+        }
+        match decode_key(key) {
+            Ok((kid, decoding_key)) => {
+                keys.insert(kid, decoding_key);
+            }
+            Err(e) => {
+                warn!("Skipping a key of {}: {}", jwks_url, e);
+                skipped += 1;
+            }
+        }
+    }
+    if keys.is_empty() && skipped > 0 {
+        return Err(JwksError::InvalidDocument(format!(
+            "none of the {} signing keys of {} can be decoded",
+            skipped, jwks_url
+        )));
+    }
+    Ok(keys)
+}
+
+/// Decodes one RSA signing key of a JWKS into its kid and key.
+fn decode_key(key: &serde_json::Value) -> Result<(String, DecodingKey), JwksError> {
+    let kid = key["kid"].as_str().map(str::to_string);
+    let invalid = |reason: String| JwksError::InvalidKey {
+        kid: kid.clone(),
+        reason,
+    };
+    let field = |name: &str| {
+        key[name]
+            .as_str()
+            .ok_or_else(|| invalid(format!("no {} string", name)))
+    };
+    match key["kty"].as_str() {
+        Some("RSA") | None => {}
+        Some(kty) => return Err(invalid(format!("kty is {}, not RSA", kty))),
+    }
+    let n = field("n")?;
+    let e = field("e")?;
+    let kid = kid.clone().ok_or_else(|| invalid("no kid".to_string()))?;
+    let decoding_key = DecodingKey::from_rsa_components(n, e)
+        .map_err(|e| invalid(format!("invalid n or e: {}", e)))?;
+    Ok((kid, decoding_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(kids: &[&str]) -> HashMap<String, DecodingKey> {
+        kids.iter()
+            .map(|kid| (kid.to_string(), DecodingKey::from_secret(kid.as_bytes())))
+            .collect()
+    }
+
+    fn sorted_kids(keys: &HashMap<String, DecodingKey>) -> Vec<String> {
+        let mut kids: Vec<String> = keys.keys().cloned().collect();
+        kids.sort();
+        kids
+    }
+
+    fn pause() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    #[test]
+    fn eviction_drops_the_least_recently_used_kids() {
+        let cache =
+            JwksCache::new("https://keys.test/", Duration::from_secs(60)).with_max_keys(Some(3));
+        let mut merged = keys(&["a", "b", "c"]);
+        cache.evict_least_recently_used(&mut merged, &mut HashMap::new());
+        pause();
+        cache.record_use("a");
+        pause();
+        cache.record_use("c");
+        pause();
+
+        // d and e rotated in: b, then a, are the least recently used
+        let mut merged = keys(&["a", "b", "c", "d", "e"]);
+        let mut retired = HashMap::new();
+        retired.insert(
+            "b".to_string(),
+            (DecodingKey::from_secret(b"b"), Instant::now()),
+        );
+        cache.evict_least_recently_used(&mut merged, &mut retired);
+
+        assert_eq!(sorted_kids(&merged), ["c", "d", "e"]);
+        assert!(retired.is_empty());
+        assert_eq!(cache.metrics().evicted_keys, 2);
+    }
+
+    #[test]
+    fn new_kids_are_kept_over_unused_old_ones() {
+        let cache =
+            JwksCache::new("https://keys.test/", Duration::from_secs(60)).with_max_keys(Some(2));
+        let mut merged = keys(&["old-1", "old-2"]);
+        cache.evict_least_recently_used(&mut merged, &mut HashMap::new());
+        pause();
+
+        let mut merged = keys(&["old-1", "old-2", "new"]);
+        cache.evict_least_recently_used(&mut merged, &mut HashMap::new());
+
+        assert!(merged.contains_key("new"));
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn pinned_keys_are_never_evicted() {
+        let cache = JwksCache::new("https://keys.test/", Duration::from_secs(60))
+            .with_pinned_keys(keys(&["pinned"]))
+            .with_max_keys(Some(1));
+        let mut merged = keys(&["pinned", "fetched"]);
+        cache.evict_least_recently_used(&mut merged, &mut HashMap::new());
+
+        assert_eq!(sorted_kids(&merged), ["pinned"]);
+    }
+}
@@ -0,0 +1,64 @@
+//! Building blocks for protecting actix-web APIs with Azure AD (managed identity) access tokens.
+//!
+//! The `server` binary is a thin wrapper around this library: it reads its configuration from
+//! the environment, builds a `JwtValidator` and wraps its routes with `BearerAuth`. Other actix
+//! apps can do the same; see `examples/protected_app.rs`.
+
+pub mod access_rule;
+pub mod authority;
+#[cfg(feature = "azure-app-config")]
+pub mod azure_config;
+pub mod challenge;
+pub mod claims;
+pub mod clock;
+pub mod concurrency;
+pub mod config;
+pub mod content_type;
+pub mod correlation;
+pub mod cors;
+pub mod credentials;
+pub mod deadline;
+pub mod discovery;
+#[cfg(feature = "dpop")]
+pub mod dpop;
+pub mod error;
+#[cfg(feature = "auth-events")]
+pub mod events;
+#[cfg(feature = "federation-metadata")]
+pub mod federation;
+#[cfg(feature = "graph-groups")]
+pub mod graph;
+pub mod inflight;
+pub mod ipfilter;
+#[cfg(feature = "jwe")]
+pub mod jwe;
+pub mod jwks;
+pub mod middleware;
+pub mod mtls;
+pub mod outbound;
+#[cfg(feature = "policy-engine")]
+pub mod policy;
+pub mod principal;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod reload;
+pub mod sampling;
+#[cfg(feature = "signed-requests")]
+pub mod signed_request;
+pub mod subscription;
+#[cfg(feature = "test-utils")]
+pub mod testing;
+pub mod timing;
+pub mod usage;
+pub mod validator;
+
+pub use authority::Authority;
+pub use claims::Claims;
+pub use error::ApiError;
+pub use jwks::JwksCache;
+pub use middleware::{
+    AudienceDeprecation, AudienceProfile, AuthDecision, AuthorizationRules, BearerAuth,
+    BearerAuthConfig, ClaimsTransform, Enforcement, TokenConflictPolicy,
+};
+pub use principal::Principal;
+pub use validator::{JwtValidator, TokenValidator, ValidatedToken, ValidationError};
@@ -0,0 +1,1591 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue, HttpDate};
+use actix_web::http::StatusCode;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::{debug, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::access_rule::AccessRule;
+use crate::challenge::BearerChallenge;
+use crate::claims::Claims;
+#[cfg(feature = "auth-events")]
+use crate::correlation::CorrelationId;
+use crate::credentials::bearer_token;
+#[cfg(feature = "dpop")]
+use crate::credentials::dpop_token;
+#[cfg(feature = "dpop")]
+use crate::dpop::DpopVerifier;
+#[cfg(feature = "auth-events")]
+use crate::events::{AuthEvent, EventSink};
+#[cfg(feature = "graph-groups")]
+use crate::graph::GroupResolver;
+use crate::ipfilter::client_origin;
+use crate::mtls::ClientCertBinding;
+#[cfg(feature = "policy-engine")]
+use crate::policy::{PolicyDecision, PolicyEngine};
+use crate::reload::Reloadable;
+#[cfg(feature = "signed-requests")]
+use crate::signed_request::SignedRequestUnwrapper;
+use crate::timing::ServerTiming;
+use crate::validator::{
+    audience_aliases, peek_audiences, JwtValidator, TokenValidator, ValidationError,
+};
+
+/// Configuration for the `BearerAuth` middleware.
+///
+/// # Fields
+///
+/// * `validator` - The validator used to verify the bearer token.
+/// * `token_validator` - Verifies tokens matching no profile in place of `validator`, see
+///   `with_token_validator`.
+/// * `required_roles` - The caller must hold at least one of these roles. When empty, any
+///   authenticated caller is allowed.
+/// * `client_cert` - When set, a client certificate is required and optionally bound to the token.
+/// * `role_case_insensitive` - Compare roles ignoring ASCII case. App role values are
+///   case-sensitive in AAD, so a case-only match is logged as a configuration warning.
+/// * `realm` - The realm reported in `WWW-Authenticate` challenges.
+/// * `app_only` - Only accept app-only tokens, rejecting tokens issued on behalf of a user.
+/// * `require_managed_identity` - Only accept tokens issued to a managed identity, see
+///   `Claims::is_managed_identity`.
+/// * `require_https` - Refuse tokens sent over plain HTTP, see `with_https_required`.
+/// * `cert_client_auth` - Only accept tokens the client got by authenticating with a
+///   certificate (`azpacr`/`appidacr` of `2`), not a shared secret.
+/// * `required_scopes` - Delegated (user) tokens must carry at least one of these scopes in
+///   `scp`. App-only tokens have no scopes and are not checked.
+/// * `token_type_authorization` - Authorize app-only tokens by `required_roles` and delegated
+///   tokens by `required_scopes` alone, see `with_token_type_authorization`.
+/// * `required_wids` - The caller must hold at least one of these directory roles (template IDs
+///   in `wids`). When empty, directory roles are not checked.
+/// * `required_claim_values` - Extra claims the token must carry with the given value, see
+///   `Claims::has_claim_value`.
+/// * `require_mfa` - The caller must have completed multi-factor authentication (`mfa` in `amr`).
+/// * `allowed_tenant_apps` - The `tid` and client ID (`azp` or `appid`) of the token must be one
+///   of these pairs, see `with_allowed_tenant_apps`. When empty, any pairing is accepted.
+/// * `access_rule` - A boolean rule over roles, scopes and groups checked instead of
+///   `required_roles`, see `with_access_rule`.
+/// * `deprecated_audiences` - Audiences still accepted but being retired, see
+///   `with_deprecated_audience`.
+/// * `enforcement` - Whether failed checks reject the request, see `Enforcement`.
+/// * `defer_authorization` - Let denied callers through to the handler with an `AuthDecision`.
+/// * `diagnostics` - Report the required and present roles in 403 responses, and the time spent
+///   in each phase in a `Server-Timing` header.
+/// * `claims_transform` - Rewrites the verified claims before authorization.
+/// * `event_sink` - Receives an `AuthEvent` for every request. Only exists with the
+///   `auth-events` feature.
+/// * `group_resolver` - Resolves `groups` from Microsoft Graph for tokens with a group overage.
+///   Only exists with the `graph-groups` feature.
+/// * `dpop` - Accepts DPoP-bound tokens with their proofs. Only exists with the `dpop` feature.
+/// * `policy_engine` - Asks an external policy engine about callers that passed the other
+///   checks. Only exists with the `policy-engine` feature.
+/// * `signed_requests` - Takes the access token from a partner's signed request when one is
+///   sent. Only exists with the `signed-requests` feature.
+/// * `token_conflict` - What to do with requests whose signed request and `Authorization` header
+///   carry different tokens, see `TokenConflictPolicy`. Only exists with the `signed-requests`
+///   feature.
+/// * `profiles` - Per-audience validation profiles, selected by the token's `aud` before
+///   validation. Tokens matching no profile use `validator` and `required_roles`.
+#[derive(Clone)]
+pub struct BearerAuthConfig {
+    validator: JwtValidator,
+    token_validator: Option<Arc<dyn TokenValidator>>,
+    required_roles: Vec<String>,
+    profiles: Vec<AudienceProfile>,
+    client_cert: Option<ClientCertBinding>,
+    role_case_insensitive: bool,
+    realm: String,
+    app_only: bool,
+    require_managed_identity: bool,
+    require_https: bool,
+    cert_client_auth: bool,
+    required_scopes: Vec<String>,
+    token_type_authorization: bool,
+    required_wids: Vec<String>,
+    required_claim_values: Vec<(String, String)>,
+    require_mfa: bool,
+    allowed_tenant_apps: Vec<(String, String)>,
+    access_rule: Option<AccessRule>,
+    deprecated_audiences: Vec<AudienceDeprecation>,
+    enforcement: Enforcement,
+    defer_authorization: bool,
+    diagnostics: bool,
+    claims_transform: Option<ClaimsTransform>,
+    #[cfg(feature = "auth-events")]
+    event_sink: Option<EventSink>,
+    #[cfg(feature = "graph-groups")]
+    group_resolver: Option<Arc<GroupResolver>>,
+    #[cfg(feature = "dpop")]
+    dpop: Option<DpopVerifier>,
+    #[cfg(feature = "policy-engine")]
+    policy_engine: Option<PolicyEngine>,
+    #[cfg(feature = "signed-requests")]
+    signed_requests: Option<SignedRequestUnwrapper>,
+    #[cfg(feature = "signed-requests")]
+    token_conflict: TokenConflictPolicy,
+}
+
+/// A normalization applied to verified claims, see `BearerAuthConfig::with_claims_transform`.
+pub type ClaimsTransform = Arc<dyn Fn(Claims) -> Claims + Send + Sync>;
+
+impl BearerAuthConfig {
+    /// Creates a configuration that authenticates with `validator` and requires no role.
+    pub fn new(validator: JwtValidator) -> Self {
+        Self {
+            validator,
+            token_validator: None,
+            required_roles: Vec::new(),
+            profiles: Vec::new(),
+            client_cert: None,
+            role_case_insensitive: false,
+            realm: "api".to_string(),
+            app_only: false,
+            require_managed_identity: false,
+            require_https: false,
+            cert_client_auth: false,
+            required_scopes: Vec::new(),
+            token_type_authorization: false,
+            required_wids: Vec::new(),
+            required_claim_values: Vec::new(),
+            require_mfa: false,
+            allowed_tenant_apps: Vec::new(),
+            access_rule: None,
+            deprecated_audiences: Vec::new(),
+            enforcement: Enforcement::Enforce,
+            defer_authorization: false,
+            diagnostics: false,
+            claims_transform: None,
+            #[cfg(feature = "auth-events")]
+            event_sink: None,
+            #[cfg(feature = "graph-groups")]
+            group_resolver: None,
+            #[cfg(feature = "dpop")]
+            dpop: None,
+            #[cfg(feature = "policy-engine")]
+            policy_engine: None,
+            #[cfg(feature = "signed-requests")]
+            signed_requests: None,
+            #[cfg(feature = "signed-requests")]
+            token_conflict: TokenConflictPolicy::FirstWins,
+        }
+    }
+
+    /// Replaces the audiences accepted by the validator.
+    pub fn with_audiences(mut self, audiences: Vec<String>) -> Self {
+        self.validator = self.validator.with_audiences(audiences);
+        self
+    }
+
+    /// Replaces the issuers accepted by the validator.
+    pub fn with_issuers(mut self, issuers: Vec<String>) -> Self {
+        self.validator = self.validator.with_issuers(issuers);
+        self
+    }
+
+    /// Verifies tokens matching no audience profile with `validator` instead of the
+    /// `JwtValidator` given to `new`, e.g. a test double. The `JwtValidator` still provides the
+    /// audiences and issuers reported by `rules` and the clock DPoP proofs are checked against.
+    pub fn with_token_validator(mut self, validator: Arc<dyn TokenValidator>) -> Self {
+        self.token_validator = Some(validator);
+        self
+    }
+
+    /// Requires the caller to hold at least one of `roles`.
+    pub fn with_required_roles(mut self, roles: Vec<String>) -> Self {
+        self.required_roles = roles;
+        self
+    }
+
+    /// Adds a validation profile for tokens carrying `profile.audience`.
+    pub fn with_audience_profile(mut self, profile: AudienceProfile) -> Self {
+        self.profiles.push(profile);
+        self
+    }
+
+    /// Enables or disables case-insensitive role comparison.
+    pub fn with_role_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.role_case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Sets the realm reported in `WWW-Authenticate` challenges (default `api`).
+    pub fn with_realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Requires a client certificate, optionally bound to the token via `cnf.x5t#S256`.
+    pub fn with_client_cert(mut self, binding: ClientCertBinding) -> Self {
+        self.client_cert = Some(binding);
+        self
+    }
+
+    /// Only accepts app-only tokens (see `Claims::is_app_only`) when `app_only` is set. Delegated
+    /// tokens get a 403 with the `app_only_required` error.
+    pub fn with_app_only(mut self, app_only: bool) -> Self {
+        self.app_only = app_only;
+        self
+    }
+
+    /// Only accepts tokens issued to a managed identity (see `Claims::is_managed_identity`) when
+    /// `required` is set. User and other delegated tokens get a 403 with the
+    /// `managed_identity_required` error.
+    pub fn with_managed_identity_required(mut self, required: bool) -> Self {
+        self.require_managed_identity = required;
+        self
+    }
+
+    /// Refuses requests that reached the client side of the server over plain HTTP when
+    /// `required` is set, answering `400` with the `insecure_transport` error before the token
+    /// is looked at. Behind a TLS-terminating proxy the scheme comes from `X-Forwarded-Proto`,
+    /// which is only believed from `TrustedProxies` (see `ipfilter::client_origin`).
+    pub fn with_https_required(mut self, required: bool) -> Self {
+        self.require_https = required;
+        self
+    }
+
+    /// Only accepts tokens issued to clients that authenticated with a certificate when
+    /// `required` is set. Tokens without `azpacr`/`appidacr` are rejected too. Refused callers get
+    /// a 403 with the `cert_client_auth_required` error.
+    pub fn with_cert_client_auth(mut self, required: bool) -> Self {
+        self.cert_client_auth = required;
+        self
+    }
+
+    /// Requires delegated tokens to carry at least one of `scopes`. A token without any is
+    /// answered `403` with an `insufficient_scope` challenge listing `scopes`, so the client can
+    /// request them and retry (RFC 6750, section 3.1).
+    pub fn with_required_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.required_scopes = scopes;
+        self
+    }
+
+    /// Authorizes by token type when `by_token_type` is set, for APIs called both by daemons and
+    /// on behalf of users (`access_as_user`): app-only tokens need one of the required roles and
+    /// delegated tokens one of the required scopes, whatever roles they carry.
+    ///
+    /// Delegated tokens are then refused when no scopes are required, rather than let through
+    /// without any check. Which kind a token is comes from `Claims::is_app_only`.
+    pub fn with_token_type_authorization(mut self, by_token_type: bool) -> Self {
+        self.token_type_authorization = by_token_type;
+        self
+    }
+
+    /// Requires the caller to hold at least one of the directory roles `wids`, given as role
+    /// template IDs (e.g. `62e90394-69f5-4237-9190-012177145e10` for Global Administrator).
+    ///
+    /// The `wids` claim is only issued when the app registration requests it, and only for the
+    /// built-in roles assigned tenant-wide. Callers without one get a 403 with the
+    /// `directory_role_missing` error.
+    pub fn with_required_wids(mut self, wids: Vec<String>) -> Self {
+        self.required_wids = wids;
+        self
+    }
+
+    /// Requires the extra claim `name` to have `value`, e.g. `env` = `prod`. Every required
+    /// claim must match; callers failing one get a 403 with the `custom_claim_mismatch` error.
+    pub fn with_required_claim_value(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.required_claim_values.push((name.into(), value.into()));
+        self
+    }
+
+    /// Requires the caller to have completed multi-factor authentication when `required` is set.
+    /// Tokens without `mfa` in `amr` get a 403 with the `mfa_required` error; app-only tokens
+    /// never carry `amr`, so they are refused too.
+    pub fn with_mfa_required(mut self, required: bool) -> Self {
+        self.require_mfa = required;
+        self
+    }
+
+    /// Only accepts tokens whose tenant (`tid`) and client ID (`azp` or `appid`) are one of the
+    /// `(tenant, app)` pairs, compared ignoring case. Other callers get a 403 with the
+    /// `tenant_app_not_allowed` error, even when their signature, audience and roles are fine.
+    pub fn with_allowed_tenant_apps(mut self, pairs: Vec<(String, String)>) -> Self {
+        self.allowed_tenant_apps = pairs;
+        self
+    }
+
+    /// Authorizes callers by `rule` instead of the required roles (including those of audience
+    /// profiles), e.g. `role:Admin OR (scope:Data.Read AND group:Analysts)` to let admin apps
+    /// and analysts reading data in alike. Callers not satisfying it get a 403 with the
+    /// `access_rule_denied` error. The other checks still apply. `None` (the default) checks the
+    /// required roles.
+    pub fn with_access_rule(mut self, rule: Option<AccessRule>) -> Self {
+        self.access_rule = rule;
+        self
+    }
+
+    /// Marks `audience` as deprecated: responses to tokens for it carry `Deprecation: true` and,
+    /// with a `sunset` date, a `Sunset` header (RFC 8594), nudging clients to move to the new
+    /// audience before the old one is removed.
+    ///
+    /// This does not make `audience` accepted; it must be one of the validator's audiences.
+    pub fn with_deprecated_audience(
+        mut self,
+        audience: impl Into<String>,
+        sunset: Option<HttpDate>,
+    ) -> Self {
+        self.deprecated_audiences.push(AudienceDeprecation {
+            audience: audience.into(),
+            sunset: sunset.map(|date| date.to_string()),
+        });
+        self
+    }
+
+    /// Sets what happens to requests that fail authentication or authorization, e.g.
+    /// `Enforcement::Log` while introducing authentication to an API that used to be open.
+    pub fn with_enforcement(mut self, enforcement: Enforcement) -> Self {
+        self.enforcement = enforcement;
+        self
+    }
+
+    /// Leaves the authorization decision to the handler when `deferred` is set, e.g. to audit
+    /// denied attempts before answering them.
+    ///
+    /// Authenticated callers are let through whether or not they are authorized, with an
+    /// `AuthDecision` in the request extensions. `Claims` (and `Principal`) are only stored for
+    /// allowed callers, so handlers that take them keep rejecting everyone else. Requests that
+    /// fail authentication are still answered by the middleware.
+    ///
+    /// ```ignore
+    /// async fn audited(decision: AuthDecision) -> HttpResponse {
+    ///     if !decision.allowed {
+    ///         warn!("Denied {}: {:?}", decision.claims.sub, decision.reason);
+    ///         return HttpResponse::Forbidden().json("Access denied and recorded");
+    ///     }
+    ///     HttpResponse::Ok().finish()
+    /// }
+    /// ```
+    pub fn with_deferred_authorization(mut self, deferred: bool) -> Self {
+        self.defer_authorization = deferred;
+        self
+    }
+
+    /// Includes the `required` and `present` roles in the JSON body of a 403 when `diagnostics`
+    /// is set, to help fix app role assignments, along with `checks`: the outcome of every
+    /// authorization check (see `CheckOutcome`), not only the first that failed, so a caller
+    /// missing several grants can be fixed in one pass. Leave it off in production: it tells
+    /// callers which roles would let them in.
+    ///
+    /// Responses then also carry a `Server-Timing` header with the time spent in the
+    /// `jwks-lookup`, `decode` and `authorize` phases, which browser developer tools display.
+    pub fn with_diagnostics(mut self, diagnostics: bool) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// Runs `transform` over the claims after the token is verified and before roles are checked,
+    /// e.g. to lowercase roles or map group GUIDs to friendly names. Handlers see the result.
+    ///
+    /// The transform runs on every request, so it must be pure and fast: no I/O, no locks.
+    ///
+    /// ```ignore
+    /// let config = BearerAuthConfig::new(validator).with_claims_transform(|mut claims| {
+    ///     claims.roles = claims
+    ///         .roles
+    ///         .map(|roles| roles.iter().map(|role| role.to_lowercase()).collect());
+    ///     claims
+    /// });
+    /// ```
+    pub fn with_claims_transform(
+        mut self,
+        transform: impl Fn(Claims) -> Claims + Send + Sync + 'static,
+    ) -> Self {
+        self.claims_transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Reports every authentication outcome to `sink`.
+    #[cfg(feature = "auth-events")]
+    pub fn with_event_sink(mut self, sink: EventSink) -> Self {
+        self.event_sink = Some(sink);
+        self
+    }
+
+    /// Resolves group memberships with `resolver` when a token has a group overage, before the
+    /// claims transform runs.
+    #[cfg(feature = "graph-groups")]
+    pub fn with_group_resolver(mut self, resolver: GroupResolver) -> Self {
+        self.group_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Accepts the `DPoP` authorization scheme and checks the proof of every DPoP-bound token
+    /// (one with `cnf.jkt`) with `verifier`. Bound tokens sent as `Bearer` tokens are refused.
+    ///
+    /// Tokens taken from a signed request (see `with_signed_requests`) have no scheme and count
+    /// as `Bearer` tokens, so DPoP-bound ones are refused there too.
+    #[cfg(feature = "dpop")]
+    pub fn with_dpop(mut self, verifier: DpopVerifier) -> Self {
+        self.dpop = Some(verifier);
+        self
+    }
+
+    /// Asks `engine` about every caller that passed the other checks, honoring its decision.
+    ///
+    /// Denied callers get a 403 with the `policy_denied` error, and a `503` is sent when the
+    /// engine cannot be asked. To leave authorization to the engine entirely, require no roles
+    /// and turn off the validator's audience check, so only signature, issuer and expiry are
+    /// verified locally.
+    #[cfg(feature = "policy-engine")]
+    pub fn with_policy_engine(mut self, engine: PolicyEngine) -> Self {
+        self.policy_engine = Some(engine);
+        self
+    }
+
+    /// Accepts requests carrying their access token inside a signed request, unwrapped and
+    /// verified with `unwrapper`. The inner token is then validated as usual; requests without a
+    /// signed request still use `Authorization`.
+    ///
+    /// A signed request carries no authorization scheme, so DPoP-bound tokens cannot be sent in
+    /// one, see `with_dpop`.
+    #[cfg(feature = "signed-requests")]
+    pub fn with_signed_requests(mut self, unwrapper: SignedRequestUnwrapper) -> Self {
+        self.signed_requests = Some(unwrapper);
+        self
+    }
+
+    /// Sets what happens to requests whose signed request and `Authorization` header carry
+    /// different tokens. By default the signed request wins and the header is ignored.
+    #[cfg(feature = "signed-requests")]
+    pub fn with_token_conflict_policy(mut self, policy: TokenConflictPolicy) -> Self {
+        self.token_conflict = policy;
+        self
+    }
+
+    /// This configuration accepting `audience` instead of the configured audiences.
+    ///
+    /// Audience profiles for other audiences are dropped, as they would otherwise still accept
+    /// their tokens.
+    pub fn for_audience(mut self, audience: impl Into<String>) -> Self {
+        let audience = audience.into();
+        self.profiles.retain(|profile| profile.audience == audience);
+        self.with_audiences(vec![audience])
+    }
+
+    /// The validator used by this configuration.
+    pub fn validator(&self) -> &JwtValidator {
+        &self.validator
+    }
+
+    /// The authorization rules this configuration enforces, for introspection.
+    pub fn rules(&self) -> AuthorizationRules {
+        AuthorizationRules {
+            audiences: self.validator.audiences().to_vec(),
+            issuers: self.validator.issuers().to_vec(),
+            required_roles: self.required_roles.clone(),
+            role_match: if self.role_case_insensitive {
+                "case_insensitive"
+            } else {
+                "exact"
+            },
+            app_only: self.app_only,
+            require_managed_identity: self.require_managed_identity,
+            require_https: self.require_https,
+            cert_client_auth: self.cert_client_auth,
+            required_scopes: self.required_scopes.clone(),
+            token_type_authorization: self.token_type_authorization,
+            required_wids: self.required_wids.clone(),
+            required_claim_values: self.required_claim_values.clone(),
+            require_mfa: self.require_mfa,
+            allowed_tenant_apps: self.allowed_tenant_apps.clone(),
+            access_rule: self.access_rule.clone(),
+            deprecated_audiences: self.deprecated_audiences.clone(),
+            client_cert: self.client_cert.clone(),
+            enforcement: self.enforcement,
+            defer_authorization: self.defer_authorization,
+            #[cfg(feature = "dpop")]
+            dpop: self.dpop.clone(),
+            #[cfg(feature = "signed-requests")]
+            signed_request_header: self
+                .signed_requests
+                .as_ref()
+                .map(|unwrapper| unwrapper.header_name().to_string()),
+            #[cfg(feature = "signed-requests")]
+            token_conflict: self.token_conflict,
+            #[cfg(feature = "policy-engine")]
+            policy_url: self
+                .policy_engine
+                .as_ref()
+                .map(|engine| engine.url().to_string()),
+            profiles: self
+                .profiles
+                .iter()
+                .map(|profile| ProfileRules {
+                    audience: profile.audience.clone(),
+                    issuers: profile.validator.issuers().to_vec(),
+                    required_roles: profile.required_roles.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Authenticates and authorizes a request, returning the token claims on success.
+    ///
+    /// # Errors
+    ///
+    /// Returns the response to send back when the `Authorization` header is missing, the token
+    /// fails validation, a required client certificate is missing or not bound to the token, or
+    /// the caller lacks the required roles.
+    pub async fn authenticate(&self, req: &HttpRequest) -> Result<Claims, HttpResponse> {
+        let (claims, authorization) = self.verify(req, &mut ServerTiming::default()).await?;
+        authorization.map_err(|denied| denied.response)?;
+        Ok(claims)
+    }
+
+    /// Authenticates a request and reports the authorization decision instead of enforcing it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the response to send back when the request cannot be authenticated: the
+    /// `Authorization` header is missing, the token fails validation, or a required client
+    /// certificate is missing or not bound to the token.
+    pub async fn decide(&self, req: &HttpRequest) -> Result<AuthDecision, HttpResponse> {
+        let (claims, authorization) = self.verify(req, &mut ServerTiming::default()).await?;
+        Ok(AuthDecision {
+            claims,
+            allowed: authorization.is_ok(),
+            reason: authorization.err().map(|denied| denied.reason),
+        })
+    }
+
+    /// Authenticates a request, returning the claims with the outcome of `authorize`. The time
+    /// spent in each phase is recorded in `timing`.
+    async fn verify(
+        &self,
+        req: &HttpRequest,
+        timing: &mut ServerTiming,
+    ) -> Result<(Claims, Result<(), Denied>), HttpResponse> {
+        if self.require_https {
+            let scheme = client_origin(req).scheme;
+            if scheme != "https" {
+                debug!("Refusing a token sent over {}", scheme);
+                return Err(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "insecure_transport",
+                    "error_description": "Tokens must be sent over HTTPS",
+                })));
+            }
+        }
+        let certificate = match &self.client_cert {
+            Some(binding) => Some(
+                binding
+                    .presented_certificate(req)
+                    .map_err(|message| self.unauthorized(Some("invalid_request"), message))?,
+            ),
+            None => None,
+        };
+
+        #[cfg(feature = "signed-requests")]
+        let unwrapped = self.unwrap_signed_request(req).await?;
+        #[cfg(feature = "signed-requests")]
+        if let Some(token) = &unwrapped {
+            self.resolve_token_conflict(req, token)?;
+        }
+        #[cfg(not(feature = "signed-requests"))]
+        let unwrapped: Option<String> = None;
+        // Signed requests have no scheme, so their tokens are checked as Bearer tokens
+        #[cfg_attr(not(feature = "dpop"), allow(unused_variables))]
+        let (token, dpop_scheme) = match &unwrapped {
+            Some(token) => (token.as_str(), false),
+            None => {
+                let auth_header = req
+                    .headers()
+                    .get("Authorization")
+                    .ok_or_else(|| self.unauthorized(None, "Missing Authorization header"))?;
+                let auth_header = auth_header.to_str().map_err(|_| {
+                    self.unauthorized(Some("invalid_request"), "Invalid Authorization header")
+                })?;
+                self.access_token(auth_header)
+                    .map_err(|message| self.unauthorized(Some("invalid_request"), message))?
+            }
+        };
+
+        debug!("Token fingerprint: {}", token_fingerprint(token));
+
+        let (validator, required_roles) = self.select_profile(token);
+        let claims = validator
+            .validate_timed(token, timing)
+            .await
+            .map_err(|err| err.to_response(&self.realm))?;
+        if let (Some(binding), Some(certificate)) = (&self.client_cert, &certificate) {
+            binding
+                .check_binding(certificate, &claims)
+                .map_err(|message| self.unauthorized(Some("invalid_token"), message))?;
+        }
+        #[cfg(feature = "dpop")]
+        if let Some(dpop) = &self.dpop {
+            dpop.check_binding(req, token, dpop_scheme, &claims, self.validator.unix_now())
+                .map_err(|message| self.invalid_dpop_proof(message))?;
+        }
+        #[cfg(feature = "graph-groups")]
+        let claims = match &self.group_resolver {
+            Some(resolver) => resolver.resolve(token, claims).await,
+            None => claims,
+        };
+        let claims = match &self.claims_transform {
+            Some(transform) => transform(claims),
+            None => claims,
+        };
+        let started = Instant::now();
+        let mut authorization = self.authorize(&claims, required_roles);
+        if self.diagnostics {
+            authorization =
+                authorization.map_err(|denied| self.diagnose(denied, &claims, required_roles));
+        }
+        timing.record("authorize", started.elapsed());
+        #[cfg(feature = "policy-engine")]
+        let authorization = match (&self.policy_engine, authorization) {
+            (Some(engine), Ok(())) => {
+                let started = Instant::now();
+                let decision = engine.decide(req, &claims).await;
+                timing.record("policy", started.elapsed());
+                self.policy_outcome(decision)
+            }
+            (_, authorization) => authorization,
+        };
+        Ok((claims, authorization))
+    }
+
+    /// Validates a bare token with the validator of its audience profile and applies the claims
+    /// transform, without any request-level checks (client certificate, roles).
+    ///
+    /// # Errors
+    ///
+    /// Returns the `ValidationError` of the selected validator.
+    pub async fn validate_token(&self, token: &str) -> Result<Claims, ValidationError> {
+        let (validator, _) = self.select_profile(token);
+        let claims = validator.validate(token).await?;
+        Ok(match &self.claims_transform {
+            Some(transform) => transform(claims),
+            None => claims,
+        })
+    }
+
+    /// The deprecation of the first deprecated audience `claims` were issued for, if any.
+    fn deprecation(&self, claims: &Claims) -> Option<&AudienceDeprecation> {
+        self.deprecated_audiences.iter().find(|deprecation| {
+            audience_aliases(&deprecation.audience)
+                .iter()
+                .any(|alias| claims.aud.contains(alias))
+        })
+    }
+
+    /// Picks the validator and required roles for a token based on its (unverified) audience.
+    fn select_profile(&self, token: &str) -> (&dyn TokenValidator, &[String]) {
+        let audiences = peek_audiences(token);
+        self.profiles
+            .iter()
+            .find(|profile| {
+                audience_aliases(&profile.audience)
+                    .iter()
+                    .any(|alias| audiences.contains(alias))
+            })
+            .map(|profile| {
+                debug!("Using validation profile for audience {}", profile.audience);
+                (
+                    &profile.validator as &dyn TokenValidator,
+                    profile.required_roles.as_slice(),
+                )
+            })
+            .unwrap_or_else(|| {
+                let validator: &dyn TokenValidator = match &self.token_validator {
+                    Some(validator) => validator.as_ref(),
+                    None => &self.validator,
+                };
+                (validator, self.required_roles.as_slice())
+            })
+    }
+
+    /// A 401 response with a `WWW-Authenticate` challenge.
+    ///
+    /// Pass `error: None` when the request carried no credentials, so no error code is reported.
+    fn unauthorized(&self, error: Option<&str>, description: &str) -> HttpResponse {
+        BearerChallenge {
+            error,
+            description: error.map(|_| description),
+            scope: None,
+        }
+        .response(
+            StatusCode::UNAUTHORIZED,
+            &self.realm,
+            description.to_string(),
+        )
+    }
+
+    /// The access token of `auth_header` and whether it was sent with the `DPoP` scheme, which is
+    /// only accepted with DPoP support.
+    fn access_token<'a>(&self, auth_header: &'a str) -> Result<(&'a str, bool), &'static str> {
+        #[cfg(feature = "dpop")]
+        if self.dpop.is_some() {
+            if let Some(token) = dpop_token(auth_header)? {
+                return Ok((token, true));
+            }
+        }
+        bearer_token(auth_header).map(|token| (token, false))
+    }
+
+    /// The access token inside the signed request of `req`, or `None` when it carries none and
+    /// the token is read from `Authorization`.
+    #[cfg(feature = "signed-requests")]
+    async fn unwrap_signed_request(
+        &self,
+        req: &HttpRequest,
+    ) -> Result<Option<String>, HttpResponse> {
+        let Some(unwrapper) = &self.signed_requests else {
+            return Ok(None);
+        };
+        let Some(wrapper) = unwrapper
+            .wrapper(req)
+            .map_err(|message| self.unauthorized(Some("invalid_request"), message))?
+        else {
+            return Ok(None);
+        };
+        unwrapper
+            .unwrap(wrapper)
+            .await
+            .map(Some)
+            .map_err(|message| self.unauthorized(Some("invalid_token"), message))
+    }
+
+    /// Checks the `Authorization` header of a request whose signed request carries `token`.
+    /// A header with the same token, or none, is fine; a different one is ignored or refused
+    /// with a 400 `ambiguous_token`, as `token_conflict` says.
+    #[cfg(feature = "signed-requests")]
+    fn resolve_token_conflict(&self, req: &HttpRequest, token: &str) -> Result<(), HttpResponse> {
+        let Some(auth_header) = req.headers().get("Authorization") else {
+            return Ok(());
+        };
+        let other = auth_header
+            .to_str()
+            .ok()
+            .and_then(|header| self.access_token(header).ok())
+            .map(|(other, _)| other);
+        if other == Some(token) {
+            return Ok(());
+        }
+        match self.token_conflict {
+            TokenConflictPolicy::FirstWins => {
+                debug!("Ignoring the Authorization header, the signed request takes precedence");
+                Ok(())
+            }
+            TokenConflictPolicy::RejectOnConflict => {
+                debug!("Refusing a request whose signed request and Authorization header disagree");
+                Err(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "ambiguous_token",
+                    "error_description":
+                        "The signed request and the Authorization header carry different tokens",
+                })))
+            }
+        }
+    }
+
+    /// A 401 response with a `DPoP` challenge for a failed DPoP check (RFC 9449, section 7.1).
+    #[cfg(feature = "dpop")]
+    fn invalid_dpop_proof(&self, description: &str) -> HttpResponse {
+        HttpResponse::Unauthorized()
+            .insert_header((
+                "WWW-Authenticate",
+                BearerChallenge::new("invalid_dpop_proof", description)
+                    .header_value_for("DPoP", &self.realm),
+            ))
+            .body(description.to_string())
+    }
+
+    /// Checks the claims against the token type, the required scopes, directory roles,
+    /// authentication methods, claim values, tenant and app pairs, and the access rule or roles.
+    /// The caller is refused with the first check of `evaluate_all` they fail.
+    fn authorize(&self, claims: &Claims, required_roles: &[String]) -> Result<(), Denied> {
+        match self
+            .evaluate_all(claims, required_roles)
+            .into_iter()
+            .find(|outcome| !outcome.passed)
+        {
+            Some(failed) => Err(self.denial(&failed, claims, required_roles)),
+            None => Ok(()),
+        }
+    }
+
+    /// The 403 for the `failed` check, carrying its error code.
+    fn denial(&self, failed: &CheckOutcome, claims: &Claims, required_roles: &[String]) -> Denied {
+        match failed.error.unwrap_or_default() {
+            "app_only_required" => self.json_denied(
+                "App-only token required",
+                "app_only_required",
+                "Only app-only tokens may call this API".to_string(),
+            ),
+            "managed_identity_required" => {
+                debug!(
+                    "Token of {:?} (idtyp {:?}, client authentication {:?}) is not a managed identity",
+                    claims.sub,
+                    claims.idtyp,
+                    claims.client_auth_method()
+                );
+                self.json_denied(
+                    "Managed identity required",
+                    "managed_identity_required",
+                    "Only managed identities may call this API".to_string(),
+                )
+            }
+            "cert_client_auth_required" => {
+                debug!(
+                    "Client authentication method {:?} is not a certificate",
+                    claims.client_auth_method()
+                );
+                self.json_denied(
+                    "Certificate client authentication required",
+                    "cert_client_auth_required",
+                    "The client must authenticate with a certificate".to_string(),
+                )
+            }
+            "insufficient_scope" => {
+                debug!(
+                    "Scopes {:?}, one of {:?} required",
+                    claims.scp, self.required_scopes
+                );
+                self.missing_scopes()
+            }
+            "directory_role_missing" => {
+                debug!(
+                    "Directory roles {:?}, one of {:?} required",
+                    claims.wids, self.required_wids
+                );
+                self.json_denied(
+                    "Required directory role missing",
+                    "directory_role_missing",
+                    "The caller holds none of the required directory roles".to_string(),
+                )
+            }
+            "mfa_required" => {
+                debug!("Authentication methods {:?}, mfa required", claims.amr);
+                self.json_denied(
+                    "MFA required",
+                    "mfa_required",
+                    "Multi-factor authentication required".to_string(),
+                )
+            }
+            "custom_claim_mismatch" => {
+                let name = failed.check.strip_prefix("claim:").unwrap_or_default();
+                let value = self
+                    .required_claim_values
+                    .iter()
+                    .find(|(required, value)| {
+                        required == name && !claims.has_claim_value(required, value)
+                    })
+                    .map(|(_, value)| value);
+                debug!(
+                    "Claim {} is {:?}, {:?} required",
+                    name,
+                    claims.extra.get(name),
+                    value
+                );
+                self.custom_claim_mismatch(name)
+            }
+            "tenant_app_not_allowed" => {
+                debug!(
+                    "Tenant {:?} and app {:?} are not an allowed pair",
+                    claims.tid,
+                    claims.client_id()
+                );
+                self.json_denied(
+                    "Tenant and app not allowed",
+                    "tenant_app_not_allowed",
+                    "The calling app is not allowed from this tenant".to_string(),
+                )
+            }
+            "access_rule_denied" => {
+                if let Some(rule) = &self.access_rule {
+                    debug!(
+                        "Roles {:?}, scopes {:?} and groups {:?} do not satisfy {}",
+                        claims.roles, claims.scp, claims.groups, rule
+                    );
+                }
+                self.json_denied(
+                    "Access rule not satisfied",
+                    "access_rule_denied",
+                    "The caller does not satisfy the access rule of this route".to_string(),
+                )
+            }
+            _ => match &claims.roles {
+                Some(roles) => {
+                    debug!("Roles {:?}, one of {:?} required", roles, required_roles);
+                    self.missing_roles("Token holds none of the required roles")
+                }
+                None => self.missing_roles("Token has no roles"),
+            },
+        }
+    }
+
+    /// Whether the `scp` of `claims` holds one of the required scopes.
+    fn has_required_scope(&self, claims: &Claims) -> bool {
+        let scopes = claims.scp.as_deref().unwrap_or_default();
+        scopes.split(' ').any(|scope| {
+            self.required_scopes
+                .iter()
+                .any(|required| required == scope)
+        })
+    }
+
+    /// Whether the `wids` of `claims` hold one of the required directory roles.
+    fn has_required_wid(&self, claims: &Claims) -> bool {
+        let wids = claims.wids.as_deref().unwrap_or_default();
+        wids.iter().any(|wid| {
+            self.required_wids
+                .iter()
+                .any(|required| required.eq_ignore_ascii_case(wid))
+        })
+    }
+
+    /// Whether the tenant and client ID of `claims` are one of the allowed pairs.
+    fn is_allowed_tenant_app(&self, claims: &Claims) -> bool {
+        let tenant = claims.tid.as_deref().unwrap_or_default();
+        let client_id = claims.client_id().unwrap_or_default();
+        self.allowed_tenant_apps.iter().any(|(tid, app)| {
+            tid.eq_ignore_ascii_case(tenant) && app.eq_ignore_ascii_case(client_id)
+        })
+    }
+
+    /// Evaluates every check the configuration asks for, in order, each with the error code it
+    /// fails with. `authorize` refuses callers with the first failure and `diagnose` lists them
+    /// all, so a check has the same code in both. Checks the configuration does not ask for are
+    /// left out.
+    fn evaluate_all(&self, claims: &Claims, required_roles: &[String]) -> Vec<CheckOutcome> {
+        let mut outcomes = Vec::new();
+        let mut check = |check: String, passed: bool, error: &'static str| {
+            outcomes.push(CheckOutcome {
+                check,
+                passed,
+                error: (!passed).then_some(error),
+            });
+        };
+        if self.app_only {
+            check("app_only".into(), claims.is_app_only(), "app_only_required");
+        }
+        if self.require_managed_identity {
+            check(
+                "managed_identity".into(),
+                claims.is_managed_identity(),
+                "managed_identity_required",
+            );
+        }
+        if self.cert_client_auth {
+            check(
+                "cert_client_auth".into(),
+                claims.client_auth_method() == Some("2"),
+                "cert_client_auth_required",
+            );
+        }
+        let delegated = !claims.is_app_only();
+        if (!self.required_scopes.is_empty() || self.token_type_authorization) && delegated {
+            check(
+                "scopes".into(),
+                self.has_required_scope(claims),
+                "insufficient_scope",
+            );
+        }
+        if !self.required_wids.is_empty() {
+            check(
+                "directory_roles".into(),
+                self.has_required_wid(claims),
+                "directory_role_missing",
+            );
+        }
+        if self.require_mfa {
+            check("mfa".into(), claims.used_mfa(), "mfa_required");
+        }
+        for (name, value) in &self.required_claim_values {
+            check(
+                format!("claim:{}", name),
+                claims.has_claim_value(name, value),
+                "custom_claim_mismatch",
+            );
+        }
+        if !self.allowed_tenant_apps.is_empty() {
+            check(
+                "tenant_app".into(),
+                self.is_allowed_tenant_app(claims),
+                "tenant_app_not_allowed",
+            );
+        }
+        if let Some(rule) = &self.access_rule {
+            check(
+                "access_rule".into(),
+                rule.evaluate(claims),
+                "access_rule_denied",
+            );
+        } else if !(required_roles.is_empty() || self.token_type_authorization && delegated) {
+            check(
+                "roles".into(),
+                self.holds_required_role(claims, required_roles),
+                "role_missing",
+            );
+        }
+        outcomes
+    }
+
+    /// Whether `claims` hold one of `required_roles`, ignoring case if `role_case_insensitive`
+    /// is set. Matches that only hold ignoring case are logged, so the app role can be fixed.
+    fn holds_required_role(&self, claims: &Claims, required_roles: &[String]) -> bool {
+        let roles = claims.roles.as_deref().unwrap_or_default();
+        if roles.iter().any(|role| required_roles.contains(role)) {
+            return true;
+        }
+        if !self.role_case_insensitive {
+            return false;
+        }
+        let case_only_match = roles.iter().find_map(|role| {
+            required_roles
+                .iter()
+                .find(|required| required.eq_ignore_ascii_case(role))
+                .map(|required| (role, required))
+        });
+        match case_only_match {
+            Some((role, required)) => {
+                warn!(
+                    "Role {:?} only matches required role {:?} ignoring case; fix the app role value",
+                    role, required
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The 403 of diagnostics mode for `denied`: the JSON body lists the outcome of every check,
+    /// so all of a caller's missing grants can be fixed at once. The response keeps the challenge
+    /// of `denied`.
+    fn diagnose(&self, denied: Denied, claims: &Claims, required_roles: &[String]) -> Denied {
+        let outcomes = self.evaluate_all(claims, required_roles);
+        let error = outcomes
+            .iter()
+            .find_map(|outcome| outcome.error)
+            .unwrap_or("insufficient_scope");
+        let mut response = HttpResponse::Forbidden();
+        if let Some(challenge) = denied.response.headers().get(header::WWW_AUTHENTICATE) {
+            response.insert_header((header::WWW_AUTHENTICATE, challenge.clone()));
+        }
+        let roles = claims.roles.as_deref().unwrap_or_default();
+        Denied {
+            reason: denied.reason,
+            response: response.json(serde_json::json!({
+                "error": error,
+                "error_description": denied.reason,
+                "required": required_roles,
+                "present": roles,
+                "checks": outcomes,
+            })),
+        }
+    }
+
+    /// The 403 for a delegated token without any of the required scopes, with the
+    /// `insufficient_scope` error and the scopes named in the challenge.
+    fn missing_scopes(&self) -> Denied {
+        const REASON: &str = "Required scope missing";
+        let scope = self.required_scopes.join(" ");
+        Denied {
+            reason: REASON,
+            response: HttpResponse::Forbidden()
+                .insert_header((
+                    "WWW-Authenticate",
+                    BearerChallenge::new("insufficient_scope", REASON)
+                        .with_scope(&scope)
+                        .header_value(&self.realm),
+                ))
+                .json(serde_json::json!({
+                    "error": "insufficient_scope",
+                    "error_description": REASON,
+                })),
+        }
+    }
+
+    /// The 403 for a caller whose claim `name` lacks the required value.
+    fn custom_claim_mismatch(&self, name: &str) -> Denied {
+        self.json_denied(
+            "Custom claim mismatch",
+            "custom_claim_mismatch",
+            format!("Claim {} does not have the required value", name),
+        )
+    }
+
+    /// The authorization outcome for a policy engine `decision`.
+    #[cfg(feature = "policy-engine")]
+    fn policy_outcome(&self, decision: PolicyDecision) -> Result<(), Denied> {
+        match decision {
+            PolicyDecision::Allow => Ok(()),
+            PolicyDecision::Deny => Err(self.json_denied(
+                "Denied by policy",
+                "policy_denied",
+                "The authorization policy denied the request".to_string(),
+            )),
+            PolicyDecision::Unavailable => Err(Denied {
+                reason: "Policy engine unavailable",
+                response: HttpResponse::ServiceUnavailable()
+                    .insert_header(("Retry-After", "5"))
+                    .body("Authorization policy unavailable"),
+            }),
+        }
+    }
+
+    /// A denial answered with a 403 whose JSON body carries the `error` code and `description`.
+    fn json_denied(&self, reason: &'static str, error: &str, description: String) -> Denied {
+        let response = HttpResponse::Forbidden()
+            .insert_header((
+                "WWW-Authenticate",
+                BearerChallenge::new("insufficient_scope", &description).header_value(&self.realm),
+            ))
+            .json(serde_json::json!({
+                "error": error,
+                "error_description": description,
+            }));
+        Denied { reason, response }
+    }
+
+    /// The 403 for a caller without any of the required roles, with the `role_missing` error.
+    /// In diagnostics mode `diagnose` replaces it with the body listing every check.
+    fn missing_roles(&self, description: &'static str) -> Denied {
+        self.json_denied(description, "role_missing", description.to_string())
+    }
+}
+
+/// What `BearerAuth` does with its verdict, set with `BearerAuthConfig::with_enforcement`.
+///
+/// * `Enforce` - Requests that fail a check are answered by the middleware (the default).
+/// * `Log` - Tokens are checked and failures logged with the response that would have been sent,
+///   but every request reaches the handler. `Claims` are only stored for callers that passed,
+///   so handlers should take `Option<Claims>` during the grace period.
+/// * `Off` - Tokens are not looked at and every request reaches the handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Enforcement {
+    Off,
+    Log,
+    Enforce,
+}
+
+impl std::str::FromStr for Enforcement {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Enforcement::Off),
+            "log" => Ok(Enforcement::Log),
+            "enforce" => Ok(Enforcement::Enforce),
+            other => Err(format!(
+                "unknown enforcement {:?}, expected off, log or enforce",
+                other
+            )),
+        }
+    }
+}
+
+/// What `BearerAuth` does with a request carrying different tokens in its signed request and
+/// its `Authorization` header, set with `BearerAuthConfig::with_token_conflict_policy`.
+///
+/// * `FirstWins` - The signed request is used and the header ignored (the default).
+/// * `RejectOnConflict` - The request is refused with a 400 `ambiguous_token`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenConflictPolicy {
+    FirstWins,
+    RejectOnConflict,
+}
+
+impl std::str::FromStr for TokenConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "first-wins" => Ok(TokenConflictPolicy::FirstWins),
+            "reject-on-conflict" => Ok(TokenConflictPolicy::RejectOnConflict),
+            other => Err(format!(
+                "unknown token conflict policy {:?}, expected first-wins or reject-on-conflict",
+                other
+            )),
+        }
+    }
+}
+
+/// The outcome of one authorization check, as listed in the 403 of diagnostics mode.
+///
+/// # Fields
+///
+/// * `check` - What was checked: `app_only`, `managed_identity`, `cert_client_auth`, `scopes`,
+///   `directory_roles`, `mfa`, `claim:<name>`, `tenant_app`, `access_rule` or `roles`.
+/// * `passed` - Whether the caller passed it.
+/// * `error` - The error code of the failure, when it failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckOutcome {
+    pub check: String,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+/// Why `authorize` refused a caller, with the 403 to send back.
+struct Denied {
+    reason: &'static str,
+    response: HttpResponse,
+}
+
+/// The outcome of authorization for an authenticated caller, stored in the request extensions
+/// when `BearerAuthConfig::with_deferred_authorization` is set.
+///
+/// # Fields
+///
+/// * `claims` - The verified claims, whether or not the caller is authorized.
+/// * `allowed` - Whether the caller passed the token type and role checks.
+/// * `reason` - Why the caller was refused, when `allowed` is `false`.
+#[derive(Debug, Clone)]
+pub struct AuthDecision {
+    pub claims: Claims,
+    pub allowed: bool,
+    pub reason: Option<&'static str>,
+}
+
+/// The non-secret authorization settings of a `BearerAuthConfig`, see `BearerAuthConfig::rules`.
+///
+/// # Fields
+///
+/// * `audiences` - The accepted `aud` values.
+/// * `issuers` - The accepted `iss` values; empty when the issuer is not checked.
+/// * `required_roles` - The caller must hold at least one of these roles; empty allows any.
+/// * `role_match` - How roles are compared: `exact` or `case_insensitive`.
+/// * `app_only` - Whether only app-only tokens are accepted.
+/// * `require_managed_identity` - Whether only managed identity tokens are accepted.
+/// * `require_https` - Whether tokens sent over plain HTTP are refused.
+/// * `cert_client_auth` - Whether the client must have authenticated with a certificate.
+/// * `required_scopes` - Delegated tokens must carry one of these scopes.
+/// * `token_type_authorization` - Whether app-only tokens are checked for roles and delegated
+///   tokens for scopes only.
+/// * `required_wids` - The caller must hold one of these directory roles.
+/// * `required_claim_values` - Extra claims that must have the given value, as name-value pairs.
+/// * `require_mfa` - Whether the caller must have completed multi-factor authentication.
+/// * `allowed_tenant_apps` - The tenant and client ID pairs callers must be one of.
+/// * `access_rule` - The rule checked instead of `required_roles`, if any.
+/// * `deprecated_audiences` - Audiences whose tokens get `Deprecation` headers.
+/// * `client_cert` - The client certificate requirement, if any.
+/// * `enforcement` - Whether failed checks reject the request.
+/// * `defer_authorization` - Whether denied callers are passed to the handler.
+/// * `dpop` - The DPoP proof requirements, if DPoP is accepted. Only exists with the `dpop`
+///   feature.
+/// * `policy_url` - The policy engine asked about callers, if any. Only exists with the
+///   `policy-engine` feature.
+/// * `signed_request_header` - The header signed requests are read from, if accepted. Only exists
+///   with the `signed-requests` feature.
+/// * `token_conflict` - What happens when the signed request and `Authorization` carry different
+///   tokens. Only exists with the `signed-requests` feature.
+/// * `profiles` - The per-audience profiles.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorizationRules {
+    pub audiences: Vec<String>,
+    pub issuers: Vec<String>,
+    pub required_roles: Vec<String>,
+    pub role_match: &'static str,
+    pub app_only: bool,
+    pub require_managed_identity: bool,
+    pub require_https: bool,
+    pub cert_client_auth: bool,
+    pub required_scopes: Vec<String>,
+    pub token_type_authorization: bool,
+    pub required_wids: Vec<String>,
+    pub required_claim_values: Vec<(String, String)>,
+    pub require_mfa: bool,
+    pub allowed_tenant_apps: Vec<(String, String)>,
+    pub access_rule: Option<AccessRule>,
+    pub deprecated_audiences: Vec<AudienceDeprecation>,
+    pub client_cert: Option<ClientCertBinding>,
+    pub enforcement: Enforcement,
+    pub defer_authorization: bool,
+    #[cfg(feature = "dpop")]
+    pub dpop: Option<DpopVerifier>,
+    #[cfg(feature = "policy-engine")]
+    pub policy_url: Option<String>,
+    #[cfg(feature = "signed-requests")]
+    pub signed_request_header: Option<String>,
+    #[cfg(feature = "signed-requests")]
+    pub token_conflict: TokenConflictPolicy,
+    pub profiles: Vec<ProfileRules>,
+}
+
+/// An audience being retired, see `BearerAuthConfig::with_deprecated_audience`.
+///
+/// # Fields
+///
+/// * `audience` - The deprecated `aud` value.
+/// * `sunset` - When the audience stops being accepted, as an HTTP date.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudienceDeprecation {
+    pub audience: String,
+    pub sunset: Option<String>,
+}
+
+/// The rules of one `AudienceProfile`, as reported in `AuthorizationRules`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileRules {
+    pub audience: String,
+    pub issuers: Vec<String>,
+    pub required_roles: Vec<String>,
+}
+
+/// A validation profile applied to tokens for one audience.
+///
+/// # Fields
+///
+/// * `audience` - The `aud` value selecting this profile.
+/// * `validator` - The validator for these tokens, with its own issuers, algorithms and
+///   possibly its own JWKS (e.g. another cloud).
+/// * `required_roles` - The caller must hold at least one of these roles; empty allows any.
+#[derive(Clone)]
+pub struct AudienceProfile {
+    pub audience: String,
+    pub validator: JwtValidator,
+    pub required_roles: Vec<String>,
+}
+
+/// Actix middleware that requires a valid Azure AD bearer token.
+///
+/// On success the decoded `Claims` are stored in the request extensions, where handlers can
+/// take them as an extractor argument. Failures are answered directly by the middleware.
+///
+/// # Example
+///
+/// ```ignore
+/// let validator = JwtValidator::from_jwks_url(jwks_url, Duration::from_secs(3600), audience);
+/// let config = BearerAuthConfig::new(validator).with_required_roles(vec!["Task.HelloWorld".into()]);
+///
+/// App::new().service(
+///     web::scope("/api")
+///         .wrap(BearerAuth::new(config))
+///         .route("/hello", web::get().to(|claims: Claims| async move { claims.sub })),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct BearerAuth {
+    config: Arc<Reloadable<BearerAuthConfig>>,
+}
+
+impl BearerAuth {
+    /// Creates the middleware from its configuration.
+    pub fn new(config: BearerAuthConfig) -> Self {
+        Self {
+            config: Arc::new(Reloadable::new(config)),
+        }
+    }
+
+    /// The configuration requests are currently authenticated with.
+    pub fn config(&self) -> Arc<BearerAuthConfig> {
+        self.config.load()
+    }
+
+    /// Replaces the configuration of this middleware and all its clones, without restarting the
+    /// server. Requests already being authenticated finish with the old configuration.
+    ///
+    /// Middleware made with `for_audience` has its own configuration and is not affected.
+    pub fn reload(&self, config: BearerAuthConfig) {
+        self.config.store(config);
+    }
+
+    /// A copy of this middleware that accepts `audience` instead of the configured audiences.
+    ///
+    /// Everything else is kept, including the JWKS cache, so several routes fronting different
+    /// APIs of the same tenant share one set of keys:
+    ///
+    /// ```ignore
+    /// let orders = bearer_auth.for_audience("api://orders");
+    /// let billing = bearer_auth.for_audience("api://billing");
+    /// ```
+    pub fn for_audience(&self, audience: impl Into<String>) -> Self {
+        Self::new(self.config().as_ref().clone().for_audience(audience))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BearerAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = BearerAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BearerAuthMiddleware {
+            service: Rc::new(service),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+/// The service produced by `BearerAuth`.
+pub struct BearerAuthMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<Reloadable<BearerAuthConfig>>,
+}
+
+impl<S, B> Service<ServiceRequest> for BearerAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.load();
+        Box::pin(async move {
+            if config.enforcement == Enforcement::Off {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+            let mut timing = ServerTiming::default();
+            let outcome = match config.verify(req.request(), &mut timing).await {
+                Ok((claims, Ok(()))) => Ok(AuthDecision {
+                    claims,
+                    allowed: true,
+                    reason: None,
+                }),
+                Ok((claims, Err(denied))) if config.defer_authorization => Ok(AuthDecision {
+                    claims,
+                    allowed: false,
+                    reason: Some(denied.reason),
+                }),
+                Ok((_, Err(denied))) => Err(denied.response),
+                Err(response) => Err(response),
+            };
+            #[cfg(feature = "auth-events")]
+            if let Some(sink) = &config.event_sink {
+                let correlation_id = req
+                    .extensions()
+                    .get::<CorrelationId>()
+                    .map(|id| id.0.clone());
+                let (method, path) = (req.method().as_str(), req.path());
+                let event = match &outcome {
+                    Ok(decision) if decision.allowed => {
+                        AuthEvent::success(method, path, correlation_id, &decision.claims)
+                    }
+                    Ok(_) => AuthEvent::failure(
+                        method,
+                        path,
+                        correlation_id,
+                        StatusCode::FORBIDDEN.as_u16(),
+                    ),
+                    Err(response) => {
+                        AuthEvent::failure(method, path, correlation_id, response.status().as_u16())
+                            .with_error(
+                                response
+                                    .extensions()
+                                    .get::<ValidationError>()
+                                    .map(|error| error.code()),
+                            )
+                    }
+                };
+                sink.emit(event.with_route(req.match_pattern()));
+            }
+            let deprecation = outcome
+                .as_ref()
+                .ok()
+                .and_then(|decision| config.deprecation(&decision.claims))
+                .cloned();
+            let mut res = match outcome {
+                Ok(decision) => {
+                    if decision.allowed {
+                        req.extensions_mut().insert(decision.claims.clone());
+                    }
+                    if config.defer_authorization {
+                        req.extensions_mut().insert(decision);
+                    }
+                    service.call(req).await?.map_into_left_body()
+                }
+                Err(response) if config.enforcement == Enforcement::Log => {
+                    warn!(
+                        "Not enforced: {} {} would have been answered {}",
+                        req.method(),
+                        req.path(),
+                        response.status()
+                    );
+                    service.call(req).await?.map_into_left_body()
+                }
+                Err(response) => req.into_response(response).map_into_right_body(),
+            };
+            if let Some(deprecation) = deprecation {
+                let headers = res.headers_mut();
+                headers.insert(
+                    HeaderName::from_static("deprecation"),
+                    HeaderValue::from_static("true"),
+                );
+                if let Some(sunset) = deprecation
+                    .sunset
+                    .and_then(|sunset| HeaderValue::from_str(&sunset).ok())
+                {
+                    headers.insert(HeaderName::from_static("sunset"), sunset);
+                }
+            }
+            if config.diagnostics {
+                if let Ok(value) = HeaderValue::from_str(&timing.to_string()) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static("server-timing"), value);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// The first 8 bytes of the SHA-256 of `token` in hex, to tell tokens apart in debug logs
+/// without logging a bearer credential.
+fn token_fingerprint(token: &str) -> String {
+    Sha256::digest(token.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Extracts the `Claims` stored by `BearerAuth`.
+///
+/// Fails with 401 when the route is not wrapped by the middleware.
+impl FromRequest for Claims {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Claims>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing claims")),
+        )
+    }
+}
+
+/// Extracts the `AuthDecision` stored by `BearerAuth` in deferred authorization mode.
+///
+/// Fails with 401 when the route is not wrapped by the middleware, or the middleware enforces
+/// authorization itself.
+impl FromRequest for AuthDecision {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthDecision>()
+                .cloned()
+                .ok_or_else(|| {
+                    actix_web::error::ErrorUnauthorized("Missing authorization decision")
+                }),
+        )
+    }
+}
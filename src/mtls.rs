@@ -0,0 +1,84 @@
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use log::debug;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::claims::Claims;
+
+/// Requires a client certificate and optionally binds the token to it (RFC 8705).
+///
+/// TLS is expected to be terminated by a proxy that forwards the verified client certificate
+/// in `header_name` (e.g. `X-ARR-ClientCert` on Azure App Service), either as base64 DER or PEM.
+///
+/// # Fields
+///
+/// * `header_name` - The header carrying the client certificate.
+/// * `require_token_binding` - When `true`, the token's `cnf.x5t#S256` must equal the
+///   SHA-256 thumbprint of the presented certificate.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientCertBinding {
+    header_name: String,
+    require_token_binding: bool,
+}
+
+impl ClientCertBinding {
+    /// Creates a binding that requires a certificate in `header_name`.
+    pub fn new(header_name: impl Into<String>, require_token_binding: bool) -> Self {
+        Self {
+            header_name: header_name.into(),
+            require_token_binding,
+        }
+    }
+
+    /// Returns the DER bytes of the presented client certificate.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem when the header is missing or cannot be decoded.
+    pub fn presented_certificate(&self, req: &HttpRequest) -> Result<Vec<u8>, &'static str> {
+        req.headers()
+            .get(&self.header_name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(decode_certificate)
+            .ok_or("Client certificate required")
+    }
+
+    /// Checks that the token is bound to the presented certificate, if binding is required.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the mismatch when the token's `cnf.x5t#S256` differs.
+    pub fn check_binding(&self, certificate: &[u8], claims: &Claims) -> Result<(), &'static str> {
+        if !self.require_token_binding {
+            return Ok(());
+        }
+        let presented = certificate_thumbprint(certificate);
+        let bound = claims.cnf.as_ref().and_then(|cnf| cnf.x5t_s256.as_deref());
+        debug!(
+            "Certificate thumbprint: {}, token cnf: {:?}",
+            presented, bound
+        );
+        if bound != Some(presented.as_str()) {
+            return Err("Token is not bound to the presented client certificate");
+        }
+        Ok(())
+    }
+}
+
+/// Computes the `x5t#S256` thumbprint of a DER certificate: base64url(SHA-256(der)), unpadded.
+pub fn certificate_thumbprint(der: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(der))
+}
+
+/// Decodes a forwarded certificate given either as PEM or as bare base64 DER.
+fn decode_certificate(value: &str) -> Option<Vec<u8>> {
+    let body: String = value
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .flat_map(|line| line.chars())
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    STANDARD.decode(body).ok()
+}
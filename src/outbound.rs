@@ -0,0 +1,275 @@
+use azure_core::auth::TokenCredential;
+use log::{debug, warn};
+use reqwest::RequestBuilder;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// An access token the server got for itself, which cannot leak through logging: `Debug` and
+/// `Display` print a placeholder.
+///
+/// The only way to the secret is `expose`, which keeps every use of it easy to find.
+pub struct OutboundToken {
+    secret: String,
+    expires_on: i64,
+}
+
+impl OutboundToken {
+    /// The raw token, for the `Authorization` header.
+    pub fn expose(&self) -> &str {
+        &self.secret
+    }
+
+    /// When the token expires, in seconds since the Unix epoch.
+    pub fn expires_on(&self) -> i64 {
+        self.expires_on
+    }
+}
+
+impl std::fmt::Debug for OutboundToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "OutboundToken(<redacted, {} bytes>, expires_on: {})",
+            self.secret.len(),
+            self.expires_on
+        )
+    }
+}
+
+impl std::fmt::Display for OutboundToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Gets and caches the server's own tokens for one downstream API, e.g. with the managed
+/// identity through `DefaultAzureCredential`, registered as `web::Data<OutboundTokenProvider>`
+/// app data for handlers calling that API.
+///
+/// The token is reused until it is within `refresh_margin` of expiring. Concurrent callers
+/// needing a new one wait for a single acquisition instead of each asking the credential.
+/// Failed acquisitions are retried like the client does, to ride out IMDS throttling.
+///
+/// # Fields
+///
+/// * `credential` - Where tokens come from.
+/// * `scope` - The scope tokens are requested for, e.g. `api://downstream/.default`.
+/// * `refresh_margin` - How long before expiry a cached token is replaced.
+/// * `max_attempts` - Attempts per acquisition, including the first one.
+/// * `base_delay` - Delay before the first retry, doubled for each further one.
+/// * `cached` - The current token, also serializing acquisitions.
+/// * `acquisitions` - Tokens acquired since startup.
+pub struct OutboundTokenProvider {
+    credential: Arc<dyn TokenCredential>,
+    scope: String,
+    refresh_margin: Duration,
+    max_attempts: u32,
+    base_delay: Duration,
+    cached: Mutex<Option<Arc<OutboundToken>>>,
+    acquisitions: AtomicU64,
+}
+
+impl OutboundTokenProvider {
+    /// Gets tokens for `scope` from `credential`, replacing them 5 minutes before they expire
+    /// and trying each acquisition 3 times.
+    pub fn new(credential: Arc<dyn TokenCredential>, scope: impl Into<String>) -> Self {
+        Self {
+            credential,
+            scope: scope.into(),
+            refresh_margin: Duration::from_secs(300),
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            cached: Mutex::new(None),
+            acquisitions: AtomicU64::new(0),
+        }
+    }
+
+    /// Replaces cached tokens `margin` before they expire instead of 5 minutes.
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.refresh_margin = margin;
+        self
+    }
+
+    /// Tries each acquisition `max_attempts` times, waiting `base_delay * 2^(n-1)` after
+    /// attempt `n`.
+    pub fn with_retry(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// The scope tokens are requested for.
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    /// The number of tokens acquired since startup; calls answered from the cache are not
+    /// counted.
+    pub fn acquisitions(&self) -> u64 {
+        self.acquisitions.load(Ordering::Relaxed)
+    }
+
+    /// A token for the scope, from the cache while it is not about to expire.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt when the credential fails every time.
+    pub async fn token(&self) -> azure_core::Result<Arc<OutboundToken>> {
+        let mut cached = self.cached.lock().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or(0);
+        let margin = i64::try_from(self.refresh_margin.as_secs()).unwrap_or(i64::MAX);
+        if let Some(token) = cached
+            .as_ref()
+            .filter(|token| token.expires_on.saturating_sub(margin) > now)
+        {
+            return Ok(token.clone());
+        }
+        let token = Arc::new(self.acquire().await?);
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Acquired a token for {}, expiring in {}s",
+            self.scope,
+            token.expires_on - now
+        );
+        *cached = Some(token.clone());
+        Ok(token)
+    }
+
+    /// `request` with the token for the scope as its bearer credentials.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `token`.
+    pub async fn authorize(&self, request: RequestBuilder) -> azure_core::Result<RequestBuilder> {
+        let token = self.token().await?;
+        Ok(request.bearer_auth(token.expose()))
+    }
+
+    /// Drops the cached token, e.g. after the downstream API rejected it, so the next call gets
+    /// a new one.
+    pub async fn invalidate(&self) {
+        *self.cached.lock().await = None;
+    }
+
+    /// Asks the credential for a token, retrying failures.
+    async fn acquire(&self) -> azure_core::Result<OutboundToken> {
+        let mut attempt = 1;
+        loop {
+            match self.credential.get_token(&[self.scope.as_str()]).await {
+                Ok(token) => {
+                    return Ok(OutboundToken {
+                        secret: token.token.secret().to_string(),
+                        expires_on: token.expires_on.unix_timestamp(),
+                    })
+                }
+                Err(e) if attempt < self.max_attempts => {
+                    let delay = self.base_delay * 2u32.saturating_pow(attempt - 1);
+                    warn!(
+                        "Token acquisition for {} attempt {}/{} failed, retrying in {:?}: {}",
+                        self.scope, attempt, self.max_attempts, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Gets and caches the tokens of one credential for any number of resources, e.g. for a
+/// long-running client calling APIs in a loop. Each resource gets its own
+/// `OutboundTokenProvider`, created on first use, so tokens for different resources are cached
+/// and refreshed independently, and concurrent callers of one resource share an acquisition.
+///
+/// # Fields
+///
+/// * `credential` - Where tokens come from.
+/// * `refresh_margin` - How long before expiry a cached token is replaced.
+/// * `max_attempts` - Attempts per acquisition, including the first one.
+/// * `base_delay` - Delay before the first retry, doubled for each further one.
+/// * `providers` - The provider of each resource asked for so far.
+pub struct OutboundTokenCache {
+    credential: Arc<dyn TokenCredential>,
+    refresh_margin: Duration,
+    max_attempts: u32,
+    base_delay: Duration,
+    providers: std::sync::Mutex<HashMap<String, Arc<OutboundTokenProvider>>>,
+}
+
+impl OutboundTokenCache {
+    /// Gets tokens from `credential` with the defaults of `OutboundTokenProvider::new`.
+    pub fn new(credential: Arc<dyn TokenCredential>) -> Self {
+        Self {
+            credential,
+            refresh_margin: Duration::from_secs(300),
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            providers: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces cached tokens `margin` before they expire instead of 5 minutes.
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.refresh_margin = margin;
+        self
+    }
+
+    /// Tries each acquisition `max_attempts` times, as `OutboundTokenProvider::with_retry`.
+    pub fn with_retry(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// The number of tokens acquired since startup, over all resources.
+    pub fn acquisitions(&self) -> u64 {
+        self.lock_providers()
+            .values()
+            .map(|provider| provider.acquisitions())
+            .sum()
+    }
+
+    /// A token for `resource` (a scope such as `api://<app-id>/.default`), from the cache while
+    /// it is not about to expire.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt when the credential fails every time.
+    pub async fn get_valid_token(&self, resource: &str) -> azure_core::Result<Arc<OutboundToken>> {
+        let provider = self
+            .lock_providers()
+            .entry(resource.to_string())
+            .or_insert_with(|| {
+                Arc::new(
+                    OutboundTokenProvider::new(self.credential.clone(), resource)
+                        .with_refresh_margin(self.refresh_margin)
+                        .with_retry(self.max_attempts, self.base_delay),
+                )
+            })
+            .clone();
+        provider.token().await
+    }
+
+    /// Drops the cached token for `resource`, so the next call gets a new one.
+    pub async fn invalidate(&self, resource: &str) {
+        let provider = self.lock_providers().get(resource).cloned();
+        if let Some(provider) = provider {
+            provider.invalidate().await;
+        }
+    }
+
+    fn lock_providers(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<String, Arc<OutboundTokenProvider>>> {
+        self.providers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
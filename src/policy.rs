@@ -0,0 +1,115 @@
+use actix_web::HttpRequest;
+use log::{debug, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::claims::Claims;
+
+/// Hands the authorization decision to an external policy engine such as Open Policy Agent.
+///
+/// For every authenticated request the engine is POSTed
+/// `{"input": {"claims": {...}, "method": "GET", "path": "/api_protected"}}` and must answer
+/// `{"result": true}` or `{"result": {"allow": true}}`, the shape OPA gives a boolean rule or a
+/// package with an `allow` rule. Any other answer, an error status or an unreachable engine
+/// denies the request.
+///
+/// # Fields
+///
+/// * `url` - The decision endpoint, e.g. `http://localhost:8181/v1/data/api/authz`.
+/// * `client` - The HTTP client used to ask the engine, with the decision timeout.
+#[derive(Debug, Clone)]
+pub struct PolicyEngine {
+    url: String,
+    client: Client,
+}
+
+/// The outcome of asking the policy engine.
+///
+/// * `Allow` - The engine allowed the request.
+/// * `Deny` - The engine denied the request.
+/// * `Unavailable` - The engine could not be asked or gave no usable answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    Deny,
+    Unavailable,
+}
+
+/// The document the engine is asked about.
+#[derive(Serialize)]
+struct PolicyQuery<'a> {
+    input: PolicyInput<'a>,
+}
+
+#[derive(Serialize)]
+struct PolicyInput<'a> {
+    claims: &'a Claims,
+    method: &'a str,
+    path: &'a str,
+}
+
+impl PolicyEngine {
+    /// Asks the engine at `url`, waiting at most `timeout` for each decision.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of building the HTTP client.
+    pub fn new(url: impl Into<String>, timeout: Duration) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            url: url.into(),
+            client: Client::builder().timeout(timeout).build()?,
+        })
+    }
+
+    /// The decision endpoint.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Asks the engine whether the caller with `claims` may make `req`.
+    pub async fn decide(&self, req: &HttpRequest, claims: &Claims) -> PolicyDecision {
+        let query = PolicyQuery {
+            input: PolicyInput {
+                claims,
+                method: req.method().as_str(),
+                path: req.path(),
+            },
+        };
+        let response = match self
+            .client
+            .post(&self.url)
+            .json(&query)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Policy engine request failed: {}", e);
+                return PolicyDecision::Unavailable;
+            }
+        };
+        let body: serde_json::Value = match response.json().await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Invalid policy engine response: {}", e);
+                return PolicyDecision::Unavailable;
+            }
+        };
+        let allowed = match &body["result"] {
+            serde_json::Value::Bool(allowed) => Some(*allowed),
+            result => result["allow"].as_bool(),
+        };
+        debug!("Policy engine decision for {}: {}", claims.sub, body);
+        match allowed {
+            Some(true) => PolicyDecision::Allow,
+            Some(false) => PolicyDecision::Deny,
+            None => {
+                // OPA answers `{}` when the rule is undefined, e.g. a typo in the URL
+                warn!("Policy engine response has no decision: {}", body);
+                PolicyDecision::Unavailable
+            }
+        }
+    }
+}
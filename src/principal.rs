@@ -0,0 +1,65 @@
+use actix_web::dev::Payload;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use serde::Serialize;
+use std::future::{ready, Ready};
+
+use crate::claims::Claims;
+
+/// The identity of an authenticated caller, without the token details.
+///
+/// Handlers that only need to know who is calling can take a `Principal` instead of `Claims`;
+/// it is built from the claims `BearerAuth` stored for the request, and is easy to construct
+/// by hand in tests.
+///
+/// # Fields
+///
+/// * `subject` - The `sub` claim (service principal or managed identity object).
+/// * `tenant` - The tenant of the caller, from `tid`.
+/// * `app_id` - The client ID of the caller, from `azp` or `appid`.
+/// * `roles` - The app roles granted to the caller; empty when the token has none.
+/// * `scopes` - The delegated scopes of a user token, split from `scp`; empty for app tokens.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub subject: String,
+    pub tenant: Option<String>,
+    pub app_id: Option<String>,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+impl Principal {
+    /// Whether the caller holds `role`.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+impl From<Claims> for Principal {
+    fn from(claims: Claims) -> Self {
+        Self {
+            app_id: claims.client_id().map(str::to_string),
+            subject: claims.sub,
+            tenant: claims.tid,
+            roles: claims.roles.unwrap_or_default(),
+            scopes: claims
+                .scp
+                .map(|scp| scp.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Claims>()
+                .cloned()
+                .map(Principal::from)
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing claims")),
+        )
+    }
+}
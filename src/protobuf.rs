@@ -0,0 +1,119 @@
+use prost::Message;
+use std::collections::HashMap;
+
+/// The media type of protobuf requests and answers.
+pub const CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// The outcome of every token of a batch, in request order. The `ValidationResults` message of
+/// `proto/validation.proto`.
+#[derive(Clone, PartialEq, Message)]
+pub struct ValidationResults {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<ValidationResult>,
+}
+
+/// The outcome for one token: the claims when `valid`, the error code and description
+/// otherwise.
+#[derive(Clone, PartialEq, Message)]
+pub struct ValidationResult {
+    #[prost(bool, tag = "1")]
+    pub valid: bool,
+    #[prost(message, optional, tag = "2")]
+    pub claims: Option<Claims>,
+    #[prost(string, optional, tag = "3")]
+    pub error: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub error_description: Option<String>,
+}
+
+/// The validated claims of a token. Claims without a field of their own (including `cnf`,
+/// `azpacr`, `appidacr` and `_claim_names`) are in `extra`, with their values as JSON.
+#[derive(Clone, PartialEq, Message)]
+pub struct Claims {
+    #[prost(string, repeated, tag = "1")]
+    pub aud: Vec<String>,
+    #[prost(string, tag = "2")]
+    pub iss: String,
+    #[prost(string, tag = "3")]
+    pub sub: String,
+    #[prost(int64, tag = "4")]
+    pub exp: i64,
+    #[prost(int64, optional, tag = "5")]
+    pub nbf: Option<i64>,
+    #[prost(string, repeated, tag = "6")]
+    pub roles: Vec<String>,
+    #[prost(string, optional, tag = "7")]
+    pub ver: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub idtyp: Option<String>,
+    #[prost(string, optional, tag = "9")]
+    pub scp: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub tid: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    pub azp: Option<String>,
+    #[prost(string, optional, tag = "12")]
+    pub appid: Option<String>,
+    #[prost(string, repeated, tag = "13")]
+    pub wids: Vec<String>,
+    #[prost(string, repeated, tag = "14")]
+    pub amr: Vec<String>,
+    #[prost(string, repeated, tag = "15")]
+    pub groups: Vec<String>,
+    #[prost(map = "string, string", tag = "16")]
+    pub extra: HashMap<String, String>,
+    #[prost(int64, optional, tag = "17")]
+    pub iat: Option<i64>,
+}
+
+impl From<crate::claims::Claims> for Claims {
+    fn from(claims: crate::claims::Claims) -> Self {
+        let mut extra: HashMap<String, String> = claims
+            .extra
+            .iter()
+            .map(|(name, value)| (name.clone(), value.to_string()))
+            .collect();
+        let unmapped = [
+            ("cnf", serde_json::to_value(&claims.cnf)),
+            ("azpacr", serde_json::to_value(&claims.azpacr)),
+            ("appidacr", serde_json::to_value(&claims.appidacr)),
+            ("_claim_names", serde_json::to_value(&claims.claim_names)),
+        ];
+        for (name, value) in unmapped {
+            if let Ok(value) = value {
+                if !value.is_null() {
+                    extra.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+        Self {
+            aud: claims.aud,
+            iss: claims.iss,
+            sub: claims.sub,
+            exp: claims.exp,
+            nbf: claims.nbf,
+            roles: claims.roles.unwrap_or_default(),
+            ver: claims.ver,
+            idtyp: claims.idtyp,
+            scp: claims.scp,
+            tid: claims.tid,
+            azp: claims.azp,
+            appid: claims.appid,
+            wids: claims.wids.unwrap_or_default(),
+            amr: claims.amr.unwrap_or_default(),
+            groups: claims.groups.unwrap_or_default(),
+            extra,
+            iat: claims.iat,
+        }
+    }
+}
+
+/// Whether an `Accept` header value asks for protobuf.
+pub fn accepts_protobuf(accept: &str) -> bool {
+    accept.split(',').any(|media_type| {
+        media_type
+            .split(';')
+            .next()
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(CONTENT_TYPE))
+    })
+}
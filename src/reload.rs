@@ -0,0 +1,27 @@
+use std::sync::{Arc, RwLock};
+
+/// A value that can be replaced at runtime while it is being read, e.g. configuration reloaded
+/// on `SIGHUP`.
+///
+/// Readers take a cheap snapshot with `load` and keep using it for the rest of their work, so a
+/// request never sees half of an old and half of a new configuration. `store` swaps the value
+/// atomically; snapshots taken before keep the old one alive until they are dropped.
+#[derive(Debug, Default)]
+pub struct Reloadable<T>(RwLock<Arc<T>>);
+
+impl<T> Reloadable<T> {
+    /// Wraps the initial `value`.
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    /// The current value.
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replaces the value for every later `load`.
+    pub fn store(&self, value: T) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(value);
+    }
+}
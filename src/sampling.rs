@@ -0,0 +1,77 @@
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Thins out repeated log lines of the same kind, so a client flooding the API with bad tokens
+/// does not drown the logs.
+///
+/// Within each `window`, the first `threshold` occurrences of a key (e.g. an error code) are
+/// logged, then one in every `sample_every`. The count of lines left out is reported in a
+/// summary line with the first occurrence after the window closes.
+///
+/// # Fields
+///
+/// * `threshold` - Occurrences per window logged in full.
+/// * `sample_every` - Past the threshold, one occurrence in this many is logged.
+/// * `window` - How long counts accumulate before they start over.
+/// * `keys` - The counts of the current window, by key.
+pub struct LogSampler {
+    threshold: u64,
+    sample_every: u64,
+    window: Duration,
+    keys: Mutex<HashMap<&'static str, SampleWindow>>,
+}
+
+/// The occurrences of one key in the current window.
+struct SampleWindow {
+    started: Instant,
+    seen: u64,
+    suppressed: u64,
+}
+
+impl LogSampler {
+    /// Logs `threshold` occurrences of each key per `window` and one in `sample_every` after
+    /// that. A `sample_every` of 0 is treated as 1, logging everything.
+    pub fn new(threshold: u64, sample_every: u64, window: Duration) -> Self {
+        Self {
+            threshold,
+            sample_every: sample_every.max(1),
+            window,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts an occurrence of `key`, returning whether it should be logged.
+    pub fn should_log(&self, key: &'static str) -> bool {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let window = keys.entry(key).or_insert(SampleWindow {
+            started: now,
+            seen: 0,
+            suppressed: 0,
+        });
+        if now.duration_since(window.started) >= self.window {
+            if window.suppressed > 0 {
+                warn!(
+                    "{} more {} lines were not logged in the last {:?}",
+                    window.suppressed,
+                    key,
+                    now.duration_since(window.started)
+                );
+            }
+            *window = SampleWindow {
+                started: now,
+                seen: 0,
+                suppressed: 0,
+            };
+        }
+        window.seen += 1;
+        let log = window.seen <= self.threshold
+            || (window.seen - self.threshold).is_multiple_of(self.sample_every);
+        if !log {
+            window.suppressed += 1;
+        }
+        log
+    }
+}
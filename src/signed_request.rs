@@ -0,0 +1,217 @@
+use actix_web::HttpRequest;
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use log::debug;
+use std::sync::Arc;
+
+use crate::jwks::JwksCache;
+
+/// Unwraps access tokens sent inside a signed request object, for partners that wrap the token
+/// in a JWT of their own (in the style of RFC 9101 request objects).
+///
+/// The wrapper arrives in the `header_name` header instead of `Authorization`. It must be signed
+/// (RS256) by a key of the partner's `jwks`, unexpired, and carry the access token as a string in
+/// its `token_claim`. The inner token is then validated like any bearer token.
+///
+/// # Fields
+///
+/// * `jwks` - The partner keys the wrapper is signed with.
+/// * `header_name` - The request header carrying the wrapper.
+/// * `token_claim` - The wrapper claim holding the access token, e.g. `access_token`.
+/// * `issuer` - When set, the wrapper's `iss` must equal it.
+/// * `audience` - When set, the wrapper's `aud` must contain it.
+#[derive(Clone)]
+pub struct SignedRequestUnwrapper {
+    jwks: Arc<JwksCache>,
+    header_name: String,
+    token_claim: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl SignedRequestUnwrapper {
+    /// Reads wrappers signed by `jwks` from the `Signed-Request` header, taking the token from
+    /// `access_token`.
+    pub fn new(jwks: Arc<JwksCache>) -> Self {
+        Self {
+            jwks,
+            header_name: "Signed-Request".to_string(),
+            token_claim: "access_token".to_string(),
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    /// Reads wrappers from `header_name`.
+    pub fn with_header_name(mut self, header_name: impl Into<String>) -> Self {
+        self.header_name = header_name.into();
+        self
+    }
+
+    /// Takes the access token from the wrapper claim `claim`.
+    pub fn with_token_claim(mut self, claim: impl Into<String>) -> Self {
+        self.token_claim = claim.into();
+        self
+    }
+
+    /// Requires the wrapper's `iss` to be `issuer`.
+    pub fn with_issuer(mut self, issuer: Option<String>) -> Self {
+        self.issuer = issuer;
+        self
+    }
+
+    /// Requires the wrapper's `aud` to contain `audience`.
+    pub fn with_audience(mut self, audience: Option<String>) -> Self {
+        self.audience = audience;
+        self
+    }
+
+    /// The request header carrying the wrapper.
+    pub fn header_name(&self) -> &str {
+        &self.header_name
+    }
+
+    /// The signed request of `req`, if it carries one.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description when the header is not valid text or is sent more than once.
+    pub fn wrapper<'a>(&self, req: &'a HttpRequest) -> Result<Option<&'a str>, &'static str> {
+        let mut values = req.headers().get_all(self.header_name.as_str());
+        let Some(value) = values.next() else {
+            return Ok(None);
+        };
+        if values.next().is_some() {
+            return Err("Multiple signed requests");
+        }
+        value
+            .to_str()
+            .map(|value| Some(value.trim()))
+            .map_err(|_| "Invalid signed request header")
+    }
+
+    /// Verifies the `wrapper` JWT and returns the access token it carries.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description when the partner keys are unavailable, the wrapper is malformed,
+    /// signed by an unknown key, expired, for another issuer or audience, or has no token.
+    pub async fn unwrap(&self, wrapper: &str) -> Result<String, &'static str> {
+        let header = decode_header(wrapper).map_err(|_| "Malformed signed request")?;
+        if header.alg != Algorithm::RS256 {
+            return Err("Unsupported signed request algorithm");
+        }
+        let kid = header.kid.ok_or("Signed request has no kid")?;
+        let keys = self
+            .jwks
+            .get_keys()
+            .await
+            .map_err(|_| "Signed request keys unavailable")?;
+        let key = keys.get(&kid).ok_or("Signed request key is unknown")?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims = decode::<serde_json::Value>(wrapper, key, &validation)
+            .map_err(|e| {
+                debug!("Signed request rejected: {}", e);
+                "Invalid signed request"
+            })?
+            .claims;
+        claims[self.token_claim.as_str()]
+            .as_str()
+            .map(str::to_string)
+            .ok_or("Signed request carries no access token")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestTokenFactory;
+
+    fn partner() -> TestTokenFactory {
+        TestTokenFactory::new().expect("generate the partner key")
+    }
+
+    fn unwrapper(partner: &TestTokenFactory) -> SignedRequestUnwrapper {
+        SignedRequestUnwrapper::new(Arc::new(partner.jwks_cache().expect("write the JWKS")))
+    }
+
+    #[tokio::test]
+    async fn valid_wrappers_give_their_token() {
+        let partner = partner();
+        let wrapper = partner
+            .token()
+            .with_claim("access_token", "inner-token")
+            .sign()
+            .unwrap();
+        assert_eq!(
+            unwrapper(&partner).unwrap(&wrapper).await,
+            Ok("inner-token".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn wrappers_signed_by_another_key_are_refused() {
+        let partner = partner();
+        let impostor = TestTokenFactory::new()
+            .expect("generate the impostor key")
+            .with_kid(partner.kid());
+        let wrapper = impostor
+            .token()
+            .with_claim("access_token", "inner-token")
+            .sign()
+            .unwrap();
+        assert_eq!(
+            unwrapper(&partner).unwrap(&wrapper).await,
+            Err("Invalid signed request")
+        );
+    }
+
+    #[tokio::test]
+    async fn wrappers_must_name_a_partner_key_and_carry_a_token() {
+        let partner = partner();
+        let unknown_kid = partner
+            .token()
+            .with_kid(Some("other-key"))
+            .with_claim("access_token", "inner-token")
+            .sign()
+            .unwrap();
+        assert_eq!(
+            unwrapper(&partner).unwrap(&unknown_kid).await,
+            Err("Signed request key is unknown")
+        );
+        let without_token = partner.token().sign().unwrap();
+        assert_eq!(
+            unwrapper(&partner).unwrap(&without_token).await,
+            Err("Signed request carries no access token")
+        );
+    }
+
+    #[tokio::test]
+    async fn wrappers_for_another_issuer_or_audience_are_refused() {
+        let partner = partner();
+        let wrapper = partner
+            .token()
+            .with_claim("access_token", "inner-token")
+            .sign()
+            .unwrap();
+        let issuer = unwrapper(&partner).with_issuer(Some("https://partner.example.com".into()));
+        assert_eq!(issuer.unwrap(&wrapper).await, Err("Invalid signed request"));
+        let audience = unwrapper(&partner).with_audience(Some(partner.audience().to_string()));
+        assert_eq!(
+            audience.unwrap(&wrapper).await,
+            Ok("inner-token".to_string())
+        );
+        let audience = unwrapper(&partner).with_audience(Some("api://other".into()));
+        assert_eq!(
+            audience.unwrap(&wrapper).await,
+            Err("Invalid signed request")
+        );
+    }
+}
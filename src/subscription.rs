@@ -0,0 +1,196 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderName;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+/// The subscription keys accepted in a header set by a gateway such as Azure API Management,
+/// registered as `web::Data<SubscriptionKeys>` app data for `subscription_key`.
+///
+/// Only the SHA-256 of each key is kept, and presented keys are compared by digest, so the
+/// comparison time does not depend on how much of a key matches.
+///
+/// # Fields
+///
+/// * `header_name` - The request header carrying the key, e.g. `Ocp-Apim-Subscription-Key`.
+/// * `digests` - The SHA-256 of every accepted key.
+#[derive(Debug, Clone)]
+pub struct SubscriptionKeys {
+    header_name: HeaderName,
+    digests: Vec<[u8; 32]>,
+}
+
+impl SubscriptionKeys {
+    /// Accepts `keys` in the `header_name` header.
+    ///
+    /// # Errors
+    ///
+    /// Returns a message if `header_name` is not a valid header name or `keys` is empty.
+    pub fn new(header_name: &str, keys: &[String]) -> Result<Self, String> {
+        let header_name = HeaderName::try_from(header_name)
+            .map_err(|_| format!("{:?} is not a valid header name", header_name))?;
+        if keys.is_empty() {
+            return Err(format!("no subscription keys for {}", header_name));
+        }
+        Ok(Self {
+            header_name,
+            digests: keys.iter().map(|key| Sha256::digest(key).into()).collect(),
+        })
+    }
+
+    /// The request header carrying the key.
+    pub fn header_name(&self) -> &HeaderName {
+        &self.header_name
+    }
+
+    /// The number of accepted keys.
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    /// Whether no key is accepted; never true for a `SubscriptionKeys` built with `new`.
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    /// Whether `key` is one of the accepted keys.
+    pub fn accepts(&self, key: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(key).into();
+        self.digests.contains(&digest)
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that answers `401 Unauthorized` to requests
+/// without one of the `SubscriptionKeys` in the app data, before the token is looked at. Without
+/// `SubscriptionKeys` every request is let through.
+///
+/// It complements `BearerAuth` rather than replacing it: wrap the protected resources with both,
+/// so a request needs a valid subscription key and a valid token.
+pub async fn subscription_key(
+    keys: Option<web::Data<SubscriptionKeys>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(keys) = keys else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    let mut presented = req.headers().get_all(keys.header_name());
+    let key = presented.next().and_then(|value| value.to_str().ok());
+    let message = match key {
+        _ if presented.next().is_some() => "Multiple subscription keys",
+        None => "Missing subscription key",
+        Some(key) if !keys.accepts(key) => "Invalid subscription key",
+        Some(_) => {
+            debug!("Subscription key accepted");
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_boxed_body);
+        }
+    };
+    warn!(
+        "Rejecting {} {}: {}",
+        req.method(),
+        req.path(),
+        message.to_lowercase()
+    );
+    Ok(req.into_response(HttpResponse::Unauthorized().body(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{BearerAuth, BearerAuthConfig};
+    use crate::testing::TestTokenFactory;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    const HEADER: &str = "Ocp-Apim-Subscription-Key";
+
+    /// The status and body answered to a request with `keys` in the subscription key header
+    /// and, if `with_token`, a valid token, by a resource wrapped with both checks.
+    async fn call(keys: &[&str], with_token: bool) -> (StatusCode, String) {
+        let factory = TestTokenFactory::new().unwrap();
+        let accepted = ["key-1".to_string(), "key-2".to_string()];
+        let config = BearerAuthConfig::new(factory.validator().expect("write the JWKS"));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    SubscriptionKeys::new(HEADER, &accepted).unwrap(),
+                ))
+                .service(
+                    web::resource("/api_protected")
+                        .wrap(BearerAuth::new(config))
+                        .wrap(from_fn(subscription_key))
+                        .to(HttpResponse::Ok),
+                ),
+        )
+        .await;
+        let mut request = TestRequest::get().uri("/api_protected");
+        for key in keys {
+            request = request.append_header((HEADER, *key));
+        }
+        if with_token {
+            let token = factory.token().sign().unwrap();
+            request = request.insert_header(("Authorization", format!("Bearer {}", token)));
+        }
+        let response = call_service(&app, request.to_request()).await;
+        let status = response.status();
+        let body = read_body(response).await;
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[actix_web::test]
+    async fn valid_keys_with_valid_tokens_are_let_through() {
+        assert_eq!(call(&["key-2"], true).await.0, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn missing_invalid_and_repeated_keys_are_refused() {
+        assert_eq!(
+            call(&[], true).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                "Missing subscription key".to_string()
+            )
+        );
+        assert_eq!(
+            call(&["key-3"], true).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                "Invalid subscription key".to_string()
+            )
+        );
+        assert_eq!(
+            call(&["key-1", "key-2"], true).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                "Multiple subscription keys".to_string()
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn valid_keys_still_need_a_token() {
+        let (status, body) = call(&["key-1"], false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "Missing Authorization header");
+    }
+
+    #[test]
+    fn keys_need_a_valid_header_name_and_at_least_one_key() {
+        assert!(SubscriptionKeys::new("Bad Header", &["key".to_string()]).is_err());
+        assert!(SubscriptionKeys::new(HEADER, &[]).is_err());
+        let keys = SubscriptionKeys::new(HEADER, &["key".to_string()]).unwrap();
+        assert!(keys.accepts("key"));
+        assert!(!keys.accepts("Key"));
+        assert_eq!(keys.len(), 1);
+    }
+}
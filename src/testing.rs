@@ -0,0 +1,258 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rsa::pkcs1::EncodeRsaPrivateKey;
+use rsa::rand_core::OsRng;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::jwks::JwksCache;
+use crate::validator::JwtValidator;
+
+/// Mints RS256 tokens with a freshly generated key, for tests of code built on this crate.
+///
+/// The factory holds one RSA key pair. `jwks_document` publishes its public half as a JWKS, and
+/// `jwks_cache` and `validator` hand out a `JwksCache` and `JwtValidator` that trust it, so a
+/// test needs neither Azure AD nor a network. Tokens default to the factory's issuer and
+/// audience and expire in an hour; `token` starts a `TestTokenBuilder` to change any claim.
+///
+/// ```ignore
+/// let factory = TestTokenFactory::new()?;
+/// let validator = factory.validator()?;
+/// let token = factory.token().with_roles(&["Task.HelloWorld"]).sign()?;
+/// let claims = validator.validate(&token).await?;
+/// let expired = factory.token().expires_in(-600).sign()?;
+/// assert!(validator.validate(&expired).await.is_err());
+/// ```
+///
+/// Only exists with the `test-utils` feature.
+///
+/// # Fields
+///
+/// * `private_key` - The signing key.
+/// * `kid` - The key ID tokens are signed with and the JWKS publishes, `test-key` by default.
+/// * `issuer` - The default `iss`, `https://sts.windows.net/test-tenant/` by default.
+/// * `audience` - The default `aud`, `api://test` by default.
+/// * `tenant` - The default `tid`, `test-tenant` by default.
+pub struct TestTokenFactory {
+    private_key: RsaPrivateKey,
+    kid: String,
+    issuer: String,
+    audience: String,
+    tenant: String,
+}
+
+impl TestTokenFactory {
+    /// A factory with a new 2048-bit key.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the key generation.
+    pub fn new() -> Result<Self, rsa::Error> {
+        Self::with_key_bits(2048)
+    }
+
+    /// A factory with a new key of `bits` bits. Smaller keys are generated faster, which adds up
+    /// in test suites creating many factories.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the key generation.
+    pub fn with_key_bits(bits: usize) -> Result<Self, rsa::Error> {
+        Ok(Self {
+            private_key: RsaPrivateKey::new(&mut OsRng, bits)?,
+            kid: "test-key".to_string(),
+            issuer: "https://sts.windows.net/test-tenant/".to_string(),
+            audience: "api://test".to_string(),
+            tenant: "test-tenant".to_string(),
+        })
+    }
+
+    /// Signs with and publishes the key as `kid`.
+    pub fn with_kid(mut self, kid: impl Into<String>) -> Self {
+        self.kid = kid.into();
+        self
+    }
+
+    /// Issues tokens from `issuer` by default.
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// Issues tokens for `audience` by default.
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = audience.into();
+        self
+    }
+
+    /// Issues tokens in `tenant` by default.
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = tenant.into();
+        self
+    }
+
+    /// The key ID tokens are signed with.
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The default issuer.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// The default audience.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// The public key as a JWKS document, as Azure AD serves it.
+    pub fn jwks_document(&self) -> serde_json::Value {
+        let public_key = self.private_key.to_public_key();
+        serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.kid,
+                "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+            }],
+        })
+    }
+
+    /// A `JwksCache` trusting the key, read from a JWKS file written to the temporary directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of writing the file.
+    pub fn jwks_cache(&self) -> std::io::Result<JwksCache> {
+        let path = std::env::temp_dir().join(format!("jwks-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, self.jwks_document().to_string())?;
+        let url = format!("file://{}", path.display());
+        Ok(JwksCache::new(url, Duration::from_secs(3600)))
+    }
+
+    /// A `JwtValidator` accepting the factory's default issuer and audience, with the key from
+    /// `jwks_cache`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of writing the JWKS file.
+    pub fn validator(&self) -> std::io::Result<JwtValidator> {
+        Ok(JwtValidator::new(
+            std::sync::Arc::new(self.jwks_cache()?),
+            self.audience.clone(),
+        )
+        .with_issuers(vec![self.issuer.clone()]))
+    }
+
+    /// Starts a token with the default issuer, audience and tenant, subject `test-subject`,
+    /// issued now and expiring in an hour.
+    pub fn token(&self) -> TestTokenBuilder<'_> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or(0);
+        let mut claims = serde_json::Map::new();
+        claims.insert("aud".to_string(), self.audience.clone().into());
+        claims.insert("iss".to_string(), self.issuer.clone().into());
+        claims.insert("sub".to_string(), "test-subject".into());
+        claims.insert("tid".to_string(), self.tenant.clone().into());
+        claims.insert("iat".to_string(), now.into());
+        claims.insert("exp".to_string(), (now + 3600).into());
+        TestTokenBuilder {
+            factory: self,
+            kid: Some(self.kid.clone()),
+            now,
+            claims,
+        }
+    }
+}
+
+/// The claims and header of a token being minted by a `TestTokenFactory`.
+///
+/// # Fields
+///
+/// * `factory` - The factory whose key signs the token.
+/// * `kid` - The `kid` of the header; `None` leaves it out.
+/// * `now` - When the token was started, the base of `expires_in`.
+/// * `claims` - The payload.
+pub struct TestTokenBuilder<'a> {
+    factory: &'a TestTokenFactory,
+    kid: Option<String>,
+    now: i64,
+    claims: serde_json::Map<String, serde_json::Value>,
+}
+
+impl TestTokenBuilder<'_> {
+    /// Sets the claim `name` to `value`, replacing any default.
+    pub fn with_claim(mut self, name: &str, value: impl Into<serde_json::Value>) -> Self {
+        self.claims.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Leaves the claim `name` out.
+    pub fn without_claim(mut self, name: &str) -> Self {
+        self.claims.remove(name);
+        self
+    }
+
+    /// Sets `roles`, as an app-only token carries them.
+    pub fn with_roles(self, roles: &[&str]) -> Self {
+        self.with_claim("roles", roles.to_vec())
+    }
+
+    /// Sets `scp` to the space-separated `scopes`, as a delegated token carries them.
+    pub fn with_scopes(self, scopes: &[&str]) -> Self {
+        self.with_claim("scp", scopes.join(" "))
+    }
+
+    /// Sets `sub`.
+    pub fn with_subject(self, subject: &str) -> Self {
+        self.with_claim("sub", subject)
+    }
+
+    /// Sets `iss`.
+    pub fn with_issuer(self, issuer: &str) -> Self {
+        self.with_claim("iss", issuer)
+    }
+
+    /// Sets `aud`.
+    pub fn with_audience(self, audience: &str) -> Self {
+        self.with_claim("aud", audience)
+    }
+
+    /// Sets `exp`, in seconds since the Unix epoch.
+    pub fn with_exp(self, exp: i64) -> Self {
+        self.with_claim("exp", exp)
+    }
+
+    /// Sets `exp` to `secs` seconds from now; negative values give an expired token.
+    pub fn expires_in(self, secs: i64) -> Self {
+        let exp = self.now + secs;
+        self.with_exp(exp)
+    }
+
+    /// Puts `kid` in the header instead of the factory's key ID, e.g. to test unknown keys, or
+    /// leaves it out with `None`.
+    pub fn with_kid(mut self, kid: Option<&str>) -> Self {
+        self.kid = kid.map(str::to_string);
+        self
+    }
+
+    /// Signs the token with the factory's key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key cannot be encoded or the token cannot be signed.
+    pub fn sign(self) -> Result<String, Box<dyn std::error::Error>> {
+        let der = self.factory.private_key.to_pkcs1_der()?;
+        let key = EncodingKey::from_rsa_der(der.as_bytes());
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = self.kid;
+        Ok(jsonwebtoken::encode(&header, &self.claims, &key)?)
+    }
+}
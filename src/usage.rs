@@ -0,0 +1,199 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::claims::Claims;
+
+/// What authenticated requests are counted by.
+///
+/// * `Subject` - The `sub` claim.
+/// * `ClientId` - The `azp` or `appid` claim, falling back to `sub` when neither is present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageKey {
+    Subject,
+    ClientId,
+}
+
+impl std::str::FromStr for UsageKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sub" => Ok(Self::Subject),
+            "client_id" => Ok(Self::ClientId),
+            _ => Err(format!("expected sub or client_id, got {:?}", s)),
+        }
+    }
+}
+
+/// In-memory counts of authenticated requests per caller, registered as `web::Data<UsageCounters>`
+/// app data for `count_usage`.
+///
+/// Counts start over every `window`. At most `max_callers` callers are tracked; a new caller
+/// beyond that evicts the one seen least recently, so a flood of distinct subjects cannot grow
+/// the table without bound.
+///
+/// # Fields
+///
+/// * `key` - What requests are counted by.
+/// * `max_callers` - The most callers tracked at once.
+/// * `window` - How long counts accumulate before they start over.
+/// * `state` - The current window and its counts.
+pub struct UsageCounters {
+    key: UsageKey,
+    max_callers: usize,
+    window: Duration,
+    state: Mutex<UsageWindow>,
+}
+
+/// The counts of the current window.
+struct UsageWindow {
+    started: Instant,
+    callers: HashMap<String, CallerUsage>,
+    evicted: u64,
+}
+
+/// The requests of one caller in the current window.
+struct CallerUsage {
+    requests: u64,
+    last_seen: Instant,
+}
+
+/// The top callers of the current window, as served by `GET /admin/usage`.
+///
+/// # Fields
+///
+/// * `key` - What requests are counted by.
+/// * `window_secs` - The length of a window.
+/// * `window_elapsed_secs` - How long the current window has been open.
+/// * `tracked_callers` - The number of callers counted in the current window.
+/// * `evicted_callers` - Callers dropped from the window to stay within the limit.
+/// * `callers` - The callers with the most requests, busiest first.
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub key: UsageKey,
+    pub window_secs: u64,
+    pub window_elapsed_secs: u64,
+    pub tracked_callers: usize,
+    pub evicted_callers: u64,
+    pub callers: Vec<CallerCount>,
+}
+
+/// The requests of one caller in a `UsageReport`.
+#[derive(Debug, Serialize)]
+pub struct CallerCount {
+    pub caller: String,
+    pub requests: u64,
+}
+
+impl UsageCounters {
+    /// Counts requests by `key`, tracking at most `max_callers` callers per `window`.
+    pub fn new(key: UsageKey, max_callers: usize, window: Duration) -> Self {
+        Self {
+            key,
+            max_callers: max_callers.max(1),
+            window,
+            state: Mutex::new(UsageWindow {
+                started: Instant::now(),
+                callers: HashMap::new(),
+                evicted: 0,
+            }),
+        }
+    }
+
+    /// Counts a request by the caller with `claims`.
+    pub fn record(&self, claims: &Claims) {
+        let caller = match self.key {
+            UsageKey::Subject => claims.sub.as_str(),
+            UsageKey::ClientId => claims.client_id().unwrap_or(&claims.sub),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        state.expire(now, self.window);
+        if !state.callers.contains_key(caller) && state.callers.len() >= self.max_callers {
+            let least_recent = state
+                .callers
+                .iter()
+                .min_by_key(|(_, usage)| usage.last_seen)
+                .map(|(caller, _)| caller.clone());
+            if let Some(least_recent) = least_recent {
+                state.callers.remove(&least_recent);
+                state.evicted += 1;
+            }
+        }
+        let usage = state
+            .callers
+            .entry(caller.to_string())
+            .or_insert(CallerUsage {
+                requests: 0,
+                last_seen: now,
+            });
+        usage.requests += 1;
+        usage.last_seen = now;
+    }
+
+    /// The `top` callers with the most requests in the current window.
+    pub fn report(&self, top: usize) -> UsageReport {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        state.expire(now, self.window);
+        let mut callers: Vec<CallerCount> = state
+            .callers
+            .iter()
+            .map(|(caller, usage)| CallerCount {
+                caller: caller.clone(),
+                requests: usage.requests,
+            })
+            .collect();
+        callers.sort_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then_with(|| a.caller.cmp(&b.caller))
+        });
+        callers.truncate(top);
+        UsageReport {
+            key: self.key,
+            window_secs: self.window.as_secs(),
+            window_elapsed_secs: now.duration_since(state.started).as_secs(),
+            tracked_callers: state.callers.len(),
+            evicted_callers: state.evicted,
+            callers,
+        }
+    }
+}
+
+impl UsageWindow {
+    /// Starts a new window if the current one is older than `window`.
+    fn expire(&mut self, now: Instant, window: Duration) {
+        if now.duration_since(self.started) >= window {
+            self.started = now;
+            self.callers.clear();
+            self.evicted = 0;
+        }
+    }
+}
+
+/// Middleware (for `actix_web::middleware::from_fn`) that counts every request `BearerAuth` let
+/// through in the `UsageCounters` of the app data. Without `UsageCounters` nothing is counted.
+///
+/// Wrap it inside `BearerAuth`, so the claims it stores in the request extensions are there.
+pub async fn count_usage(
+    counters: Option<web::Data<UsageCounters>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    if let Some(counters) = counters {
+        if let Some(claims) = req.extensions().get::<Claims>() {
+            counters.record(claims);
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,1264 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::future::BoxFuture;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+#[cfg(feature = "insecure-dev")]
+use log::warn;
+use log::{debug, error};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::authority::{Cloud, TokenVersion};
+use crate::challenge::BearerChallenge;
+use crate::claims::Claims;
+use crate::clock::{Clock, SystemClock};
+#[cfg(feature = "jwe")]
+use crate::jwe::JweDecryptor;
+use crate::jwks::JwksCache;
+use crate::sampling::LogSampler;
+use crate::timing::ServerTiming;
+
+/// The reasons a token can fail validation.
+///
+/// # Variants
+///
+/// * `JwksWarmingUp` - The JWKS cache is still cold and fail-fast mode is enabled.
+/// * `JwksUnavailable` - No JWKS keys could be fetched and none are cached.
+/// * `InvalidHeader` - The token header could not be decoded.
+/// * `MissingKid` - The token header has no `kid`.
+/// * `UnknownKid` - No JWK matches the token's `kid`.
+/// * `InvalidToken` - The signature or claims were rejected.
+/// * `UnsupportedTokenVersion` - The `ver` claim does not match the required token version.
+/// * `UndecryptableToken` - An encrypted (JWE) token could not be decrypted.
+/// * `EmptyToken` - The token is empty or only whitespace.
+/// * `MissingRequiredClaim` - A claim required by `with_required_claims` is absent; carries its
+///   name.
+/// * `MalformedClaims` - A claim has the wrong type, e.g. `exp` given as a string.
+/// * `IssuerVersionMismatch` - The `iss` is the tenant's issuer for another `ver` than the
+///   token's, see `JwtValidator::with_version_issuers`.
+/// * `SubjectNotAllowed` - The `sub` is not one of those allowed by
+///   `JwtValidator::with_allowed_subjects`.
+/// * `TokenIssuedInFuture` - The `iat` is later than now, beyond the clock skew leeway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    JwksWarmingUp,
+    JwksUnavailable,
+    InvalidHeader,
+    MissingKid,
+    UnknownKid,
+    InvalidToken,
+    UnsupportedTokenVersion,
+    UndecryptableToken,
+    EmptyToken,
+    MissingRequiredClaim(&'static str),
+    MalformedClaims,
+    IssuerVersionMismatch,
+    SubjectNotAllowed,
+    TokenIssuedInFuture,
+}
+
+impl ValidationError {
+    /// Seconds a client should wait before retrying while the JWKS cache warms up.
+    const COLD_RETRY_AFTER_SECS: u64 = 5;
+
+    /// A stable, machine-readable identifier for the error.
+    pub fn code(self) -> &'static str {
+        match self {
+            ValidationError::JwksWarmingUp => "jwks_warming_up",
+            ValidationError::JwksUnavailable => "jwks_unavailable",
+            ValidationError::InvalidHeader => "invalid_header",
+            ValidationError::MissingKid => "missing_kid",
+            ValidationError::UnknownKid => "unknown_kid",
+            ValidationError::InvalidToken => "invalid_token",
+            ValidationError::UnsupportedTokenVersion => "unsupported_token_version",
+            ValidationError::UndecryptableToken => "undecryptable_token",
+            ValidationError::EmptyToken => "empty_token",
+            ValidationError::MissingRequiredClaim(_) => "missing_required_claim",
+            ValidationError::MalformedClaims => "malformed_claims",
+            ValidationError::IssuerVersionMismatch => "issuer_version_mismatch",
+            ValidationError::SubjectNotAllowed => "subject_not_allowed",
+            ValidationError::TokenIssuedInFuture => "token_issued_in_future",
+        }
+    }
+
+    /// Converts the error into the HTTP response returned to the caller.
+    ///
+    /// Token errors become a 401 with an `invalid_token` challenge for `realm`; key availability
+    /// problems are server-side and become a 503 without a challenge. The error itself is kept in
+    /// the response extensions, for middleware reporting why a request was rejected.
+    pub fn to_response(self, realm: &str) -> HttpResponse {
+        let mut response = match self {
+            ValidationError::JwksWarmingUp => HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", Self::COLD_RETRY_AFTER_SECS.to_string()))
+                .body(self.to_string()),
+            ValidationError::JwksUnavailable => {
+                HttpResponse::ServiceUnavailable().body(self.to_string())
+            }
+            _ => {
+                let description = self.to_string();
+                BearerChallenge::new("invalid_token", &description).response(
+                    StatusCode::UNAUTHORIZED,
+                    realm,
+                    description.clone(),
+                )
+            }
+        };
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ValidationError::JwksWarmingUp => "Signing keys are not loaded yet, retry shortly",
+            ValidationError::JwksUnavailable => "No JWKS keys available",
+            ValidationError::InvalidHeader => "Invalid token header",
+            ValidationError::MissingKid => "No KID found",
+            ValidationError::UnknownKid => "No matching JWK found",
+            ValidationError::InvalidToken => "Invalid token",
+            ValidationError::UnsupportedTokenVersion => "Unsupported token version",
+            ValidationError::UndecryptableToken => "Encrypted token could not be decrypted",
+            ValidationError::EmptyToken => "Empty bearer token",
+            ValidationError::MalformedClaims => "Malformed token claims",
+            ValidationError::IssuerVersionMismatch => "Token issuer does not match its version",
+            ValidationError::SubjectNotAllowed => "Token subject is not allowed",
+            ValidationError::TokenIssuedInFuture => "Token is issued in the future",
+            ValidationError::MissingRequiredClaim(claim) => {
+                return write!(f, "Missing required claim: {}", claim);
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// A validated token and how it was verified, see `JwtValidator::validate_detailed`.
+///
+/// # Fields
+///
+/// * `claims` - The validated claims.
+/// * `kid` - The ID of the key the token was signed with. `None` only for development tokens
+///   (HS256 or unverified) that carry no `kid`.
+/// * `algorithm` - The signing algorithm from the token header.
+/// * `audience` - The configured audience the token's `aud` matched, directly or through an
+///   alias. `None` when the audience is not checked.
+/// * `issuer` - The issuer the token was accepted from.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidatedToken {
+    pub claims: Claims,
+    pub kid: Option<String>,
+    pub algorithm: Algorithm,
+    pub audience: Option<String>,
+    pub issuer: String,
+}
+
+/// Validates Azure AD access tokens against a JWKS cache and a set of expectations.
+///
+/// The validator is cheap to clone: clones share the same `JwksCache`, so several
+/// validators with different audiences or issuers can reuse one set of keys.
+///
+/// # Fields
+///
+/// * `jwks` - The shared cache the signing keys are read from.
+/// * `audiences` - The accepted `aud` values. A token passes if any of its audiences (a string
+///   or an array in the JWT) matches any of these, or an alias of one (see `audience_aliases`).
+/// * `issuers` - The accepted `iss` values. When empty the issuer is not checked.
+/// * `fail_fast_on_cold_jwks` - Fail with `JwksWarmingUp` instead of waiting on the first fetch.
+/// * `required_token_version` - If set, only tokens whose `ver` claim equals it are accepted.
+/// * `algorithms` - The accepted signing algorithms (default `RS256`).
+/// * `jwe` - Decryptor for nested JWE tokens. Only exists with the `jwe` feature.
+/// * `insecure_no_verify` - Skip signature verification. Only exists with the `insecure-dev`
+///   feature and must never be enabled outside local development.
+/// * `hs256_secret` - Also accept HS256 tokens signed with this shared secret. Only exists with
+///   the `insecure-dev` feature.
+/// * `required_claims` - Registered claims that must be present, on top of `exp`.
+/// * `version_issuers` - The issuer of each token version as `(ver, iss)` pairs, see
+///   `with_version_issuers`.
+/// * `issuer_jwks` - Key sets of partner issuers, selected by the token's `iss` instead of `jwks`.
+/// * `allowed_subjects` - The accepted `sub` values, see `with_allowed_subjects`. When empty any
+///   subject is accepted.
+/// * `check_audience` - Whether `aud` is checked at all, see `with_audience_check`.
+/// * `clock` - The source of "now" for the `exp` and `nbf` checks.
+/// * `prepared` - The `Validation` built for each kid, reused until the keys are refreshed.
+/// * `claims_cache` - Recently validated tokens, see `with_claims_cache`.
+/// * `claims_cache_margin` - How long before a token's `exp` its cache entry expires, see
+///   `with_claims_cache_margin`.
+/// * `log_sampler` - Thins out the logged decode errors, see `with_error_log_sampling`.
+#[derive(Clone)]
+pub struct JwtValidator {
+    jwks: Arc<JwksCache>,
+    audiences: Vec<String>,
+    issuers: Vec<String>,
+    algorithms: Vec<Algorithm>,
+    fail_fast_on_cold_jwks: bool,
+    required_token_version: Option<String>,
+    #[cfg(feature = "jwe")]
+    jwe: Option<Arc<JweDecryptor>>,
+    #[cfg(feature = "insecure-dev")]
+    insecure_no_verify: bool,
+    #[cfg(feature = "insecure-dev")]
+    hs256_secret: Option<Arc<[u8]>>,
+    required_claims: Vec<&'static str>,
+    version_issuers: Vec<(String, String)>,
+    issuer_jwks: Vec<(String, Arc<JwksCache>)>,
+    allowed_subjects: Vec<String>,
+    check_audience: bool,
+    clock: Arc<dyn Clock>,
+    prepared: PreparedValidations,
+    claims_cache: Option<ClaimsCache>,
+    claims_cache_margin: Duration,
+    log_sampler: Option<Arc<LogSampler>>,
+}
+
+/// Per-kid `Validation` rules for the current version of each key set, keyed by the address of
+/// its `JwksCache` (the validator holds every cache it uses, so the addresses are stable).
+///
+/// `PreparedSet::keys` identifies that version: a JWKS refresh hands out a new `Arc`, which drops
+/// every entry. Clones start empty, since a clone may be reconfigured with other audiences or
+/// issuers.
+#[derive(Default)]
+struct PreparedValidations(RwLock<HashMap<usize, PreparedSet>>);
+
+struct PreparedSet {
+    keys: Arc<HashMap<String, DecodingKey>>,
+    entries: HashMap<String, (Algorithm, Arc<Validation>)>,
+}
+
+impl Clone for PreparedValidations {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// The claims of recently validated tokens, keyed by the SHA-256 of the token so the cache holds
+/// no credentials. Each entry expires after `ttl`, or a margin before the token does if that is
+/// sooner.
+///
+/// Clones start empty, for the same reason as `PreparedValidations`.
+struct ClaimsCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<[u8; 32], (SystemTime, Claims)>>,
+}
+
+impl ClaimsCache {
+    /// The most entries kept; a full cache drops its expired entries, then everything, to make
+    /// room for a new token.
+    const CAPACITY: usize = 10_000;
+
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The cached claims of the token hashed to `key`, if they have not expired at `now`.
+    fn get(&self, key: &[u8; 32], now: SystemTime) -> Option<Claims> {
+        self.entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .filter(|(expires_at, _)| *expires_at > now)
+            .map(|(_, claims)| claims.clone())
+    }
+
+    /// Caches `claims` until `ttl` from `now`, or `margin` before their `exp` if that is sooner.
+    /// Claims already within `margin` of their `exp` are not cached.
+    fn insert(&self, key: [u8; 32], claims: &Claims, now: SystemTime, margin: Duration) {
+        let token_expiry = UNIX_EPOCH + Duration::from_secs(claims.exp.max(0) as u64);
+        let expires_at =
+            (now + self.ttl).min(token_expiry.checked_sub(margin).unwrap_or(UNIX_EPOCH));
+        if expires_at <= now {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= Self::CAPACITY && !entries.contains_key(&key) {
+            entries.retain(|_, (expires_at, _)| *expires_at > now);
+            if entries.len() >= Self::CAPACITY {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (expires_at, claims.clone()));
+    }
+}
+
+impl Clone for ClaimsCache {
+    fn clone(&self) -> Self {
+        Self::new(self.ttl)
+    }
+}
+
+impl JwtValidator {
+    /// Creates a validator reading keys from `jwks` and accepting tokens for `audience`.
+    pub fn new(jwks: Arc<JwksCache>, audience: impl Into<String>) -> Self {
+        Self {
+            jwks,
+            audiences: vec![audience.into()],
+            issuers: Vec::new(),
+            algorithms: vec![Algorithm::RS256],
+            fail_fast_on_cold_jwks: false,
+            required_token_version: None,
+            #[cfg(feature = "jwe")]
+            jwe: None,
+            #[cfg(feature = "insecure-dev")]
+            insecure_no_verify: false,
+            #[cfg(feature = "insecure-dev")]
+            hs256_secret: None,
+            required_claims: Vec::new(),
+            version_issuers: Vec::new(),
+            issuer_jwks: Vec::new(),
+            allowed_subjects: Vec::new(),
+            check_audience: true,
+            clock: Arc::new(SystemClock),
+            prepared: PreparedValidations::default(),
+            claims_cache: None,
+            claims_cache_margin: Duration::ZERO,
+            log_sampler: None,
+        }
+    }
+
+    /// Convenience constructor that creates its own `JwksCache` for `jwks_url`.
+    pub fn from_jwks_url(
+        jwks_url: impl Into<String>,
+        jwks_cache_ttl: Duration,
+        audience: impl Into<String>,
+    ) -> Self {
+        Self::new(Arc::new(JwksCache::new(jwks_url, jwks_cache_ttl)), audience)
+    }
+
+    /// Convenience constructor for tokens issued by `tenant_id` in the public Azure cloud for
+    /// `audience`, with keys from `jwks_url` cached for an hour. Both the v1.0 and the v2.0
+    /// issuer of the tenant are accepted, as managed identity tokens may carry either; use
+    /// `with_issuers` for another cloud.
+    pub fn for_tenant(
+        jwks_url: impl Into<String>,
+        audience: impl Into<String>,
+        tenant_id: &str,
+    ) -> Self {
+        Self::from_jwks_url(jwks_url, Duration::from_secs(3600), audience).with_issuers(
+            crate::authority::expected_issuers(tenant_id, Cloud::Public, &TokenVersion::ALL),
+        )
+    }
+
+    /// Replaces the JWKS cache the signing keys are read from.
+    pub fn with_jwks(mut self, jwks: Arc<JwksCache>) -> Self {
+        self.jwks = jwks;
+        self.prepared = PreparedValidations::default();
+        self
+    }
+
+    /// Replaces the accepted audiences.
+    pub fn with_audiences(mut self, audiences: Vec<String>) -> Self {
+        self.audiences = audiences;
+        self.prepared = PreparedValidations::default();
+        self
+    }
+
+    /// Replaces the accepted issuers. An empty list disables the issuer check.
+    pub fn with_issuers(mut self, issuers: Vec<String>) -> Self {
+        self.issuers = issuers;
+        self.prepared = PreparedValidations::default();
+        self
+    }
+
+    /// Clock skew tolerated when checking `exp` and `nbf`, the same as `jsonwebtoken`'s default.
+    pub const LEEWAY_SECS: u64 = 60;
+
+    /// Reads the current time from `clock` instead of the system clock, e.g. a `FixedClock` to
+    /// test expiry deterministically.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The registered claims `with_required_claims` accepts.
+    pub const REQUIRABLE_CLAIMS: [&'static str; 5] = ["exp", "nbf", "aud", "iss", "sub"];
+
+    /// Requires the registered `claims` (any of `exp`, `nbf`, `aud`, `iss`, `sub`) to be present.
+    /// `exp` is always required.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first name that is not a requirable claim.
+    pub fn with_required_claims<S: AsRef<str>>(mut self, claims: &[S]) -> Result<Self, String> {
+        let mut required = Vec::new();
+        for claim in claims {
+            let claim = claim.as_ref();
+            let known = Self::REQUIRABLE_CLAIMS
+                .iter()
+                .find(|known| **known == claim)
+                .ok_or_else(|| format!("{} is not a requirable claim", claim))?;
+            if !required.contains(known) {
+                required.push(*known);
+            }
+        }
+        self.required_claims = required;
+        self.prepared = PreparedValidations::default();
+        Ok(self)
+    }
+
+    /// Accepts tokens from `issuer`, verified with the keys of `jwks`.
+    ///
+    /// The key set is picked by the token's (unverified) `iss` before the signature is checked,
+    /// so federated partners can bring their own keys independently of the tenant. `issuer` is
+    /// added to the accepted issuers unless the issuer check is disabled (no issuers set).
+    pub fn with_issuer_jwks(mut self, issuer: impl Into<String>, jwks: Arc<JwksCache>) -> Self {
+        self.issuer_jwks.push((issuer.into(), jwks));
+        self.prepared = PreparedValidations::default();
+        self
+    }
+
+    /// Replaces the accepted signing algorithms. Keys come from a JWKS of RSA keys, so only the
+    /// `RS*` and `PS*` families can succeed.
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.algorithms = algorithms;
+        self.prepared = PreparedValidations::default();
+        self
+    }
+
+    /// Enables or disables the audience check; signature, issuer and expiry are still checked.
+    ///
+    /// A validator without the audience check is NOT an authorization check: it accepts tokens
+    /// minted for any API of a trusted issuer. Only use it to introspect tokens, e.g. to report
+    /// their audience, never to protect a route.
+    pub fn with_audience_check(mut self, check_audience: bool) -> Self {
+        self.check_audience = check_audience;
+        self.prepared = PreparedValidations::default();
+        self
+    }
+
+    /// Enables or disables failing fast while the JWKS cache is cold.
+    pub fn with_fail_fast_on_cold_jwks(mut self, fail_fast: bool) -> Self {
+        self.fail_fast_on_cold_jwks = fail_fast;
+        self
+    }
+
+    /// Checks that a token's `iss` is the one its `ver` calls for, given `(ver, iss)` pairs such as
+    /// those of `Authority::issuers_by_version`. A token for `ver` `2.0` carrying the v1.0 issuer
+    /// fails with `IssuerVersionMismatch`, even when both issuers are accepted.
+    ///
+    /// Only tokens whose `iss` is one of the listed issuers and whose `ver` is listed are checked,
+    /// so partner issuers and tokens without `ver` are left to the issuer check.
+    pub fn with_version_issuers(mut self, version_issuers: Vec<(String, String)>) -> Self {
+        self.version_issuers = version_issuers;
+        self
+    }
+
+    /// Only accepts tokens whose `sub` is one of `subjects`, e.g. the repositories allowed to
+    /// call with a GitHub Actions token. An entry ending in `*` accepts every subject starting
+    /// with the rest of it, such as `repo:contoso/api:*`. An empty list accepts any subject.
+    pub fn with_allowed_subjects(mut self, subjects: Vec<String>) -> Self {
+        self.allowed_subjects = subjects;
+        self
+    }
+
+    /// Requires the `ver` claim to equal `version` (`1.0` or `2.0`).
+    pub fn with_required_token_version(mut self, version: Option<String>) -> Self {
+        self.required_token_version = version;
+        self
+    }
+
+    /// Decrypts five-segment JWE tokens with `decryptor` before validating the inner JWS.
+    #[cfg(feature = "jwe")]
+    pub fn with_jwe_decryptor(mut self, decryptor: JweDecryptor) -> Self {
+        self.jwe = Some(Arc::new(decryptor));
+        self
+    }
+
+    /// Disables signature verification: claims are parsed and checked, but anyone can forge them.
+    ///
+    /// Only available with the `insecure-dev` feature, for local testing with hand-crafted tokens.
+    #[cfg(feature = "insecure-dev")]
+    pub fn with_insecure_no_verify(mut self, insecure_no_verify: bool) -> Self {
+        self.insecure_no_verify = insecure_no_verify;
+        self
+    }
+
+    /// Also accepts HS256 tokens signed with `secret`, so tokens can be minted locally without an
+    /// identity provider. Tokens with other algorithms are still verified against the JWKS.
+    ///
+    /// Only available with the `insecure-dev` feature: anyone holding the secret can mint tokens.
+    #[cfg(feature = "insecure-dev")]
+    pub fn with_hs256_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.hs256_secret = Some(secret.into().into());
+        self
+    }
+
+    /// Remembers the claims of each valid token for `ttl` (never past the token's `exp`, see
+    /// `with_claims_cache_margin`), so a token presented again, e.g. by a burst of requests from
+    /// one client, skips signature verification. `Duration::ZERO` disables the cache.
+    ///
+    /// A cached token keeps being accepted until its entry expires, even if its signing key is
+    /// removed from the JWKS in the meantime, so keep `ttl` short.
+    pub fn with_claims_cache(mut self, ttl: Duration) -> Self {
+        self.claims_cache = (!ttl.is_zero()).then(|| ClaimsCache::new(ttl));
+        self
+    }
+
+    /// Expires cached claims `margin` before their token's `exp` instead of at it, and does not
+    /// cache tokens closer than that to expiring, so the cache never answers for a token past
+    /// its expiry even if the server clock is slightly behind the issuer's. Defaults to
+    /// `Duration::ZERO`. Has no effect without `with_claims_cache`.
+    pub fn with_claims_cache_margin(mut self, margin: Duration) -> Self {
+        self.claims_cache_margin = margin;
+        self
+    }
+
+    /// Samples the error logged for each rejected token with `sampler`, keyed by the error code
+    /// (e.g. `invalid_token`), so a misbehaving client cannot flood the logs. Clones share the
+    /// sampler and its counts.
+    pub fn with_error_log_sampling(mut self, sampler: LogSampler) -> Self {
+        self.log_sampler = Some(Arc::new(sampler));
+        self
+    }
+
+    /// The accepted `aud` values, as configured (without aliases).
+    pub fn audiences(&self) -> &[String] {
+        &self.audiences
+    }
+
+    /// The accepted `iss` values, not counting partner issuers. Empty when the issuer is not
+    /// checked.
+    pub fn issuers(&self) -> &[String] {
+        &self.issuers
+    }
+
+    /// The current time of the validator's clock, in seconds since the Unix epoch.
+    pub fn unix_now(&self) -> u64 {
+        self.clock.unix_now()
+    }
+
+    /// The shared JWKS cache.
+    pub fn jwks(&self) -> &Arc<JwksCache> {
+        &self.jwks
+    }
+
+    /// Validates a JWT token and returns its claims.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - A string slice that holds the JWT token to be validated.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is:
+    /// * `Ok(Claims)` if the token is valid and contains the expected claims.
+    /// * `Err(ValidationError)` if the token is invalid or any error occurs during validation.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// * The token is empty.
+    /// * The JWKS cache is cold and fail-fast mode is enabled.
+    /// * No JWKS keys could be fetched and none are cached.
+    /// * The token is an encrypted JWE that cannot be decrypted.
+    /// * The token header is invalid.
+    /// * The KID (Key ID) is not found in the token header.
+    /// * There is no matching JWK (JSON Web Key) for the KID.
+    /// * The token is invalid according to the provided validation criteria.
+    /// * A required claim is missing.
+    /// * The `ver` claim does not match the required token version.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let token = "your.jwt.token";
+    /// let claims = validator.validate(token).await;
+    /// ```
+    pub async fn validate(&self, token: &str) -> Result<Claims, ValidationError> {
+        self.validate_timed(token, &mut ServerTiming::default())
+            .await
+    }
+
+    /// Like `validate`, recording the time spent getting the keys (`jwks-lookup`) and verifying
+    /// the token (`decode`) in `timing`, or looking it up in the claims cache (`claims-cache`).
+    pub async fn validate_timed(
+        &self,
+        token: &str,
+        timing: &mut ServerTiming,
+    ) -> Result<Claims, ValidationError> {
+        let Some(cache) = &self.claims_cache else {
+            return self.validate_uncached(token, timing).await;
+        };
+        let started = Instant::now();
+        let key: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let cached = cache.get(&key, self.clock.now());
+        timing.record("claims-cache", started.elapsed());
+        if let Some(claims) = cached {
+            debug!("Claims of {} served from the claims cache", claims.sub);
+            return Ok(claims);
+        }
+        let claims = self.validate_uncached(token, timing).await?;
+        cache.insert(key, &claims, self.clock.now(), self.claims_cache_margin);
+        Ok(claims)
+    }
+
+    /// Like `validate`, but also reports how the token was verified, for audit logs and
+    /// diagnostics.
+    ///
+    /// The header is decoded again after validation (and a JWE decrypted again), so this costs a
+    /// little more than `validate`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `validate`.
+    pub async fn validate_detailed(&self, token: &str) -> Result<ValidatedToken, ValidationError> {
+        let claims = self.validate(token).await?;
+        let header = self.signed_header(token)?;
+        let audience = if self.check_audience {
+            self.audiences
+                .iter()
+                .find(|audience| {
+                    audience_aliases(audience)
+                        .iter()
+                        .any(|alias| claims.aud.contains(alias))
+                })
+                .cloned()
+        } else {
+            None
+        };
+        Ok(ValidatedToken {
+            kid: header.kid,
+            algorithm: header.alg,
+            audience,
+            issuer: claims.iss.clone(),
+            claims,
+        })
+    }
+
+    /// The header of the signed token: that of `token` itself, or of the JWS nested in it when
+    /// it is a JWE.
+    fn signed_header(&self, token: &str) -> Result<jsonwebtoken::Header, ValidationError> {
+        #[cfg(feature = "jwe")]
+        if let Some(decryptor) = &self.jwe {
+            if crate::jwe::is_jwe(token) {
+                let decrypted = decryptor.decrypt(token)?;
+                return jsonwebtoken::decode_header(&decrypted)
+                    .map_err(|_| ValidationError::InvalidHeader);
+            }
+        }
+        jsonwebtoken::decode_header(token).map_err(|_| ValidationError::InvalidHeader)
+    }
+
+    /// Validates `token` without consulting the claims cache.
+    async fn validate_uncached(
+        &self,
+        token: &str,
+        timing: &mut ServerTiming,
+    ) -> Result<Claims, ValidationError> {
+        if token.trim().is_empty() {
+            return Err(ValidationError::EmptyToken);
+        }
+
+        #[cfg(feature = "jwe")]
+        let decrypted;
+        #[cfg(feature = "jwe")]
+        let token = match &self.jwe {
+            Some(decryptor) if crate::jwe::is_jwe(token) => {
+                decrypted = decryptor.decrypt(token)?;
+                decrypted.as_str()
+            }
+            _ => token,
+        };
+
+        #[cfg(feature = "insecure-dev")]
+        if self.insecure_no_verify {
+            let started = Instant::now();
+            let claims = self
+                .decode_unverified(token)
+                .and_then(|claims| self.check_claims(claims));
+            timing.record("decode", started.elapsed());
+            return claims;
+        }
+
+        #[cfg(feature = "insecure-dev")]
+        if let Some(secret) = &self.hs256_secret {
+            let header =
+                jsonwebtoken::decode_header(token).map_err(|_| ValidationError::InvalidHeader)?;
+            if header.alg == Algorithm::HS256 {
+                let started = Instant::now();
+                let claims = self.decode_hs256(token, secret);
+                timing.record("decode", started.elapsed());
+                return claims;
+            }
+        }
+
+        let jwks = self.jwks_for(token);
+        let started = Instant::now();
+        let keys = if self.fail_fast_on_cold_jwks {
+            jwks.get_keys_or_warm().await
+        } else {
+            jwks.get_keys().await
+        };
+        timing.record("jwks-lookup", started.elapsed());
+        let keys = keys?;
+
+        let started = Instant::now();
+        let claims = self.decode_verified(token, jwks, &keys);
+        timing.record("decode", started.elapsed());
+        let Err(ValidationError::UnknownKid) = claims else {
+            return claims;
+        };
+
+        // The key may have been rotated in since the keys were fetched
+        let kid = jsonwebtoken::decode_header(token)
+            .ok()
+            .and_then(|header| header.kid)
+            .ok_or(ValidationError::UnknownKid)?;
+        let started = Instant::now();
+        let keys = jwks.refresh_for_unknown_kid(&kid).await;
+        timing.record("jwks-refresh", started.elapsed());
+        match keys {
+            Some(keys) if keys.contains_key(&kid) => self.decode_verified(token, jwks, &keys),
+            _ => Err(ValidationError::UnknownKid),
+        }
+    }
+
+    /// Verifies the signature of `token` with the key for its `kid` among `keys` and checks its
+    /// claims.
+    fn decode_verified(
+        &self,
+        token: &str,
+        jwks: &Arc<JwksCache>,
+        keys: &Arc<HashMap<String, DecodingKey>>,
+    ) -> Result<Claims, ValidationError> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|_| ValidationError::InvalidHeader)?;
+        debug!("Header: {:#?}", header);
+        let kid = header.kid.ok_or(ValidationError::MissingKid)?;
+        debug!("KID: {}", kid);
+        let decoding_key = keys.get(&kid).ok_or_else(|| {
+            debug!(
+                "{}: kid {} (alg {:?}) is not among the {} cached keys",
+                ValidationError::UnknownKid.code(),
+                kid,
+                header.alg,
+                keys.len()
+            );
+            ValidationError::UnknownKid
+        })?;
+        if !self.algorithms.contains(&header.alg) {
+            debug!("Algorithm {:?} is not accepted", header.alg);
+            return Err(ValidationError::InvalidToken);
+        }
+        let validation = self.prepared_validation(jwks, keys, &kid, header.alg);
+        let token_data = decode::<Claims>(token, decoding_key, &validation).map_err(|e| {
+            let err = match (self.missing_required_claim(&e), e.kind()) {
+                (Some(claim), _) => ValidationError::MissingRequiredClaim(claim),
+                // The signature is valid, so the issuer minted claims `Claims` cannot read
+                (None, ErrorKind::Json(_)) => ValidationError::MalformedClaims,
+                (None, _) => ValidationError::InvalidToken,
+            };
+            self.log_decode_error(err, &e);
+            debug!("Rejected token had kid {} and alg {:?}", kid, header.alg);
+            err
+        })?;
+        debug!("Token: {:#?}", token_data);
+        jwks.record_use(&kid);
+
+        self.check_claims(token_data.claims)
+    }
+
+    /// The key set for `token`: the one registered for its issuer, or the default one.
+    fn jwks_for(&self, token: &str) -> &Arc<JwksCache> {
+        if self.issuer_jwks.is_empty() {
+            return &self.jwks;
+        }
+        let issuer = peek_issuer(token);
+        self.issuer_jwks
+            .iter()
+            .find(|(candidate, _)| Some(candidate) == issuer.as_ref())
+            .map(|(_, jwks)| jwks)
+            .unwrap_or(&self.jwks)
+    }
+
+    /// Returns the validation rules for tokens signed by `kid` with `algorithm`, building them
+    /// only on the first use of the kid since `keys` were fetched.
+    fn prepared_validation(
+        &self,
+        jwks: &Arc<JwksCache>,
+        keys: &Arc<HashMap<String, DecodingKey>>,
+        kid: &str,
+        algorithm: Algorithm,
+    ) -> Arc<Validation> {
+        let cache_id = Arc::as_ptr(jwks) as usize;
+        if let Some(set) = self
+            .prepared
+            .0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&cache_id)
+        {
+            if Arc::ptr_eq(&set.keys, keys) {
+                if let Some((cached_algorithm, validation)) = set.entries.get(kid) {
+                    if *cached_algorithm == algorithm {
+                        return validation.clone();
+                    }
+                }
+            }
+        }
+
+        let validation = Arc::new(self.validation(algorithm));
+        let mut prepared = self.prepared.0.write().unwrap_or_else(|e| e.into_inner());
+        let set = prepared.entry(cache_id).or_insert_with(|| PreparedSet {
+            keys: keys.clone(),
+            entries: HashMap::new(),
+        });
+        if !Arc::ptr_eq(&set.keys, keys) {
+            set.keys = keys.clone();
+            set.entries.clear();
+        }
+        set.entries
+            .insert(kid.to_string(), (algorithm, validation.clone()));
+        validation
+    }
+
+    /// The required claim a decode error complains about, if any.
+    ///
+    /// `aud`, `iss` and `sub` are non-optional fields of [`Claims`], so a token missing them
+    /// fails deserialization before `jsonwebtoken` checks the required claims; the serde
+    /// "missing field" error is mapped back to the claim here.
+    fn missing_required_claim(&self, error: &jsonwebtoken::errors::Error) -> Option<&'static str> {
+        let claim = match error.kind() {
+            ErrorKind::MissingRequiredClaim(claim) => claim.clone(),
+            ErrorKind::Json(e) => e
+                .to_string()
+                .strip_prefix("missing field `")?
+                .split('`')
+                .next()?
+                .to_string(),
+            _ => return None,
+        };
+        ["exp"]
+            .iter()
+            .chain(&self.required_claims)
+            .find(|required| **required == claim)
+            .copied()
+    }
+
+    /// Builds the `jsonwebtoken` validation rules for `algorithm`.
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.algorithms = self.algorithms.clone();
+        // `exp` and `nbf` are checked against `clock` in `check_claims`
+        validation.validate_exp = false;
+        validation.validate_nbf = false;
+        // `jsonwebtoken` only reads `exp` as an unsigned integer; presence is enforced by `Claims`,
+        // which accepts any NumericDate
+        let required: Vec<&str> = self
+            .required_claims
+            .iter()
+            .copied()
+            .filter(|claim| *claim != "exp")
+            .collect();
+        validation.set_required_spec_claims(&required);
+        if self.check_audience {
+            let audiences: Vec<String> = self
+                .audiences
+                .iter()
+                .flat_map(|audience| audience_aliases(audience))
+                .collect();
+            validation.set_audience(&audiences);
+        } else {
+            validation.validate_aud = false;
+        }
+        if !self.issuers.is_empty() {
+            let partners = self.issuer_jwks.iter().map(|(issuer, _)| issuer);
+            let issuers: Vec<&String> = self.issuers.iter().chain(partners).collect();
+            validation.set_issuer(&issuers);
+        }
+        validation
+    }
+
+    /// Checks `exp`, `nbf` and `iat` against the clock, and the claims that `jsonwebtoken` does not cover.
+    fn check_claims(&self, claims: Claims) -> Result<Claims, ValidationError> {
+        let now = i64::try_from(self.clock.unix_now()).unwrap_or(i64::MAX);
+        let leeway = Self::LEEWAY_SECS as i64;
+        if claims.exp.saturating_add(leeway) < now {
+            debug!("Token expired at {}, now is {}", claims.exp, now);
+            return Err(ValidationError::InvalidToken);
+        }
+        if let Some(nbf) = claims.nbf {
+            if nbf > now.saturating_add(leeway) {
+                debug!("Token is not valid before {}, now is {}", nbf, now);
+                return Err(ValidationError::InvalidToken);
+            }
+        }
+        if let Some(iat) = claims.iat {
+            // Issuers never date tokens ahead, so even with a valid `nbf` this is suspect
+            if iat > now.saturating_add(leeway) {
+                debug!(
+                    "{}: iat {}, now is {}",
+                    ValidationError::TokenIssuedInFuture.code(),
+                    iat,
+                    now
+                );
+                return Err(ValidationError::TokenIssuedInFuture);
+            }
+        }
+        self.check_version_issuer(&claims)?;
+        self.check_subject(&claims)?;
+        if let Some(required) = &self.required_token_version {
+            if claims.ver.as_deref() != Some(required.as_str()) {
+                debug!(
+                    "{}: expected ver {}, got {:?}",
+                    ValidationError::UnsupportedTokenVersion.code(),
+                    required,
+                    claims.ver
+                );
+                return Err(ValidationError::UnsupportedTokenVersion);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// Logs why `jsonwebtoken` rejected a token, unless the sampler leaves this one out.
+    fn log_decode_error(&self, err: ValidationError, e: &jsonwebtoken::errors::Error) {
+        if self
+            .log_sampler
+            .as_ref()
+            .is_none_or(|sampler| sampler.should_log(err.code()))
+        {
+            error!("Error: {:#?}", e);
+        }
+    }
+
+    /// Checks that a tenant-issued token carries the issuer of its `ver`.
+    fn check_version_issuer(&self, claims: &Claims) -> Result<(), ValidationError> {
+        let Some(ver) = claims.ver.as_deref() else {
+            return Ok(());
+        };
+        let is_tenant_issuer = self
+            .version_issuers
+            .iter()
+            .any(|(_, issuer)| *issuer == claims.iss);
+        let mut expected = self
+            .version_issuers
+            .iter()
+            .filter(|(version, _)| version == ver)
+            .map(|(_, issuer)| issuer)
+            .peekable();
+        if !is_tenant_issuer || expected.peek().is_none() {
+            return Ok(());
+        }
+        if expected.any(|issuer| *issuer == claims.iss) {
+            return Ok(());
+        }
+        debug!(
+            "{}: ver {} with issuer {}",
+            ValidationError::IssuerVersionMismatch.code(),
+            ver,
+            claims.iss
+        );
+        Err(ValidationError::IssuerVersionMismatch)
+    }
+
+    /// Checks the `sub` against the allowed subjects, if any.
+    fn check_subject(&self, claims: &Claims) -> Result<(), ValidationError> {
+        if self.allowed_subjects.is_empty() {
+            return Ok(());
+        }
+        let allowed = self
+            .allowed_subjects
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => claims.sub.starts_with(prefix),
+                None => *allowed == claims.sub,
+            });
+        if allowed {
+            return Ok(());
+        }
+        debug!(
+            "{}: {}",
+            ValidationError::SubjectNotAllowed.code(),
+            claims.sub
+        );
+        Err(ValidationError::SubjectNotAllowed)
+    }
+
+    /// Decodes the claims WITHOUT verifying the signature. Only compiled with `insecure-dev`.
+    #[cfg(feature = "insecure-dev")]
+    fn decode_unverified(&self, token: &str) -> Result<Claims, ValidationError> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|_| ValidationError::InvalidHeader)?;
+        let mut validation = self.validation(header.alg);
+        validation.insecure_disable_signature_validation();
+        warn!("INSECURE: accepting token without signature verification");
+        decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                self.log_decode_error(ValidationError::InvalidToken, &e);
+                ValidationError::InvalidToken
+            })
+    }
+
+    /// Verifies an HS256 `token` with the shared `secret` and checks its claims. Only compiled
+    /// with `insecure-dev`.
+    #[cfg(feature = "insecure-dev")]
+    fn decode_hs256(&self, token: &str, secret: &[u8]) -> Result<Claims, ValidationError> {
+        let mut validation = self.validation(Algorithm::HS256);
+        validation.algorithms = vec![Algorithm::HS256];
+        let claims = decode::<Claims>(token, &DecodingKey::from_secret(secret), &validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                self.log_decode_error(ValidationError::InvalidToken, &e);
+                ValidationError::InvalidToken
+            })?;
+        self.check_claims(claims)
+    }
+}
+
+/// Verifies bearer tokens and returns their claims.
+///
+/// `BearerAuthConfig` validates tokens through this trait, so an app can put another
+/// implementation behind the middleware with `BearerAuthConfig::with_token_validator`, e.g. a
+/// test double accepting fixed tokens. `JwtValidator` is the implementation used otherwise.
+pub trait TokenValidator: Send + Sync {
+    /// Validates `token`, recording the time spent in each phase in `timing`.
+    fn validate_timed<'a>(
+        &'a self,
+        token: &'a str,
+        timing: &'a mut ServerTiming,
+    ) -> BoxFuture<'a, Result<Claims, ValidationError>>;
+
+    /// Validates `token`.
+    fn validate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Claims, ValidationError>> {
+        Box::pin(async move {
+            self.validate_timed(token, &mut ServerTiming::default())
+                .await
+        })
+    }
+}
+
+impl TokenValidator for JwtValidator {
+    fn validate_timed<'a>(
+        &'a self,
+        token: &'a str,
+        timing: &'a mut ServerTiming,
+    ) -> BoxFuture<'a, Result<Claims, ValidationError>> {
+        Box::pin(JwtValidator::validate_timed(self, token, timing))
+    }
+}
+
+/// The application ID of Microsoft Graph, the `aud` of v1.0 tokens issued for Graph.
+pub const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
+
+/// The resource URIs of Microsoft Graph in the public and national clouds.
+const GRAPH_RESOURCE_URIS: [&str; 4] = [
+    "https://graph.microsoft.com",
+    "https://graph.microsoft.us",
+    "https://dod-graph.microsoft.us",
+    "https://microsoftgraph.chinacloudapi.cn",
+];
+
+/// The `aud` values a token for `audience` may carry.
+///
+/// Microsoft Graph is named by its app ID or its resource URI, with or without a trailing
+/// slash, depending on how the token was requested; configuring any of these forms accepts all
+/// of them. Every other audience only matches itself.
+pub fn audience_aliases(audience: &str) -> Vec<String> {
+    let is_graph = audience.eq_ignore_ascii_case(GRAPH_APP_ID)
+        || GRAPH_RESOURCE_URIS
+            .iter()
+            .any(|uri| audience.trim_end_matches('/').eq_ignore_ascii_case(uri));
+    if !is_graph {
+        return vec![audience.to_string()];
+    }
+    std::iter::once(GRAPH_APP_ID.to_string())
+        .chain(
+            GRAPH_RESOURCE_URIS
+                .iter()
+                .flat_map(|uri| [uri.to_string(), format!("{}/", uri)]),
+        )
+        .collect()
+}
+
+/// The equivalent forms of an app's own audience: its client ID GUID and its default App ID
+/// URI `api://<client ID>`, which Entra ID puts in `aud` depending on how the token was
+/// requested. Either form yields both; any other audience only yields itself.
+pub fn client_id_audiences(audience: &str) -> Vec<String> {
+    let client_id = audience.strip_prefix("api://").unwrap_or(audience);
+    if uuid::Uuid::try_parse(client_id).is_err() {
+        return vec![audience.to_string()];
+    }
+    vec![client_id.to_string(), format!("api://{}", client_id)]
+}
+
+/// Reads the `iss` claim of a token WITHOUT verifying it, to pick the key set to verify it with.
+pub fn peek_issuer(token: &str) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct IssuerOnly {
+        iss: Option<String>,
+    }
+
+    token
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<IssuerOnly>(&payload).ok())
+        .and_then(|claims| claims.iss)
+}
+
+/// Reads the `aud` claim(s) of a token WITHOUT verifying it.
+///
+/// Only meant for routing a token to the right validation profile; the selected validator still
+/// verifies the signature and the audience.
+pub fn peek_audiences(token: &str) -> Vec<String> {
+    #[derive(serde::Deserialize)]
+    struct AudienceOnly {
+        #[serde(default, deserialize_with = "crate::claims::one_or_many")]
+        aud: Vec<String>,
+    }
+
+    token
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<AudienceOnly>(&payload).ok())
+        .map(|claims| claims.aud)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestTokenFactory;
+    use std::sync::Mutex;
+
+    /// A clock the test moves by hand.
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<SystemTime>>);
+
+    impl ManualClock {
+        fn set(&self, now: SystemTime) {
+            *self.0.lock().unwrap() = now;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// The phases `validator` went through for `token`: `claims-cache` alone for a cache hit.
+    async fn phases(validator: &JwtValidator, token: &str) -> Vec<&'static str> {
+        let mut timing = ServerTiming::default();
+        validator.validate_timed(token, &mut timing).await.unwrap();
+        timing.phases().iter().map(|(name, _)| *name).collect()
+    }
+
+    fn claims(exp: i64) -> Claims {
+        serde_json::from_value(serde_json::json!({
+            "aud": "api://test",
+            "iss": "https://sts.windows.net/test-tenant/",
+            "sub": "test-subject",
+            "exp": exp,
+        }))
+        .unwrap()
+    }
+
+    fn key(index: usize) -> [u8; 32] {
+        let mut key = [0; 32];
+        key[..8].copy_from_slice(&(index as u64).to_be_bytes());
+        key
+    }
+
+    #[tokio::test]
+    async fn repeated_tokens_are_served_from_the_cache() {
+        let factory = TestTokenFactory::new().unwrap();
+        let validator = factory
+            .validator()
+            .unwrap()
+            .with_claims_cache(Duration::from_secs(60));
+        let token = factory.token().sign().unwrap();
+        assert!(phases(&validator, &token).await.contains(&"decode"));
+        assert_eq!(phases(&validator, &token).await, ["claims-cache"]);
+
+        let other = factory.token().with_subject("other").sign().unwrap();
+        assert!(phases(&validator, &other).await.contains(&"decode"));
+    }
+
+    #[tokio::test]
+    async fn entries_expire_a_margin_before_the_token() {
+        let factory = TestTokenFactory::new().unwrap();
+        let now = SystemTime::now();
+        let clock = ManualClock(Arc::new(Mutex::new(now)));
+        let validator = factory
+            .validator()
+            .unwrap()
+            .with_clock(clock.clone())
+            .with_claims_cache(Duration::from_secs(3600))
+            .with_claims_cache_margin(Duration::from_secs(60));
+        let token = factory.token().expires_in(300).sign().unwrap();
+        let exp =
+            UNIX_EPOCH + Duration::from_secs(validator.validate(&token).await.unwrap().exp as u64);
+
+        clock.set(exp - Duration::from_secs(61));
+        assert_eq!(phases(&validator, &token).await, ["claims-cache"]);
+        // Still a valid token, verified again and no longer cached
+        clock.set(exp - Duration::from_secs(60));
+        assert!(phases(&validator, &token).await.contains(&"decode"));
+        assert!(phases(&validator, &token).await.contains(&"decode"));
+    }
+
+    #[test]
+    fn entries_never_outlive_the_margin() {
+        let cache = ClaimsCache::new(Duration::from_secs(3600));
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let margin = Duration::from_secs(60);
+        cache.insert(key(0), &claims(1_000_100), now, margin);
+        assert!(cache.get(&key(0), now + Duration::from_secs(39)).is_some());
+        assert!(cache.get(&key(0), now + Duration::from_secs(40)).is_none());
+
+        cache.insert(key(1), &claims(1_000_060), now, margin);
+        assert!(cache.get(&key(1), now).is_none());
+    }
+
+    #[test]
+    fn full_caches_drop_expired_entries_then_everything() {
+        let cache = ClaimsCache::new(Duration::from_secs(60));
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let later = now + Duration::from_secs(30);
+        let half = ClaimsCache::CAPACITY / 2;
+        for index in 0..half {
+            cache.insert(key(index), &claims(1_000_020), now, Duration::ZERO);
+        }
+        for index in half..ClaimsCache::CAPACITY {
+            cache.insert(key(index), &claims(2_000_000), now, Duration::ZERO);
+        }
+        cache.insert(
+            key(ClaimsCache::CAPACITY),
+            &claims(2_000_000),
+            later,
+            Duration::ZERO,
+        );
+        let entries = |cache: &ClaimsCache| cache.entries.read().unwrap().len();
+        assert_eq!(entries(&cache), ClaimsCache::CAPACITY - half + 1);
+        assert!(cache.get(&key(0), now).is_none());
+        assert!(cache.get(&key(half), later).is_some());
+
+        let cache = ClaimsCache::new(Duration::from_secs(60));
+        for index in 0..ClaimsCache::CAPACITY {
+            cache.insert(key(index), &claims(2_000_000), now, Duration::ZERO);
+        }
+        cache.insert(key(0), &claims(2_000_000), later, Duration::ZERO);
+        assert_eq!(entries(&cache), ClaimsCache::CAPACITY);
+        cache.insert(
+            key(ClaimsCache::CAPACITY),
+            &claims(2_000_000),
+            later,
+            Duration::ZERO,
+        );
+        assert_eq!(entries(&cache), 1);
+        assert!(cache.get(&key(ClaimsCache::CAPACITY), later).is_some());
+    }
+}
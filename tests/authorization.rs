@@ -0,0 +1,189 @@
+//! Callers let through or refused by the authorization checks of `BearerAuthConfig`.
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use managed_identity_concept::testing::{TestTokenBuilder, TestTokenFactory};
+use managed_identity_concept::{BearerAuth, BearerAuthConfig};
+
+/// The status and `error` answered by `config` to a caller with the token from `token`.
+async fn outcome(
+    config: fn(BearerAuthConfig) -> BearerAuthConfig,
+    token: fn(TestTokenBuilder<'_>) -> TestTokenBuilder<'_>,
+) -> (StatusCode, Option<String>) {
+    let factory = TestTokenFactory::new().expect("generate the test key");
+    let auth = config(BearerAuthConfig::new(
+        factory.validator().expect("write the JWKS"),
+    ));
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/api_protected")
+                .wrap(BearerAuth::new(auth))
+                .to(HttpResponse::Ok),
+        ),
+    )
+    .await;
+    let token = token(factory.token()).sign().unwrap();
+    let request = test::TestRequest::get()
+        .uri("/api_protected")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+    let error = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string));
+    (status, error)
+}
+
+fn require_mfa(config: BearerAuthConfig) -> BearerAuthConfig {
+    config.with_mfa_required(true)
+}
+
+#[actix_web::test]
+async fn mfa_tokens_pass_the_mfa_requirement() {
+    let (status, _) = outcome(require_mfa, |token| {
+        token
+            .with_scopes(&["Tasks.Read"])
+            .with_claim("amr", vec!["pwd", "mfa"])
+    })
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn tokens_without_mfa_are_refused() {
+    let (status, error) = outcome(require_mfa, |token| {
+        token
+            .with_scopes(&["Tasks.Read"])
+            .with_claim("amr", vec!["pwd"])
+    })
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("mfa_required"));
+
+    // App-only tokens never carry amr
+    let (status, error) = outcome(require_mfa, |token| token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("mfa_required"));
+}
+
+#[actix_web::test]
+async fn mfa_is_not_required_by_default() {
+    let (status, _) = outcome(
+        |config| config,
+        |token| token.with_claim("amr", vec!["pwd"]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+fn allow_tenant_app(config: BearerAuthConfig) -> BearerAuthConfig {
+    config.with_allowed_tenant_apps(vec![(
+        "test-tenant".to_string(),
+        "11111111-1111-1111-1111-111111111111".to_string(),
+    )])
+}
+
+#[actix_web::test]
+async fn allowed_tenant_app_pairs_pass() {
+    let (status, _) = outcome(allow_tenant_app, |token| {
+        token.with_claim("azp", "11111111-1111-1111-1111-111111111111")
+    })
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // v1.0 tokens name the client in appid, and IDs are compared ignoring case
+    let (status, _) = outcome(allow_tenant_app, |token| {
+        token
+            .with_claim("tid", "TEST-TENANT")
+            .with_claim("appid", "11111111-1111-1111-1111-111111111111")
+    })
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn the_right_app_from_the_wrong_tenant_is_refused() {
+    let (status, error) = outcome(allow_tenant_app, |token| {
+        token
+            .with_claim("tid", "other-tenant")
+            .with_claim("azp", "11111111-1111-1111-1111-111111111111")
+    })
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("tenant_app_not_allowed"));
+}
+
+#[actix_web::test]
+async fn the_wrong_app_from_the_right_tenant_is_refused() {
+    let (status, error) = outcome(allow_tenant_app, |token| {
+        token.with_claim("azp", "22222222-2222-2222-2222-222222222222")
+    })
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("tenant_app_not_allowed"));
+
+    let (status, _) = outcome(allow_tenant_app, |token| token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+fn require_managed_identity(config: BearerAuthConfig) -> BearerAuthConfig {
+    config.with_managed_identity_required(true)
+}
+
+#[actix_web::test]
+async fn managed_identity_tokens_pass() {
+    let (status, _) = outcome(require_managed_identity, |token| {
+        token
+            .with_claim("idtyp", "app")
+            .with_claim("azpacr", "2")
+            .with_claim(
+                "xms_mirid",
+                "/subscriptions/s/resourcegroups/g/providers/Microsoft.ManagedIdentity/userAssignedIdentities/i",
+            )
+    })
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // v1.0 tokens may lack idtyp and xms_mirid; certificate client authentication still counts
+    let (status, _) = outcome(require_managed_identity, |token| {
+        token.with_claim("appidacr", "2")
+    })
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn user_tokens_are_refused() {
+    let (status, error) = outcome(require_managed_identity, |token| {
+        token
+            .with_claim("idtyp", "user")
+            .with_scopes(&["user_impersonation"])
+            .with_claim("upn", "user@example.com")
+            .with_claim("azpacr", "2")
+    })
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("managed_identity_required"));
+
+    // User claims give a token away even without idtyp or scp
+    let (status, error) = outcome(require_managed_identity, |token| {
+        token
+            .with_claim("azpacr", "2")
+            .with_claim("preferred_username", "user@example.com")
+    })
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("managed_identity_required"));
+}
+
+#[actix_web::test]
+async fn app_tokens_authenticated_with_a_secret_are_refused() {
+    let (status, error) = outcome(require_managed_identity, |token| {
+        token.with_claim("idtyp", "app").with_claim("azpacr", "1")
+    })
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error.as_deref(), Some("managed_identity_required"));
+}
@@ -0,0 +1,100 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a `MockServer` answers and how often it was asked.
+///
+/// # Fields
+///
+/// * `requests` - The requests received so far.
+/// * `body` - The JSON answered with 200.
+/// * `statuses` - Statuses answered, with an empty body, before `body` is answered again.
+/// * `delay` - How long each request waits before it is answered.
+struct MockState {
+    requests: AtomicU64,
+    body: Mutex<serde_json::Value>,
+    statuses: Mutex<VecDeque<u16>>,
+    delay: Mutex<Duration>,
+}
+
+/// An HTTP server on a free local port answering every request with a settable JSON body,
+/// standing in for a JWKS endpoint or other upstream. Stops when dropped.
+pub struct MockServer {
+    pub url: String,
+    state: web::Data<MockState>,
+    handle: ServerHandle,
+}
+
+impl MockServer {
+    /// Starts a server answering `body`. Must be called on an actix runtime.
+    pub fn start(body: serde_json::Value) -> Self {
+        let state = web::Data::new(MockState {
+            requests: AtomicU64::new(0),
+            body: Mutex::new(body),
+            statuses: Mutex::new(VecDeque::new()),
+            delay: Mutex::new(Duration::ZERO),
+        });
+        let app_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_state.clone())
+                .default_service(web::to(answer))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .expect("bind the mock server");
+        let url = format!("http://{}/", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        Self { url, state, handle }
+    }
+
+    /// The requests received so far.
+    pub fn requests(&self) -> u64 {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+
+    /// Answers `body` from now on.
+    pub fn set_body(&self, body: serde_json::Value) {
+        *self.state.body.lock().unwrap() = body;
+    }
+
+    /// Answers the next requests with `statuses`, one each, before answering the body again.
+    pub fn fail_next(&self, statuses: &[u16]) {
+        self.state.statuses.lock().unwrap().extend(statuses);
+    }
+
+    /// Waits `delay` before answering each request.
+    pub fn set_delay(&self, delay: Duration) {
+        *self.state.delay.lock().unwrap() = delay;
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        let handle = self.handle.clone();
+        actix_web::rt::spawn(async move { handle.stop(false).await });
+    }
+}
+
+async fn answer(state: web::Data<MockState>) -> HttpResponse {
+    state.requests.fetch_add(1, Ordering::SeqCst);
+    let delay = *state.delay.lock().unwrap();
+    tokio::time::sleep(delay).await;
+    let status = state.statuses.lock().unwrap().pop_front();
+    match status {
+        Some(status) => HttpResponse::build(
+            actix_web::http::StatusCode::from_u16(status).expect("a valid status"),
+        )
+        .finish(),
+        None => HttpResponse::Ok().json(state.body.lock().unwrap().clone()),
+    }
+}
@@ -0,0 +1,223 @@
+//! The 403 bodies for callers failing an authorization check, with and without diagnostics.
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use managed_identity_concept::testing::{TestTokenBuilder, TestTokenFactory};
+use managed_identity_concept::{BearerAuth, BearerAuthConfig};
+
+async fn forbidden_body(diagnostics: bool) -> serde_json::Value {
+    let factory = TestTokenFactory::new().expect("generate the test key");
+    let config = BearerAuthConfig::new(factory.validator().expect("write the JWKS"))
+        .with_required_roles(vec!["Task.HelloWorld".to_string()])
+        .with_mfa_required(true)
+        .with_diagnostics(diagnostics);
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/api_protected")
+                .wrap(BearerAuth::new(config))
+                .to(HttpResponse::Ok),
+        ),
+    )
+    .await;
+    let token = factory
+        .token()
+        .with_roles(&["Task.Other"])
+        .with_claim("amr", vec!["pwd", "mfa"])
+        .sign()
+        .unwrap();
+    let request = test::TestRequest::get()
+        .uri("/api_protected")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    test::read_body_json(response).await
+}
+
+#[actix_web::test]
+async fn missing_roles_have_their_own_error() {
+    let body = forbidden_body(false).await;
+    assert_eq!(body["error"], "role_missing");
+    assert_eq!(
+        body["error_description"],
+        "Token holds none of the required roles"
+    );
+    assert!(body.get("checks").is_none());
+}
+
+#[actix_web::test]
+async fn diagnostics_list_every_check() {
+    let body = forbidden_body(true).await;
+    assert_eq!(body["error"], "role_missing");
+    assert_eq!(body["required"], serde_json::json!(["Task.HelloWorld"]));
+    assert_eq!(body["present"], serde_json::json!(["Task.Other"]));
+    assert_eq!(
+        body["checks"],
+        serde_json::json!([
+            { "check": "mfa", "passed": true },
+            { "check": "roles", "passed": false, "error": "role_missing" },
+        ])
+    );
+}
+
+/// Checks that a caller with the token from `token`, refused by the single check `config` adds,
+/// gets a 403 with `error`, and that diagnostics mode lists `check` as the one failure with the
+/// same error.
+async fn assert_denied(
+    config: fn(BearerAuthConfig) -> BearerAuthConfig,
+    token: fn(TestTokenBuilder<'_>) -> TestTokenBuilder<'_>,
+    check: &str,
+    error: &str,
+) {
+    let factory = TestTokenFactory::new().expect("generate the test key");
+    let token = token(factory.token()).sign().unwrap();
+    for diagnostics in [false, true] {
+        let auth = config(BearerAuthConfig::new(
+            factory.validator().expect("write the JWKS"),
+        ))
+        .with_diagnostics(diagnostics);
+        let app = test::init_service(
+            App::new().service(
+                web::resource("/api_protected")
+                    .wrap(BearerAuth::new(auth))
+                    .to(HttpResponse::Ok),
+            ),
+        )
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/api_protected")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", check);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(
+            body["error"], error,
+            "{} with diagnostics {}",
+            check, diagnostics
+        );
+        if diagnostics {
+            assert_eq!(
+                body["checks"],
+                serde_json::json!([{ "check": check, "passed": false, "error": error }])
+            );
+        }
+    }
+}
+
+#[actix_web::test]
+async fn app_only_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_app_only(true),
+        |token| token.with_scopes(&["Tasks.Read"]),
+        "app_only",
+        "app_only_required",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn managed_identity_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_managed_identity_required(true),
+        |token| token.with_claim("azpacr", "1"),
+        "managed_identity",
+        "managed_identity_required",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn cert_client_auth_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_cert_client_auth(true),
+        |token| token.with_claim("azpacr", "1"),
+        "cert_client_auth",
+        "cert_client_auth_required",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn scope_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_required_scopes(vec!["Tasks.Write".to_string()]),
+        |token| token.with_scopes(&["Tasks.Read"]),
+        "scopes",
+        "insufficient_scope",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn directory_role_denials_have_one_error() {
+    assert_denied(
+        |config| {
+            config.with_required_wids(vec!["62e90394-69f5-4237-9190-012177145e10".to_string()])
+        },
+        |token| token.with_claim("wids", vec!["fe930be7-5e62-47db-91af-98c3a49a38b1"]),
+        "directory_roles",
+        "directory_role_missing",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn mfa_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_mfa_required(true),
+        |token| token.with_claim("amr", vec!["pwd"]),
+        "mfa",
+        "mfa_required",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn claim_value_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_required_claim_value("env", "prod"),
+        |token| token.with_claim("env", "test"),
+        "claim:env",
+        "custom_claim_mismatch",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn tenant_app_denials_have_one_error() {
+    assert_denied(
+        |config| {
+            config.with_allowed_tenant_apps(vec![(
+                "test-tenant".to_string(),
+                "allowed-app".to_string(),
+            )])
+        },
+        |token| token.with_claim("azp", "other-app"),
+        "tenant_app",
+        "tenant_app_not_allowed",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn access_rule_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_access_rule("role:Task.Admin".parse().ok()),
+        |token| token.with_roles(&["Task.Other"]),
+        "access_rule",
+        "access_rule_denied",
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn role_denials_have_one_error() {
+    assert_denied(
+        |config| config.with_required_roles(vec!["Task.HelloWorld".to_string()]),
+        |token| token.with_roles(&["Task.Other"]),
+        "roles",
+        "role_missing",
+    )
+    .await;
+}
@@ -0,0 +1,93 @@
+//! The issuers accepted for the configured tenant.
+
+use managed_identity_concept::authority::{Authority, Cloud, TokenVersion};
+use managed_identity_concept::testing::TestTokenFactory;
+use managed_identity_concept::validator::JwtValidator;
+use std::sync::Arc;
+
+const TENANT: &str = "test-tenant";
+
+fn factory() -> TestTokenFactory {
+    TestTokenFactory::new().expect("generate the test key")
+}
+
+/// A validator accepting the tenant's issuers the way the server builds it.
+fn tenant_validator(factory: &TestTokenFactory) -> JwtValidator {
+    let authority = Authority::AzureAd {
+        tenant_id: TENANT.to_string(),
+        cloud: Cloud::Public,
+    };
+    JwtValidator::new(
+        Arc::new(factory.jwks_cache().expect("write the JWKS")),
+        factory.audience(),
+    )
+    .with_issuers(authority.issuers(&TokenVersion::ALL))
+    .with_version_issuers(authority.issuers_by_version())
+}
+
+#[actix_web::test]
+async fn v1_and_v2_tokens_for_the_tenant_validate() {
+    let factory = factory();
+    let validator = tenant_validator(&factory);
+    let v1 = factory
+        .token()
+        .with_issuer(&format!("https://sts.windows.net/{}/", TENANT))
+        .with_claim("ver", "1.0")
+        .sign()
+        .unwrap();
+    let v2 = factory
+        .token()
+        .with_issuer(&format!(
+            "https://login.microsoftonline.com/{}/v2.0",
+            TENANT
+        ))
+        .with_claim("ver", "2.0")
+        .sign()
+        .unwrap();
+
+    validator.validate(&v1).await.expect("v1.0 token");
+    validator.validate(&v2).await.expect("v2.0 token");
+}
+
+#[actix_web::test]
+async fn tokens_from_another_tenant_are_rejected() {
+    let factory = factory();
+    let validator = tenant_validator(&factory);
+    for (issuer, ver) in [
+        ("https://sts.windows.net/other-tenant/", "1.0"),
+        ("https://login.microsoftonline.com/other-tenant/v2.0", "2.0"),
+        ("https://issuer.example.com/", "2.0"),
+    ] {
+        let token = factory
+            .token()
+            .with_issuer(issuer)
+            .with_claim("ver", ver)
+            .sign()
+            .unwrap();
+        assert!(
+            validator.validate(&token).await.is_err(),
+            "accepted a token from {}",
+            issuer
+        );
+    }
+}
+
+#[actix_web::test]
+async fn for_tenant_accepts_only_the_tenant_issuers() {
+    let factory = factory();
+    let jwks = factory.jwks_cache().expect("write the JWKS");
+    let validator = JwtValidator::for_tenant(jwks.jwks_url(), factory.audience(), TENANT);
+    for issuer in [
+        format!("https://sts.windows.net/{}/", TENANT),
+        format!("https://login.microsoftonline.com/{}/v2.0", TENANT),
+    ] {
+        let token = factory.token().with_issuer(&issuer).sign().unwrap();
+        validator.validate(&token).await.expect(&issuer);
+    }
+    let foreign = factory
+        .token()
+        .with_issuer("https://sts.windows.net/other-tenant/")
+        .sign()
+        .unwrap();
+    assert!(validator.validate(&foreign).await.is_err());
+}
@@ -0,0 +1,362 @@
+//! `JwksCache` against a mock JWKS endpoint.
+
+mod common;
+
+use common::MockServer;
+use futures_util::future::join_all;
+use managed_identity_concept::jwks::{fetch_jwks_with_retry, JwksCache, JwksError, RetryPolicy};
+use managed_identity_concept::testing::TestTokenFactory;
+use managed_identity_concept::validator::JwtValidator;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn factory() -> TestTokenFactory {
+    TestTokenFactory::new().expect("generate the test key")
+}
+
+#[actix_web::test]
+async fn concurrent_cold_start_fetches_once() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    server.set_delay(Duration::from_millis(200));
+    let cache = Arc::new(JwksCache::new(&server.url, Duration::from_secs(3600)));
+
+    let results = join_all((0..20).map(|_| {
+        let cache = cache.clone();
+        async move { cache.get_keys().await }
+    }))
+    .await;
+
+    for keys in results {
+        assert!(keys.expect("keys").contains_key(factory.kid()));
+    }
+    assert_eq!(server.requests(), 1);
+    assert_eq!(cache.metrics().refreshes, 1);
+}
+
+#[actix_web::test]
+async fn stale_keys_are_served_when_a_refresh_fails() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    let cache = Arc::new(
+        JwksCache::new(&server.url, Duration::from_millis(100)).with_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+        }),
+    );
+    let validator = JwtValidator::new(cache.clone(), factory.audience())
+        .with_issuers(vec![factory.issuer().to_string()]);
+    let token = factory.token().sign().unwrap();
+    validator
+        .validate(&token)
+        .await
+        .expect("valid with fresh keys");
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    server.fail_next(&[503]);
+    validator
+        .validate(&token)
+        .await
+        .expect("valid with stale keys");
+
+    assert_eq!(server.requests(), 2);
+    let metrics = cache.metrics();
+    assert_eq!(metrics.refresh_failures, 1);
+    assert_eq!(metrics.served_stale, 1);
+    // The failed refresh is not retried on every request
+    validator
+        .validate(&token)
+        .await
+        .expect("valid with stale keys");
+    assert_eq!(server.requests(), 2);
+}
+
+#[actix_web::test]
+async fn transient_failures_are_retried() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    server.fail_next(&[503, 500]);
+    let retry_policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(10),
+    };
+
+    let keys = fetch_jwks_with_retry(&server.url, &retry_policy)
+        .await
+        .expect("fetched on the third attempt");
+
+    assert!(keys.contains_key(factory.kid()));
+    assert_eq!(server.requests(), 3);
+}
+
+#[actix_web::test]
+async fn client_errors_are_not_retried() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    server.fail_next(&[404]);
+    let retry_policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(10),
+    };
+
+    let result = fetch_jwks_with_retry(&server.url, &retry_policy).await;
+
+    assert!(matches!(result, Err(JwksError::Request(_))));
+    assert_eq!(server.requests(), 1);
+}
+
+/// The keys of both factories in one JWKS document.
+fn merged_jwks(first: &TestTokenFactory, second: &TestTokenFactory) -> serde_json::Value {
+    let mut keys = first.jwks_document()["keys"].as_array().unwrap().clone();
+    keys.extend(second.jwks_document()["keys"].as_array().unwrap().clone());
+    serde_json::json!({ "keys": keys })
+}
+
+#[actix_web::test]
+async fn unknown_kid_refetches_once() {
+    let current = factory().with_kid("current");
+    let rotated = factory().with_kid("rotated");
+    let server = MockServer::start(current.jwks_document());
+    let cache = Arc::new(JwksCache::new(&server.url, Duration::from_secs(3600)));
+    let validator = JwtValidator::new(cache.clone(), current.audience())
+        .with_issuers(vec![current.issuer().to_string()]);
+    validator
+        .validate(&current.token().sign().unwrap())
+        .await
+        .expect("valid with the current key");
+    assert_eq!(server.requests(), 1);
+
+    server.set_body(merged_jwks(&current, &rotated));
+    validator
+        .validate(&rotated.token().sign().unwrap())
+        .await
+        .expect("valid once the rotated key is fetched");
+    assert_eq!(server.requests(), 2);
+    assert_eq!(cache.metrics().unknown_kid_refreshes, 1);
+
+    // Another unknown kid within the interval does not fetch again
+    let bogus = rotated.token().with_kid(Some("bogus")).sign().unwrap();
+    assert!(validator.validate(&bogus).await.is_err());
+    assert_eq!(server.requests(), 2);
+    assert_eq!(cache.metrics().unknown_kid_refreshes, 1);
+}
+
+#[actix_web::test]
+async fn concurrent_unknown_kids_share_one_refetch() {
+    let current = factory().with_kid("current");
+    let rotated = factory().with_kid("rotated");
+    let server = MockServer::start(current.jwks_document());
+    let cache = Arc::new(JwksCache::new(&server.url, Duration::from_secs(3600)));
+    let validator = Arc::new(
+        JwtValidator::new(cache.clone(), current.audience())
+            .with_issuers(vec![current.issuer().to_string()]),
+    );
+    cache.get_keys().await.expect("keys");
+    server.set_body(merged_jwks(&current, &rotated));
+    server.set_delay(Duration::from_millis(200));
+
+    let token = rotated.token().sign().unwrap();
+    let results = join_all((0..20).map(|_| {
+        let validator = validator.clone();
+        let token = token.clone();
+        async move { validator.validate(&token).await }
+    }))
+    .await;
+
+    for result in results {
+        result.expect("valid once the rotated key is fetched");
+    }
+    assert_eq!(server.requests(), 2);
+    assert_eq!(cache.metrics().unknown_kid_refreshes, 1);
+}
+
+#[actix_web::test]
+async fn validation_never_fails_while_the_keys_are_refreshed() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    let cache = Arc::new(JwksCache::new(&server.url, Duration::from_secs(3600)));
+    let validator = JwtValidator::new(cache.clone(), factory.audience())
+        .with_issuers(vec![factory.issuer().to_string()]);
+    let token = factory.token().sign().unwrap();
+    cache.get_keys().await.expect("keys");
+    server.set_delay(Duration::from_millis(50));
+    let refreshing = AtomicBool::new(true);
+
+    let refresh = async {
+        for _ in 0..5 {
+            cache.refresh_now().await.expect("refreshed");
+        }
+        refreshing.store(false, Ordering::SeqCst);
+    };
+    let validate = async {
+        let mut validations = 0;
+        while refreshing.load(Ordering::SeqCst) {
+            validator
+                .validate(&token)
+                .await
+                .expect("valid during a refresh");
+            validations += 1;
+            tokio::task::yield_now().await;
+        }
+        validations
+    };
+    let ((), validations) = tokio::join!(refresh, validate);
+
+    assert!(validations > 5, "only {} validations", validations);
+    assert_eq!(cache.metrics().refreshes, 6);
+}
+
+/// A validator retaining retired keys for `retention`, whose JWKS rotated from an old key to a
+/// new one, with a token signed by each and the JWKS cache and server.
+async fn rotate_keys(
+    retention: Duration,
+) -> (JwtValidator, String, String, Arc<JwksCache>, MockServer) {
+    let old = factory().with_kid("old-key");
+    let new = factory().with_kid("new-key");
+    let server = MockServer::start(old.jwks_document());
+    let cache = Arc::new(
+        JwksCache::new(&server.url, Duration::from_secs(3600))
+            .with_retired_key_retention(retention),
+    );
+    let validator = JwtValidator::new(cache.clone(), old.audience())
+        .with_issuers(vec![old.issuer().to_string()]);
+    let old_token = old.token().sign().unwrap();
+    validator
+        .validate(&old_token)
+        .await
+        .expect("valid before the rotation");
+
+    server.set_body(new.jwks_document());
+    cache
+        .refresh_now()
+        .await
+        .expect("refresh after the rotation");
+    let new_token = new.token().sign().unwrap();
+    (validator, old_token, new_token, cache, server)
+}
+
+#[actix_web::test]
+async fn retired_keys_are_trusted_during_their_retention() {
+    let (validator, old_token, new_token, _cache, _server) =
+        rotate_keys(Duration::from_secs(600)).await;
+    validator
+        .validate(&old_token)
+        .await
+        .expect("valid with the retained key");
+    validator
+        .validate(&new_token)
+        .await
+        .expect("valid with the new key");
+}
+
+#[actix_web::test]
+async fn retired_keys_are_dropped_without_retention() {
+    let (validator, old_token, new_token, _cache, _server) = rotate_keys(Duration::ZERO).await;
+    assert!(validator.validate(&old_token).await.is_err());
+    validator
+        .validate(&new_token)
+        .await
+        .expect("valid with the new key");
+}
+
+#[actix_web::test]
+async fn retired_keys_are_dropped_after_their_retention() {
+    let (validator, old_token, _, cache, _server) = rotate_keys(Duration::from_millis(200)).await;
+    validator
+        .validate(&old_token)
+        .await
+        .expect("valid with the retained key");
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    cache
+        .refresh_now()
+        .await
+        .expect("refresh after the retention");
+    assert!(validator.validate(&old_token).await.is_err());
+}
+
+fn disk_cache_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("jwks-disk-cache-{}.json", uuid::Uuid::new_v4()))
+}
+
+#[actix_web::test]
+async fn refreshes_write_the_disk_cache() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    let path = disk_cache_path();
+    let cache = JwksCache::new(&server.url, Duration::from_secs(3600))
+        .with_disk_cache(&path, Duration::from_secs(3600));
+    cache.get_keys().await.expect("keys");
+
+    let saved: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&path).expect("the disk cache")).unwrap();
+    assert_eq!(saved["jwks_urls"], serde_json::json!([server.url]));
+    assert_eq!(
+        saved["documents"],
+        serde_json::json!([factory.jwks_document()])
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[actix_web::test]
+async fn cold_starts_load_a_fresh_disk_cache() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    let path = disk_cache_path();
+    let first = JwksCache::new(&server.url, Duration::from_secs(3600))
+        .with_disk_cache(&path, Duration::from_secs(3600));
+    first.get_keys().await.expect("keys");
+    assert_eq!(server.requests(), 1);
+
+    let restarted = Arc::new(
+        JwksCache::new(&server.url, Duration::from_secs(3600))
+            .with_disk_cache(&path, Duration::from_secs(3600)),
+    );
+    let validator = JwtValidator::new(restarted, factory.audience())
+        .with_issuers(vec![factory.issuer().to_string()]);
+    validator
+        .validate(&factory.token().sign().unwrap())
+        .await
+        .expect("valid with the keys from disk");
+    assert_eq!(server.requests(), 1);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[actix_web::test]
+async fn stale_or_foreign_disk_caches_are_ignored() {
+    let factory = factory();
+    let server = MockServer::start(factory.jwks_document());
+    let path = disk_cache_path();
+    let write = |urls: serde_json::Value, fetched_at: u64| {
+        let saved = serde_json::json!({
+            "jwks_urls": urls,
+            "fetched_at": fetched_at,
+            "documents": [factory.jwks_document()],
+        });
+        std::fs::write(&path, saved.to_string()).unwrap();
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let load = || async {
+        JwksCache::new(&server.url, Duration::from_secs(3600))
+            .with_disk_cache(&path, Duration::from_secs(600))
+            .get_keys()
+            .await
+            .expect("keys");
+    };
+
+    write(serde_json::json!([server.url]), now - 900);
+    load().await;
+    assert_eq!(server.requests(), 1);
+
+    write(serde_json::json!(["https://other.example.com/keys"]), now);
+    load().await;
+    assert_eq!(server.requests(), 2);
+
+    std::fs::write(&path, "not json").unwrap();
+    load().await;
+    assert_eq!(server.requests(), 3);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,127 @@
+//! `OutboundTokenCache` over a mock credential.
+
+use azure_core::auth::{AccessToken, Secret, TokenCredential};
+use futures_util::future::join_all;
+use managed_identity_concept::outbound::OutboundTokenCache;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A credential issuing tokens valid for `lifetime`, counting the calls per scope.
+#[derive(Debug)]
+struct MockCredential {
+    lifetime: time::Duration,
+    calls: Mutex<HashMap<String, u64>>,
+}
+
+impl MockCredential {
+    fn new(lifetime: time::Duration) -> Arc<Self> {
+        Arc::new(Self {
+            lifetime,
+            calls: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn calls(&self, scope: &str) -> u64 {
+        self.calls.lock().unwrap().get(scope).copied().unwrap_or(0)
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for MockCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(scopes[0].to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(AccessToken::new(
+            Secret::new(format!("{}#{}", scopes[0], call)),
+            time::OffsetDateTime::now_utc() + self.lifetime,
+        ))
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn tokens_are_reused_across_calls() {
+    let credential = MockCredential::new(time::Duration::hours(1));
+    let cache = OutboundTokenCache::new(credential.clone());
+
+    for _ in 0..5 {
+        let token = cache
+            .get_valid_token("api://orders/.default")
+            .await
+            .unwrap();
+        assert_eq!(token.expose(), "api://orders/.default#1");
+    }
+
+    assert_eq!(credential.calls("api://orders/.default"), 1);
+    assert_eq!(cache.acquisitions(), 1);
+}
+
+#[tokio::test]
+async fn each_resource_gets_its_own_token() {
+    let credential = MockCredential::new(time::Duration::hours(1));
+    let cache = Arc::new(OutboundTokenCache::new(credential.clone()));
+
+    let tokens = join_all((0..20).map(|call| {
+        let cache = cache.clone();
+        let resource = if call % 2 == 0 {
+            "api://orders/.default"
+        } else {
+            "api://billing/.default"
+        };
+        async move { cache.get_valid_token(resource).await.unwrap() }
+    }))
+    .await;
+
+    for (call, token) in tokens.iter().enumerate() {
+        let expected = if call % 2 == 0 {
+            "api://orders/.default#1"
+        } else {
+            "api://billing/.default#1"
+        };
+        assert_eq!(token.expose(), expected);
+    }
+    assert_eq!(credential.calls("api://orders/.default"), 1);
+    assert_eq!(credential.calls("api://billing/.default"), 1);
+    assert_eq!(cache.acquisitions(), 2);
+}
+
+#[tokio::test]
+async fn tokens_about_to_expire_or_invalidated_are_replaced() {
+    let credential = MockCredential::new(time::Duration::minutes(2));
+    let cache = OutboundTokenCache::new(credential.clone());
+
+    // Within the default 5 minute refresh margin, every call gets a new token
+    for _ in 0..3 {
+        cache
+            .get_valid_token("api://orders/.default")
+            .await
+            .unwrap();
+    }
+    assert_eq!(credential.calls("api://orders/.default"), 3);
+
+    let cache = OutboundTokenCache::new(credential.clone()).with_refresh_margin(Duration::ZERO);
+    cache
+        .get_valid_token("api://orders/.default")
+        .await
+        .unwrap();
+    cache
+        .get_valid_token("api://orders/.default")
+        .await
+        .unwrap();
+    assert_eq!(credential.calls("api://orders/.default"), 4);
+    cache.invalidate("api://orders/.default").await;
+    let token = cache
+        .get_valid_token("api://orders/.default")
+        .await
+        .unwrap();
+    assert_eq!(token.expose(), "api://orders/.default#5");
+}
@@ -0,0 +1,77 @@
+//! Requests authorized by an external policy engine, answered by a mock engine: only an
+//! explicit allow lets the caller through.
+
+#![cfg(feature = "policy-engine")]
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use common::MockServer;
+use managed_identity_concept::policy::PolicyEngine;
+use managed_identity_concept::testing::TestTokenFactory;
+use managed_identity_concept::{BearerAuth, BearerAuthConfig};
+use std::time::Duration;
+
+/// The status answered to a caller with a valid token when the engine is `engine`, which must
+/// have been asked once.
+async fn status_with(engine: &MockServer) -> StatusCode {
+    let factory = TestTokenFactory::new().expect("generate the test key");
+    let policy = PolicyEngine::new(engine.url.clone(), Duration::from_millis(500)).unwrap();
+    let config = BearerAuthConfig::new(factory.validator().expect("write the JWKS"))
+        .with_policy_engine(policy);
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/api_protected")
+                .wrap(BearerAuth::new(config))
+                .to(HttpResponse::Ok),
+        ),
+    )
+    .await;
+    let token = factory.token().sign().unwrap();
+    let request = test::TestRequest::get()
+        .uri("/api_protected")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let status = test::call_service(&app, request).await.status();
+    assert_eq!(engine.requests(), 1);
+    status
+}
+
+#[actix_web::test]
+async fn allowed_requests_reach_the_handler() {
+    let engine = MockServer::start(serde_json::json!({ "result": true }));
+    assert_eq!(status_with(&engine).await, StatusCode::OK);
+
+    let engine = MockServer::start(serde_json::json!({ "result": { "allow": true } }));
+    assert_eq!(status_with(&engine).await, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn denied_requests_are_forbidden() {
+    let engine = MockServer::start(serde_json::json!({ "result": false }));
+    assert_eq!(status_with(&engine).await, StatusCode::FORBIDDEN);
+
+    let engine = MockServer::start(serde_json::json!({ "result": { "allow": false } }));
+    assert_eq!(status_with(&engine).await, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn undefined_decisions_are_refused() {
+    let engine = MockServer::start(serde_json::json!({}));
+    assert_eq!(status_with(&engine).await, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn engine_errors_are_refused() {
+    let engine = MockServer::start(serde_json::json!({ "result": true }));
+    engine.fail_next(&[500]);
+    assert_eq!(status_with(&engine).await, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn slow_engines_are_refused() {
+    let engine = MockServer::start(serde_json::json!({ "result": true }));
+    engine.set_delay(Duration::from_secs(2));
+    assert_eq!(status_with(&engine).await, StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,92 @@
+//! The library middleware on an app of its own, wired as in `examples/protected_app.rs`.
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse, Responder};
+use managed_identity_concept::testing::TestTokenFactory;
+use managed_identity_concept::{BearerAuth, BearerAuthConfig, Claims, JwtValidator, Principal};
+
+async fn whoami(claims: Claims) -> impl Responder {
+    HttpResponse::Ok().json(claims)
+}
+
+async fn me(principal: Principal) -> impl Responder {
+    HttpResponse::Ok().json(principal)
+}
+
+async fn public() -> impl Responder {
+    HttpResponse::Ok().body("Hello from a public route")
+}
+
+/// Sends a GET for `path` with `token` to the example's app, which trusts the keys of
+/// `factory` for its default tenant.
+async fn call(factory: &TestTokenFactory, path: &str, token: Option<&str>) -> ServiceResponse {
+    let jwks = factory.jwks_cache().expect("write the JWKS");
+    let validator = JwtValidator::for_tenant(jwks.jwks_url(), factory.audience(), "test-tenant");
+    let config =
+        BearerAuthConfig::new(validator).with_required_roles(vec!["Task.HelloWorld".to_string()]);
+    let app = test::init_service(
+        App::new().route("/public", web::get().to(public)).service(
+            web::scope("/api")
+                .wrap(BearerAuth::new(config))
+                .route("/whoami", web::get().to(whoami))
+                .route("/me", web::get().to(me)),
+        ),
+    )
+    .await;
+    let mut request = test::TestRequest::get().uri(path);
+    if let Some(token) = token {
+        request = request.insert_header(("Authorization", format!("Bearer {}", token)));
+    }
+    test::call_service(&app, request.to_request()).await
+}
+
+#[actix_web::test]
+async fn public_routes_need_no_token() {
+    let factory = TestTokenFactory::new().unwrap();
+    let response = call(&factory, "/public", None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn protected_routes_get_the_claims_of_a_valid_token() {
+    let factory = TestTokenFactory::new().unwrap();
+    let token = factory
+        .token()
+        .with_subject("caller")
+        .with_roles(&["Task.HelloWorld"])
+        .sign()
+        .unwrap();
+
+    let response = call(&factory, "/api/whoami", Some(&token)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let claims: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(claims["sub"], "caller");
+
+    let response = call(&factory, "/api/me", Some(&token)).await;
+    let principal: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(principal["subject"], "caller");
+    assert_eq!(principal["roles"], serde_json::json!(["Task.HelloWorld"]));
+}
+
+#[actix_web::test]
+async fn protected_routes_refuse_missing_and_unauthorized_tokens() {
+    let factory = TestTokenFactory::new().unwrap();
+
+    let response = call(&factory, "/api/whoami", None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().contains_key("www-authenticate"));
+
+    let other_tenant = factory
+        .token()
+        .with_issuer("https://sts.windows.net/other-tenant/")
+        .with_roles(&["Task.HelloWorld"])
+        .sign()
+        .unwrap();
+    let response = call(&factory, "/api/whoami", Some(&other_tenant)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let no_role = factory.token().sign().unwrap();
+    let response = call(&factory, "/api/me", Some(&no_role)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
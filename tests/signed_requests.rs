@@ -0,0 +1,114 @@
+//! Requests whose signed request and `Authorization` header carry different tokens, under each
+//! `TokenConflictPolicy`.
+
+#![cfg(feature = "signed-requests")]
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use managed_identity_concept::signed_request::SignedRequestUnwrapper;
+use managed_identity_concept::testing::TestTokenFactory;
+use managed_identity_concept::{BearerAuth, BearerAuthConfig, TokenConflictPolicy};
+use std::sync::Arc;
+
+/// The status and body answered for a request carrying `signed_token` wrapped in a signed
+/// request and `authorization_token` as a bearer token. Only tokens with `Task.HelloWorld` are
+/// let through, so the token that was used shows in the status.
+async fn call(
+    policy: TokenConflictPolicy,
+    signed_token: impl FnOnce(&TestTokenFactory) -> String,
+    authorization_token: impl FnOnce(&TestTokenFactory) -> String,
+) -> (StatusCode, actix_web::web::Bytes) {
+    let factory = TestTokenFactory::new().expect("generate the test key");
+    let partner = TestTokenFactory::new()
+        .expect("generate the partner key")
+        .with_kid("partner-key");
+    let unwrapper =
+        SignedRequestUnwrapper::new(Arc::new(partner.jwks_cache().expect("write the JWKS")));
+    let config = BearerAuthConfig::new(factory.validator().expect("write the JWKS"))
+        .with_required_roles(vec!["Task.HelloWorld".to_string()])
+        .with_signed_requests(unwrapper)
+        .with_token_conflict_policy(policy);
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/api_protected")
+                .wrap(BearerAuth::new(config))
+                .to(HttpResponse::Ok),
+        ),
+    )
+    .await;
+    let wrapper = partner
+        .token()
+        .with_claim("access_token", signed_token(&factory))
+        .sign()
+        .unwrap();
+    let request = test::TestRequest::get()
+        .uri("/api_protected")
+        .insert_header(("Signed-Request", wrapper))
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", authorization_token(&factory)),
+        ))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    let status = response.status();
+    (status, test::read_body(response).await)
+}
+
+fn authorized(factory: &TestTokenFactory) -> String {
+    factory
+        .token()
+        .with_roles(&["Task.HelloWorld"])
+        .sign()
+        .unwrap()
+}
+
+fn unauthorized(factory: &TestTokenFactory) -> String {
+    factory.token().with_roles(&["Task.Other"]).sign().unwrap()
+}
+
+#[actix_web::test]
+async fn first_wins_uses_the_signed_request() {
+    let (status, _) = call(TokenConflictPolicy::FirstWins, authorized, unauthorized).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = call(TokenConflictPolicy::FirstWins, unauthorized, authorized).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn reject_on_conflict_refuses_different_tokens() {
+    let (status, body) = call(
+        TokenConflictPolicy::RejectOnConflict,
+        authorized,
+        unauthorized,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"], "ambiguous_token");
+}
+
+#[actix_web::test]
+async fn reject_on_conflict_accepts_the_same_token_twice() {
+    let token = std::cell::OnceCell::new();
+    let (status, _) = call(
+        TokenConflictPolicy::RejectOnConflict,
+        |factory| token.get_or_init(|| authorized(factory)).clone(),
+        |factory| token.get_or_init(|| authorized(factory)).clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn policies_parse_from_their_names() {
+    assert_eq!(
+        " First-Wins ".parse::<TokenConflictPolicy>(),
+        Ok(TokenConflictPolicy::FirstWins)
+    );
+    assert_eq!(
+        "reject-on-conflict".parse::<TokenConflictPolicy>(),
+        Ok(TokenConflictPolicy::RejectOnConflict)
+    );
+    assert!("last-wins".parse::<TokenConflictPolicy>().is_err());
+}
@@ -0,0 +1,100 @@
+//! Tokens minted by `TestTokenFactory`, validated by `JwtValidator` and `BearerAuth`.
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use managed_identity_concept::claims::Claims;
+use managed_identity_concept::middleware::{BearerAuth, BearerAuthConfig};
+use managed_identity_concept::testing::TestTokenFactory;
+use managed_identity_concept::validator::ValidationError;
+
+fn factory() -> TestTokenFactory {
+    TestTokenFactory::new().expect("generate the test key")
+}
+
+async fn status_for(factory: &TestTokenFactory, token: &str) -> StatusCode {
+    let config = BearerAuthConfig::new(factory.validator().expect("write the JWKS"))
+        .with_required_roles(vec!["Task.HelloWorld".to_string()]);
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/api_protected")
+                .wrap(BearerAuth::new(config))
+                .to(|claims: Claims| async move { HttpResponse::Ok().body(claims.sub) }),
+        ),
+    )
+    .await;
+    let request = test::TestRequest::get()
+        .uri("/api_protected")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    test::call_service(&app, request).await.status()
+}
+
+#[actix_web::test]
+async fn validator_accepts_a_valid_token() {
+    let factory = factory();
+    let token = factory.token().with_subject("caller").sign().unwrap();
+    let claims = factory.validator().unwrap().validate(&token).await.unwrap();
+    assert_eq!(claims.sub, "caller");
+    assert_eq!(claims.aud, vec![factory.audience().to_string()]);
+    assert_eq!(claims.iss, factory.issuer());
+}
+
+#[actix_web::test]
+async fn validator_rejects_an_expired_token() {
+    let factory = factory();
+    let token = factory.token().expires_in(-600).sign().unwrap();
+    let result = factory.validator().unwrap().validate(&token).await;
+    assert_eq!(result.unwrap_err(), ValidationError::InvalidToken);
+}
+
+#[actix_web::test]
+async fn validator_rejects_a_token_for_another_audience() {
+    let factory = factory();
+    let token = factory.token().with_audience("api://other").sign().unwrap();
+    let result = factory.validator().unwrap().validate(&token).await;
+    assert_eq!(result.unwrap_err(), ValidationError::InvalidToken);
+}
+
+#[actix_web::test]
+async fn bearer_auth_accepts_a_token_with_the_required_role() {
+    let factory = factory();
+    let token = factory
+        .token()
+        .with_roles(&["Task.HelloWorld"])
+        .sign()
+        .unwrap();
+    assert_eq!(status_for(&factory, &token).await, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn bearer_auth_rejects_an_expired_token() {
+    let factory = factory();
+    let token = factory
+        .token()
+        .with_roles(&["Task.HelloWorld"])
+        .expires_in(-600)
+        .sign()
+        .unwrap();
+    assert_eq!(status_for(&factory, &token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn bearer_auth_rejects_a_token_for_another_audience() {
+    let factory = factory();
+    let token = factory
+        .token()
+        .with_roles(&["Task.HelloWorld"])
+        .with_audience("api://other")
+        .sign()
+        .unwrap();
+    assert_eq!(status_for(&factory, &token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn bearer_auth_rejects_a_token_missing_the_required_role() {
+    let factory = factory();
+    let token = factory.token().with_roles(&["Task.Other"]).sign().unwrap();
+    assert_eq!(status_for(&factory, &token).await, StatusCode::FORBIDDEN);
+    let token = factory.token().sign().unwrap();
+    assert_eq!(status_for(&factory, &token).await, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,87 @@
+//! `BearerAuth` validating through a fake `TokenValidator`.
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use futures_util::future::BoxFuture;
+use managed_identity_concept::timing::ServerTiming;
+use managed_identity_concept::{
+    BearerAuth, BearerAuthConfig, Claims, JwksCache, JwtValidator, TokenValidator, ValidationError,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Accepts `app-token` (with the `Task.HelloWorld` role) and `user-token` (without roles),
+/// counting the tokens it was asked about.
+#[derive(Default)]
+struct FakeValidator {
+    calls: AtomicU64,
+}
+
+impl TokenValidator for FakeValidator {
+    fn validate_timed<'a>(
+        &'a self,
+        token: &'a str,
+        _timing: &'a mut ServerTiming,
+    ) -> BoxFuture<'a, Result<Claims, ValidationError>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let roles = match token {
+            "app-token" => serde_json::json!(["Task.HelloWorld"]),
+            "user-token" => serde_json::Value::Null,
+            _ => return Box::pin(async { Err(ValidationError::InvalidToken) }),
+        };
+        let claims = serde_json::json!({
+            "aud": "api://fake",
+            "iss": "https://fake/",
+            "sub": token,
+            "exp": i64::MAX,
+            "roles": roles,
+        });
+        Box::pin(async move {
+            serde_json::from_value(claims).map_err(|_| ValidationError::MalformedClaims)
+        })
+    }
+}
+
+async fn status_for(fake: Arc<FakeValidator>, token: &str) -> (StatusCode, String) {
+    // Never consulted: the fake validates every token
+    let unused = JwtValidator::new(Arc::new(JwksCache::from_keys(HashMap::new())), "api://real");
+    let config = BearerAuthConfig::new(unused)
+        .with_token_validator(fake)
+        .with_required_roles(vec!["Task.HelloWorld".to_string()]);
+    let app = test::init_service(
+        App::new().service(
+            web::resource("/api_protected")
+                .wrap(BearerAuth::new(config))
+                .to(|claims: Claims| async move { HttpResponse::Ok().body(claims.sub) }),
+        ),
+    )
+    .await;
+    let request = test::TestRequest::get()
+        .uri("/api_protected")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[actix_web::test]
+async fn bearer_auth_validates_through_the_injected_validator() {
+    let fake = Arc::new(FakeValidator::default());
+
+    assert_eq!(
+        status_for(fake.clone(), "app-token").await,
+        (StatusCode::OK, "app-token".to_string())
+    );
+    assert_eq!(
+        status_for(fake.clone(), "user-token").await.0,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status_for(fake.clone(), "forged-token").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(fake.calls.load(Ordering::SeqCst), 3);
+}